@@ -0,0 +1,38 @@
+# The library page (templates/search.html)
+library-title = My Music!
+whats-new = What's new, pussycat?
+browse-folders = Browse folders
+surprise-me = Surprise me
+random-album = Random album
+shuffle-all = Shuffle all (or the current search)
+library-statistics = Library statistics
+name-this-device = Name this device
+hand-off-to = Play on…
+theme = Theme
+theme-light = Light
+theme-dark = Dark
+theme-high-contrast = High contrast
+search-placeholder = Search...
+no-audio = Your browser does not support the audio element.
+add-to-queue = Add to queue
+
+column-track = Track
+column-song = Song
+column-artist = Artist
+column-album = Album
+column-year = Year
+column-duration = Duration
+unknown-year = N/A
+
+previous-page = Previous
+next-page = Next
+page-of = Page { $page } of { $pages }
+more-results = More results
+other-albums = Other albums:
+
+now-playing = Now Playing:
+now-playing-on = on
+now-playing-by = by
+discography = discography
+composed-by = composed by
+start-radio = Start radio
@@ -0,0 +1,38 @@
+# La página de la biblioteca (templates/search.html)
+library-title = ¡Mi música!
+whats-new = What's new, pussycat?
+browse-folders = Explorar carpetas
+surprise-me = Sorpréndeme
+random-album = Álbum al azar
+shuffle-all = Mezclar todo (o la búsqueda actual)
+library-statistics = Estadísticas de la biblioteca
+name-this-device = Nombrar este dispositivo
+hand-off-to = Reproducir en…
+theme = Tema
+theme-light = Claro
+theme-dark = Oscuro
+theme-high-contrast = Alto contraste
+search-placeholder = Buscar...
+no-audio = Tu navegador no admite el elemento de audio.
+add-to-queue = Añadir a la cola
+
+column-track = Pista
+column-song = Canción
+column-artist = Artista
+column-album = Álbum
+column-year = Año
+column-duration = Duración
+unknown-year = N/D
+
+previous-page = Anterior
+next-page = Siguiente
+page-of = Página { $page } de { $pages }
+more-results = Más resultados
+other-albums = Otros álbumes:
+
+now-playing = Reproduciendo:
+now-playing-on = en
+now-playing-by = de
+discography = discografía
+composed-by = compuesta por
+start-radio = Iniciar radio
@@ -0,0 +1,267 @@
+//! Reports for maintaining the library, served under `/admin`.
+
+use crate::music_db::{missing_tracks, MissingTrack, MusicDB, ScanError, SortBy};
+use crate::song::{Song, SongResult};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+};
+
+#[derive(Deserialize, Debug)]
+pub struct LowBitrateTerms {
+    /// Report songs below this bitrate, in kbps
+    pub below: Option<u16>,
+}
+
+impl LowBitrateTerms {
+    const DEFAULT_THRESHOLD: u16 = 192;
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SilenceTerms {
+    /// Report songs with more silence than this at either end, in seconds
+    pub over: Option<f32>,
+}
+
+impl SilenceTerms {
+    const DEFAULT_THRESHOLD: f32 = 5.0;
+}
+
+#[derive(Deserialize, Debug)]
+pub struct IncompleteTerms {
+    /// Also look up albums without track totals on MusicBrainz
+    pub musicbrainz: Option<bool>,
+}
+
+impl IncompleteTerms {
+    /// The most albums looked up on MusicBrainz per request, at a second each. Those left over
+    /// are looked up by the next request.
+    pub const MUSICBRAINZ_LOOKUPS: usize = 20;
+}
+
+/// Where an album's track count came from.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TrackCountSource {
+    Tags,
+    Musicbrainz,
+}
+
+/// An album with gaps in its track numbering, as listed by `/admin/incomplete-albums`.
+#[derive(Serialize, Debug)]
+pub struct IncompleteAlbum {
+    pub artist: String,
+    pub album: String,
+    pub year: u16,
+    /// How many of its tracks are in the library
+    pub tracks: usize,
+    pub missing_tracks: Vec<MissingTrack>,
+    pub source: TrackCountSource,
+}
+
+/// What `/admin/incomplete-albums` found.
+#[derive(Serialize)]
+pub struct IncompleteAlbums {
+    pub albums: Vec<IncompleteAlbum>,
+    /// How many albums without track totals haven't been checked against MusicBrainz
+    pub unchecked: usize,
+}
+
+/// An album none of whose songs have a track total, so it can only be checked against
+/// MusicBrainz.
+pub struct UncheckedAlbum {
+    pub artist: String,
+    pub album: String,
+    year: u16,
+    /// Each song's disc and track number
+    tracks: Vec<(Option<u16>, Option<u16>)>,
+}
+
+impl UncheckedAlbum {
+    /// Checks the album against how many tracks each of its discs should have. Songs without a
+    /// disc number count as being on the first disc.
+    pub fn check(&self, counts: &[u16]) -> Option<IncompleteAlbum> {
+        let present = self
+            .tracks
+            .iter()
+            .filter_map(|&(disc, track)| Some((disc.unwrap_or(1), track?)))
+            .collect::<HashSet<_>>();
+        // A single disc's tracks needn't have a disc number
+        let numbered = counts.len() > 1 || self.tracks.iter().any(|(disc, _)| disc.is_some());
+
+        let missing_tracks = (1..)
+            .zip(counts)
+            .flat_map(|(disc, &count)| {
+                let present = &present;
+                (1..=count)
+                    .filter(move |&track| !present.contains(&(disc, track)))
+                    .map(move |track| MissingTrack {
+                        disc: numbered.then_some(disc),
+                        track,
+                    })
+            })
+            .collect::<Vec<_>>();
+        (!missing_tracks.is_empty()).then(|| IncompleteAlbum {
+            artist: self.artist.clone(),
+            album: self.album.clone(),
+            year: self.year,
+            tracks: self.tracks.len(),
+            missing_tracks,
+            source: TrackCountSource::Musicbrainz,
+        })
+    }
+}
+
+/// A song whose file couldn't be read when last requested.
+#[derive(Serialize)]
+pub struct UnavailableSong {
+    #[serde(with = "crate::paths::serde_path")]
+    pub path: PathBuf,
+    pub reason: String,
+    pub song: SongResult,
+}
+
+impl MusicDB {
+    /// Finds lossy rips worth replacing: songs encoded below a bitrate threshold, worst first.
+    pub fn low_bitrate(&self, terms: &LowBitrateTerms) -> Vec<SongResult> {
+        let below = terms.below.unwrap_or(LowBitrateTerms::DEFAULT_THRESHOLD);
+
+        let mut songs = self
+            .records
+            .values()
+            .filter(|s| s.bitrate > 0 && s.bitrate < below)
+            .collect::<Vec<_>>();
+        songs.sort_unstable_by(|a, b| {
+            a.bitrate
+                .cmp(&b.bitrate)
+                .then(a.artist_lower.cmp(&b.artist_lower))
+                .then(a.album_lower.cmp(&b.album_lower))
+        });
+
+        songs.into_iter().map(|s| s.into()).collect()
+    }
+
+    /// Finds songs that analysis found long stretches of silence at the start or end of, often a
+    /// sign of a botched rip, longest first.
+    pub fn long_silences(&self, terms: &SilenceTerms) -> Vec<SongResult> {
+        let over = terms.over.unwrap_or(SilenceTerms::DEFAULT_THRESHOLD);
+        let longest = |song: &Song| {
+            song.analysis
+                .map_or(0.0, |a| a.silence.leading.max(a.silence.trailing))
+        };
+
+        let mut songs = self
+            .records
+            .values()
+            .filter(|s| longest(s) > over)
+            .collect::<Vec<_>>();
+        songs.sort_unstable_by(|a, b| {
+            longest(b)
+                .total_cmp(&longest(a))
+                .then(a.artist_lower.cmp(&b.artist_lower))
+                .then(a.album_lower.cmp(&b.album_lower))
+        });
+
+        songs.into_iter().map(|s| s.into()).collect()
+    }
+
+    /// Lists the files and directories that couldn't be read when last scanned, by path.
+    pub fn scan_errors(&self) -> Vec<&ScanError> {
+        self.scan_errors.values().collect()
+    }
+
+    /// Finds albums with gaps in their track numbering, going by their tags' track totals, in
+    /// artist and album order. Albums without any track totals are returned separately, to be
+    /// checked against MusicBrainz.
+    pub fn incomplete_albums(&self) -> (Vec<IncompleteAlbum>, Vec<UncheckedAlbum>) {
+        let mut albums: BTreeMap<(&str, &str), Vec<&Song>> = BTreeMap::new();
+        for song in self.records.values().filter(|s| !s.album_lower.is_empty()) {
+            let name = (&*song.artist_lower, &*song.album_lower);
+            albums.entry(name).or_default().push(song);
+        }
+
+        let mut incomplete = Vec::new();
+        let mut unchecked = Vec::new();
+        for mut songs in albums.into_values() {
+            songs.sort_unstable_by(|&a, &b| a.cmp(b, SortBy::track));
+            let first = songs[0];
+            let year = songs.iter().map(|s| s.year).max().unwrap_or_default();
+
+            if songs.iter().all(|s| s.track_total.is_none()) {
+                unchecked.push(UncheckedAlbum {
+                    artist: first.artist.clone(),
+                    album: first.album.clone(),
+                    year,
+                    tracks: songs.iter().map(|s| (s.disc, s.track)).collect(),
+                });
+                continue;
+            }
+
+            let missing_tracks = missing_tracks(&songs);
+            if !missing_tracks.is_empty() {
+                incomplete.push(IncompleteAlbum {
+                    artist: first.artist.clone(),
+                    album: first.album.clone(),
+                    year,
+                    tracks: songs.len(),
+                    missing_tracks,
+                    source: TrackCountSource::Tags,
+                });
+            }
+        }
+
+        (incomplete, unchecked)
+    }
+
+    /// Lists songs whose files couldn't be read the last time they were requested.
+    pub fn unavailable(&self) -> Vec<UnavailableSong> {
+        let mut songs = self
+            .records
+            .values()
+            .filter_map(|s| {
+                s.unavailable.as_ref().map(|reason| UnavailableSong {
+                    path: s.path.clone(),
+                    reason: reason.clone(),
+                    song: s.into(),
+                })
+            })
+            .collect::<Vec<_>>();
+        songs.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+
+        songs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn album(tracks: Vec<(Option<u16>, Option<u16>)>) -> UncheckedAlbum {
+        UncheckedAlbum {
+            artist: "Nick Drake".to_string(),
+            album: "Pink Moon".to_string(),
+            year: 1972,
+            tracks,
+        }
+    }
+
+    #[test]
+    fn checks_against_track_counts() {
+        let missing = |album: UncheckedAlbum, counts: &[u16]| {
+            album.check(counts).map(|a| {
+                a.missing_tracks
+                    .into_iter()
+                    .map(|m| (m.disc, m.track))
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        let single = || album(vec![(None, Some(1)), (None, Some(2)), (None, Some(4))]);
+        assert_eq!(missing(single(), &[4]), Some(vec![(None, 3)]));
+        assert_eq!(missing(single(), &[2]), None);
+
+        let double = album(vec![(Some(1), Some(1)), (Some(2), Some(2))]);
+        assert_eq!(missing(double, &[1, 2]), Some(vec![(Some(2), 1)]));
+    }
+}
@@ -0,0 +1,10 @@
+use crate::themes::Theme;
+use askama::Template;
+use bwaabwaa::music_db::AlbumDetails;
+
+#[derive(Template)]
+#[template(path = "album.html")]
+pub struct AlbumPage<'a> {
+    pub album: &'a AlbumDetails,
+    pub theme: Theme,
+}
@@ -0,0 +1,568 @@
+//! Listening to the songs themselves, rather than their tags. With `--analyze`, the server works
+//! through every song that hasn't been analyzed in the background, going on serving while it
+//! does, and looks again every so often for songs added since.
+//!
+//! For now, analysis finds each song's tempo, key, loudness, and dynamics, and from those a coarse
+//! mood, so that eg shuffles can be kept upbeat without tagging anything by hand, searches kept to
+//! a running pace, or DJ sets kept in harmony. It also finds any silence at either end, which the
+//! jukebox can skip, and which can give away a botched rip. Only MP3s are analyzed.
+
+use crate::camelot::Key;
+use crate::music_db::MusicDB;
+use crate::song::Song;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs::File,
+    hash::{Hash, Hasher},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use symphonia::core::{
+    audio::SampleBuffer, codecs::DecoderOptions, errors::Error, formats::FormatOptions,
+    io::MediaSourceStream, meta::MetadataOptions, probe::Hint,
+};
+use tokio::sync::Mutex;
+
+/// Bumped whenever analysis finds something new, so that songs analyzed before are done again
+pub const VERSION: u8 = 4;
+
+/// The formats that can be decoded
+const FORMATS: &[&str] = &["mp3"];
+
+/// About the sample rate audio is analyzed at, which is plenty for tempo, key, and loudness
+const ANALYSIS_RATE: u32 = 11025;
+
+/// How long to wait before looking for songs to analyze again
+const RECHECK: Duration = Duration::from_secs(10 * 60);
+
+/// How many songs to analyze between saving the library
+const SAVE_EVERY: usize = 50;
+
+/// How quiet a hundredth of a second must be to count as silence, in dBFS
+const SILENCE: f32 = -60.0;
+
+/// A song's character, coarsely: how energetic it is.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Mood {
+    Calm,
+    Mellow,
+    Upbeat,
+    Intense,
+}
+
+/// One mood, as listed by `/moods`.
+#[derive(Serialize)]
+pub struct MoodCount {
+    pub mood: Mood,
+    /// A color for showing it, as CSS
+    pub color: &'static str,
+    pub songs: usize,
+}
+
+impl Mood {
+    pub const ALL: [Mood; 4] = [Mood::Calm, Mood::Mellow, Mood::Upbeat, Mood::Intense];
+
+    /// A color for showing the mood, from cool to hot, as CSS.
+    pub fn color(self) -> &'static str {
+        match self {
+            Mood::Calm => "#4a90d9",
+            Mood::Mellow => "#59b36b",
+            Mood::Upbeat => "#f2b632",
+            Mood::Intense => "#d9453b",
+        }
+    }
+
+    /// Places a song by how loud and fast it is. A wide dynamic range (as in most classical music)
+    /// makes it calmer than its average loudness alone would.
+    fn classify(bpm: Option<f32>, loudness: f32, dynamics: f32) -> Mood {
+        let energy = ((loudness + 30.0) / 24.0).clamp(0.0, 1.0);
+        let pace = bpm.map_or(0.5, |bpm| ((bpm - 70.0) / 90.0).clamp(0.0, 1.0));
+        let spread = ((dynamics - 6.0) / 20.0).clamp(0.0, 1.0);
+        match 0.5 * energy + 0.5 * pace - 0.2 * spread {
+            arousal if arousal < 0.3 => Mood::Calm,
+            arousal if arousal < 0.55 => Mood::Mellow,
+            arousal if arousal < 0.85 => Mood::Upbeat,
+            _ => Mood::Intense,
+        }
+    }
+}
+
+/// How long the silence at the start and end of a song is, in seconds.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct Silence {
+    pub leading: f32,
+    pub trailing: f32,
+}
+
+impl Silence {
+    pub fn leading(self) -> Duration {
+        Duration::from_secs_f32(self.leading)
+    }
+
+    pub fn trailing(self) -> Duration {
+        Duration::from_secs_f32(self.trailing)
+    }
+}
+
+/// What analyzing a song found.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct Analysis {
+    /// The `VERSION` of the analysis
+    pub version: u8,
+    pub mood: Mood,
+    /// The tempo, in beats per minute, if it has a steady one
+    #[serde(default)]
+    pub bpm: Option<f32>,
+    /// The key, if it's clearly in one
+    #[serde(default)]
+    pub key: Option<Key>,
+    /// Average loudness, in dBFS
+    pub loudness: f32,
+    /// How much louder its loud passages are than its quiet ones, in dB
+    pub dynamics: f32,
+    #[serde(default)]
+    pub silence: Silence,
+}
+
+// Songs are hashed for their ids; floats don't implement `Hash`, but their bits do
+impl Hash for Analysis {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.version.hash(state);
+        self.mood.hash(state);
+        self.bpm.map(f32::to_bits).hash(state);
+        self.key.hash(state);
+        self.loudness.to_bits().hash(state);
+        self.dynamics.to_bits().hash(state);
+        self.silence.leading.to_bits().hash(state);
+        self.silence.trailing.to_bits().hash(state);
+    }
+}
+
+/// Decoded audio, mixed down to mono.
+pub(crate) struct Audio {
+    pub samples: Vec<f32>,
+    pub rate: u32,
+}
+
+/// Decodes a file, or `length` of it from `from`, mixing it down to mono and reducing its sample
+/// rate to about `ANALYSIS_RATE`.
+pub(crate) fn decode(
+    path: &Path,
+    from: Duration,
+    length: Option<Duration>,
+) -> Result<Audio, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+    let probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(|e| e.to_string())?;
+    let mut format = probed.format;
+    let track = format.default_track().ok_or("it has no audio")?;
+    let track_id = track.id;
+    let rate = track
+        .codec_params
+        .sample_rate
+        .ok_or("its sample rate isn't known")?;
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(|e| e.to_string())?;
+
+    let factor = (rate / ANALYSIS_RATE).max(1) as usize;
+    // How many of the file's samples to skip, and how many of `samples` to stop at
+    let mut skip = (from.as_secs_f64() * rate as f64) as u64;
+    let limit = length.map_or(usize::MAX, |length| {
+        (length.as_secs_f64() * rate as f64) as usize / factor
+    });
+    let mut samples = Vec::new();
+    // The mono samples not yet averaged into one of `samples`
+    let (mut sum, mut summed) = (0.0, 0);
+    'packets: while samples.len() < limit {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.to_string()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A corrupt frame or two shouldn't spoil the rest
+            Err(Error::DecodeError(_)) => continue,
+            Err(e) => return Err(e.to_string()),
+        };
+        let spec = *decoded.spec();
+        let channels = spec.channels.count().max(1);
+        let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        buffer.copy_interleaved_ref(decoded);
+        for frame in buffer.samples().chunks(channels) {
+            if skip > 0 {
+                skip -= 1;
+                continue;
+            }
+            if samples.len() == limit {
+                break 'packets;
+            }
+            sum += frame.iter().sum::<f32>() / channels as f32;
+            summed += 1;
+            if summed == factor {
+                samples.push(sum / factor as f32);
+                (sum, summed) = (0.0, 0);
+            }
+        }
+    }
+
+    if samples.is_empty() {
+        return Err("it has no audio".to_string());
+    }
+    Ok(Audio {
+        samples,
+        rate: rate / factor as u32,
+    })
+}
+
+/// The mean power of some samples, in dBFS.
+pub(crate) fn power_db(samples: &[f32]) -> f32 {
+    let power = samples.iter().map(|s| s * s).sum::<f32>() / samples.len().max(1) as f32;
+    (10.0 * power.log10()).max(-100.0)
+}
+
+/// The average loudness, in dBFS.
+fn loudness(audio: &Audio) -> f32 {
+    power_db(&audio.samples)
+}
+
+/// The spread between the loud and quiet passages, in dB: the 95th percentile of the loudness of
+/// each three seconds, less the 10th. Silence isn't counted as a quiet passage.
+pub(crate) fn dynamics(audio: &Audio) -> f32 {
+    let window = audio.rate as usize * 3;
+    let mut levels = audio
+        .samples
+        .chunks(window)
+        .filter(|chunk| chunk.len() == window)
+        .map(power_db)
+        .filter(|&db| db > -60.0)
+        .collect::<Vec<_>>();
+    if levels.len() < 2 {
+        return 0.0;
+    }
+    levels.sort_by(f32::total_cmp);
+    let at = |fraction: f32| levels[((levels.len() - 1) as f32 * fraction).round() as usize];
+    at(0.95) - at(0.1)
+}
+
+/// How long the song is silent for at the start and end, to the nearest hundredth of a second.
+/// A song that's silent throughout is all leading silence.
+fn silence(audio: &Audio) -> Silence {
+    let hop = (audio.rate / 100).max(1) as usize;
+    let quiet = audio
+        .samples
+        .chunks(hop)
+        .map(|chunk| power_db(chunk) < SILENCE)
+        .collect::<Vec<_>>();
+    let leading = quiet.iter().take_while(|&&quiet| quiet).count();
+    let trailing = match leading {
+        all if all == quiet.len() => 0,
+        _ => quiet.iter().rev().take_while(|&&quiet| quiet).count(),
+    };
+    let seconds = |hops: usize| (hops * hop) as f32 / audio.rate as f32;
+    Silence {
+        leading: seconds(leading).min(audio.samples.len() as f32 / audio.rate as f32),
+        trailing: seconds(trailing),
+    }
+}
+
+/// Estimates the tempo, in beats per minute, from how regularly the loudness jumps.
+///
+/// The loudness of each hundredth of a second is compared with the one before, and the rises are
+/// autocorrelated: the lag with the strongest correlation is the beat. Tempos near 120 are
+/// favored, since a beat also correlates with half and double its tempo.
+fn tempo(audio: &Audio) -> Option<f32> {
+    let hop = (audio.rate / 100).max(1) as usize;
+    let fps = audio.rate as f32 / hop as f32;
+    let levels = audio
+        .samples
+        .chunks_exact(hop)
+        .map(|chunk| (chunk.iter().map(|s| s * s).sum::<f32>() / hop as f32 + 1e-10).log10())
+        .collect::<Vec<_>>();
+    let mut onsets = levels
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).max(0.0))
+        .collect::<Vec<_>>();
+    // Too short to find a beat in
+    if onsets.len() < (fps * 10.0) as usize {
+        return None;
+    }
+    let mean = onsets.iter().sum::<f32>() / onsets.len() as f32;
+    onsets.iter_mut().for_each(|onset| *onset -= mean);
+
+    let correlation = |lag: usize| -> f32 {
+        onsets
+            .iter()
+            .zip(&onsets[lag..])
+            .map(|(a, b)| a * b)
+            .sum::<f32>()
+            / (onsets.len() - lag) as f32
+    };
+    let (shortest, longest) = ((fps * 60.0 / 200.0) as usize, (fps * 60.0 / 50.0) as usize);
+    // Beats rarely fall a whole number of hundredths apart, so each lag takes in its neighbors
+    let correlations = (shortest - 2..=longest + 2)
+        .map(correlation)
+        .collect::<Vec<_>>()
+        .windows(3)
+        .map(|three| three.iter().sum::<f32>())
+        .collect::<Vec<_>>();
+    let weight = |lag: f32| {
+        let octaves = (fps * 60.0 / lag / 120.0).log2();
+        (-0.5 * (octaves / 0.9).powi(2)).exp()
+    };
+    let (best, &peak) = correlations[1..correlations.len() - 1]
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (i + 1, c))
+        .max_by(|(i, a), (j, b)| {
+            let lag = |i: usize| (i + shortest - 1) as f32;
+            (*a * weight(lag(*i))).total_cmp(&(*b * weight(lag(*j))))
+        })?;
+    if peak <= 0.0 {
+        return None;
+    }
+
+    // Between lags, the peak is likely nearer the stronger of its neighbors
+    let (before, after) = (correlations[best - 1], correlations[best + 1]);
+    let curve = before - 2.0 * peak + after;
+    let offset = if curve < 0.0 {
+        (0.5 * (before - after) / curve).clamp(-0.5, 0.5)
+    } else {
+        0.0
+    };
+    let lag = (best + shortest - 1) as f32 + offset;
+    Some(fps * 60.0 / lag)
+}
+
+/// Estimates the key from how much of each pitch class the song has.
+///
+/// Each pitch from G2 to B5 is measured (with the Goertzel algorithm) in every few tenths of a
+/// second of the song, and the pitches summed by pitch class. The key is the one whose profile
+/// best matches the sums.
+fn key(audio: &Audio) -> Option<Key> {
+    const FRAME: usize = 4096;
+    let window = (0..FRAME)
+        .map(|i| 0.5 - 0.5 * (std::f32::consts::TAU * i as f32 / FRAME as f32).cos())
+        .collect::<Vec<_>>();
+    // The MIDI note numbers of G2 and B5
+    let coefficients = (43..=83)
+        .map(|note: u8| {
+            let frequency = 440.0 * 2f32.powf((note as f32 - 69.0) / 12.0);
+            let omega = std::f32::consts::TAU * frequency / audio.rate as f32;
+            (note % 12, 2.0 * omega.cos())
+        })
+        .collect::<Vec<_>>();
+
+    let mut chroma = [0.0; 12];
+    for frame in audio.samples.chunks_exact(FRAME) {
+        if power_db(frame) < -60.0 {
+            continue;
+        }
+        for &(class, coefficient) in &coefficients {
+            let (mut s1, mut s2) = (0.0, 0.0);
+            for (sample, w) in frame.iter().zip(&window) {
+                let s0 = sample * w + coefficient * s1 - s2;
+                (s2, s1) = (s1, s0);
+            }
+            let power = s1 * s1 + s2 * s2 - coefficient * s1 * s2;
+            chroma[class as usize] += power.max(0.0).sqrt();
+        }
+    }
+    Key::estimate(&chroma)
+}
+
+/// Analyzes a song's file.
+pub fn analyze(path: &Path) -> Result<Analysis, String> {
+    let audio = decode(path, Duration::ZERO, None)?;
+    let (loudness, dynamics) = (loudness(&audio), dynamics(&audio));
+    let bpm = tempo(&audio).map(|bpm| (bpm * 10.0).round() / 10.0);
+    Ok(Analysis {
+        version: VERSION,
+        mood: Mood::classify(bpm, loudness, dynamics),
+        bpm,
+        key: key(&audio),
+        loudness,
+        dynamics,
+        silence: silence(&audio),
+    })
+}
+
+/// Whether a song's file can be decoded, for analysis or a preview.
+pub fn decodable(song: &Song) -> bool {
+    FORMATS.contains(&song.format().as_str()) && !crate::paths::is_remote(&song.path)
+}
+
+/// Whether a song is yet to be analyzed, or was by an older version of the analysis.
+pub fn pending(song: &Song) -> bool {
+    song.analysis.is_none_or(|a| a.version < VERSION) && decodable(song)
+}
+
+impl MusicDB {
+    /// Every mood, and how many songs have it.
+    pub fn moods(&self) -> Vec<MoodCount> {
+        Mood::ALL
+            .into_iter()
+            .map(|mood| MoodCount {
+                mood,
+                color: mood.color(),
+                songs: self
+                    .records
+                    .values()
+                    .filter(|s| s.analysis.is_some_and(|a| a.mood == mood))
+                    .count(),
+            })
+            .collect()
+    }
+}
+
+/// Analyzes every song that needs it, then looks for more every so often, until the server stops.
+/// Songs that can't be analyzed are skipped until it restarts.
+pub async fn run(database: Arc<Mutex<MusicDB>>) {
+    let mut failed = HashSet::new();
+    loop {
+        let songs = {
+            let db = database.lock().await;
+            db.records
+                .values()
+                .filter(|s| pending(s) && !failed.contains(&s.id))
+                .map(|s| (s.id, s.path.clone()))
+                .collect::<Vec<_>>()
+        };
+        if !songs.is_empty() {
+            println!("Analyzing {} songs...", songs.len());
+        }
+
+        let started = std::time::Instant::now();
+        let mut analyzed = 0;
+        for (id, path) in songs {
+            let analysis = {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || analyze(&path)).await
+            };
+            match analysis {
+                Ok(Ok(analysis)) => {
+                    let mut db = database.lock().await;
+                    if let Some(song) = db.records.get_mut(&id) {
+                        song.analysis = Some(analysis);
+                        analyzed += 1;
+                        if analyzed % SAVE_EVERY == 0 {
+                            db.mark_changed();
+                            db.save();
+                        }
+                    }
+                }
+                Ok(Err(e)) => {
+                    eprintln!("Unable to analyze {}: {}", path.display(), e);
+                    failed.insert(id);
+                }
+                Err(_) => {
+                    failed.insert(id);
+                }
+            }
+        }
+        if analyzed > 0 {
+            let mut db = database.lock().await;
+            db.mark_changed();
+            db.save();
+            println!("Analyzed {} songs in {:.2?}", analyzed, started.elapsed());
+        }
+
+        tokio::time::sleep(RECHECK).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 1kHz tick, a fiftieth of a second long, at every beat.
+    fn clicks(bpm: f32, seconds: f32) -> Audio {
+        let rate = ANALYSIS_RATE;
+        let beat = (rate as f32 * 60.0 / bpm) as usize;
+        let tick = rate as usize / 50;
+        let samples = (0..(rate as f32 * seconds) as usize)
+            .map(|i| match i % beat < tick {
+                true => 0.5 * (i as f32 * 1000.0 * std::f32::consts::TAU / rate as f32).sin(),
+                false => 0.0,
+            })
+            .collect();
+        Audio { samples, rate }
+    }
+
+    #[test]
+    fn finds_the_tempo() {
+        for bpm in [72.0, 96.0, 120.0, 140.0, 160.0] {
+            let found = tempo(&clicks(bpm, 30.0)).unwrap();
+            assert!((found - bpm).abs() < 2.0, "{} found as {}", bpm, found);
+        }
+        assert_eq!(tempo(&clicks(120.0, 5.0)), None);
+    }
+
+    /// Chords of sine waves, a second each, by their MIDI note numbers.
+    fn chords(chords: &[&[u8]]) -> Audio {
+        let rate = ANALYSIS_RATE;
+        let samples = chords
+            .iter()
+            .flat_map(|notes| {
+                (0..rate).map(move |i| {
+                    let t = i as f32 / rate as f32;
+                    notes
+                        .iter()
+                        .map(|&note| 440.0 * 2f32.powf((note as f32 - 69.0) / 12.0))
+                        .map(|frequency| 0.2 * (std::f32::consts::TAU * frequency * t).sin())
+                        .sum::<f32>()
+                })
+            })
+            .collect();
+        Audio { samples, rate }
+    }
+
+    #[test]
+    fn finds_the_key() {
+        // I-IV-V-I in G major, and i-iv-V-i in A minor
+        let g_major = chords(&[&[55, 59, 62], &[60, 64, 67], &[62, 66, 69], &[55, 59, 62]]);
+        assert_eq!(key(&g_major).map(|k| k.to_string()), Some("9B".to_string()));
+        let a_minor = chords(&[&[57, 60, 64], &[62, 65, 69], &[64, 68, 71], &[57, 60, 64]]);
+        assert_eq!(key(&a_minor).map(|k| k.to_string()), Some("8A".to_string()));
+        assert_eq!(key(&chords(&[&[], &[]])), None);
+    }
+
+    #[test]
+    fn finds_silence() {
+        let mut audio = chords(&[&[], &[], &[57, 60, 64], &[]]);
+        let found = silence(&audio);
+        assert!((found.leading - 2.0).abs() < 0.02, "{:?}", found);
+        assert!((found.trailing - 1.0).abs() < 0.02, "{:?}", found);
+
+        audio.samples.iter_mut().for_each(|s| *s = 0.0);
+        let found = silence(&audio);
+        assert!((found.leading - 4.0).abs() < 0.02, "{:?}", found);
+        assert_eq!(found.trailing, 0.0);
+    }
+
+    #[test]
+    fn classifies_moods() {
+        assert_eq!(Mood::classify(Some(75.0), -22.0, 20.0), Mood::Calm);
+        assert_eq!(Mood::classify(Some(75.0), -14.0, 8.0), Mood::Mellow);
+        assert_eq!(Mood::classify(Some(128.0), -8.0, 4.0), Mood::Upbeat);
+        assert_eq!(Mood::classify(Some(170.0), -6.0, 3.0), Mood::Intense);
+    }
+}
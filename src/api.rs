@@ -0,0 +1,94 @@
+//! The versioned JSON API under `/api/v1`, kept stable so that client apps and integrations (eg,
+//! a Home Assistant `media_player`) can be built against it without tracking the HTML pages.
+//!
+//! Every JSON endpoint is available under `/api/v1` with the same path and response as the
+//! unversioned one it aliases, eg `/api/v1/search` for `/search`. The endpoints that render a
+//! page (`/artist`, `/album`, `/stats`) always return JSON under `/api/v1`, whatever the `Accept`
+//! header says. The unversioned paths remain, but new clients should use `/api/v1`.
+//!
+//! Playback control lives only under `/api/v1`:
+//!
+//! - `GET /api/v1/state` returns an [`ApiState`].
+//! - `POST /api/v1/command` takes an [`ApiCommand`] as a JSON body, eg `{"command": "pause"}` or
+//!   `{"command": "volume", "level": 0.5}`, and returns the resulting [`ApiState`].
+//!
+//! Playback commands (`play`, `pause`, `stop`, `next`, `volume`) need the server to be running in
+//! jukebox mode (`--jukebox`), and fail with a 400 otherwise; `enqueue` always works.
+//!
+//! So do `POST /player/sleep?minutes=30`, which fades out and stops playback after that long
+//! (`minutes=0` cancels it), and `POST /player/stop-after-current`, which stops it once the song
+//! playing finishes (`?on=false` changes that back). Both return the resulting [`ApiState`].
+//!
+//! `GET /player/settings` returns the jukebox's [`PlayerSettings`](bwaabwaa::jukebox::PlayerSettings):
+//! how many seconds songs crossfade for, whether they play gaplessly, and how ReplayGain evens out
+//! their loudness (`replay_gain` is `off`, `track`, or `album`, `preamp` is in dB, and
+//! `prevent_clipping` limits the gain to each song's peak), and whether the silence `--analyze`
+//! found at either end of songs is skipped (`trim_silence`). `POST` a JSON body with any of them,
+//! eg `{"crossfade": 5}` or `{"replay_gain": "album", "preamp": 3}`, to change them; it returns
+//! the result.
+//!
+//! `GET /player/eq` returns an [`EqState`]: the equalizer for the audio output playing. `POST` a
+//! JSON body with any of `gains` (in dB, one per band), `mono`, and `balance` to change it; it's
+//! saved for that output, and applied from then on.
+//!
+//! `GET /player/outputs` returns an [`OutputsState`]: the audio outputs the server has, and which
+//! one's playing. `POST` a JSON body of `{"name": ...}` to switch to another; whatever's playing
+//! carries on there, with that output's equalizer. It returns the result.
+
+use bwaabwaa::{dsp::Equalizer, jukebox::Status, queue::QueueState, song::SongResult};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize)]
+pub struct ApiState {
+    /// Whether the server is playing through its own audio output
+    pub jukebox: bool,
+    pub status: Status,
+    /// From 0.0 (silent) to 1.0 (full volume)
+    pub volume: f32,
+    /// How far into `now_playing` playback is, in seconds
+    pub position: f64,
+    pub now_playing: Option<SongResult>,
+    /// How long until the sleep timer stops playback, in seconds, if it's set
+    pub sleep_in: Option<f64>,
+    /// Whether playback stops once `now_playing` finishes
+    pub stop_after_current: bool,
+    pub queue: QueueState,
+}
+
+#[derive(Serialize)]
+pub struct EqState {
+    /// The audio output's name, which the equalizer is saved under
+    pub device: Option<String>,
+    /// The center frequency of each band, in Hz
+    pub bands: [f32; 10],
+    #[serde(flatten)]
+    pub equalizer: Equalizer,
+}
+
+#[derive(Serialize)]
+pub struct OutputsState {
+    /// The name of the audio output playing, if it has one
+    pub current: Option<String>,
+    /// The names of every audio output, to switch to
+    pub outputs: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ApiCommand {
+    /// Resumes if paused; otherwise starts the next song in the queue
+    Play,
+    Pause,
+    Stop,
+    /// Skips to the next song in the queue
+    Next,
+    Volume {
+        /// From 0.0 (silent) to 1.0 (full volume)
+        level: f32,
+    },
+    /// Adds a song to the queue: either `id`, or the best match for `query`
+    Enqueue {
+        id: Option<String>,
+        query: Option<String>,
+    },
+}
@@ -0,0 +1,211 @@
+//! API keys for scripts and other clients that aren't a browser, saved in `keys.json`. Only a hash
+//! of each key is kept; the key itself is shown once, when it's created.
+//!
+//! A key looks like `bwaa_<id>_<secret>`. The id is not secret: it's how a key is listed and
+//! revoked.
+
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, fs::File, io::BufReader, path::Path};
+
+pub(crate) const KEYS_FILE: &str = "keys.json";
+
+const PREFIX: &str = "bwaa_";
+
+/// How often a key's `last_used` is saved; it's only a rough guide to which keys are still in use
+const LAST_USED_EVERY: u64 = 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    /// What it's for, eg "nightly rescan"
+    pub name: String,
+    /// Whose it is
+    pub user: String,
+    /// In seconds since the Unix epoch
+    pub created: u64,
+    pub last_used: Option<u64>,
+    /// The SHA-256 of the secret, in hex
+    #[serde(skip_serializing_if = "String::is_empty")]
+    hash: String,
+}
+
+/// A key, as listed by `/admin/keys`: without its hash.
+#[derive(Serialize)]
+pub struct KeySummary {
+    pub id: String,
+    #[serde(flatten)]
+    pub key: ApiKey,
+}
+
+/// A key that was just created, with the only copy of its secret.
+#[derive(Serialize)]
+pub struct NewKey {
+    pub id: String,
+    pub key: String,
+}
+
+/// The API keys, by id. Made with `default()`, eg in tests, they're only kept in memory.
+#[derive(Default)]
+pub struct ApiKeys {
+    keys: BTreeMap<String, ApiKey>,
+    /// Whether changes are saved to `keys.json`
+    saved: bool,
+}
+
+fn hash(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn random(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// The keys saved in `path`, by id, or none if there's no such file.
+pub(crate) fn read(path: &Path) -> Result<BTreeMap<String, ApiKey>, String> {
+    let keys = match File::open(path) {
+        Ok(file) => serde_json::from_reader(BufReader::new(file)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(format!("unable to read {}: {}", path.display(), e)),
+    };
+    keys.map_err(|e| format!("unable to read {}: {}", path.display(), e))
+}
+
+impl ApiKeys {
+    /// Loads the keys. Without `keys.json` there are none; but one that can't be read is an
+    /// error, since taking it for none would open `/admin` and `/api/v1` to everyone.
+    pub fn load() -> Result<Self, String> {
+        Ok(Self {
+            keys: read(Path::new(KEYS_FILE))?,
+            saved: true,
+        })
+    }
+
+    fn save(&self) {
+        if !self.saved {
+            return;
+        }
+        let saved = serde_json::to_vec_pretty(&self.keys)
+            .map_err(std::io::Error::from)
+            .and_then(|json| crate::paths::replace(Path::new(KEYS_FILE), &json));
+        if let Err(e) = saved {
+            eprintln!("Unable to save API keys: {:?}", e);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Creates a key, returning it. It can't be gotten again later.
+    pub fn create(&mut self, name: String, user: String) -> NewKey {
+        let id = random(8);
+        let secret = random(32);
+        self.keys.insert(
+            id.clone(),
+            ApiKey {
+                name,
+                user,
+                created: crate::history::now(),
+                last_used: None,
+                hash: hash(&secret),
+            },
+        );
+        self.save();
+
+        NewKey {
+            key: format!("{PREFIX}{id}_{secret}"),
+            id,
+        }
+    }
+
+    /// Lists the keys, without their hashes.
+    pub fn list(&self) -> Vec<KeySummary> {
+        self.keys
+            .iter()
+            .map(|(id, key)| KeySummary {
+                id: id.clone(),
+                key: ApiKey {
+                    hash: String::new(),
+                    ..key.clone()
+                },
+            })
+            .collect()
+    }
+
+    /// Revokes a key; returns whether there was one with that id.
+    pub fn revoke(&mut self, id: &str) -> bool {
+        let revoked = self.keys.remove(id).is_some();
+        if revoked {
+            self.save();
+        }
+        revoked
+    }
+
+    /// The key `presented` is, if it's valid.
+    pub fn check(&mut self, presented: &str) -> Option<&ApiKey> {
+        let (id, secret) = presented.strip_prefix(PREFIX)?.split_once('_')?;
+        let key = self.keys.get_mut(id)?;
+        if key.hash != hash(secret) {
+            return None;
+        }
+
+        let now = crate::history::now();
+        if key
+            .last_used
+            .is_none_or(|last| now >= last + LAST_USED_EVERY)
+        {
+            key.last_used = Some(now);
+            self.save();
+        }
+
+        self.keys.get(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_broken_files_but_not_missing_ones() {
+        let path = std::env::temp_dir().join(format!("bwaabwaa-keys-{:x}", rand::random::<u64>()));
+        assert!(read(&path).unwrap().is_empty());
+        for broken in ["", "[", "[]", "{\"abc\": {\"name\": \"nightly\"}}"] {
+            std::fs::write(&path, broken).unwrap();
+            assert!(read(&path).is_err(), "{:?}", broken);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn keys_are_checked_until_revoked() {
+        let mut keys = ApiKeys::default();
+        let new = keys.create("nightly rescan".to_string(), "me".to_string());
+        assert!(new.key.starts_with("bwaa_"));
+
+        let key = keys.check(&new.key).unwrap();
+        assert_eq!(key.name, "nightly rescan");
+        assert!(key.last_used.is_some());
+        // The hash isn't listed
+        assert!(keys.list().iter().all(|k| k.key.hash.is_empty()));
+
+        // Right id, wrong secret; and no such id
+        let (id, _) = new.key.rsplit_once('_').unwrap();
+        assert!(keys.check(&format!("{}_{}", id, "x".repeat(32))).is_none());
+        assert!(keys.check("bwaa_nope_secret").is_none());
+        assert!(keys.check("secret").is_none());
+
+        assert!(keys.revoke(&new.id));
+        assert!(!keys.revoke(&new.id));
+        assert!(keys.check(&new.key).is_none());
+        assert!(keys.is_empty());
+    }
+}
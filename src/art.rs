@@ -0,0 +1,113 @@
+use image::imageops::FilterType;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// File stems commonly used for album art, in order of preference.
+const COVER_STEMS: &[&str] = &["cover", "folder", "front", "album"];
+const COVER_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "webp"];
+
+/// Covers are shrunk to this size (at most) before their colors are worked out; that's plenty for
+/// an average, and for a BlurHash with only a few components.
+const THUMBNAIL_SIZE: u32 = 32;
+
+/// How many BlurHash components to use, across and down. 4x3 suits square covers.
+const BLURHASH_COMPONENTS: (u32, u32) = (4, 3);
+
+/// Colors for a placeholder to show while the cover art loads.
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
+pub struct CoverColors {
+    /// The cover's average color, eg "#3a5f8c"
+    pub color: String,
+    /// A blurred version of the cover (see https://blurha.sh)
+    pub blurhash: String,
+}
+
+/// Looks for album art next to `song_path`.
+///
+/// Well-known names (eg, `cover.jpg` or `Folder.png`) are preferred; failing that, any image in
+/// the directory will do.
+pub fn find_cover(song_path: &Path) -> Option<PathBuf> {
+    let directory = song_path.parent()?;
+
+    let images = std::fs::read_dir(directory)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && is_image(path))
+        .collect::<Vec<_>>();
+
+    COVER_STEMS
+        .iter()
+        .find_map(|&name| {
+            images.iter().find(|path| {
+                path.file_stem()
+                    .and_then(|s| s.to_str())
+                    .is_some_and(|s| s.eq_ignore_ascii_case(name))
+            })
+        })
+        .or_else(|| images.first())
+        .cloned()
+}
+
+fn is_image(path: &Path) -> bool {
+    path.extension().and_then(|e| e.to_str()).is_some_and(|e| {
+        COVER_EXTENSIONS
+            .iter()
+            .any(|ext| e.eq_ignore_ascii_case(ext))
+    })
+}
+
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    match &extension[..] {
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        _ => "image/jpeg",
+    }
+}
+
+/// Works out the placeholder colors for a cover image. `None` if it can't be read.
+pub fn cover_colors(cover: &Path) -> Option<CoverColors> {
+    // Going by the content rather than the extension, which is sometimes wrong
+    let image = match image::ImageReader::open(cover)
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(image::ImageError::from)
+        .and_then(|reader| reader.decode())
+    {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("Unable to read cover art {}: {}", cover.display(), e);
+            return None;
+        }
+    };
+    let thumbnail = image
+        .resize(THUMBNAIL_SIZE, THUMBNAIL_SIZE, FilterType::Triangle)
+        .to_rgba8();
+
+    // Weighted by alpha, so that transparent areas don't drag the color toward black
+    let mut sums = [0u64; 3];
+    let mut weight = 0u64;
+    for pixel in thumbnail.pixels() {
+        let alpha = pixel[3] as u64;
+        for (sum, &channel) in sums.iter_mut().zip(&pixel.0[..3]) {
+            *sum += channel as u64 * alpha;
+        }
+        weight += alpha;
+    }
+    let [r, g, b] = sums.map(|sum| sum.checked_div(weight).unwrap_or_default());
+
+    let (x, y) = BLURHASH_COMPONENTS;
+    let blurhash =
+        blurhash::encode(x, y, thumbnail.width(), thumbnail.height(), &thumbnail).ok()?;
+
+    Some(CoverColors {
+        color: format!("#{:02x}{:02x}{:02x}", r, g, b),
+        blurhash,
+    })
+}
@@ -0,0 +1,10 @@
+use crate::themes::Theme;
+use askama::Template;
+use bwaabwaa::music_db::ArtistDetails;
+
+#[derive(Template)]
+#[template(path = "artist.html")]
+pub struct ArtistPage<'a> {
+    pub artist: &'a ArtistDetails,
+    pub theme: Theme,
+}
@@ -0,0 +1,100 @@
+//! Static assets (CSS, scripts, icons) from the `static` directory, embedded in the binary.
+//!
+//! Pages refer to assets through `url`, which puts a hash of the content in the file name (eg
+//! `/static/style.1a2b3c4d.css`). Since that URL changes whenever the file does, browsers are told
+//! to cache it forever; the plain, unhashed URL also works, but is revalidated every time.
+
+use crate::cache::{self, Conditional, Validators};
+use rust_embed::{EmbeddedFile, RustEmbed};
+use warp::http::{Response, StatusCode};
+
+#[derive(RustEmbed)]
+#[folder = "static/"]
+struct Static;
+
+/// How many hex digits of the SHA-256 go in a hashed name
+const HASH_LEN: usize = 8;
+
+/// Gets an asset by its path under `static`.
+pub fn get(name: &str) -> Option<EmbeddedFile> {
+    Static::get(name)
+}
+
+fn hash(file: &EmbeddedFile) -> String {
+    file.metadata.sha256_hash()[..HASH_LEN / 2]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// The content-hashed URL of an asset, eg `/static/style.1a2b3c4d.css` for `style.css`.
+pub fn url(name: &str) -> String {
+    let file = match get(name) {
+        Some(file) => file,
+        // Leave it to 404
+        None => return format!("/static/{}", name),
+    };
+
+    let hash = hash(&file);
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !extension.contains('/') => {
+            format!("/static/{}.{}.{}", stem, hash, extension)
+        }
+        _ => format!("/static/{}.{}", name, hash),
+    }
+}
+
+/// Finds the asset a URL (relative to `/static/`) refers to, and whether it was the hashed URL
+/// of its current content.
+fn resolve(requested: &str) -> Option<(EmbeddedFile, bool)> {
+    if let Some(file) = get(requested) {
+        return Some((file, false));
+    }
+
+    // "style.1a2b3c4d.css" -> "style.css", or "LICENSE.1a2b3c4d" -> "LICENSE"
+    let (rest, extension) = requested.rsplit_once('.')?;
+    let (name, hash) = match rest.rsplit_once('.') {
+        Some((stem, hash)) if hash.len() == HASH_LEN => (format!("{}.{}", stem, extension), hash),
+        _ => (rest.to_string(), extension),
+    };
+
+    let file = get(&name)?;
+    // An old hash still gets the current content, just without the promise that it won't change
+    let current = self::hash(&file) == hash;
+    Some((file, current))
+}
+
+/// Serves an asset from `/static/`.
+pub fn serve(requested: &str, conditional: &Conditional) -> Option<Response<Vec<u8>>> {
+    let (file, immutable) = resolve(requested)?;
+    Some(respond(&file, immutable, conditional))
+}
+
+/// Responds with an asset; when `immutable`, browsers can cache it without ever checking back.
+pub fn respond(
+    file: &EmbeddedFile,
+    immutable: bool,
+    conditional: &Conditional,
+) -> Response<Vec<u8>> {
+    let validators = Validators::for_static(&file.data);
+    let cache_control = if immutable {
+        "public, max-age=31536000, immutable"
+    } else {
+        "no-cache"
+    };
+
+    if conditional.is_fresh(&validators) {
+        let mut response = cache::not_modified(&validators);
+        response
+            .headers_mut()
+            .insert("cache-control", cache_control.parse().unwrap());
+        return response;
+    }
+
+    validators
+        .apply(Response::builder().status(StatusCode::OK))
+        .header("content-type", file.metadata.mimetype())
+        .header("cache-control", cache_control)
+        .body(file.data.to_vec())
+        .unwrap()
+}
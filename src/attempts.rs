@@ -0,0 +1,62 @@
+//! Counting wrong guesses, eg at a guest code or a password, by client, so that a client that makes
+//! too many is turned away for a while rather than left to keep guessing.
+
+use std::{collections::HashMap, net::IpAddr};
+
+/// Wrong guesses made lately, by client: how many, and when the first was.
+pub struct Attempts {
+    failures: HashMap<IpAddr, (u32, u64)>,
+    /// How many wrong guesses a client may make before it's turned away
+    max: u32,
+    /// How long a client is turned away for, in seconds, from its first wrong guess
+    window: u64,
+}
+
+impl Attempts {
+    pub fn new(max: u32, window: u64) -> Self {
+        Attempts {
+            failures: HashMap::new(),
+            max,
+            window,
+        }
+    }
+
+    /// Whether `client` has made too many wrong guesses lately to be let guess again.
+    pub fn refused(&mut self, client: IpAddr) -> bool {
+        let now = crate::history::now();
+        let window = self.window;
+        self.failures
+            .retain(|_, (_, since)| now < since.saturating_add(window));
+        self.failures
+            .get(&client)
+            .is_some_and(|&(count, _)| count >= self.max)
+    }
+
+    /// Counts a wrong guess by `client`.
+    pub fn failed(&mut self, client: IpAddr) {
+        let now = crate::history::now();
+        self.failures.entry(client).or_insert((0, now)).0 += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn turns_away_clients_that_guess_too_often() {
+        let mut attempts = Attempts::new(2, 60);
+        let guesser: IpAddr = "192.168.1.2".parse().unwrap();
+        let other: IpAddr = "192.168.1.3".parse().unwrap();
+
+        attempts.failed(guesser);
+        assert!(!attempts.refused(guesser));
+        attempts.failed(guesser);
+        assert!(attempts.refused(guesser));
+        assert!(!attempts.refused(other));
+
+        // Until the window's over
+        attempts.failures.get_mut(&guesser).unwrap().1 -= 60;
+        assert!(!attempts.refused(guesser));
+    }
+}
@@ -0,0 +1,135 @@
+//! Recently streamed files, kept in memory up to a byte budget given by `--audio-cache=` (eg
+//! `--audio-cache=512M`), so replaying them doesn't wait on a sleeping disk or a remote source.
+//! When the budget is used up, the least recently streamed files are dropped first.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// No one file may take more than this fraction of the budget, so a single long mix can't push
+/// out everything else
+const MAX_SHARE: u64 = 4;
+
+#[derive(Default)]
+pub struct AudioCache {
+    /// In bytes; 0 turns the cache off
+    budget: u64,
+    used: u64,
+    files: HashMap<PathBuf, Cached>,
+    /// Counts up with every use, to find the least recently used file
+    clock: u64,
+}
+
+struct Cached {
+    data: Vec<u8>,
+    /// The file's modification time when it was read, so a changed file isn't served stale
+    modified: Option<SystemTime>,
+    last_used: u64,
+}
+
+impl AudioCache {
+    pub fn new(budget: u64) -> Self {
+        AudioCache {
+            budget,
+            ..Default::default()
+        }
+    }
+
+    /// A copy of the file at `path`, if it's cached and hasn't been modified since.
+    pub fn get(&mut self, path: &Path, modified: Option<SystemTime>) -> Option<Vec<u8>> {
+        let cached = self.files.get_mut(path)?;
+        if cached.modified != modified {
+            let stale = self.files.remove(path)?;
+            self.used -= stale.data.len() as u64;
+            return None;
+        }
+
+        self.clock += 1;
+        cached.last_used = self.clock;
+        Some(cached.data.clone())
+    }
+
+    /// Whether a file of `size` bytes would be kept, so it's worth holding on to while it's read.
+    pub fn takes(&self, size: u64) -> bool {
+        size > 0 && size <= self.budget / MAX_SHARE
+    }
+
+    /// Caches a file that was just read, making room for it if need be.
+    pub fn insert(&mut self, path: &Path, modified: Option<SystemTime>, data: &[u8]) {
+        let size = data.len() as u64;
+        if !self.takes(size) {
+            return;
+        }
+
+        if let Some(old) = self.files.remove(path) {
+            self.used -= old.data.len() as u64;
+        }
+        while self.used + size > self.budget {
+            let Some(oldest) = self
+                .files
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(path, _)| path.clone())
+            else {
+                break;
+            };
+            if let Some(evicted) = self.files.remove(&oldest) {
+                self.used -= evicted.data.len() as u64;
+            }
+        }
+
+        self.clock += 1;
+        self.used += size;
+        self.files.insert(
+            path.to_path_buf(),
+            Cached {
+                data: data.to_vec(),
+                modified,
+                last_used: self.clock,
+            },
+        );
+    }
+}
+
+/// Parses a size like `512M`, `2G`, or `65536` (bytes). Suffixes are powers of 1024.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (number, multiplier) = match s.char_indices().last()? {
+        (i, 'k' | 'K') => (&s[..i], 1 << 10),
+        (i, 'm' | 'M') => (&s[..i], 1 << 20),
+        (i, 'g' | 'G') => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    number.trim().parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_least_recently_used() {
+        let mut cache = AudioCache::new(400);
+        let (a, b, c) = (
+            Path::new("/a.mp3"),
+            Path::new("/b.mp3"),
+            Path::new("/c.mp3"),
+        );
+        cache.insert(a, None, &[1; 100]);
+        cache.insert(b, None, &[2; 100]);
+        cache.get(a, None).unwrap();
+        cache.insert(c, None, &[3; 100]);
+        cache.insert(Path::new("/d.mp3"), None, &[4; 100]);
+        cache.insert(Path::new("/e.mp3"), None, &[5; 100]);
+
+        assert!(cache.get(a, None).is_some());
+        assert!(cache.get(b, None).is_none());
+        assert_eq!(cache.used, 400);
+
+        // A file that's changed since it was cached is read again
+        assert!(cache.get(a, Some(SystemTime::UNIX_EPOCH)).is_none());
+        assert_eq!(parse_size("512M"), Some(512 << 20));
+    }
+}
@@ -0,0 +1,339 @@
+//! A record of changes to the library, the shared queue, and the server's settings, with who made
+//! them and when. It's appended to `audit.jsonl`, one JSON object per line, and never rewritten;
+//! `/admin/audit` lists it, newest first, a page at a time (see `AuditQuery::before`).
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{
+        mpsc::{self, Sender},
+        OnceLock,
+    },
+};
+
+pub(crate) const AUDIT_FILE: &str = "audit.jsonl";
+
+/// Who made changes that weren't asked for by a request, eg scans at startup
+pub const SERVER: &str = "server";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Directories or remote sources were scanned
+    Scan,
+    /// Songs were added to the queue
+    QueueAdd,
+    /// The queue was replaced, eg by a shuffle or a radio station
+    QueueReplace,
+    QueueClear,
+    KeyCreate,
+    KeyRevoke,
+    GuestCodeCreate,
+    GuestCodeExpire,
+    /// Play history and resume positions were imported
+    DataImport,
+    /// A playlist was imported from another service
+    PlaylistImport,
+    /// Duplicate songs were resolved, keeping the best copy of each
+    DuplicatesResolve,
+    /// The server's files were restored from a backup
+    Restore,
+    /// Kid mode was turned on or off
+    KidMode,
+    /// Artists were merged into one; see `overrides`
+    ArtistMerge,
+    /// Artists merged into one were split back out
+    ArtistSplit,
+    /// Labels were added to songs
+    LabelAdd,
+    /// Labels were taken off songs
+    LabelRemove,
+    /// Songs were hidden, or shown again
+    Hide,
+    /// Songs were marked explicit or clean, whatever their tags say
+    Explicit,
+    /// What an album shows in place of its tags was set, or cleared
+    AlbumOverride,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    /// In seconds since the Unix epoch
+    pub at: u64,
+    /// An API key's user and name, eg `me (nightly rescan)`; a user; a guest code's name, eg
+    /// `guest (Saturday's party)`; a client's address; or `server`
+    pub who: String,
+    pub action: Action,
+    /// What was changed, eg a song's id
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub what: String,
+}
+
+/// What the writer is given.
+enum Job {
+    Append(AuditEntry),
+    /// Says so once everything before it is written
+    Flush(Sender<()>),
+}
+
+fn writer() -> &'static Sender<Job> {
+    static WRITER: OnceLock<Sender<Job>> = OnceLock::new();
+    WRITER.get_or_init(|| {
+        let (writer, jobs) = mpsc::channel();
+        std::thread::spawn(move || {
+            for job in jobs {
+                match job {
+                    Job::Append(entry) => {
+                        if let Err(e) = append(Path::new(AUDIT_FILE), &entry) {
+                            eprintln!("Unable to write to {AUDIT_FILE}: {:?}", e);
+                        }
+                    }
+                    Job::Flush(done) => {
+                        let _ = done.send(());
+                    }
+                }
+            }
+        });
+        writer
+    })
+}
+
+/// Appends to the log, in the background, so that a handler holding locks isn't kept waiting on
+/// the disk. Entries are written in the order they're recorded; failures are logged and otherwise
+/// ignored.
+pub fn record(who: &str, action: Action, what: impl Into<String>) {
+    let entry = AuditEntry {
+        at: crate::history::now(),
+        who: who.to_string(),
+        action,
+        what: what.into(),
+    };
+    // Fails only if the writer has gone, which it doesn't
+    let _ = writer().send(Job::Append(entry));
+}
+
+/// Waits until everything recorded has been written, eg before exiting.
+pub fn flush() {
+    let (done, written) = mpsc::channel();
+    if writer().send(Job::Flush(done)).is_ok() {
+        let _ = written.recv();
+    }
+}
+
+fn append(path: &Path, entry: &AuditEntry) -> io::Result<()> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    // One write per entry, so that entries from elsewhere don't interleave
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(line.as_bytes())
+}
+
+/// Query parameters for `/admin/audit`.
+#[derive(Deserialize, Debug, Default)]
+pub struct AuditQuery {
+    /// Only this kind of change
+    pub action: Option<Action>,
+    /// Only changes made by someone whose `who` contains this
+    pub who: Option<String>,
+    /// Only changes from before this time, in seconds since the Unix epoch, for paging
+    pub before: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    const DEFAULT_LIMIT: usize = 100;
+}
+
+/// Reads the log, newest first, from the end of the file back only as far as it needs to. Lines
+/// that can't be read are skipped. It's a read of the disk, so it belongs off the async runtime.
+pub fn read(query: &AuditQuery) -> Vec<AuditEntry> {
+    read_from(Path::new(AUDIT_FILE), query)
+}
+
+fn read_from(path: &Path, query: &AuditQuery) -> Vec<AuditEntry> {
+    let Ok(file) = File::open(path) else {
+        return Vec::new();
+    };
+
+    NewestFirst::new(file)
+        .filter_map(|line| serde_json::from_slice::<AuditEntry>(&line).ok())
+        .filter(|e| query.action.is_none_or(|action| e.action == action))
+        .filter(|e| query.who.as_ref().is_none_or(|who| e.who.contains(who)))
+        .filter(|e| query.before.is_none_or(|before| e.at < before))
+        .take(query.limit.unwrap_or(AuditQuery::DEFAULT_LIMIT))
+        .collect()
+}
+
+/// A file's lines, last first, read a block at a time from its end.
+struct NewestFirst {
+    file: File,
+    /// Where the part of the file not read yet ends
+    unread: u64,
+    /// The start of the earliest line read, which may go on in the block before
+    partial: Vec<u8>,
+    /// Whole lines read, in the file's order
+    lines: Vec<Vec<u8>>,
+}
+
+impl NewestFirst {
+    const BLOCK: u64 = 64 * 1024;
+
+    fn new(file: File) -> Self {
+        let unread = file.metadata().map_or(0, |m| m.len());
+        NewestFirst {
+            file,
+            unread,
+            partial: Vec::new(),
+            lines: Vec::new(),
+        }
+    }
+
+    fn read_block(&mut self) -> io::Result<()> {
+        let start = self.unread.saturating_sub(Self::BLOCK);
+        let mut block = vec![0; (self.unread - start) as usize];
+        self.file.seek(SeekFrom::Start(start))?;
+        self.file.read_exact(&mut block)?;
+        self.unread = start;
+
+        block.append(&mut self.partial);
+        // Everything after the block's first newline is whole lines; before it, unless this is the
+        // start of the file, is the end of a line that started earlier
+        let whole = match block.iter().position(|&b| b == b'\n') {
+            Some(newline) if start > 0 => newline + 1,
+            _ if start > 0 => block.len(),
+            _ => 0,
+        };
+        self.partial = block[..whole].to_vec();
+        self.lines.extend(
+            block[whole..]
+                .split(|&b| b == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(<[u8]>::to_vec),
+        );
+        Ok(())
+    }
+}
+
+impl Iterator for NewestFirst {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        while self.lines.is_empty() && self.unread > 0 {
+            if let Err(e) = self.read_block() {
+                eprintln!("Unable to read {AUDIT_FILE}: {:?}", e);
+                return None;
+            }
+        }
+        self.lines.pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A log of its own under the system's temporary directory.
+    fn log(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "bwaabwaa-audit-{}-{:x}.jsonl",
+            name,
+            rand::random::<u64>()
+        ))
+    }
+
+    fn entry(at: u64, who: &str, action: Action) -> AuditEntry {
+        AuditEntry {
+            at,
+            who: who.to_string(),
+            action,
+            what: String::new(),
+        }
+    }
+
+    #[test]
+    fn reads_newest_first_with_filters() {
+        let path = log("filters");
+        for (at, who, action) in [
+            (1, "me", Action::QueueAdd),
+            (2, "guest (party)", Action::QueueAdd),
+            (3, "me", Action::QueueClear),
+            (4, "me", Action::QueueAdd),
+        ] {
+            append(&path, &entry(at, who, action)).unwrap();
+        }
+        let times = |query: AuditQuery| {
+            read_from(&path, &query)
+                .iter()
+                .map(|e| e.at)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(times(AuditQuery::default()), [4, 3, 2, 1]);
+        assert_eq!(
+            times(AuditQuery {
+                action: Some(Action::QueueAdd),
+                ..Default::default()
+            }),
+            [4, 2, 1]
+        );
+        assert_eq!(
+            times(AuditQuery {
+                who: Some("guest".into()),
+                ..Default::default()
+            }),
+            [2]
+        );
+        assert_eq!(
+            times(AuditQuery {
+                before: Some(4),
+                limit: Some(2),
+                ..Default::default()
+            }),
+            [3, 2]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_lines_across_blocks() {
+        let path = log("blocks");
+        // Long enough that lines straddle the blocks read
+        let what = "x".repeat(1000);
+        let entries = 3 * NewestFirst::BLOCK / 1000;
+        for at in 0..entries {
+            let mut entry = entry(at, "me", Action::Scan);
+            entry.what = what.clone();
+            append(&path, &entry).unwrap();
+        }
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"not json\n")
+            .unwrap();
+
+        let read = read_from(
+            &path,
+            &AuditQuery {
+                limit: Some(usize::MAX),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            read.iter().map(|e| e.at).collect::<Vec<_>>(),
+            (0..entries).rev().collect::<Vec<_>>()
+        );
+        assert!(read.iter().all(|e| e.what == what));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_nothing_without_a_log() {
+        assert!(read_from(&log("missing"), &AuditQuery::default()).is_empty());
+    }
+}
@@ -0,0 +1,512 @@
+//! Who may use what.
+//!
+//! The web UI: while there are no accounts (see `bwaabwaa::users`), anyone can, as before. Once
+//! there are, only signed-in browsers (see `/login`) and requests with an API key can; anything
+//! else is sent to sign in, or for JSON, turned away with a 401.
+//!
+//! `/admin` and `/api/v1`: while there are no API keys (see `POST /admin/keys`) or accounts, anyone
+//! can. Once there are, requests need an API key, in an `Authorization: Bearer <key>` or
+//! `X-Api-Key: <key>` header, or an admin's session. The JSON endpoints' unversioned paths, and
+//! `/graphql`, need the same once there are keys, though any signed-in user or guest may use them.
+//!
+//! With `--trust-local`, requests from the server's own machine need nothing, so that a lost key
+//! can always be replaced. Don't use it behind a reverse proxy on the same machine, where every
+//! request comes from there.
+//!
+//! Guests (see `bwaabwaa::guest_codes`) can browse the library and add to the queue, but nothing
+//! else.
+//!
+//! A signed-in browser's requests that change something (anything but `GET` and `HEAD`) must also
+//! send its session's CSRF token in an `X-CSRF-Token` header, so that another site can't make them
+//! on its behalf. Signing in puts the token in a `csrf` cookie for the pages' scripts to read. The
+//! sign-in form has a token of its own, and so does the sign-out form.
+//!
+//! A client that gives too many wrong passwords, or guest codes, is turned away for a while.
+
+use crate::error;
+use bwaabwaa::{
+    api_keys::ApiKeys, attempts::Attempts, guest_codes::GuestCodes, sessions::Sessions,
+    users::Users,
+};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::sync::Mutex;
+use warp::{http::Method, path::FullPath, Filter, Rejection};
+
+pub const SESSION_COOKIE: &str = "session";
+/// The session's CSRF token, for the pages' scripts to send back
+pub const CSRF_COOKIE: &str = "csrf";
+pub const GUEST_COOKIE: &str = "guest";
+/// Given with the sign-in form, for its CSRF token to be checked against
+pub const LOGIN_COOKIE: &str = "login";
+
+/// How many wrong passwords a client may give before it's turned away for a while
+const MAX_LOGIN_FAILURES: u32 = 10;
+/// How long it's turned away for, in seconds, from its first wrong password
+const LOGIN_FAILURE_WINDOW: u64 = 15 * 60;
+
+/// Everything requests are checked against. Cheap to clone.
+#[derive(Clone)]
+pub struct Auth {
+    pub keys: Arc<Mutex<ApiKeys>>,
+    pub users: Arc<Mutex<Users>>,
+    pub sessions: Arc<Mutex<Sessions>>,
+    pub guests: Arc<Mutex<GuestCodes>>,
+    /// Wrong passwords given lately
+    pub logins: Arc<Mutex<Attempts>>,
+    /// Whether requests from the server's own machine are let through, as `--trust-local`
+    pub trust_local: bool,
+}
+
+impl Auth {
+    /// Loads the keys and accounts; fails if either can't be read, rather than let everyone in.
+    pub fn load(trust_local: bool) -> Result<Self, String> {
+        Ok(Auth {
+            keys: Arc::new(Mutex::new(ApiKeys::load()?)),
+            users: Arc::new(Mutex::new(Users::load()?)),
+            sessions: Arc::new(Mutex::new(Sessions::load())),
+            guests: Arc::new(Mutex::new(GuestCodes::load())),
+            logins: Arc::new(Mutex::new(Attempts::new(
+                MAX_LOGIN_FAILURES,
+                LOGIN_FAILURE_WINDOW,
+            ))),
+            trust_local,
+        })
+    }
+}
+
+/// Who a request is from.
+struct Caller {
+    client: Option<SocketAddr>,
+    /// Whether it's from the server's own machine, and those are trusted
+    trusted: bool,
+    /// The API key it presented, as `user (name)`
+    key: Option<String>,
+    /// Whether it presented a key that isn't valid
+    bad_key: bool,
+    /// The signed-in user's name, and whether they're an admin
+    user: Option<(String, bool)>,
+    /// The session cookie, if it's for a current session
+    session: Option<String>,
+    /// The name of the guest code its guest session was started with, if that hasn't expired
+    guest: Option<String>,
+}
+
+fn caller(auth: Auth) -> impl Filter<Extract = (Caller,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>("x-api-key"))
+        .and(warp::cookie::optional::<String>(SESSION_COOKIE))
+        .and(warp::cookie::optional::<String>(GUEST_COOKIE))
+        .then(
+            move |client: Option<SocketAddr>,
+                  authorization: Option<String>,
+                  api_key: Option<String>,
+                  cookie: Option<String>,
+                  guest: Option<String>| {
+                let auth = auth.clone();
+                async move {
+                    let presented = presented(&authorization, &api_key);
+                    let key =
+                        match presented {
+                            Some(key) => auth.keys.lock().await.check(key).map(|key| {
+                                match key.user.as_str() {
+                                    "" => key.name.clone(),
+                                    user => format!("{} ({})", user, key.name),
+                                }
+                            }),
+                            None => None,
+                        };
+
+                    let name = match &cookie {
+                        Some(cookie) => auth
+                            .sessions
+                            .lock()
+                            .await
+                            .get(cookie)
+                            .map(|s| s.user.clone()),
+                        None => None,
+                    };
+                    let user = match name {
+                        Some(name) => auth
+                            .users
+                            .lock()
+                            .await
+                            .get(&name)
+                            .map(|user| (user.name.clone(), user.admin)),
+                        None => None,
+                    };
+
+                    let guest = match guest {
+                        Some(token) => auth
+                            .guests
+                            .lock()
+                            .await
+                            .session(&token)
+                            .map(|guest| guest.name.clone()),
+                        None => None,
+                    };
+
+                    Caller {
+                        client,
+                        trusted: auth.trust_local && client.is_some_and(|c| c.ip().is_loopback()),
+                        bad_key: presented.is_some() && key.is_none(),
+                        key,
+                        session: cookie.filter(|_| user.is_some()),
+                        user,
+                        guest,
+                    }
+                }
+            },
+        )
+}
+
+/// Paths anyone may see: signing in, and what the sign-in page itself needs.
+fn is_public(path: &str) -> bool {
+    matches!(
+        path,
+        "/login" | "/logout" | "/guest" | "/favicon.ico" | "/manifest.json" | "/sw.js"
+    ) || path.starts_with("/static/")
+        || path.starts_with("/icons/")
+}
+
+/// What guests may do: look around the library, and add to the queue.
+fn guest_may(method: &Method, path: &str) -> bool {
+    match *method {
+        Method::GET | Method::HEAD => matches!(
+            path,
+            "/" | "/search"
+                | "/details"
+                | "/loudness"
+                | "/artists"
+                | "/artist"
+                | "/album"
+                | "/browse"
+                | "/years"
+                | "/random"
+                | "/random/album"
+                | "/shuffle"
+                | "/art"
+                | "/labels"
+                | "/moods"
+                | "/queue"
+                | "/now-playing"
+                | "/whatsnew"
+                | "/capabilities"
+        ),
+        Method::POST => path == "/queue/add",
+        _ => false,
+    }
+}
+
+/// Turns away requests from anyone who isn't signed in, once there are accounts, and requests
+/// from signed-in browsers without their CSRF token. Goes in front of every route.
+pub fn guard(auth: Auth) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::header::optional::<String>("x-csrf-token"))
+        .and(caller(auth.clone()))
+        .and_then(
+            move |method: Method,
+                  path: FullPath,
+                  query: String,
+                  accept: Option<String>,
+                  csrf: Option<String>,
+                  caller: Caller| {
+                let auth = auth.clone();
+                async move {
+                    let path = path.as_str();
+                    let safe = method == Method::GET || method == Method::HEAD;
+
+                    // Sign-in and sign-out check their forms' own tokens
+                    if let Some(cookie) = caller.session.as_deref().filter(|_| !safe) {
+                        if caller.key.is_none() && !is_public(path) {
+                            let sessions = auth.sessions.lock().await;
+                            if !sessions.check_csrf(cookie, csrf.as_deref().unwrap_or_default()) {
+                                return Err(error::forbidden("missing or invalid CSRF token"));
+                            }
+                        }
+                    }
+
+                    if caller.key.is_some()
+                        || caller.user.is_some()
+                        || is_public(path)
+                        || (caller.guest.is_some() && guest_may(&method, path))
+                        // These check for themselves
+                        || path.starts_with("/admin/")
+                        || path.starts_with("/api/v1/")
+                        || auth.users.lock().await.is_empty()
+                    {
+                        return Ok(());
+                    }
+
+                    let wants_html = accept.is_some_and(|a| a.contains("text/html"));
+                    if method == Method::GET && wants_html {
+                        let next = match query.as_str() {
+                            "" => path.to_string(),
+                            query => format!("{}?{}", path, query),
+                        };
+                        Err(error::login_required(next))
+                    } else {
+                        Err(error::unauthorized("sign in, or use an API key"))
+                    }
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// Passes requests that may use `/admin` and `/api/v1`. With `always`, a key (or an admin's
+/// session) is needed even before there are any keys or accounts, eg to create a key.
+pub fn authorized(
+    auth: Auth,
+    always: bool,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    caller(auth.clone())
+        .and_then(move |caller: Caller| {
+            let auth = auth.clone();
+            async move {
+                if caller.trusted || caller.key.is_some() || caller.user.is_some_and(|u| u.1) {
+                    return Ok(());
+                }
+                if caller.bad_key {
+                    return Err(error::unauthorized("invalid API key"));
+                }
+                if !always
+                    && auth.keys.lock().await.is_empty()
+                    && auth.users.lock().await.is_empty()
+                {
+                    return Ok(());
+                }
+
+                Err(error::unauthorized("an API key is required"))
+            }
+        })
+        .untuple_one()
+}
+
+/// Passes requests that may use the JSON endpoints at their unversioned paths, and `/graphql`: as
+/// `authorized`, but any signed-in user or guest may, since the pages' own scripts use them.
+/// Guests are held to what they may do by `guard`.
+pub fn api(auth: Auth) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    caller(auth.clone())
+        .and_then(move |caller: Caller| {
+            let auth = auth.clone();
+            async move {
+                if caller.trusted
+                    || caller.key.is_some()
+                    || caller.user.is_some()
+                    || caller.guest.is_some()
+                {
+                    return Ok(());
+                }
+                if caller.bad_key {
+                    return Err(error::unauthorized("invalid API key"));
+                }
+                if auth.keys.lock().await.is_empty() && auth.users.lock().await.is_empty() {
+                    return Ok(());
+                }
+
+                Err(error::unauthorized("an API key is required"))
+            }
+        })
+        .untuple_one()
+}
+
+/// Who's making a request, for the audit log: the user and name of the API key it presents, the
+/// signed-in user, or failing those, the client's address.
+pub fn who(auth: Auth) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+    caller(auth).map(|caller: Caller| {
+        caller
+            .key
+            .or(caller.user.map(|(name, _)| name))
+            .or(caller.guest.map(|name| format!("guest ({})", name)))
+            .or(caller.client.map(|c| c.ip().to_string()))
+            .unwrap_or_else(|| "unknown".to_string())
+    })
+}
+
+/// The API key in a request's headers, if any.
+fn presented<'a>(
+    authorization: &'a Option<String>,
+    api_key: &'a Option<String>,
+) -> Option<&'a str> {
+    authorization
+        .as_deref()
+        .and_then(|a| a.strip_prefix("Bearer "))
+        .or(api_key.as_deref())
+        .map(str::trim)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::test::RequestBuilder;
+
+    fn auth(trust_local: bool) -> Auth {
+        Auth {
+            keys: Default::default(),
+            users: Default::default(),
+            sessions: Default::default(),
+            guests: Default::default(),
+            logins: Arc::new(Mutex::new(Attempts::new(
+                MAX_LOGIN_FAILURES,
+                LOGIN_FAILURE_WINDOW,
+            ))),
+            trust_local,
+        }
+    }
+
+    fn request(from: &str) -> RequestBuilder {
+        warp::test::request().remote_addr(from.parse().unwrap())
+    }
+
+    const ELSEWHERE: &str = "192.168.1.2:50000";
+
+    #[test]
+    fn guests_may_only_look_and_add_to_the_queue() {
+        for path in ["/", "/search", "/album", "/queue"] {
+            assert!(guest_may(&Method::GET, path), "{}", path);
+        }
+        for path in [
+            "/listen",
+            "/download",
+            "/radio/seed",
+            "/history",
+            "/devices",
+            "/handoff",
+            "/rooms/party",
+            "/admin/keys",
+        ] {
+            assert!(!guest_may(&Method::GET, path), "{}", path);
+        }
+        assert!(guest_may(&Method::POST, "/queue/add"));
+        for path in ["/queue/shuffle", "/queue/next", "/radio/seed", "/search"] {
+            assert!(!guest_may(&Method::POST, path), "{}", path);
+        }
+        assert!(!guest_may(&Method::DELETE, "/queue"));
+    }
+
+    /// The status, and where it sends the browser, of a request through `guard`.
+    async fn guarded(auth: &Auth, request: RequestBuilder) -> (u16, Option<String>) {
+        let routes = guard(auth.clone())
+            .map(warp::reply)
+            .recover(error::handle_rejection);
+        let response = request.reply(&routes).await;
+        let location = response.headers().get("location");
+        (
+            response.status().as_u16(),
+            location.map(|l| l.to_str().unwrap().to_string()),
+        )
+    }
+
+    #[tokio::test]
+    async fn strangers_are_sent_to_sign_in_once_there_are_accounts() {
+        let auth = auth(false);
+        let page = || request(ELSEWHERE).path("/search?term=abba");
+        assert_eq!(guarded(&auth, page()).await.0, 200);
+
+        auth.users
+            .lock()
+            .await
+            .set_password("me", "hunter2")
+            .unwrap();
+        let html = page().header("accept", "text/html");
+        assert_eq!(
+            guarded(&auth, html).await,
+            (303, Some("/login?next=%2Fsearch%3Fterm%3Dabba".to_string()))
+        );
+        assert_eq!(guarded(&auth, page()).await.0, 401);
+        let login = request(ELSEWHERE).path("/login");
+        assert_eq!(guarded(&auth, login).await.0, 200);
+
+        let key = auth.keys.lock().await.create("test".into(), String::new());
+        let with_key = page().header("x-api-key", &key.key);
+        assert_eq!(guarded(&auth, with_key).await.0, 200);
+    }
+
+    #[tokio::test]
+    async fn changes_need_the_sessions_csrf_token() {
+        let auth = auth(false);
+        auth.users
+            .lock()
+            .await
+            .set_password("me", "hunter2")
+            .unwrap();
+        let (cookie, token) = {
+            let mut sessions = auth.sessions.lock().await;
+            let cookie = sessions.start("me", false);
+            let token = sessions.csrf_token(&cookie);
+            (format!("{}={}", SESSION_COOKIE, cookie), token)
+        };
+        let signed_in = |method: &str| {
+            request(ELSEWHERE)
+                .method(method)
+                .path("/queue/add?id=1")
+                .header("cookie", &cookie)
+        };
+
+        assert_eq!(guarded(&auth, signed_in("GET")).await.0, 200);
+        assert_eq!(guarded(&auth, signed_in("POST")).await.0, 403);
+        let wrong = signed_in("POST").header("x-csrf-token", "0".repeat(token.len()));
+        assert_eq!(guarded(&auth, wrong).await.0, 403);
+        let right = signed_in("POST").header("x-csrf-token", &token);
+        assert_eq!(guarded(&auth, right).await.0, 200);
+    }
+
+    #[tokio::test]
+    async fn keys_are_needed_once_there_are_some() {
+        let auth = auth(false);
+        let admin = authorized(auth.clone(), false);
+        let keys_admin = authorized(auth.clone(), true);
+        assert!(request(ELSEWHERE).filter(&admin).await.is_ok());
+        // Even the first key needs a key
+        assert!(request(ELSEWHERE).filter(&keys_admin).await.is_err());
+
+        let key = auth.keys.lock().await.create("test".into(), String::new());
+        assert!(request(ELSEWHERE).filter(&admin).await.is_err());
+        let bearer = format!("Bearer {}", key.key);
+        for (header, value) in [("authorization", &bearer), ("x-api-key", &key.key)] {
+            let with_key = || request(ELSEWHERE).header(header, value);
+            assert!(with_key().filter(&admin).await.is_ok());
+            assert!(with_key().filter(&keys_admin).await.is_ok());
+        }
+        let wrong = format!("{}x", key.key);
+        let with_wrong_key = request(ELSEWHERE).header("x-api-key", wrong);
+        assert!(with_wrong_key.filter(&admin).await.is_err());
+
+        auth.keys.lock().await.revoke(&key.id);
+        let with_revoked_key = request(ELSEWHERE).header("x-api-key", &key.key);
+        assert!(with_revoked_key.filter(&admin).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn local_requests_are_only_trusted_when_asked() {
+        for trust_local in [false, true] {
+            let auth = auth(trust_local);
+            auth.keys.lock().await.create("test".into(), String::new());
+            let admin = authorized(auth.clone(), true);
+            let local = request("127.0.0.1:50000").filter(&admin).await;
+            assert_eq!(local.is_ok(), trust_local);
+            assert!(request(ELSEWHERE).filter(&admin).await.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn unversioned_paths_need_a_key_too() {
+        let auth = auth(false);
+        let routes = guard(auth.clone())
+            .and(api(auth.clone()))
+            .and(warp::path!("queue" / "add"))
+            .and(warp::post())
+            .map(|| "added");
+        let add = || request(ELSEWHERE).method("POST").path("/queue/add");
+        assert!(add().filter(&routes).await.is_ok());
+
+        let key = auth.keys.lock().await.create("test".into(), String::new());
+        assert!(add().filter(&routes).await.is_err());
+        let with_key = add().header("x-api-key", &key.key);
+        assert!(with_key.filter(&routes).await.is_ok());
+    }
+}
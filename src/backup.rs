@@ -0,0 +1,251 @@
+//! Backing up everything the server keeps in its working directory to a single tar archive, and
+//! restoring from one, so that moving to another machine is one download and one upload.
+//!
+//! The archive holds the library (including each song's cover colors), its roots and scan errors,
+//! the play history and resume positions, playlists and the wishlist, accounts, API keys, guest codes, webhooks,
+//! WebDAV shares, the audit log, and the jukebox's equalizers; whichever of them exist. Sessions aren't
+//! included, and restoring ends those there are, signing everyone out.
+//!
+//! Restoring checks that every file parses as what the server loads it as before writing any, and
+//! replaces each whole. The audit log isn't restored: it's only ever appended to, so a backup can't
+//! rewrite the record of what was changed, and the restore itself is recorded in it.
+
+use serde::de::DeserializeOwned;
+use std::{
+    io::{Cursor, Read},
+    path::Path,
+};
+
+/// The largest archive that's restored, in bytes
+pub const MAX_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Every file that's backed up, relative to the working directory
+pub const FILES: &[&str] = &[
+    crate::music_db::LIBRARY_FILE,
+    crate::music_db::ROOTS_FILE,
+    crate::music_db::SCAN_ERRORS_FILE,
+    crate::overrides::ALBUM_OVERRIDES_FILE,
+    crate::overrides::ARTIST_ALIASES_FILE,
+    crate::history::HISTORY_FILE,
+    crate::resume::RESUME_FILE,
+    crate::playlists::PLAYLISTS_FILE,
+    crate::wishlist::WISHLIST_FILE,
+    crate::users::USERS_FILE,
+    crate::api_keys::KEYS_FILE,
+    crate::guest_codes::GUESTS_FILE,
+    crate::webhooks::WEBHOOKS_FILE,
+    crate::remote::WEBDAV_FILE,
+    crate::audit::AUDIT_FILE,
+    crate::dsp::EQ_FILE,
+    crate::genres::GENRES_FILE,
+    crate::explicit::KID_MODE_FILE,
+    crate::devices::DEVICES_FILE,
+];
+
+/// Archives whichever of `FILES` exist.
+pub fn create() -> std::io::Result<Vec<u8>> {
+    create_from(Path::new("."))
+}
+
+fn create_from(directory: &Path) -> std::io::Result<Vec<u8>> {
+    let mut archive = tar::Builder::new(Vec::new());
+    for &name in FILES {
+        match std::fs::File::open(directory.join(name)) {
+            Ok(mut file) => archive.append_file(name, &mut file)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    archive.into_inner()
+}
+
+/// Writes out the files in an archive made by `create`, except the audit log, replacing those
+/// already there, and returns their names. Anything else in the archive is ignored.
+///
+/// The whole archive is read and checked before anything is written, so a broken one changes
+/// nothing.
+pub fn restore(archive: &[u8]) -> Result<Vec<String>, String> {
+    restore_to(Path::new("."), archive)
+}
+
+fn restore_to(directory: &Path, archive: &[u8]) -> Result<Vec<String>, String> {
+    let mut files = Vec::new();
+    let mut entries = tar::Archive::new(Cursor::new(archive));
+    for entry in entries.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| e.to_string())?;
+        let path = entry.path().map_err(|e| e.to_string())?;
+        // Only known names, so the archive can't write anywhere else
+        let Some(&name) = FILES
+            .iter()
+            .find(|&&name| path.as_os_str() == name && name != crate::audit::AUDIT_FILE)
+        else {
+            continue;
+        };
+
+        let mut contents = Vec::new();
+        entry
+            .read_to_end(&mut contents)
+            .map_err(|e| format!("{}: {}", name, e))?;
+        check(name, &contents).map_err(|e| format!("{}: {}", name, e))?;
+        files.push((name, contents));
+    }
+    if files.is_empty() {
+        return Err("the archive has nothing to restore".to_string());
+    }
+
+    for (name, contents) in &files {
+        crate::paths::replace(&directory.join(name), contents)
+            .map_err(|e| format!("unable to write {}: {}", name, e))?;
+    }
+    // The accounts and keys may be different now
+    match std::fs::remove_file(directory.join(crate::sessions::SESSIONS_FILE)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            return Err(format!("unable to sign everyone out: {}", e))
+        }
+        _ => {}
+    }
+    Ok(files
+        .into_iter()
+        .map(|(name, _)| name.to_string())
+        .collect())
+}
+
+/// Whether `contents` parses as a whole JSON `T`.
+fn parses<T: DeserializeOwned>(contents: &[u8]) -> serde_json::Result<()> {
+    serde_json::from_slice::<T>(contents).map(drop)
+}
+
+/// Whether each line of `contents` parses as a `T`, as in files that are appended to.
+fn lines_parse<T: DeserializeOwned>(contents: &[u8]) -> serde_json::Result<()> {
+    contents
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+        .try_for_each(parses::<T>)
+}
+
+/// Whether `contents` is what the server would load from `name`, so that a restore can't leave it
+/// a file it would ignore, or refuse to start with.
+fn check(name: &str, contents: &[u8]) -> Result<(), String> {
+    use crate::{
+        api_keys, devices, dsp, explicit, genres, guest_codes, history, music_db, overrides,
+        playlists, remote, resume, roots, song, users, webhooks, wishlist,
+    };
+    use std::collections::{BTreeMap, HashMap};
+
+    match name {
+        music_db::LIBRARY_FILE => lines_parse::<song::Song>(contents),
+        music_db::ROOTS_FILE => parses::<Vec<roots::SavedRoot>>(contents),
+        music_db::SCAN_ERRORS_FILE => parses::<Vec<music_db::ScanError>>(contents),
+        overrides::ALBUM_OVERRIDES_FILE => parses::<Vec<overrides::Overridden>>(contents),
+        overrides::ARTIST_ALIASES_FILE => parses::<Vec<overrides::Alias>>(contents),
+        history::HISTORY_FILE => lines_parse::<history::Play>(contents),
+        resume::RESUME_FILE => parses::<HashMap<u64, resume::Position>>(contents),
+        playlists::PLAYLISTS_FILE => parses::<BTreeMap<u64, playlists::Playlist>>(contents),
+        wishlist::WISHLIST_FILE => parses::<BTreeMap<u64, wishlist::Wish>>(contents),
+        users::USERS_FILE => parses::<Vec<users::User>>(contents),
+        api_keys::KEYS_FILE => parses::<BTreeMap<String, api_keys::ApiKey>>(contents),
+        guest_codes::GUESTS_FILE => parses::<BTreeMap<String, guest_codes::GuestCode>>(contents),
+        webhooks::WEBHOOKS_FILE => parses::<Vec<webhooks::Webhook>>(contents),
+        remote::WEBDAV_FILE => parses::<Vec<remote::webdav::Config>>(contents),
+        dsp::EQ_FILE => parses::<HashMap<String, dsp::Equalizer>>(contents),
+        genres::GENRES_FILE => parses::<genres::Config>(contents),
+        explicit::KID_MODE_FILE => parses::<explicit::KidMode>(contents),
+        devices::DEVICES_FILE => parses::<BTreeMap<String, devices::Device>>(contents),
+        _ => return Err("isn't restored".to_string()),
+    }
+    .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory of its own under the system's temporary directory.
+    fn directory(name: &str) -> std::path::PathBuf {
+        let directory = std::env::temp_dir().join(format!(
+            "bwaabwaa-backup-{}-{:x}",
+            name,
+            rand::random::<u64>()
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn restores_what_was_backed_up() {
+        let from = directory("from");
+        let play = r#"{"id": 1, "at": 2}"#;
+        std::fs::write(from.join(crate::history::HISTORY_FILE), format!("{play}\n")).unwrap();
+        std::fs::write(from.join(crate::users::USERS_FILE), "[]").unwrap();
+        std::fs::write(from.join(crate::audit::AUDIT_FILE), "{}\n").unwrap();
+        std::fs::write(from.join("notes.txt"), "not backed up").unwrap();
+        let archive = create_from(&from).unwrap();
+
+        let to = directory("to");
+        let sessions = to.join(crate::sessions::SESSIONS_FILE);
+        std::fs::write(&sessions, "{}").unwrap();
+        let mut restored = restore_to(&to, &archive).unwrap();
+        restored.sort();
+        assert_eq!(
+            restored,
+            [crate::history::HISTORY_FILE, crate::users::USERS_FILE]
+        );
+        let read = |name| std::fs::read_to_string(to.join(name)).unwrap();
+        assert_eq!(read(crate::history::HISTORY_FILE), format!("{play}\n"));
+        assert_eq!(read(crate::users::USERS_FILE), "[]");
+        assert!(!to.join("notes.txt").exists());
+        assert!(!to.join(crate::audit::AUDIT_FILE).exists());
+        assert!(!sessions.exists());
+
+        std::fs::remove_dir_all(from).unwrap();
+        std::fs::remove_dir_all(to).unwrap();
+    }
+
+    #[test]
+    fn ignores_names_it_doesnt_know() {
+        let mut archive = tar::Builder::new(Vec::new());
+        for name in ["../escape.json", "/etc/passwd", "notes.txt"] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(2);
+            header.set_mode(0o644);
+            // `append_data` refuses names like these, as a restore would
+            header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_cksum();
+            archive.append(&header, &b"{}"[..]).unwrap();
+        }
+        let archive = archive.into_inner().unwrap();
+
+        let to = directory("unknown");
+        assert!(restore_to(&to, &archive).is_err());
+        assert_eq!(std::fs::read_dir(&to).unwrap().count(), 0);
+        std::fs::remove_dir_all(to).unwrap();
+    }
+
+    #[test]
+    fn restores_nothing_if_anything_is_broken() {
+        let from = directory("broken");
+        std::fs::write(from.join(crate::playlists::PLAYLISTS_FILE), "{}").unwrap();
+        // Valid JSON, but not accounts: taken for none, it would open the UI to everyone
+        std::fs::write(from.join(crate::users::USERS_FILE), r#"{"me": "hunter2"}"#).unwrap();
+        let archive = create_from(&from).unwrap();
+
+        let to = directory("intact");
+        let error = restore_to(&to, &archive).unwrap_err();
+        assert!(error.starts_with(crate::users::USERS_FILE), "{}", error);
+        assert_eq!(std::fs::read_dir(&to).unwrap().count(), 0);
+
+        std::fs::remove_dir_all(from).unwrap();
+        std::fs::remove_dir_all(to).unwrap();
+    }
+
+    #[test]
+    fn checks_appended_files_line_by_line() {
+        let play = r#"{"id": 1, "at": 2}"#;
+        let file = crate::history::HISTORY_FILE;
+        assert!(check(file, b"").is_ok());
+        assert!(check(file, format!("{play}\n{play}\n").as_bytes()).is_ok());
+        assert!(check(file, format!("{play}\n{{\n").as_bytes()).is_err());
+        assert!(check(file, b"[]").is_err());
+        assert!(check(crate::audit::AUDIT_FILE, b"").is_err());
+    }
+}
@@ -0,0 +1,158 @@
+//! Browsing the scanned roots by folder, for `/browse`. Paths are relative to the roots, named
+//! as in `/browse`'s top level, eg `Music/Foo Fighters/The Colour and the Shape`; anything that
+//! could reach outside them, like `..`, an absolute path, or a symlink out, gets nothing.
+
+use crate::music_db::MusicDB;
+use crate::sections::Section;
+use crate::song::SongResult;
+use serde::Serialize;
+use std::path::{Component, PathBuf};
+
+/// The contents of one directory under the scanned roots, as returned by `/browse`.
+#[derive(Serialize)]
+pub struct BrowseResults {
+    /// The relative path that was listed; empty for the top level
+    pub path: String,
+    /// The relative path of the parent directory, if there is one
+    pub parent: Option<String>,
+    pub directories: Vec<BrowseEntry>,
+    pub songs: Vec<SongResult>,
+}
+
+#[derive(Serialize)]
+pub struct BrowseEntry {
+    pub name: String,
+    pub path: String,
+}
+
+impl MusicDB {
+    /// Lists the directory at `relative`, which is of the form `root-name/sub/dir`.
+    ///
+    /// An empty path lists the roots in `section` (music by default). Returns `None` if the path doesn't name a
+    /// directory within one of the roots -- including attempts to climb out of them with `..`
+    /// or via symlinks.
+    pub fn browse(&self, relative: &str, section: Option<Section>) -> Option<BrowseResults> {
+        let components = normalize(relative)?;
+
+        let (root_name, rest) = match components.split_first() {
+            Some(split) => split,
+            None => {
+                let directories = self
+                    .roots
+                    .iter()
+                    .filter(|root| root.section == section.unwrap_or_default())
+                    .map(|root| BrowseEntry {
+                        path: root.name().to_string(),
+                        name: root.name().to_string(),
+                    })
+                    .collect();
+
+                return Some(BrowseResults {
+                    path: String::new(),
+                    parent: None,
+                    directories,
+                    songs: Vec::new(),
+                });
+            }
+        };
+
+        let root = &self.root(root_name)?.path;
+
+        let directory = rest.iter().fold(root.clone(), |dir, c| dir.join(c));
+        let directory = crate::paths::canonicalize(&directory).ok()?;
+        if !directory.starts_with(root) || !directory.is_dir() {
+            return None;
+        }
+
+        let path = components.join("/");
+        let parent = Some(components[..components.len() - 1].join("/"));
+
+        let mut directories = std::fs::read_dir(&directory)
+            .ok()?
+            .flatten()
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_str()?.to_string();
+                Some(BrowseEntry {
+                    path: format!("{}/{}", path, name),
+                    name,
+                })
+            })
+            .collect::<Vec<_>>();
+        directories.sort_unstable_by_key(|d| d.name.to_lowercase());
+
+        let mut songs = self
+            .records
+            .values()
+            .filter(|song| song.path.parent() == Some(&directory))
+            .collect::<Vec<_>>();
+        songs.sort_unstable_by(|a, b| crate::sort_key::natural_cmp(&a.stem_lower, &b.stem_lower));
+
+        Some(BrowseResults {
+            path,
+            parent,
+            directories,
+            songs: songs.into_iter().map(|s| s.into()).collect(),
+        })
+    }
+}
+
+/// Splits a relative path into its components, rejecting anything that isn't a plain name.
+///
+/// `.` and empty components are dropped; `..`, absolute paths, and Windows prefixes mean the
+/// path is rejected outright rather than resolved.
+fn normalize(relative: &str) -> Option<Vec<&str>> {
+    if relative.starts_with(['/', '\\']) {
+        return None;
+    }
+    let mut components = Vec::new();
+
+    for part in relative.split(['/', '\\']) {
+        if part.is_empty() || part == "." {
+            continue;
+        }
+
+        match PathBuf::from(part).components().next() {
+            Some(Component::Normal(_)) if !part.contains('\0') => components.push(part),
+            _ => return None,
+        }
+    }
+
+    Some(components)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_plain_names() {
+        for (relative, components) in [
+            ("", vec![]),
+            ("Music", vec!["Music"]),
+            ("Music/Foo Fighters/", vec!["Music", "Foo Fighters"]),
+            ("Music//./Foo Fighters", vec!["Music", "Foo Fighters"]),
+            ("Music\\Foo Fighters", vec!["Music", "Foo Fighters"]),
+            ("Music/..hidden", vec!["Music", "..hidden"]),
+        ] {
+            assert_eq!(normalize(relative), Some(components), "{:?}", relative);
+        }
+    }
+
+    #[test]
+    fn rejects_paths_that_could_climb_out() {
+        for relative in [
+            "..",
+            "../x",
+            "a/../../x",
+            "Music/..",
+            "/etc/passwd",
+            "\\server\\share",
+            "..\\x",
+            "a\\..\\..\\x",
+            "Music/a\0b",
+        ] {
+            assert_eq!(normalize(relative), None, "{:?}", relative);
+        }
+    }
+}
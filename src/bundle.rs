@@ -0,0 +1,327 @@
+//! Bundles of songs for copying onto a device that can't stream, eg a basic music player or a USB
+//! stick for the car: a ZIP of the songs, transcoded to fit, along with an M3U playlist of them in
+//! order.
+//!
+//! Transcoding needs `ffmpeg`, on the `PATH` or at `FFMPEG`, and the `transcode` feature; built
+//! without it, only `original` bundles can be made. Songs in remote sources are left out.
+
+use crate::music_db::{MusicDB, SearchTerms};
+use crate::paths;
+use crate::song::Song;
+use serde::Deserialize;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    process::Command,
+};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+/// In kbps
+pub const DEFAULT_KBPS: u32 = 128;
+
+const PLAYLIST: &str = "playlist.m3u";
+
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// The files as they are
+    Original,
+    Mp3,
+    #[default]
+    Opus,
+}
+
+impl Format {
+    /// The file extension, and for transcoding, `ffmpeg`'s encoder and output format.
+    fn encoding(self) -> Option<(&'static str, &'static str, &'static str)> {
+        match self {
+            Format::Original => None,
+            Format::Mp3 => Some(("mp3", "libmp3lame", "mp3")),
+            Format::Opus => Some(("opus", "libopus", "ogg")),
+        }
+    }
+}
+
+/// The body of `POST /export/bundle`.
+#[derive(Deserialize, Debug, Default)]
+pub struct BundleRequest {
+    /// The songs to include, in order; otherwise, those matching the search terms
+    pub ids: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub terms: SearchTerms,
+    #[serde(default)]
+    pub format: Format,
+    /// The bitrate to transcode to; ignored for `original`
+    pub kbps: Option<u32>,
+    /// The most the bundle may take up, in megabytes. Songs past that are left out.
+    pub max_mb: Option<u64>,
+}
+
+/// A song to put in a bundle.
+pub struct Entry {
+    path: PathBuf,
+    artist: String,
+    title: String,
+    seconds: u64,
+}
+
+/// What `write` put in a bundle.
+#[derive(Debug)]
+pub struct Written {
+    pub songs: usize,
+    /// Songs that couldn't be read or transcoded
+    pub skipped: usize,
+}
+
+/// How big a song will be in the bundle, roughly.
+fn estimated_size(song: &Song, format: Format, kbps: u32) -> u64 {
+    match format {
+        Format::Original => song.size,
+        _ => song
+            .duration
+            .as_secs()
+            .saturating_mul(u64::from(kbps) * 1000 / 8),
+    }
+}
+
+/// The songs to bundle, in order, as many as fit in `max_mb`.
+pub fn select(db: &MusicDB, request: &BundleRequest) -> Result<Vec<Entry>, String> {
+    if request.format.encoding().is_some() && !cfg!(feature = "transcode") {
+        return Err(
+            "this server was built without transcoding, so only original files can be bundled"
+                .to_string(),
+        );
+    }
+
+    let songs: Vec<&Song> = match &request.ids {
+        Some(ids) => ids
+            .iter()
+            .map(|id| {
+                id.parse()
+                    .ok()
+                    .and_then(|id| db.records.get(&id))
+                    .ok_or_else(|| format!("id={} not found", id))
+            })
+            .collect::<Result<_, _>>()?,
+        None => {
+            let mut songs = db.matching(&request.terms).collect::<Vec<_>>();
+            let section = request.terms.section.unwrap_or_default();
+            let sort_by = request.terms.sort_by.unwrap_or(section.default_sort());
+            songs.sort_by(|a, b| a.cmp(b, sort_by));
+            songs
+        }
+    };
+
+    let kbps = request.kbps.unwrap_or(DEFAULT_KBPS);
+    let budget = request
+        .max_mb
+        .map_or(u64::MAX, |mb| mb.saturating_mul(1000 * 1000));
+    let mut size = 0;
+    let mut entries = Vec::new();
+    for song in songs {
+        if paths::is_remote(&song.path) {
+            continue;
+        }
+        size = estimated_size(song, request.format, kbps).saturating_add(size);
+        if size > budget {
+            break;
+        }
+        entries.push(Entry {
+            path: song.path.clone(),
+            artist: song.artist.clone(),
+            title: match &song.title[..] {
+                "" => song.file_stem().unwrap_or_default().into_owned(),
+                title => title.to_string(),
+            },
+            seconds: song.duration.as_secs(),
+        });
+    }
+
+    if entries.is_empty() {
+        return Err("no songs match, or none fit".to_string());
+    }
+    Ok(entries)
+}
+
+/// The `ffmpeg` to run: `FFMPEG`, or otherwise whichever's on the `PATH`.
+pub(crate) fn ffmpeg() -> String {
+    std::env::var("FFMPEG").unwrap_or_else(|_| "ffmpeg".to_string())
+}
+
+/// Transcodes a song with `ffmpeg`, keeping its tags.
+fn transcode(path: &Path, encoder: &str, format: &str, kbps: u32) -> std::io::Result<Vec<u8>> {
+    let output = Command::new(ffmpeg())
+        .args(["-nostdin", "-v", "error", "-i"])
+        .arg(path)
+        .args(["-vn", "-map_metadata", "0", "-c:a", encoder, "-b:a"])
+        .arg(format!("{}k", kbps))
+        .args(["-f", format, "-"])
+        .output()?;
+    if !output.status.success() {
+        let error = String::from_utf8_lossy(&output.stderr);
+        return Err(std::io::Error::other(error.trim().to_string()));
+    }
+    Ok(output.stdout)
+}
+
+/// Writes a bundle of `entries` to `file`. Slow, since each song may be transcoded; songs that
+/// can't be are left out, and only a missing `ffmpeg` fails the whole bundle.
+pub fn write(
+    entries: &[Entry],
+    format: Format,
+    kbps: Option<u32>,
+    file: File,
+) -> Result<Written, String> {
+    let kbps = kbps.unwrap_or(DEFAULT_KBPS);
+    let mut zip = ZipWriter::new(BufWriter::new(file));
+    // Audio is compressed already
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .large_file(true);
+
+    let mut playlist = "#EXTM3U\n".to_string();
+    let mut skipped = 0;
+    for (number, entry) in entries.iter().enumerate() {
+        let (data, extension) = match format.encoding() {
+            None => (
+                std::fs::read(&entry.path),
+                entry
+                    .path
+                    .extension()
+                    .map(|e| e.to_string_lossy().to_lowercase())
+                    .unwrap_or_default(),
+            ),
+            Some((extension, encoder, container)) => (
+                transcode(&entry.path, encoder, container, kbps),
+                extension.to_string(),
+            ),
+        };
+        let data = match data {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && format != Format::Original => {
+                return Err(format!("unable to run {}: {}", ffmpeg(), e));
+            }
+            Err(e) => {
+                eprintln!("Leaving {} out of the bundle: {}", entry.path.display(), e);
+                skipped += 1;
+                continue;
+            }
+        };
+
+        let description = match entry.artist.as_str() {
+            "" => entry.title.clone(),
+            artist => format!("{} - {}", artist, entry.title),
+        };
+        let name = format!(
+            "{:03} {}.{}",
+            number + 1,
+            paths::file_name(&description),
+            extension
+        );
+        zip.start_file(name.as_str(), options)
+            .and_then(|()| Ok(zip.write_all(&data)?))
+            .map_err(|e| format!("unable to write the bundle: {}", e))?;
+        playlist += &format!("#EXTINF:{},{}\n{}\n", entry.seconds, description, name);
+    }
+
+    zip.start_file(PLAYLIST, options)
+        .and_then(|()| Ok(zip.write_all(playlist.as_bytes())?))
+        .and_then(|()| zip.finish())
+        .and_then(|mut file| Ok(file.flush()?))
+        .map_err(|e| format!("unable to write the bundle: {}", e))?;
+
+    Ok(Written {
+        songs: entries.len() - skipped,
+        skipped,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn db() -> MusicDB {
+        let mut db = MusicDB::default();
+        for id in 1..=3 {
+            db.records.insert(
+                id,
+                Song {
+                    id,
+                    path: PathBuf::from(format!("/music/{}.mp3", id)),
+                    title: format!("Song {}", id),
+                    // A megabyte at 128 kbps, give or take
+                    duration: Duration::from_secs(60),
+                    size: 400 * 1000,
+                    ..Default::default()
+                },
+            );
+        }
+        db
+    }
+
+    fn request(max_mb: Option<u64>, format: Format) -> BundleRequest {
+        BundleRequest {
+            ids: Some(vec!["3".into(), "1".into(), "2".into()]),
+            format,
+            max_mb,
+            ..Default::default()
+        }
+    }
+
+    fn titles(db: &MusicDB, request: BundleRequest) -> Result<Vec<String>, String> {
+        select(db, &request).map(|entries| entries.into_iter().map(|e| e.title).collect())
+    }
+
+    #[test]
+    fn stops_at_the_budget() {
+        let db = db();
+        assert_eq!(
+            titles(&db, request(None, Format::Original)).unwrap(),
+            ["Song 3", "Song 1", "Song 2"]
+        );
+        assert_eq!(
+            titles(&db, request(Some(1), Format::Original)).unwrap(),
+            ["Song 3", "Song 1"]
+        );
+        assert!(titles(&db, request(Some(0), Format::Original)).is_err());
+        assert_eq!(
+            titles(&db, request(Some(u64::MAX), Format::Original))
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "transcode")]
+    fn estimates_transcodes_by_bitrate() {
+        let db = db();
+        assert_eq!(
+            titles(&db, request(Some(2), Format::Opus)).unwrap(),
+            ["Song 3", "Song 1"]
+        );
+        assert_eq!(
+            titles(&db, request(Some(u64::MAX), Format::Mp3))
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "transcode"))]
+    fn only_bundles_originals_without_transcoding() {
+        assert!(titles(&db(), request(None, Format::Opus)).is_err());
+    }
+
+    #[test]
+    fn refuses_unknown_songs() {
+        let request = BundleRequest {
+            ids: Some(vec!["1".into(), "9".into()]),
+            ..Default::default()
+        };
+        assert!(select(&db(), &request).is_err());
+    }
+}
@@ -0,0 +1,189 @@
+//! HTTP cache validators (`ETag` and `Last-Modified`), so that clients replaying a song or
+//! reloading art get a `304 Not Modified` rather than the whole file again.
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use std::{
+    collections::hash_map::DefaultHasher,
+    convert::Infallible,
+    hash::{Hash, Hasher},
+    sync::OnceLock,
+    time::SystemTime,
+};
+use warp::{
+    http::{response::Builder, Response, StatusCode},
+    Filter,
+};
+
+/// The format of an HTTP date, eg "Sun, 06 Nov 1994 08:49:37 GMT".
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// What a response can be validated against.
+pub struct Validators {
+    etag: String,
+    last_modified: DateTime<Utc>,
+}
+
+impl Validators {
+    /// Validators for a file on disk, from its modification time and size.
+    pub fn for_file(metadata: &std::fs::Metadata) -> Self {
+        let modified: DateTime<Utc> = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH).into();
+
+        Validators {
+            etag: format!("\"{:x}-{:x}\"", modified.timestamp(), metadata.len()),
+            last_modified: modified,
+        }
+    }
+
+    /// Validators for content compiled into the server, which can only change when the server
+    /// restarts.
+    pub fn for_static(bytes: &[u8]) -> Self {
+        static STARTED: OnceLock<DateTime<Utc>> = OnceLock::new();
+
+        let mut hasher = DefaultHasher::new();
+        bytes.hash(&mut hasher);
+
+        Validators {
+            etag: format!("\"{:x}\"", hasher.finish()),
+            last_modified: *STARTED.get_or_init(Utc::now),
+        }
+    }
+
+    /// Adds the `ETag` and `Last-Modified` headers to a response.
+    pub fn apply(&self, builder: Builder) -> Builder {
+        builder.header("etag", &self.etag).header(
+            "last-modified",
+            self.last_modified.format(HTTP_DATE).to_string(),
+        )
+    }
+}
+
+/// An entity tag without its weakness indicator, if it has one.
+fn weak(tag: &str) -> &str {
+    tag.strip_prefix("W/").unwrap_or(tag)
+}
+
+/// The conditional headers of a request.
+#[derive(Debug, Default)]
+pub struct Conditional {
+    if_none_match: Option<String>,
+    if_modified_since: Option<String>,
+}
+
+impl Conditional {
+    /// Whether the client's copy is still current.
+    pub fn is_fresh(&self, validators: &Validators) -> bool {
+        // If-None-Match takes precedence when both are given (RFC 7232, section 6)
+        if let Some(if_none_match) = &self.if_none_match {
+            // Compared weakly, as for GET and HEAD (section 3.2)
+            return if_none_match
+                .split(',')
+                .map(str::trim)
+                .any(|tag| tag == "*" || weak(tag) == weak(&validators.etag));
+        }
+
+        match self
+            .if_modified_since
+            .as_deref()
+            .and_then(|since| NaiveDateTime::parse_from_str(since, HTTP_DATE).ok())
+        {
+            // HTTP dates only have second precision
+            Some(since) => validators.last_modified.timestamp() <= since.and_utc().timestamp(),
+            None => false,
+        }
+    }
+}
+
+/// Extracts the conditional headers of a request.
+pub fn conditional() -> impl Filter<Extract = (Conditional,), Error = Infallible> + Clone {
+    warp::header::optional::<String>("if-none-match")
+        .and(warp::header::optional::<String>("if-modified-since"))
+        .map(|if_none_match, if_modified_since| Conditional {
+            if_none_match,
+            if_modified_since,
+        })
+        .or(warp::any().map(Conditional::default))
+        .unify()
+}
+
+/// A `304 Not Modified` response.
+pub fn not_modified(validators: &Validators) -> Response<Vec<u8>> {
+    validators
+        .apply(Response::builder().status(StatusCode::NOT_MODIFIED))
+        .body(Vec::new())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validators() -> Validators {
+        Validators {
+            etag: "\"5f-10\"".to_string(),
+            last_modified: DateTime::from_timestamp(784111777, 0).unwrap(),
+        }
+    }
+
+    fn conditional(if_none_match: Option<&str>, if_modified_since: Option<&str>) -> Conditional {
+        Conditional {
+            if_none_match: if_none_match.map(str::to_string),
+            if_modified_since: if_modified_since.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn follows_rfc_7232() {
+        let modified = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let before = "Sun, 06 Nov 1994 08:49:36 GMT";
+        let after = "Mon, 07 Nov 1994 08:49:37 GMT";
+        for (if_none_match, if_modified_since, fresh) in [
+            (None, None, false),
+            // Entity tags, compared weakly
+            (Some("\"5f-10\""), None, true),
+            (Some("W/\"5f-10\""), None, true),
+            (Some("\"5f-11\""), None, false),
+            (Some("5f-10"), None, false),
+            (Some("\"a\", W/\"5f-10\" , \"b\""), None, true),
+            (Some("\"a\", \"b\""), None, false),
+            (Some("*"), None, true),
+            // Dates, to the second
+            (None, Some(modified), true),
+            (None, Some(after), true),
+            (None, Some(before), false),
+            (None, Some("yesterday"), false),
+            // If-None-Match wins over If-Modified-Since, either way
+            (Some("\"5f-11\""), Some(after), false),
+            (Some("\"5f-10\""), Some(before), true),
+            (Some("*"), Some(before), true),
+        ] {
+            assert_eq!(
+                conditional(if_none_match, if_modified_since).is_fresh(&validators()),
+                fresh,
+                "{:?} {:?}",
+                if_none_match,
+                if_modified_since
+            );
+        }
+    }
+
+    #[test]
+    fn weak_validators_match_strong_tags() {
+        let weak = Validators {
+            etag: "W/\"5f-10\"".to_string(),
+            ..validators()
+        };
+        assert!(conditional(Some("\"5f-10\""), None).is_fresh(&weak));
+        assert!(conditional(Some("W/\"5f-10\""), None).is_fresh(&weak));
+    }
+
+    #[test]
+    fn gives_the_validators_back() {
+        let response = not_modified(&validators());
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()["etag"], "\"5f-10\"");
+        assert_eq!(
+            response.headers()["last-modified"],
+            "Sun, 06 Nov 1994 08:49:37 GMT"
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! Musical keys, written in Camelot notation for DJs: the twelve keys of each mode go round a
+//! wheel, numbered like a clock in fifths, with `A` for minor and `B` for major. C major is `8B`,
+//! and A minor, its relative minor, `8A`. Songs mix well with the same key, the keys either side
+//! of it on the wheel, and its relative major or minor.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+/// How strongly each pitch class figures in major and minor keys, starting from the tonic
+/// (Krumhansl and Kessler's profiles)
+const MAJOR: [f32; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR: [f32; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+/// How closely a song's pitches must follow a key's profile for it to be taken as in that key
+const MIN_CORRELATION: f32 = 0.5;
+
+/// A key: its tonic, as a pitch class (0 for C, 1 for C♯, and so on), and mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Key {
+    pub tonic: u8,
+    pub minor: bool,
+}
+
+/// The Pearson correlation of two profiles.
+fn correlation(a: &[f32; 12], b: &[f32; 12]) -> f32 {
+    let mean = |x: &[f32; 12]| x.iter().sum::<f32>() / 12.0;
+    let (mean_a, mean_b) = (mean(a), mean(b));
+    let (mut ab, mut aa, mut bb) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        let (x, y) = (x - mean_a, y - mean_b);
+        ab += x * y;
+        aa += x * x;
+        bb += y * y;
+    }
+    match aa * bb {
+        product if product > 0.0 => ab / product.sqrt(),
+        _ => 0.0,
+    }
+}
+
+impl Key {
+    /// The key whose profile best matches how much of each pitch class (starting from C) a song
+    /// has, if any matches well enough.
+    pub fn estimate(chroma: &[f32; 12]) -> Option<Key> {
+        let mut best = None;
+        let mut best_correlation = MIN_CORRELATION;
+        for tonic in 0..12 {
+            // The song's pitches, starting from this tonic
+            let rotated: [f32; 12] = std::array::from_fn(|i| chroma[(i + tonic) % 12]);
+            for (profile, minor) in [(&MAJOR, false), (&MINOR, true)] {
+                let r = correlation(&rotated, profile);
+                if r > best_correlation {
+                    best_correlation = r;
+                    best = Some(Key {
+                        tonic: tonic as u8,
+                        minor,
+                    });
+                }
+            }
+        }
+        best
+    }
+
+    /// The key's position on the Camelot wheel, from 1 to 12.
+    pub fn number(self) -> u8 {
+        // Minor keys share their relative major's number, three semitones up
+        let major = if self.minor {
+            (self.tonic + 3) % 12
+        } else {
+            self.tonic
+        };
+        (major * 7 + 7) % 12 + 1
+    }
+
+    /// `A` for minor, `B` for major.
+    pub fn letter(self) -> char {
+        if self.minor {
+            'A'
+        } else {
+            'B'
+        }
+    }
+
+    /// Whether songs in the two keys mix harmonically: they're the same, next to each other on
+    /// the wheel, or relative major and minor.
+    pub fn mixes_with(self, other: Key) -> bool {
+        let steps = (self.number() + 12 - other.number()) % 12;
+        match self.minor == other.minor {
+            true => matches!(steps, 0 | 1 | 11),
+            false => steps == 0,
+        }
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.number(), self.letter())
+    }
+}
+
+impl FromStr for Key {
+    type Err = String;
+
+    /// Parses Camelot notation, eg `8A`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("not a Camelot key: {}", s);
+        let s = s.trim();
+        let (number, letter) = s.split_at(s.len().saturating_sub(1));
+        let number = number.parse::<u8>().map_err(|_| invalid())?;
+        let minor = match letter {
+            "A" | "a" => true,
+            "B" | "b" => false,
+            _ => return Err(invalid()),
+        };
+        if !(1..=12).contains(&number) {
+            return Err(invalid());
+        }
+        // Inverts `number`: 7 is the inverse of 7, modulo 12
+        let major = ((number + 11 - 7) % 12) * 7 % 12;
+        let tonic = if minor { (major + 9) % 12 } else { major };
+        Ok(Key { tonic, minor })
+    }
+}
+
+/// Keys sort round the wheel, minor before major.
+impl Ord for Key {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.number(), self.letter()).cmp(&(other.number(), other.letter()))
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Serialize for Key {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notation() {
+        let c_major = Key {
+            tonic: 0,
+            minor: false,
+        };
+        let a_minor = Key {
+            tonic: 9,
+            minor: true,
+        };
+        assert_eq!(c_major.to_string(), "8B");
+        assert_eq!(a_minor.to_string(), "8A");
+        for tonic in 0..12 {
+            for minor in [false, true] {
+                let key = Key { tonic, minor };
+                assert_eq!(key.to_string().parse(), Ok(key));
+            }
+        }
+        assert!("13A".parse::<Key>().is_err());
+        assert!("8C".parse::<Key>().is_err());
+
+        let g_major: Key = "9B".parse().unwrap();
+        assert!(c_major.mixes_with(g_major));
+        assert!(c_major.mixes_with(a_minor));
+        assert!(!g_major.mixes_with(a_minor));
+        assert!(!c_major.mixes_with("10B".parse().unwrap()));
+    }
+}
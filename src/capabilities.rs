@@ -0,0 +1,109 @@
+//! What this server can do, for `GET /capabilities`, so that clients can show what works rather
+//! than try it and handle the 404s: its version, its features, the formats it reads, and the
+//! versions of the API it serves.
+//!
+//! The heavier parts of the server are cargo features (see `Cargo.toml`), so a minimal build can
+//! leave them out; and some of those built in still depend on something at runtime, like `ffmpeg`
+//! or an audio output. Each feature says both.
+
+use crate::{metadata, transcode};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// The versions of the API served, under `/api/{version}`; the unversioned paths are the latest
+pub const API_VERSIONS: &[&str] = &["v1"];
+
+/// A cargo feature, and whether it works.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Feature {
+    /// Whether the server was built with it
+    pub built: bool,
+    /// Whether it can be used now: it's built, and whatever it needs at runtime is there
+    pub available: bool,
+}
+
+impl Feature {
+    fn new(built: bool, available: bool) -> Self {
+        Feature {
+            built,
+            available: built && available,
+        }
+    }
+}
+
+/// A format the library can hold.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Format {
+    /// Its extension, eg `mp3`
+    pub extension: String,
+    /// Whether `/listen` transcodes it for browsers, since none play it; otherwise it's served as
+    /// it is. ALAC M4As are transcoded too, whatever this says for `m4a`.
+    pub transcoded: bool,
+}
+
+/// What `GET /capabilities` gives.
+#[derive(Serialize, Debug)]
+pub struct Capabilities {
+    /// The server's version
+    pub version: &'static str,
+    /// By feature name, as in `Cargo.toml`
+    pub features: BTreeMap<&'static str, Feature>,
+    /// By extension
+    pub formats: Vec<Format>,
+    pub api_versions: &'static [&'static str],
+}
+
+impl Capabilities {
+    /// Finds what works; `jukebox` is whether jukebox mode started.
+    pub fn detect(jukebox: bool) -> Self {
+        let features = BTreeMap::from([
+            ("graphql", Feature::new(cfg!(feature = "graphql"), true)),
+            ("jukebox", Feature::new(cfg!(feature = "jukebox"), jukebox)),
+            ("s3", Feature::new(cfg!(feature = "s3"), true)),
+            (
+                "transcode",
+                Feature::new(cfg!(feature = "transcode"), transcode::available()),
+            ),
+            ("tui", Feature::new(cfg!(feature = "tui"), true)),
+        ]);
+
+        let transcoding = cfg!(feature = "transcode");
+        let mut formats = metadata::readers()
+            .extensions()
+            .map(|extension| Format {
+                extension: extension.to_string(),
+                transcoded: transcoding && transcode::unplayable(extension),
+            })
+            .collect::<Vec<_>>();
+        formats.sort_by(|a, b| a.extension.cmp(&b.extension));
+
+        Capabilities {
+            version: env!("CARGO_PKG_VERSION"),
+            features,
+            formats,
+            api_versions: API_VERSIONS,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_formats_and_how_theyre_served() {
+        let capabilities = Capabilities::detect(false);
+        assert!(!capabilities.features["jukebox"].available);
+
+        let format = |extension: &str| {
+            capabilities
+                .formats
+                .iter()
+                .find(|f| f.extension == extension)
+                .map(|f| f.transcoded)
+        };
+        assert_eq!(format("mp3"), Some(false));
+        assert_eq!(format("dsf"), Some(cfg!(feature = "transcode")));
+        assert_eq!(format("flac"), None);
+    }
+}
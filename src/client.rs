@@ -0,0 +1,235 @@
+//! `bwaabwaa remote ...`: drives a running server through its `/api/v1` API, from a terminal or a
+//! script.
+//!
+//! ```text
+//! bwaabwaa remote search <terms>       the best matches, one per line: id, then artist - title
+//! bwaabwaa remote enqueue <id>         adds a song to the queue
+//! bwaabwaa remote now-playing          what's playing, on the jukebox or in the web UI
+//! bwaabwaa remote play|pause|next|stop controls the jukebox
+//! ```
+//!
+//! The server is `--server=<url>`, or `BWAA_SERVER`, or failing those, this machine. If it needs
+//! an API key, give it in `BWAA_API_KEY`. With `--json`, the server's responses are printed as they
+//! are.
+
+use serde_json::Value;
+
+const USAGE: &str = "Usage: bwaabwaa remote [--server=<url>] [--json] <command>
+
+Commands:
+    search <terms>          lists the best matches
+    enqueue <id>            adds a song to the queue
+    now-playing             shows what's playing
+    play, pause, next, stop controls the jukebox";
+
+/// A running server's API.
+pub struct Client {
+    server: String,
+    key: Option<String>,
+    http: reqwest::Client,
+}
+
+/// The server given by `--server=<url>` or `BWAA_SERVER`, if any.
+pub fn server(args: &[String]) -> Option<String> {
+    args.iter()
+        .find_map(|a| a.strip_prefix("--server="))
+        .map(str::to_string)
+        .or_else(|| std::env::var("BWAA_SERVER").ok())
+}
+
+/// The server on this machine, on `$PORT` as it would be started.
+pub fn local() -> String {
+    let port = std::env::var("PORT").unwrap_or_else(|_| crate::DEFAULT_PORT.to_string());
+    format!("http://localhost:{}", port)
+}
+
+impl Client {
+    pub fn new(server: &str) -> Self {
+        Client {
+            server: server.trim_end_matches('/').to_string(),
+            key: std::env::var("BWAA_API_KEY").ok(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<Option<Value>, String> {
+        let request = match &self.key {
+            Some(key) => request.bearer_auth(key),
+            None => request,
+        };
+        let response = request
+            .send()
+            .await
+            .map_err(|e| format!("unable to reach {}: {}", self.server, e))?;
+
+        let status = response.status();
+        let body = response.bytes().await.map_err(|e| e.to_string())?;
+        if body.is_empty() {
+            return Ok(None);
+        }
+        let body: Value = serde_json::from_slice(&body)
+            .map_err(|_| format!("{} didn't answer with JSON ({})", self.server, status))?;
+
+        if status.is_success() {
+            Ok(Some(body))
+        } else {
+            let error = body["error"].as_str().unwrap_or_default();
+            Err(format!("{} ({})", error, status))
+        }
+    }
+
+    pub async fn get(&self, path: &str, query: &[(&str, &str)]) -> Result<Option<Value>, String> {
+        let url = format!("{}/api/v1/{}", self.server, path);
+        self.send(self.http.get(url).query(query)).await
+    }
+
+    /// Fetches a file, eg from `/download`, along with its `Content-Type`. Not found is `None`.
+    pub async fn file(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+    ) -> Result<Option<(Vec<u8>, String)>, String> {
+        let request = self
+            .http
+            .get(format!("{}/{}", self.server, path))
+            .query(query);
+        let request = match &self.key {
+            Some(key) => request.bearer_auth(key),
+            None => request,
+        };
+        let response = request
+            .send()
+            .await
+            .map_err(|e| format!("unable to reach {}: {}", self.server, e))?;
+
+        let status = response.status();
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            return Err(format!("unable to fetch /{} ({})", path, status));
+        }
+        let content_type = response
+            .headers()
+            .get("content-type")
+            .and_then(|t| t.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+        let body = response.bytes().await.map_err(|e| e.to_string())?;
+        Ok(Some((body.to_vec(), content_type)))
+    }
+
+    pub async fn command(&self, command: Value) -> Result<Option<Value>, String> {
+        let url = format!("{}/api/v1/command", self.server);
+        self.send(self.http.post(url).json(&command)).await
+    }
+}
+
+/// A song, as `artist - title`.
+fn describe(song: &Value) -> String {
+    let title = song["title"].as_str().unwrap_or_default();
+    match song["artist"].as_str().unwrap_or_default() {
+        "" => title.to_string(),
+        artist => format!("{} - {}", artist, title),
+    }
+}
+
+fn minutes(seconds: f64) -> String {
+    let seconds = seconds.max(0.0) as u64;
+    format!("{}:{:02}", seconds / 60, seconds % 60)
+}
+
+/// Runs the command in `args`, the arguments after `remote`.
+pub async fn run(args: &[String]) -> Result<(), String> {
+    let json = args.iter().any(|a| a == "--json");
+    let words = args
+        .iter()
+        .filter(|a| !a.starts_with("--"))
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+
+    let server = server(args).unwrap_or_else(local);
+    let client = Client::new(&server);
+
+    let response = match words.as_slice() {
+        ["search", terms @ ..] if !terms.is_empty() => {
+            let terms = terms.join(" ");
+            let found = client.get("search", &[("term", &terms)]).await?;
+            if !json {
+                let results = found.as_ref().and_then(|f| f["results"].as_array());
+                for song in results.into_iter().flatten() {
+                    println!(
+                        "{}\t{}",
+                        song["id"].as_str().unwrap_or_default(),
+                        describe(song)
+                    );
+                }
+                return Ok(());
+            }
+            found
+        }
+        ["enqueue", id] => {
+            let state = client
+                .command(serde_json::json!({ "command": "enqueue", "id": id }))
+                .await?;
+            if !json {
+                let queue = state.as_ref().and_then(|s| s["queue"]["songs"].as_array());
+                if let Some(song) = queue.and_then(|q| q.last()) {
+                    println!("Added {}", describe(song));
+                }
+                return Ok(());
+            }
+            state
+        }
+        ["now-playing"] => {
+            let state = client.get("state", &[]).await?.unwrap_or_default();
+            // Without the jukebox, it's whatever the web UI last reported
+            let (song, position, paused) = if state["jukebox"] == true {
+                let song = state["now_playing"].clone();
+                let paused = state["status"] == "paused";
+                (song, state["position"].as_f64().unwrap_or_default(), paused)
+            } else {
+                let reported = client.get("now-playing", &[]).await?.unwrap_or_default();
+                let paused = reported["paused"] == true;
+                let position = reported["position"].as_f64().unwrap_or_default();
+                (reported["song"].clone(), position, paused)
+            };
+
+            if !json {
+                if song.is_null() {
+                    println!("Nothing is playing");
+                } else {
+                    let paused = if paused { ", paused" } else { "" };
+                    println!("{} ({}{})", describe(&song), minutes(position), paused);
+                }
+                return Ok(());
+            }
+            Some(song)
+        }
+        [command @ ("play" | "pause" | "next" | "stop")] => {
+            let state = client
+                .command(serde_json::json!({ "command": command }))
+                .await?;
+            if !json {
+                let state = state.unwrap_or_default();
+                if state["now_playing"].is_null() {
+                    println!("Nothing is playing");
+                } else {
+                    let status = state["status"].as_str().unwrap_or_default();
+                    println!("{}: {}", status, describe(&state["now_playing"]));
+                }
+                return Ok(());
+            }
+            state
+        }
+        _ => return Err(USAGE.to_string()),
+    };
+
+    if let Some(response) = response {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&response).unwrap_or_default()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,86 @@
+//! Checking the server's configuration (its command line and environment) as it starts. Every
+//! problem is collected, and they're reported together before exiting, rather than one at a time.
+
+use bwaabwaa::{genres::Genres, music_db::ScanOptions, scan_filter::ScanFilter};
+use std::fmt::Display;
+
+/// The problems found with the configuration so far.
+#[derive(Default)]
+pub struct Problems(Vec<String>);
+
+impl Problems {
+    /// Notes a problem with `what`, eg `--max-kbps=fast`.
+    pub fn note(&mut self, what: impl Display, problem: impl Display) {
+        self.0.push(format!("{}: {}", what, problem));
+    }
+
+    /// Gives `result`'s value, or notes its error as a problem with `what`.
+    pub fn check<T, E: Display>(&mut self, what: impl Display, result: Result<T, E>) -> Option<T> {
+        result.map_err(|e| self.note(what, e)).ok()
+    }
+
+    /// If there were any problems, lists them and exits.
+    pub fn report(self) {
+        if self.0.is_empty() {
+            return;
+        }
+
+        eprintln!(
+            "Unable to start: {} configuration problem{}",
+            self.0.len(),
+            if self.0.len() == 1 { "" } else { "s" }
+        );
+        for problem in self.0 {
+            eprintln!("  {}", problem);
+        }
+        std::process::exit(1);
+    }
+}
+
+/// The scan options in `args`: `--include=` and `--exclude=` patterns, `--scan-hidden`,
+/// `--accurate-durations`, and `--follow-symlinks`; and the genres in `genres.json`.
+pub fn scan_options(args: &[String], problems: &mut Problems) -> ScanOptions {
+    let has = |flag: &str| args.iter().any(|arg| arg == flag);
+    let patterns = |prefix: &str| {
+        args.iter()
+            .filter_map(|arg| arg.strip_prefix(prefix).map(str::to_string))
+            .collect::<Vec<_>>()
+    };
+    let filter = problems
+        .check(
+            "--include or --exclude",
+            ScanFilter::new(
+                &patterns("--include="),
+                &patterns("--exclude="),
+                has("--scan-hidden"),
+            ),
+        )
+        .unwrap_or_default();
+
+    ScanOptions {
+        accurate_durations: has("--accurate-durations"),
+        filter,
+        follow_symlinks: has("--follow-symlinks"),
+        genres: Genres::load(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scan_options_come_from_the_arguments() {
+        let args = ["--exclude=*.tmp", "--follow-symlinks", "--scan=/music"].map(String::from);
+        let mut problems = Problems::default();
+        let options = scan_options(&args, &mut problems);
+        assert!(problems.0.is_empty());
+        assert!(options.follow_symlinks && !options.accurate_durations);
+        let music = std::path::Path::new("/music");
+        assert!(!options.filter.wants_file(music, &music.join("a.tmp")));
+        assert!(options.filter.wants_file(music, &music.join("a.mp3")));
+
+        scan_options(&["--include=[".to_string()], &mut problems);
+        assert_eq!(problems.0.len(), 1);
+    }
+}
@@ -0,0 +1,141 @@
+//! The phones, tablets, and computers that listen, registered under names like "Kitchen tablet" so
+//! that play history, resume positions, and `/admin/streams` can say which was which. They're
+//! saved in `devices.json`.
+//!
+//! A client registers once with `POST /devices`, keeps the id it's given, and sends it with its
+//! requests as an `X-Device` header, or as a `device=` parameter where it can't set headers (eg an
+//! `<audio>` element's `/listen` URL). Ids that aren't registered are ignored.
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, BufWriter},
+};
+
+pub(crate) const DEVICES_FILE: &str = "devices.json";
+
+/// How often a device's `last_seen` is saved, in seconds, so that it isn't on every request
+const SEEN_EVERY: u64 = 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Device {
+    /// Its name, eg "Office PC"
+    pub name: String,
+    /// In seconds since the Unix epoch
+    pub registered: u64,
+    /// When it last made a request, to the minute, in seconds since the Unix epoch
+    pub last_seen: u64,
+}
+
+/// A device, as listed by `/devices`.
+#[derive(Serialize)]
+pub struct DeviceSummary {
+    pub id: String,
+    #[serde(flatten)]
+    pub device: Device,
+}
+
+/// The body of `POST /devices` and `PUT /devices/{id}`.
+#[derive(Deserialize)]
+pub struct DeviceName {
+    pub name: String,
+}
+
+/// The registered devices, by id.
+#[derive(Default)]
+pub struct Devices {
+    devices: BTreeMap<String, Device>,
+}
+
+impl Devices {
+    pub fn load() -> Self {
+        let devices = File::open(DEVICES_FILE)
+            .ok()
+            .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+            .unwrap_or_default();
+
+        Self { devices }
+    }
+
+    fn save(&self) {
+        let saved = File::create(DEVICES_FILE).and_then(|file| {
+            serde_json::to_writer_pretty(BufWriter::new(file), &self.devices)?;
+            Ok(())
+        });
+        if let Err(e) = saved {
+            eprintln!("Unable to save devices: {:?}", e);
+        }
+    }
+
+    /// Registers a device, giving it a new id.
+    pub fn register(&mut self, name: &str) -> DeviceSummary {
+        let mut id = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut id);
+        let id = id.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+
+        let now = crate::history::now();
+        let device = Device {
+            name: name.trim().to_string(),
+            registered: now,
+            last_seen: now,
+        };
+        self.devices.insert(id.clone(), device.clone());
+        self.save();
+
+        DeviceSummary { id, device }
+    }
+
+    /// Renames a device, returning it, or `None` if there's no such device.
+    pub fn rename(&mut self, id: &str, name: &str) -> Option<DeviceSummary> {
+        let device = self.devices.get_mut(id)?;
+        device.name = name.trim().to_string();
+        let device = device.clone();
+        self.save();
+
+        Some(DeviceSummary {
+            id: id.to_string(),
+            device,
+        })
+    }
+
+    /// Forgets a device; returns whether there was one.
+    pub fn remove(&mut self, id: &str) -> bool {
+        let removed = self.devices.remove(id).is_some();
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    /// Lists the devices, most recently seen first.
+    pub fn list(&self) -> Vec<DeviceSummary> {
+        let mut devices = self
+            .devices
+            .iter()
+            .map(|(id, device)| DeviceSummary {
+                id: id.clone(),
+                device: device.clone(),
+            })
+            .collect::<Vec<_>>();
+        devices.sort_by_key(|d| std::cmp::Reverse(d.device.last_seen));
+        devices
+    }
+
+    /// A device's name, if it's registered.
+    pub fn name(&self, id: &str) -> Option<&str> {
+        self.devices.get(id).map(|d| d.name.as_str())
+    }
+
+    /// Notes that a device made a request, returning its id if it's registered.
+    pub fn seen(&mut self, id: &str) -> Option<String> {
+        let device = self.devices.get_mut(id)?;
+        let now = crate::history::now();
+        if now >= device.last_seen + SEEN_EVERY {
+            device.last_seen = now;
+            self.save();
+        }
+        Some(id.to_string())
+    }
+}
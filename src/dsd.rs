@@ -0,0 +1,232 @@
+//! DSD files, as Sony's DSF or Philips' DSDIFF (`.dff`): one-bit audio at several megahertz, as
+//! on SACDs. Their stream info comes from their headers, and their tags from the ID3v2 tag both
+//! can carry in a chunk of their own. No browser plays DSD, so `/listen` serves them converted to
+//! high-rate PCM; see `transcode`.
+
+use crate::metadata::MetadataReader;
+use crate::song::Song;
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+    time::Duration,
+};
+
+/// The extensions of DSD files
+pub const FORMATS: &[&str] = &["dsf", "dff"];
+
+/// The sample rate of CD audio, which DSD's are multiples of
+const CD_RATE: u32 = 44100;
+
+/// What a DSD file's header gives, and where its ID3 tag is, if it has one.
+#[derive(Debug, PartialEq)]
+struct Header {
+    sample_rate: u32,
+    channels: u8,
+    /// Samples per channel
+    samples: u64,
+    /// Where the ID3 tag starts, and its length
+    id3: Option<(u64, u64)>,
+}
+
+fn u32_le(buf: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(buf.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn u64_le(buf: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(buf.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+/// Reads a DSF file's `DSD ` and `fmt ` chunks, which are always first.
+fn dsf_header(buf: &[u8], size: u64) -> Option<Header> {
+    if buf.get(..4)? != b"DSD " || buf.get(28..32)? != b"fmt " {
+        return None;
+    }
+    let metadata = u64_le(buf, 20)?;
+    Some(Header {
+        channels: u32_le(buf, 52)?.min(u8::MAX as u32) as u8,
+        sample_rate: u32_le(buf, 56)?,
+        samples: u64_le(buf, 64)?,
+        // The tag runs to the end of the file
+        id3: (metadata > 0 && metadata < size).then(|| (metadata, size - metadata)),
+    })
+}
+
+/// Reads a DSDIFF file's chunks: an IFF-style `FRM8` holding the sound properties, the sound
+/// data, and perhaps an `ID3 ` chunk, all big-endian.
+fn dff_header(file: &mut (impl Read + Seek), size: u64) -> io::Result<Option<Header>> {
+    let mut form = [0u8; 16];
+    file.read_exact(&mut form)?;
+    if &form[..4] != b"FRM8" || &form[12..] != b"DSD " {
+        return Ok(None);
+    }
+
+    let (mut sample_rate, mut channels, mut data, mut id3) = (0, 0, None, None);
+    let mut pos: u64 = 16;
+    // The lengths are the file's word, so a crafted one mustn't overflow, or send this back
+    // where it started
+    while pos.saturating_add(12) <= size {
+        file.seek(SeekFrom::Start(pos))?;
+        let mut chunk = [0u8; 12];
+        file.read_exact(&mut chunk)?;
+        let len = u64::from_be_bytes(chunk[4..].try_into().unwrap_or_default());
+        let start = pos + 12;
+        match &chunk[..4] {
+            b"PROP" => {
+                // "SND ", then the properties' own chunks
+                let mut prop = vec![0u8; len.min(4096) as usize];
+                file.read_exact(&mut prop)?;
+                let mut at = 4;
+                while at + 12 <= prop.len() {
+                    let sub_len =
+                        u64::from_be_bytes(prop[at + 4..at + 12].try_into().unwrap_or_default());
+                    let body = &prop[at + 12..];
+                    match &prop[at..at + 4] {
+                        b"FS  " if body.len() >= 4 => {
+                            sample_rate =
+                                u32::from_be_bytes(body[..4].try_into().unwrap_or_default());
+                        }
+                        b"CHNL" if body.len() >= 2 => {
+                            channels =
+                                u16::from_be_bytes([body[0], body[1]]).min(u8::MAX as u16) as u8;
+                        }
+                        _ => {}
+                    }
+                    let next = usize::try_from(sub_len)
+                        .ok()
+                        .and_then(|len| len.checked_add(12 + (sub_len % 2) as usize))
+                        .and_then(|len| at.checked_add(len));
+                    match next {
+                        Some(next) => at = next,
+                        None => break,
+                    }
+                }
+            }
+            b"DSD " => data = Some(len),
+            b"ID3 " => id3 = Some((start, len)),
+            _ => {}
+        }
+        // Chunks are padded to an even length
+        match start
+            .checked_add(len)
+            .and_then(|end| end.checked_add(len % 2))
+        {
+            Some(next) if next > pos => pos = next,
+            _ => break,
+        }
+    }
+
+    let Some(data) = data.filter(|_| channels > 0) else {
+        return Ok(None);
+    };
+    Ok(Some(Header {
+        sample_rate,
+        channels,
+        // A bit per sample
+        samples: data.saturating_mul(8) / channels as u64,
+        id3,
+    }))
+}
+
+/// Reads DSF and DSDIFF files.
+pub struct DsdReader;
+
+impl MetadataReader for DsdReader {
+    fn read(&self, path: &Path) -> Result<Song, io::Error> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let header = if path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("dff"))
+        {
+            dff_header(&mut file, size)?
+        } else {
+            let mut start = [0u8; 80];
+            file.read_exact(&mut start)?;
+            dsf_header(&start, size)
+        };
+        let header = header
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Can't read DSD header"))?;
+
+        let mut song = match header.id3 {
+            Some((start, len)) => {
+                file.seek(SeekFrom::Start(start))?;
+                match id3::Tag::read_from2((&mut file).take(len)) {
+                    Ok(tag) => crate::metadata::song_from_id3(&tag),
+                    Err(_) => Song::default(),
+                }
+            }
+            None => Song::default(),
+        };
+        song.sample_rate = header.sample_rate;
+        song.channels = header.channels;
+        // eg "DSD64", for 64 times the CD sample rate
+        song.codec = format!("DSD{}", header.sample_rate / CD_RATE);
+        song.bitrate =
+            (header.sample_rate as u64 * header.channels as u64 / 1000).min(u16::MAX as u64) as u16;
+        if header.sample_rate > 0 {
+            song.duration =
+                Duration::from_secs_f64(header.samples as f64 / header.sample_rate as f64);
+        }
+        Ok(song)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_dff_headers() {
+        let mut file = Vec::new();
+        file.extend_from_slice(b"FRM8");
+        file.extend_from_slice(&0u64.to_be_bytes());
+        file.extend_from_slice(b"DSD ");
+        file.extend_from_slice(b"PROP");
+        file.extend_from_slice(&34u64.to_be_bytes());
+        file.extend_from_slice(b"SND ");
+        file.extend_from_slice(b"FS  ");
+        file.extend_from_slice(&4u64.to_be_bytes());
+        file.extend_from_slice(&2822400u32.to_be_bytes());
+        file.extend_from_slice(b"CHNL");
+        file.extend_from_slice(&2u64.to_be_bytes());
+        file.extend_from_slice(&2u16.to_be_bytes());
+        // A second of stereo sound
+        file.extend_from_slice(b"DSD ");
+        file.extend_from_slice(&705600u64.to_be_bytes());
+
+        let size = 16 + 12 + 34 + 12 + 705600;
+        let header = dff_header(&mut io::Cursor::new(file), size).unwrap();
+        assert_eq!(
+            header,
+            Some(Header {
+                sample_rate: 2822400,
+                channels: 2,
+                samples: 2822400,
+                id3: None,
+            })
+        );
+    }
+
+    #[test]
+    fn stops_at_overflowing_chunk_lengths() {
+        for len in [u64::MAX, u64::MAX - 11, u64::MAX - 12] {
+            let mut file = Vec::new();
+            file.extend_from_slice(b"FRM8");
+            file.extend_from_slice(&0u64.to_be_bytes());
+            file.extend_from_slice(b"DSD ");
+            file.extend_from_slice(b"PROP");
+            file.extend_from_slice(&28u64.to_be_bytes());
+            file.extend_from_slice(b"SND ");
+            file.extend_from_slice(b"FS  ");
+            file.extend_from_slice(&len.to_be_bytes());
+            file.extend_from_slice(&[0; 12]);
+            file.extend_from_slice(b"JUNK");
+            file.extend_from_slice(&len.to_be_bytes());
+
+            let size = file.len() as u64;
+            let header = dff_header(&mut io::Cursor::new(file), size).unwrap();
+            assert_eq!(header, None);
+        }
+    }
+}
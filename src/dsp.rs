@@ -0,0 +1,202 @@
+//! The jukebox's equalizer: a 10-band graphic EQ, an optional downmix to mono, and left/right
+//! balance, applied to every song as it plays.
+//!
+//! Speakers and headphones each want their own, so an equalizer is kept per output device, saved
+//! in `eq.json` by the device's name.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    f32::consts::PI,
+    fs::File,
+    io::{BufReader, BufWriter},
+};
+
+pub(crate) const EQ_FILE: &str = "eq.json";
+
+/// The EQ bands' center frequencies, in Hz, an octave apart
+pub const BANDS: [f32; 10] = [
+    31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+
+/// The most a band can be cut or boosted, in dB
+pub const MAX_GAIN: f32 = 12.0;
+
+/// How wide each band is: about an octave
+const Q: f32 = 1.41;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Equalizer {
+    /// The gain of each of `BANDS`, in dB
+    pub gains: [f32; 10],
+    /// Whether to mix the channels down to mono, eg for a single speaker
+    pub mono: bool,
+    /// From -1.0 (left only) through 0.0 (centered) to 1.0 (right only)
+    pub balance: f32,
+}
+
+impl Equalizer {
+    /// Keeps the gains and balance within their ranges.
+    pub fn clamped(mut self) -> Self {
+        for gain in &mut self.gains {
+            *gain = gain.clamp(-MAX_GAIN, MAX_GAIN);
+        }
+        self.balance = self.balance.clamp(-1.0, 1.0);
+        self
+    }
+}
+
+/// A peaking filter, from the Audio EQ Cookbook.
+#[derive(Clone)]
+struct Biquad {
+    b: [f32; 3],
+    a: [f32; 2],
+    /// The last two inputs and outputs
+    x: [f32; 2],
+    y: [f32; 2],
+}
+
+impl Biquad {
+    fn peaking(frequency: f32, gain: f32, sample_rate: u32) -> Self {
+        let a = 10f32.powf(gain / 40.0);
+        let w0 = 2.0 * PI * frequency / sample_rate as f32;
+        let alpha = w0.sin() / (2.0 * Q);
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha / a;
+
+        Biquad {
+            b: [
+                (1.0 + alpha * a) / a0,
+                -2.0 * cos / a0,
+                (1.0 - alpha * a) / a0,
+            ],
+            a: [-2.0 * cos / a0, (1.0 - alpha / a) / a0],
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// Applies an `Equalizer` to audio, a frame (one sample per channel) at a time.
+pub struct Processor {
+    equalizer: Equalizer,
+    /// The filters for each channel; bands with no gain, or above what the sample rate can
+    /// carry, are left out
+    filters: Vec<Vec<Biquad>>,
+}
+
+impl Processor {
+    pub fn new(equalizer: Equalizer, sample_rate: u32, channels: u16) -> Self {
+        let nyquist = sample_rate as f32 / 2.0;
+        let filters = BANDS
+            .iter()
+            .zip(equalizer.gains)
+            .filter(|&(&frequency, gain)| gain != 0.0 && frequency < nyquist)
+            .map(|(&frequency, gain)| Biquad::peaking(frequency, gain, sample_rate))
+            .collect::<Vec<_>>();
+
+        Processor {
+            equalizer,
+            filters: vec![filters; usize::from(channels)],
+        }
+    }
+
+    /// Processes a frame of samples, from -1.0 to 1.0, in place. The result may go past 1.0, if
+    /// bands are boosted.
+    pub fn process(&mut self, frame: &mut [f32]) {
+        for (sample, filters) in frame.iter_mut().zip(&mut self.filters) {
+            for filter in filters {
+                *sample = filter.process(*sample);
+            }
+        }
+
+        if self.equalizer.mono && frame.len() > 1 {
+            let mixed = frame.iter().sum::<f32>() / frame.len() as f32;
+            frame.fill(mixed);
+        }
+
+        if let [left, right] = frame {
+            let balance = self.equalizer.balance;
+            *left *= (1.0 - balance).min(1.0);
+            *right *= (1.0 + balance).min(1.0);
+        }
+    }
+}
+
+/// The equalizer for each output device, by name.
+#[derive(Default)]
+pub struct Equalizers {
+    by_device: HashMap<String, Equalizer>,
+}
+
+impl Equalizers {
+    pub fn load() -> Self {
+        let by_device = match File::open(EQ_FILE) {
+            Ok(file) => match serde_json::from_reader(BufReader::new(file)) {
+                Ok(by_device) => by_device,
+                Err(e) => {
+                    eprintln!("Ignoring {EQ_FILE}: {}", e);
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+
+        Equalizers { by_device }
+    }
+
+    fn save(&self) {
+        let saved = File::create(EQ_FILE).and_then(|file| {
+            serde_json::to_writer_pretty(BufWriter::new(file), &self.by_device)?;
+            Ok(())
+        });
+        if let Err(e) = saved {
+            eprintln!("Unable to save the equalizers: {:?}", e);
+        }
+    }
+
+    /// The equalizer for `device`; flat if it hasn't been set.
+    pub fn get(&self, device: &str) -> Equalizer {
+        self.by_device.get(device).copied().unwrap_or_default()
+    }
+
+    pub fn set(&mut self, device: &str, equalizer: Equalizer) {
+        self.by_device.insert(device.to_string(), equalizer);
+        self.save();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flat_changes_nothing() {
+        let mut processor = Processor::new(Equalizer::default(), 44100, 2);
+        let mut frame = [0.5, -0.25];
+        processor.process(&mut frame);
+        assert_eq!(frame, [0.5, -0.25]);
+    }
+
+    #[test]
+    fn mono_and_balance() {
+        let equalizer = Equalizer {
+            mono: true,
+            balance: 0.5,
+            ..Default::default()
+        };
+        let mut processor = Processor::new(equalizer, 44100, 2);
+        let mut frame = [0.5, 0.0];
+        processor.process(&mut frame);
+        assert_eq!(frame, [0.125, 0.25]);
+    }
+}
@@ -0,0 +1,327 @@
+//! Finding copies of the same song in the library, eg an old MP3 rip alongside a newer FLAC, and
+//! resolving them by keeping the best.
+//!
+//! Songs are copies when they have the same artist, album, and title, and are within a few
+//! seconds of each other in length. The best copy is lossless if any is, then has the highest
+//! bitrate, then sample rate. Resolving moves the other copies' plays and playlist entries to it;
+//! with `trash`, their files are also moved into a `.bwaabwaa-trash` directory in their root
+//! (which scans skip, being hidden) and they're dropped from the library. Files in read-only roots
+//! are never trashed.
+
+use crate::history::PlayHistory;
+use crate::music_db::MusicDB;
+use crate::playlists::Playlists;
+use crate::roots;
+use crate::song::{Song, SongResult};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    time::Duration,
+};
+
+/// Where files trashed by resolving duplicates go, under their root
+pub const TRASH_DIR: &str = ".bwaabwaa-trash";
+
+/// How different copies' lengths can be, to still be the same recording
+const SLACK: Duration = Duration::from_secs(3);
+
+/// The extensions of lossless formats, which beat any bitrate
+const LOSSLESS: &[&str] = &["flac", "wav", "aif", "aiff", "ape", "wv", "alac"];
+
+/// One copy of a song.
+#[derive(Serialize)]
+pub struct SongCopy {
+    #[serde(with = "crate::paths::serde_path")]
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub song: SongResult,
+}
+
+impl From<&Song> for SongCopy {
+    fn from(song: &Song) -> Self {
+        SongCopy {
+            path: song.path.clone(),
+            song: song.into(),
+        }
+    }
+}
+
+/// Copies of one song, best first.
+#[derive(Serialize)]
+pub struct DuplicateGroup {
+    pub copies: Vec<SongCopy>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResolveTerms {
+    /// Makes the changes; otherwise they're only reported, to be checked first
+    #[serde(default)]
+    pub apply: bool,
+    /// Moves the copies not kept to the trash, and out of the library
+    #[serde(default)]
+    pub trash: bool,
+}
+
+/// The copy kept of one song, and those it replaces.
+#[derive(Serialize)]
+pub struct Resolution {
+    pub keep: SongCopy,
+    pub replace: Vec<SongCopy>,
+}
+
+/// A file moved to the trash.
+#[derive(Serialize)]
+pub struct Trashed {
+    #[serde(with = "crate::paths::serde_path")]
+    pub from: PathBuf,
+    #[serde(with = "crate::paths::serde_path")]
+    pub to: PathBuf,
+}
+
+/// What resolving did, or would do.
+#[derive(Serialize, Default)]
+pub struct Resolved {
+    /// Whether the changes were made, rather than just reported
+    pub applied: bool,
+    pub resolutions: Vec<Resolution>,
+    /// How many plays move to the copies kept
+    pub plays: usize,
+    /// How many playlist entries move to the copies kept
+    pub playlist_entries: usize,
+    pub trashed: Vec<Trashed>,
+    /// What went wrong, eg files that couldn't be moved to the trash, which stay in the library
+    pub errors: Vec<String>,
+}
+
+/// How good a copy is: higher is better.
+fn quality(song: &Song) -> (bool, u16, u32, u64) {
+    let lossless = LOSSLESS.contains(&song.format().as_str());
+    (lossless, song.bitrate, song.sample_rate, song.size)
+}
+
+/// Finds every song with more than one copy, by artist, album, and title. Each song's copies come
+/// best first.
+pub fn find(db: &MusicDB) -> Vec<Vec<&Song>> {
+    let mut by_name: BTreeMap<(&str, &str, &str), Vec<&Song>> = BTreeMap::new();
+    for song in db.records.values().filter(|s| !s.title_lower.is_empty()) {
+        let name = (&*song.artist_lower, &*song.album_lower, &*song.title_lower);
+        by_name.entry(name).or_default().push(song);
+    }
+
+    let mut groups = Vec::new();
+    for (_, mut songs) in by_name.into_iter().filter(|(_, s)| s.len() > 1) {
+        // Split up different recordings with the same name, eg a live version, by their length
+        songs.sort_by_key(|s| s.duration);
+        let mut group: Vec<&Song> = Vec::new();
+        for song in songs {
+            if group
+                .first()
+                .is_some_and(|first| song.duration - first.duration > SLACK)
+            {
+                groups.push(std::mem::take(&mut group));
+            }
+            group.push(song);
+        }
+        groups.push(group);
+    }
+
+    groups.retain(|group| group.len() > 1);
+    for group in &mut groups {
+        group.sort_by(|a, b| quality(b).cmp(&quality(a)).then(a.id.cmp(&b.id)));
+    }
+    groups
+}
+
+/// Lists every song with more than one copy.
+pub fn report(db: &MusicDB) -> Vec<DuplicateGroup> {
+    find(db)
+        .into_iter()
+        .map(|group| DuplicateGroup {
+            copies: group.into_iter().map(SongCopy::from).collect(),
+        })
+        .collect()
+}
+
+/// Where a song's file goes in the trash, if it's a local file in one of the library's roots.
+fn trash_path(song: &Song) -> Option<PathBuf> {
+    if crate::paths::is_remote(&song.path) || song.root.as_os_str().is_empty() {
+        return None;
+    }
+    let relative = song.path.strip_prefix(&song.root).ok()?;
+    Some(song.root.join(TRASH_DIR).join(relative))
+}
+
+/// Keeps the best copy of each song with several, moving the others' plays and playlist entries
+/// to it, and trashing them if asked to. Unless `terms.apply`, nothing is changed; what would be
+/// is returned, so it can be checked first.
+pub fn resolve(
+    terms: &ResolveTerms,
+    db: &mut MusicDB,
+    history: &mut PlayHistory,
+    playlists: &mut Playlists,
+) -> Resolved {
+    let mut resolved = Resolved {
+        applied: terms.apply,
+        ..Default::default()
+    };
+    let mut replaced = HashMap::new();
+    let mut to_trash = Vec::new();
+    for group in find(db) {
+        let (keep, rest) = group.split_first().expect("groups have several copies");
+        for song in rest {
+            replaced.insert(song.id, keep.id);
+            if terms.trash {
+                let read_only = roots::find(&db.roots, &song.path).is_some_and(|r| r.read_only);
+                match trash_path(song) {
+                    _ if read_only => resolved.errors.push(format!(
+                        "{} is in a read-only root, so can't be trashed",
+                        song.path.display()
+                    )),
+                    Some(to) => to_trash.push((song.id, song.path.clone(), to)),
+                    None => resolved.errors.push(format!(
+                        "{} isn't a local file in a root, so can't be trashed",
+                        song.path.display()
+                    )),
+                }
+            }
+        }
+        resolved.resolutions.push(Resolution {
+            keep: SongCopy::from(*keep),
+            replace: rest.iter().map(|&song| SongCopy::from(song)).collect(),
+        });
+    }
+
+    if !terms.apply {
+        resolved.plays = history
+            .plays
+            .iter()
+            .filter(|p| replaced.contains_key(&p.id))
+            .count();
+        resolved.playlist_entries = playlists.count(&replaced);
+        resolved.trashed = to_trash
+            .into_iter()
+            .map(|(_, from, to)| Trashed { from, to })
+            .collect();
+        return resolved;
+    }
+
+    resolved.plays = match history.repoint(&replaced) {
+        Ok(plays) => plays,
+        Err(e) => {
+            resolved
+                .errors
+                .push(format!("unable to save play history: {}", e));
+            0
+        }
+    };
+    resolved.playlist_entries = playlists.repoint(&replaced);
+
+    for (id, from, to) in to_trash {
+        let moved = to
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::rename(&from, &to));
+        match moved {
+            Ok(()) => {
+                db.records.remove(&id);
+                resolved.trashed.push(Trashed { from, to });
+            }
+            Err(e) => resolved
+                .errors
+                .push(format!("unable to trash {}: {}", from.display(), e)),
+        }
+    }
+    if !resolved.trashed.is_empty() {
+        db.mark_changed();
+        db.save();
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roots::Root;
+
+    fn song(id: u64, path: &str, seconds: u64, bitrate: u16) -> Song {
+        Song {
+            id,
+            path: path.into(),
+            title: "Everlong".to_string(),
+            artist: "Foo Fighters".to_string(),
+            album: "The Colour and the Shape".to_string(),
+            title_lower: "everlong".to_string(),
+            artist_lower: "foo fighters".to_string(),
+            album_lower: "the colour and the shape".to_string(),
+            duration: Duration::from_secs(seconds),
+            bitrate,
+            ..Default::default()
+        }
+    }
+
+    fn ids(groups: Vec<Vec<&Song>>) -> Vec<Vec<u64>> {
+        groups
+            .into_iter()
+            .map(|group| group.into_iter().map(|s| s.id).collect())
+            .collect()
+    }
+
+    #[test]
+    fn keeps_the_best_copy_first() {
+        let mut db = MusicDB::default();
+        for song in [
+            song(1, "/music/a/everlong.mp3", 250, 128),
+            song(2, "/music/b/everlong.mp3", 251, 320),
+            song(3, "/music/c/everlong.flac", 250, 0),
+            // A live version, which isn't a copy
+            song(4, "/music/d/everlong.mp3", 290, 320),
+        ] {
+            db.records.insert(song.id, song);
+        }
+        assert_eq!(ids(find(&db)), vec![vec![3, 2, 1]]);
+    }
+
+    #[test]
+    fn trashes_within_the_root() {
+        let mut copy = song(1, "/music/a/everlong.mp3", 250, 128);
+        copy.root = "/music".into();
+        assert_eq!(
+            trash_path(&copy),
+            Some(PathBuf::from("/music/.bwaabwaa-trash/a/everlong.mp3"))
+        );
+
+        copy.path = "s3://bucket/everlong.mp3".into();
+        assert_eq!(trash_path(&copy), None);
+    }
+
+    #[test]
+    fn leaves_read_only_roots_alone() {
+        let mut db = MusicDB::default();
+        db.roots = vec![Root {
+            read_only: true,
+            ..Root::new("/share".into())
+        }];
+        for mut song in [
+            song(1, "/share/a/everlong.mp3", 250, 128),
+            song(2, "/share/b/everlong.mp3", 250, 320),
+        ] {
+            song.root = "/share".into();
+            db.records.insert(song.id, song);
+        }
+
+        let terms = ResolveTerms {
+            apply: false,
+            trash: true,
+        };
+        let resolved = resolve(
+            &terms,
+            &mut db,
+            &mut PlayHistory::default(),
+            &mut Playlists::default(),
+        );
+        assert!(resolved.trashed.is_empty());
+        assert_eq!(resolved.errors.len(), 1);
+        assert!(resolved.errors[0].contains("read-only"));
+    }
+}
@@ -0,0 +1,235 @@
+//! Errors returned to clients as JSON, eg
+//! `{"status": 404, "error": "id=123 not found", "request_id": "5f1c0a93d2e8b647"}`.
+//!
+//! Every request gets an id, which is given back in an `X-Request-Id` header (a proxy in front can
+//! set its own), and errors are logged with it, so one a client reports can be found in the logs.
+
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use rand::RngCore;
+use serde::Serialize;
+use std::convert::Infallible;
+use warp::{
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE},
+        HeaderMap, HeaderValue, Method, StatusCode,
+    },
+    path::FullPath,
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+/// The longest request id taken from a proxy
+const MAX_REQUEST_ID: usize = 64;
+
+/// A rejection carrying a message for the client.
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    NotFound(String),
+    Unauthorized(String),
+    Forbidden(String),
+    Internal(String),
+    Unavailable(String),
+}
+
+impl warp::reject::Reject for ApiError {}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            ApiError::BadRequest(m)
+            | ApiError::NotFound(m)
+            | ApiError::Unauthorized(m)
+            | ApiError::Forbidden(m)
+            | ApiError::Internal(m)
+            | ApiError::Unavailable(m) => m,
+        }
+    }
+}
+
+/// Rejects with a 400.
+pub fn bad_request(message: impl Into<String>) -> Rejection {
+    warp::reject::custom(ApiError::BadRequest(message.into()))
+}
+
+/// Rejects with a 404.
+pub fn not_found(message: impl Into<String>) -> Rejection {
+    warp::reject::custom(ApiError::NotFound(message.into()))
+}
+
+/// Rejects with a 401.
+pub fn unauthorized(message: impl Into<String>) -> Rejection {
+    warp::reject::custom(ApiError::Unauthorized(message.into()))
+}
+
+/// Rejects with a 403.
+pub fn forbidden(message: impl Into<String>) -> Rejection {
+    warp::reject::custom(ApiError::Forbidden(message.into()))
+}
+
+/// A page that needs signing in first; sends the browser to `/login`, and then back to `next`.
+#[derive(Debug)]
+pub struct LoginRequired {
+    next: String,
+}
+
+impl warp::reject::Reject for LoginRequired {}
+
+pub fn login_required(next: String) -> Rejection {
+    warp::reject::custom(LoginRequired { next })
+}
+
+/// Rejects with a 500.
+pub fn internal(message: impl Into<String>) -> Rejection {
+    warp::reject::custom(ApiError::Internal(message.into()))
+}
+
+/// Rejects with a 503, for things that should come back on their own.
+pub fn unavailable(message: impl Into<String>) -> Rejection {
+    warp::reject::custom(ApiError::Unavailable(message.into()))
+}
+
+/// Parses a song id, rejecting with a 400 if it isn't one.
+pub fn parse_id(id: &str) -> Result<u64, Rejection> {
+    id.parse::<u64>()
+        .map_err(|_| bad_request(format!("invalid id: {}", id)))
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    status: u16,
+    error: &'a str,
+}
+
+/// Turns any rejection into a JSON error response with a matching status code.
+pub async fn handle_rejection(err: Rejection) -> Result<warp::reply::Response, Infallible> {
+    if let Some(LoginRequired { next }) = err.find() {
+        let next = utf8_percent_encode(next, NON_ALPHANUMERIC);
+        return Ok(warp::http::Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header("location", format!("/login?next={}", next))
+            .body(Default::default())
+            .unwrap());
+    }
+
+    let (status, message) = if let Some(e) = err.find::<ApiError>() {
+        (e.status(), e.message().to_string())
+    } else if err.is_not_found() {
+        (StatusCode::NOT_FOUND, "not found".to_string())
+    } else if let Some(e) = err.find::<warp::reject::InvalidQuery>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::InvalidHeader>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::MissingHeader>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = err.find::<warp::body::BodyDeserializeError>() {
+        (StatusCode::BAD_REQUEST, e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::UnsupportedMediaType>() {
+        (StatusCode::UNSUPPORTED_MEDIA_TYPE, e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::PayloadTooLarge>() {
+        (StatusCode::PAYLOAD_TOO_LARGE, e.to_string())
+    } else if let Some(e) = err.find::<warp::reject::MethodNotAllowed>() {
+        (StatusCode::METHOD_NOT_ALLOWED, e.to_string())
+    } else {
+        eprintln!("Unhandled rejection: {:?}", err);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal server error".to_string(),
+        )
+    };
+
+    let body = ErrorBody {
+        status: status.as_u16(),
+        error: &message,
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&body), status).into_response())
+}
+
+/// The request's id: the `X-Request-Id` header, if a proxy in front set a sensible one, or a new
+/// one.
+pub fn request_id() -> impl Filter<Extract = (String,), Error = Infallible> + Clone {
+    warp::header::headers_cloned().map(|headers: HeaderMap| {
+        let given = headers
+            .get("x-request-id")
+            .and_then(|id| id.to_str().ok())
+            .filter(|id| {
+                // It goes in the logs, so only the likes of a UUID
+                !id.is_empty()
+                    && id.len() <= MAX_REQUEST_ID
+                    && id
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            });
+        match given {
+            Some(id) => id.to_string(),
+            None => {
+                let mut id = [0u8; 8];
+                rand::thread_rng().fill_bytes(&mut id);
+                id.iter().map(|b| format!("{:02x}", b)).collect()
+            }
+        }
+    })
+}
+
+/// Gives a response its request's id, in an `X-Request-Id` header, and if it's an error, in its
+/// JSON body too; errors are logged with it.
+pub async fn tag_response(
+    id: String,
+    method: Method,
+    path: FullPath,
+    reply: impl Reply,
+) -> Response {
+    let mut response = reply.into_response();
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert("x-request-id", value);
+    }
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+
+    let json = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|t| t.as_bytes().starts_with(b"application/json"));
+    if !json {
+        eprintln!("[{}] {} {}: {}", id, method, path.as_str(), status);
+        return response;
+    }
+
+    // Error bodies are small, so it's read and written again with the id in it
+    let (mut parts, body) = response.into_parts();
+    let body = warp::hyper::body::to_bytes(body).await.unwrap_or_default();
+    let tagged = match serde_json::from_slice(&body) {
+        Ok(serde_json::Value::Object(mut error)) => {
+            let message = error.get("error").and_then(|m| m.as_str()).unwrap_or("");
+            eprintln!(
+                "[{}] {} {}: {} {}",
+                id,
+                method,
+                path.as_str(),
+                status,
+                message
+            );
+            error.insert("request_id".to_string(), id.into());
+            serde_json::to_vec(&error).ok()
+        }
+        _ => {
+            eprintln!("[{}] {} {}: {}", id, method, path.as_str(), status);
+            None
+        }
+    };
+    parts.headers.remove(CONTENT_LENGTH);
+    Response::from_parts(parts, tagged.map_or(body, Into::into).into())
+}
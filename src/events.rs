@@ -0,0 +1,299 @@
+//! The event bus. Whatever happens that integrations care about (a scan finishing, a song
+//! starting, the queue running out) is published here once, as a `ServerEvent`, and each
+//! integration subscribes to it rather than being wired into every handler that might cause it:
+//!
+//! - webhooks, as configured in `webhooks.json`; see `webhooks`
+//! - scrobbling to ListenBrainz; see `scrobble`
+//! - posting what's playing to Discord, and telling Telegram chats; see `now_playing` and
+//!   `telegram`
+//! - clients listening live, to `GET /events` as server-sent events, or to `GET /events/ws` over a
+//!   WebSocket
+//!
+//! Every event goes out as JSON, tagged by name and timestamped, eg
+//! `{"event": "now_playing", "at": 1634400000, "song": {...}}`.
+
+use crate::music_db::MusicDB;
+use crate::song::{AlbumKey, Song, SongResult};
+use futures::{SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, convert::Infallible, sync::Arc, time::Duration};
+use tokio::sync::broadcast;
+use warp::ws::{Message, WebSocket};
+
+/// How many events a live client can fall behind before it misses some
+const LIVE_BACKLOG: usize = 64;
+
+/// Something that happened.
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ServerEvent {
+    /// A scan finished
+    ScanComplete {
+        tracks: usize,
+        /// How many of `tracks` were new to the library
+        added: usize,
+        seconds: f64,
+    },
+    /// A scan found an album that wasn't in the library before
+    NewAlbum {
+        artist: String,
+        album: String,
+        tracks: usize,
+    },
+    /// A song started playing
+    NowPlaying { song: Box<SongResult> },
+    /// A play was counted half-way through, from a client's progress reports; see `progress`
+    Listened {
+        song: Box<SongResult>,
+        /// When it started, in seconds since the Unix epoch
+        started: u64,
+    },
+    /// The last track of an album finished playing
+    AlbumFinished {
+        artist: String,
+        album: String,
+        tracks: usize,
+    },
+    /// The queue ran out, so nothing's playing next
+    QueueEmpty,
+}
+
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    ScanComplete,
+    NewAlbum,
+    NowPlaying,
+    Listened,
+    AlbumFinished,
+    QueueEmpty,
+}
+
+impl ServerEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            ServerEvent::ScanComplete { .. } => EventKind::ScanComplete,
+            ServerEvent::NewAlbum { .. } => EventKind::NewAlbum,
+            ServerEvent::NowPlaying { .. } => EventKind::NowPlaying,
+            ServerEvent::Listened { .. } => EventKind::Listened,
+            ServerEvent::AlbumFinished { .. } => EventKind::AlbumFinished,
+            ServerEvent::QueueEmpty => EventKind::QueueEmpty,
+        }
+    }
+}
+
+/// An event as it's published.
+#[derive(Serialize, Debug)]
+pub struct Published {
+    #[serde(flatten)]
+    pub event: ServerEvent,
+    /// When it happened, in seconds since the Unix epoch
+    pub at: u64,
+}
+
+/// An integration that's told about each event.
+pub trait Subscriber: Send + Sync {
+    /// Takes an event as it's published, so anything slow belongs in the background.
+    fn notify(&self, published: &Published);
+}
+
+/// Where events are published. Cheap to clone.
+#[derive(Clone)]
+pub struct EventBus {
+    subscribers: Arc<Vec<Box<dyn Subscriber>>>,
+    live: broadcast::Sender<Arc<Published>>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+impl EventBus {
+    pub fn new(subscribers: Vec<Box<dyn Subscriber>>) -> Self {
+        let (live, _) = broadcast::channel(LIVE_BACKLOG);
+        EventBus {
+            subscribers: Arc::new(subscribers),
+            live,
+        }
+    }
+
+    /// Tells every subscriber, and every live client, about `event`.
+    ///
+    /// Must be called from within a Tokio runtime, for subscribers that send it on.
+    pub fn publish(&self, event: ServerEvent) {
+        let published = Arc::new(Published {
+            event,
+            at: crate::history::now(),
+        });
+        for subscriber in self.subscribers.iter() {
+            subscriber.notify(&published);
+        }
+        // Nobody may be listening live
+        let _ = self.live.send(published);
+    }
+
+    /// The events from now on, as server-sent events.
+    pub fn sse(&self) -> impl warp::Reply {
+        let events = live(self.live.subscribe()).map(|published| {
+            Ok::<_, Infallible>(
+                warp::sse::Event::default()
+                    .json_data(&*published)
+                    .unwrap_or_default(),
+            )
+        });
+        warp::sse::reply(warp::sse::keep_alive().stream(events))
+    }
+
+    /// Sends the events from now on over `socket`, until it's closed.
+    pub async fn stream(self, socket: WebSocket) {
+        let (mut tx, mut rx) = socket.split();
+        let mut events = Box::pin(live(self.live.subscribe()));
+
+        loop {
+            tokio::select! {
+                published = events.next() => {
+                    let Some(published) = published else {
+                        break;
+                    };
+                    let Ok(json) = serde_json::to_string(&*published) else {
+                        continue;
+                    };
+                    if tx.send(Message::text(json)).await.is_err() {
+                        break;
+                    }
+                }
+                message = rx.next() => match message {
+                    Some(Ok(message)) if !message.is_close() => continue,
+                    _ => break,
+                },
+            }
+        }
+    }
+
+    /// Publishes `ScanComplete`, and `NewAlbum` for each album first added at or after `since`
+    /// (when the scan started).
+    pub fn scan_complete(&self, db: &MusicDB, since: u64, elapsed: Duration) {
+        let mut albums: HashMap<AlbumKey, (&str, &str, usize, bool)> = HashMap::new();
+        for song in db.records.values().filter(|s| !s.album.is_empty()) {
+            let artist = if song.album_artist.is_empty() {
+                &song.artist
+            } else {
+                &song.album_artist
+            };
+            let entry = albums
+                .entry(song.album_key())
+                .or_insert((artist, &song.album, 0, true));
+            entry.2 += 1;
+            // An album is only new if all of it is
+            entry.3 &= song.added >= since;
+        }
+
+        self.publish(ServerEvent::ScanComplete {
+            tracks: db.records.len(),
+            added: db.records.values().filter(|s| s.added >= since).count(),
+            seconds: elapsed.as_secs_f64(),
+        });
+
+        for (artist, album, tracks, new) in albums.into_values() {
+            if new {
+                self.publish(ServerEvent::NewAlbum {
+                    artist: artist.to_string(),
+                    album: album.to_string(),
+                    tracks,
+                });
+            }
+        }
+    }
+
+    /// Publishes `AlbumFinished` if `song`, which just finished playing, is its album's last
+    /// track.
+    pub fn song_finished(&self, db: &MusicDB, song: &Song) {
+        if let Some(tracks) = last_of_album(db, song) {
+            self.publish(ServerEvent::AlbumFinished {
+                artist: song.artist.clone(),
+                album: song.album.clone(),
+                tracks,
+            });
+        }
+    }
+}
+
+/// The events a live client gets, skipping any it fell too far behind to get.
+fn live(receiver: broadcast::Receiver<Arc<Published>>) -> impl Stream<Item = Arc<Published>> {
+    futures::stream::unfold(receiver, |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(published) => return Some((published, receiver)),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+/// If `song` is the last track of its album, by disc and track number, how many tracks the album
+/// has.
+fn last_of_album(db: &MusicDB, song: &Song) -> Option<usize> {
+    if song.album.is_empty() || song.track.is_none() {
+        return None;
+    }
+    let position = |s: &Song| (s.disc.unwrap_or(0), s.track.unwrap_or(0));
+    let album = db
+        .records
+        .values()
+        .filter(|s| s.album_key() == song.album_key())
+        .collect::<Vec<_>>();
+    album
+        .iter()
+        .all(|s| position(s) <= position(song))
+        .then_some(album.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<EventKind>>>);
+
+    impl Subscriber for Recorder {
+        fn notify(&self, published: &Published) {
+            self.0.lock().unwrap().push(published.event.kind());
+        }
+    }
+
+    #[test]
+    fn subscribers_and_live_clients_get_each_event() {
+        let recorder = Recorder::default();
+        let bus = EventBus::new(vec![Box::new(recorder.clone())]);
+        let mut live = bus.live.subscribe();
+
+        bus.publish(ServerEvent::QueueEmpty);
+        assert_eq!(*recorder.0.lock().unwrap(), [EventKind::QueueEmpty]);
+        assert_eq!(live.try_recv().unwrap().event.kind(), EventKind::QueueEmpty);
+    }
+
+    #[test]
+    fn albums_finish_on_their_last_track() {
+        let mut db = MusicDB::default();
+        for (id, disc, track) in [(1, 1, 1), (2, 1, 2), (3, 2, 1)] {
+            db.records.insert(
+                id,
+                Song {
+                    id,
+                    album: "Blonde".to_string(),
+                    album_lower: "blonde".to_string(),
+                    disc: Some(disc),
+                    track: Some(track),
+                    ..Default::default()
+                },
+            );
+        }
+
+        assert_eq!(last_of_album(&db, &db.records[&2]), None);
+        assert_eq!(last_of_album(&db, &db.records[&3]), Some(3));
+    }
+}
@@ -0,0 +1,186 @@
+//! Explicit content, and kid mode, which keeps it out of searches, shuffles, and radio.
+//!
+//! Whether a song is explicit comes from its tags: iTunes' content advisory, kept in MP4 files'
+//! `rtng` atom and elsewhere as an `ITUNESADVISORY` tag (an ID3 TXXX frame, Vorbis comment, or
+//! APEv2 item). Not every store tags it, so it can also be marked by hand, which wins over the
+//! tags and survives rescans.
+//!
+//! Kid mode is the server's, so it applies to everyone listening; it's saved in `kid_mode.json`.
+
+use crate::music_db::MusicDB;
+use crate::song::Song;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom},
+};
+
+pub(crate) const KID_MODE_FILE: &str = "kid_mode.json";
+
+/// The tag holding iTunes' content advisory, in formats without an atom for it
+pub const ADVISORY_TAG: &str = "ITUNESADVISORY";
+
+/// What `kid_mode.json` holds, and `/admin/kid-mode` takes and gives.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub struct KidMode {
+    pub kid_mode: bool,
+}
+
+impl KidMode {
+    pub fn load() -> Self {
+        File::open(KID_MODE_FILE)
+            .ok()
+            .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self) {
+        let saved = File::create(KID_MODE_FILE).and_then(|file| {
+            serde_json::to_writer_pretty(BufWriter::new(file), self)?;
+            Ok(())
+        });
+        if let Err(e) = saved {
+            eprintln!("Unable to save kid mode: {:?}", e);
+        }
+    }
+}
+
+/// Reads a content advisory: 1 (or 4, in older files) is explicit, 2 is a clean version, and 0
+/// or anything else says nothing either way.
+pub fn advisory(value: &str) -> Option<bool> {
+    match value.trim().trim_end_matches('\0') {
+        "1" | "4" => Some(true),
+        "2" => Some(false),
+        value if value.eq_ignore_ascii_case("explicit") => Some(true),
+        value if value.eq_ignore_ascii_case("clean") => Some(false),
+        _ => None,
+    }
+}
+
+/// Finds the atom named `name` between `start` and `end`, giving where its contents start and
+/// where it ends.
+fn find_atom(
+    file: &mut (impl Read + Seek),
+    mut pos: u64,
+    end: u64,
+    name: &[u8; 4],
+) -> io::Result<Option<(u64, u64)>> {
+    while pos + 8 <= end {
+        file.seek(SeekFrom::Start(pos))?;
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header)?;
+        let (mut len, mut body) = (
+            u32::from_be_bytes(header[..4].try_into().unwrap_or_default()) as u64,
+            pos + 8,
+        );
+        match len {
+            // The rest of the file
+            0 => len = end - pos,
+            // A 64-bit length follows
+            1 => {
+                let mut large = [0u8; 8];
+                file.read_exact(&mut large)?;
+                len = u64::from_be_bytes(large);
+                body += 8;
+            }
+            _ => {}
+        }
+        if len < body - pos {
+            return Ok(None);
+        }
+        if &header[4..] == name {
+            return Ok(Some((body, (pos + len).min(end))));
+        }
+        pos += len;
+    }
+    Ok(None)
+}
+
+/// Reads the content advisory in an MP4 file's iTunes metadata, at `moov/udta/meta/ilst/rtng`.
+/// `symphonia` skips it, so it's read here.
+pub fn mp4_advisory(file: &mut (impl Read + Seek), size: u64) -> io::Result<Option<bool>> {
+    let (mut start, mut end) = (0, size);
+    // `meta` has a version and flags before its atoms, and `data` a type and locale before its
+    // value
+    for (name, skip) in [
+        (b"moov", 0),
+        (b"udta", 0),
+        (b"meta", 4),
+        (b"ilst", 0),
+        (b"rtng", 0),
+        (b"data", 8),
+    ] {
+        let Some((body, body_end)) = find_atom(file, start, end, name)? else {
+            return Ok(None);
+        };
+        (start, end) = (body + skip, body_end);
+    }
+    if start >= end {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::Start(start))?;
+    let mut value = [0u8; 1];
+    file.read_exact(&mut value)?;
+    Ok(advisory(&value[0].to_string()))
+}
+
+impl MusicDB {
+    /// Marks the songs in `ids` as explicit or not, whatever their tags say, saving the library
+    /// if any changed. Returns how many of them are in the library.
+    pub fn mark_explicit(&mut self, ids: &[u64], explicit: bool) -> usize {
+        let mut found = 0;
+        let mut changed = false;
+        for id in ids {
+            if let Some(song) = self.records.get_mut(id) {
+                found += 1;
+                changed |= song.marked_explicit != Some(explicit);
+                song.marked_explicit = Some(explicit);
+            }
+        }
+        if changed {
+            self.mark_changed();
+            self.save();
+        }
+        found
+    }
+
+    /// Whether kid mode allows `song`: always, unless it's on and the song is explicit.
+    pub fn kid_safe(&self, song: &Song) -> bool {
+        !self.kid_mode || !song.is_explicit()
+    }
+
+    /// Turns kid mode on or off, saving it.
+    pub fn set_kid_mode(&mut self, kid_mode: bool) {
+        self.kid_mode = kid_mode;
+        KidMode { kid_mode }.save();
+        self.mark_changed();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn atom(name: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut atom = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        atom.extend_from_slice(name);
+        atom.extend_from_slice(body);
+        atom
+    }
+
+    #[test]
+    fn reads_mp4_advisories() {
+        let data = atom(b"data", &[0, 0, 0, 21, 0, 0, 0, 0, 1]);
+        let ilst = atom(b"ilst", &atom(b"rtng", &data));
+        let meta = atom(
+            b"meta",
+            &[&[0, 0, 0, 0][..], &atom(b"hdlr", &[0; 25]), &ilst].concat(),
+        );
+        let mut file = atom(b"ftyp", b"M4A mp42");
+        file.extend(atom(b"moov", &atom(b"udta", &meta)));
+
+        let size = file.len() as u64;
+        let explicit = mp4_advisory(&mut io::Cursor::new(file), size).unwrap();
+        assert_eq!(explicit, Some(true));
+    }
+}
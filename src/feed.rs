@@ -0,0 +1,50 @@
+//! The albums added to the library lately, for the `/feed/new` Atom feed.
+
+use crate::music_db::MusicDB;
+use crate::song::{AlbumKey, Song};
+use std::collections::HashMap;
+
+/// How far back the feed goes, in days, unless asked otherwise
+pub const DEFAULT_DAYS: u64 = 30;
+
+/// An album added to the library.
+pub struct NewAlbum {
+    pub artist: String,
+    pub album: String,
+    pub year: u16,
+    pub tracks: usize,
+    /// When its first song was added, in seconds since the Unix epoch
+    pub added: u64,
+    /// A song of it with cover art, for `/art?id=`
+    pub art: Option<u64>,
+}
+
+/// The albums whose songs were all added at or after `since`, newest first.
+pub fn new_albums(db: &MusicDB, since: u64) -> Vec<NewAlbum> {
+    let mut albums: HashMap<AlbumKey, Vec<&Song>> = HashMap::new();
+    for song in db.records.values().filter(|s| !s.album.is_empty()) {
+        albums.entry(song.album_key()).or_default().push(song);
+    }
+
+    let mut new = albums
+        .into_values()
+        .filter_map(|songs| {
+            let added = songs.iter().map(|s| s.added).min()?;
+            if added < since {
+                return None;
+            }
+            let first = songs[0];
+            Some(NewAlbum {
+                artist: first.artist.clone(),
+                album: first.album.clone(),
+                year: first.year,
+                tracks: songs.len(),
+                added,
+                art: crate::art::find_cover(&first.path).map(|_| first.id),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    new.sort_unstable_by(|a, b| b.added.cmp(&a.added).then_with(|| a.album.cmp(&b.album)));
+    new
+}
@@ -0,0 +1,31 @@
+use askama::Template;
+use bwaabwaa::feed::NewAlbum;
+
+/// `/feed/new`, an Atom feed of the albums added lately.
+#[derive(Template)]
+#[template(path = "feed.xml")]
+pub struct FeedPage<'a> {
+    /// Where the server is, eg `http://music.local:3030`, since feed links must be absolute
+    pub base: String,
+    pub days: u64,
+    pub albums: &'a [NewAlbum],
+}
+
+impl FeedPage<'_> {
+    /// When the feed last changed: when its newest album was added, or failing that, now.
+    fn updated(&self) -> String {
+        let newest = self.albums.first().map(|a| a.added);
+        timestamp(newest.unwrap_or_else(bwaabwaa::history::now))
+    }
+
+    fn added(&self, album: &NewAlbum) -> String {
+        timestamp(album.added)
+    }
+}
+
+/// Seconds since the Unix epoch as an RFC 3339 timestamp, as Atom wants.
+fn timestamp(seconds: u64) -> String {
+    chrono::DateTime::from_timestamp(seconds as i64, 0)
+        .unwrap_or_default()
+        .to_rfc3339()
+}
@@ -0,0 +1,165 @@
+//! Tidying up genre tags, which get written every which way: "Alt Rock", "alt-rock", and
+//! "Alternative Rock" are all the same genre. `genres.json` gives the name to use for each alias,
+//! and the parents of genres that belong under others:
+//!
+//! ```json
+//! {
+//!   "aliases": { "Alt Rock": "Alternative Rock", "Hip Hop": "Hip-Hop" },
+//!   "parents": { "Grunge": ["Alternative Rock"], "Alternative Rock": ["Rock"] }
+//! }
+//! ```
+//!
+//! Aliases are matched ignoring case, spaces, and punctuation, so "alt-rock" and "ALT ROCK" are
+//! covered by "Alt Rock". A genre is under its parents, their parents, and so on, and under any
+//! genre its name ends with: "Doom Metal" is under "Metal" without having to say so.
+//!
+//! Aliases are applied to songs as they're scanned, and to the whole library at startup; searching
+//! by genre finds every genre under it too.
+
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::BufReader,
+};
+
+pub(crate) const GENRES_FILE: &str = "genres.json";
+
+#[derive(Deserialize, Default)]
+pub(crate) struct Config {
+    #[serde(default)]
+    aliases: HashMap<String, String>,
+    #[serde(default)]
+    parents: HashMap<String, Vec<String>>,
+}
+
+/// The configured aliases and hierarchy. Without any, genres are left as they're tagged, and
+/// only the hierarchy implied by their names applies.
+#[derive(Debug, Default, Clone)]
+pub struct Genres {
+    /// The name to use for each alias, by `key`
+    aliases: HashMap<String, String>,
+    /// Each genre's parents, by `key`
+    parents: HashMap<String, Vec<String>>,
+}
+
+/// A genre's words, in lowercase, without punctuation.
+fn words(genre: &str) -> Vec<String> {
+    genre
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// What makes two spellings of a genre the same
+fn key(genre: &str) -> String {
+    words(genre).concat()
+}
+
+impl Genres {
+    /// Loads the genres from `genres.json`, if it exists.
+    pub fn load() -> Self {
+        match File::open(GENRES_FILE) {
+            Ok(file) => match serde_json::from_reader(BufReader::new(file)) {
+                Ok(config) => Self::from_config(config),
+                Err(e) => {
+                    eprintln!("Ignoring {GENRES_FILE}: {}", e);
+                    Self::default()
+                }
+            },
+            Err(_) => Self::default(),
+        }
+    }
+
+    fn from_config(config: Config) -> Self {
+        let aliases = config
+            .aliases
+            .into_iter()
+            .map(|(alias, name)| (key(&alias), name.trim().to_string()))
+            .collect();
+        let mut genres = Genres {
+            aliases,
+            parents: HashMap::new(),
+        };
+        for (genre, parents) in config.parents {
+            let genre = key(&genres.canonical(&genre));
+            genres.parents.entry(genre).or_default().extend(parents);
+        }
+        genres
+    }
+
+    /// The name to use for `genre`: its alias's, if it's an alias, or otherwise as it is.
+    pub fn canonical(&self, genre: &str) -> String {
+        let genre = genre.trim();
+        match self.aliases.get(&key(genre)) {
+            Some(name) => name.clone(),
+            None => genre.to_string(),
+        }
+    }
+
+    /// Whether `genre` is `ancestor`, or anywhere under it.
+    pub fn is_under(&self, genre: &str, ancestor: &str) -> bool {
+        let ancestor = key(&self.canonical(ancestor));
+        if ancestor.is_empty() {
+            return false;
+        }
+
+        let mut seen = HashSet::new();
+        let mut pending = vec![self.canonical(genre)];
+        while let Some(genre) = pending.pop() {
+            let words = words(&genre);
+            // Each name ends with its implied parents', eg "Death Metal" and "Metal" for
+            // "Melodic Death Metal"
+            for start in 0..words.len() {
+                let name = words[start..].concat();
+                if name == ancestor {
+                    return true;
+                }
+                if !seen.insert(name.clone()) {
+                    continue;
+                }
+                if let Some(parents) = self.parents.get(&name) {
+                    pending.extend(parents.iter().map(|p| self.canonical(p)));
+                }
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn genres(json: &str) -> Genres {
+        Genres::from_config(serde_json::from_str(json).unwrap())
+    }
+
+    #[test]
+    fn aliases_ignore_case_and_punctuation() {
+        let genres = genres(r#"{"aliases": {"Alt Rock": "Alternative Rock"}}"#);
+        assert_eq!(genres.canonical("alt-rock"), "Alternative Rock");
+        assert_eq!(genres.canonical(" ALT ROCK "), "Alternative Rock");
+        assert_eq!(genres.canonical("Alternative Rock"), "Alternative Rock");
+        assert_eq!(genres.canonical("Jazz"), "Jazz");
+    }
+
+    #[test]
+    fn genres_are_under_their_ancestors() {
+        let genres = genres(
+            r#"{
+                "aliases": {"Alt Rock": "Alternative Rock"},
+                "parents": {"Grunge": ["alt-rock"], "Alternative Rock": ["Rock"], "Rock": ["Grunge"]}
+            }"#,
+        );
+        assert!(genres.is_under("Doom Metal", "Metal"));
+        assert!(genres.is_under("Melodic Death Metal", "death metal"));
+        assert!(genres.is_under("Grunge", "Rock"));
+        assert!(genres.is_under("alt rock", "Alternative Rock"));
+        assert!(!genres.is_under("Metal", "Doom Metal"));
+        assert!(!genres.is_under("Gunmetal", "Metal"));
+        assert!(!genres.is_under("Rock", "Metal"));
+        assert!(!genres.is_under("Rock", ""));
+    }
+}
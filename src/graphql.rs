@@ -0,0 +1,403 @@
+//! A GraphQL view of the library at `POST /graphql`, so clients can fetch exactly the fields
+//! they need (and follow songs to their albums and artists) in one request, eg
+//!
+//! ```graphql
+//! {
+//!   search(term: "everlong", limit: 5) {
+//!     songs { id title album { title year art } }
+//!   }
+//! }
+//! ```
+//!
+//! This is read-only; changes still go through the REST endpoints. Songs lead to albums, which
+//! lead back to songs, so queries are limited in how deep they go and how many fields they ask
+//! for, to keep one from walking the whole library over and over.
+
+use async_graphql::{Context, EmptyMutation, EmptySubscription, Object, Result, SimpleObject, ID};
+use bwaabwaa::{
+    music_db::{self, MusicDB, SearchTerms, SortBy},
+    queue::PlayQueue,
+    sections::Section,
+    song::SongResult,
+};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+pub type Schema = async_graphql::Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+
+/// How deeply fields can be nested, which is plenty for a song's album's artist's albums
+const MAX_DEPTH: usize = 8;
+
+/// How many fields a query can ask for, all told
+const MAX_COMPLEXITY: usize = 500;
+
+pub fn schema(database: Arc<Mutex<MusicDB>>, queue: Arc<Mutex<PlayQueue>>) -> Schema {
+    Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
+        .data(database)
+        .data(queue)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+fn database<'a>(ctx: &Context<'a>) -> &'a Arc<Mutex<MusicDB>> {
+    ctx.data_unchecked::<Arc<Mutex<MusicDB>>>()
+}
+
+fn parse_id(id: &ID) -> Result<u64> {
+    id.parse()
+        .map_err(|_| format!("invalid id: {}", id.as_str()).into())
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    /// A single song
+    async fn song(&self, ctx: &Context<'_>, id: ID) -> Result<Option<Song>> {
+        let id = parse_id(&id)?;
+        let db = database(ctx).lock().await;
+        Ok(db.records.get(&id).map(|s| Song(s.into())))
+    }
+
+    /// Searches the library, as `/search` does
+    #[allow(clippy::too_many_arguments)]
+    async fn search(
+        &self,
+        ctx: &Context<'_>,
+        term: Option<String>,
+        artist: Option<String>,
+        album: Option<String>,
+        decade: Option<u16>,
+        composer: Option<String>,
+        #[graphql(desc = "The name of a library root, as listed by /roots")] root: Option<String>,
+        #[graphql(desc = "music (the default), audiobooks, or podcasts")] section: Option<String>,
+        #[graphql(
+            desc = "One of title, artist, album, duration, track, composer, or added (newest first); by default, depends on the section"
+        )]
+        sort_by: Option<String>,
+        limit: Option<u16>,
+        #[graphql(desc = "Continues from the last song of the previous page")] after: Option<ID>,
+    ) -> Result<SearchResults> {
+        let sort_by = match sort_by {
+            Some(s) => Some(
+                serde_json::from_value::<SortBy>(serde_json::Value::String(s.clone()))
+                    .map_err(|_| format!("invalid sortBy: {}", s))?,
+            ),
+            None => None,
+        };
+        let section = match section {
+            Some(s) => Some(
+                serde_json::from_value::<Section>(serde_json::Value::String(s.clone()))
+                    .map_err(|_| format!("invalid section: {}", s))?,
+            ),
+            None => None,
+        };
+        let after = after.as_ref().map(parse_id).transpose()?;
+
+        let terms = SearchTerms {
+            term,
+            artist,
+            album,
+            decade,
+            composer,
+            root,
+            section,
+            sort_by,
+            limit,
+            after,
+            ..Default::default()
+        };
+        let db = database(ctx).lock().await;
+        Ok(SearchResults(db.query(terms)))
+    }
+
+    /// Every artist, in sort order
+    async fn artists(&self, ctx: &Context<'_>) -> Vec<Artist> {
+        let db = database(ctx).lock().await;
+        db.artists()
+            .into_iter()
+            .map(|a| Artist { name: a.name })
+            .collect()
+    }
+
+    async fn artist(&self, ctx: &Context<'_>, name: String) -> Option<Artist> {
+        let db = database(ctx).lock().await;
+        db.artist(&name).map(|a| Artist { name: a.name })
+    }
+
+    /// An album; given a year, only the album of that year, eg rather than its re-release
+    async fn album(
+        &self,
+        ctx: &Context<'_>,
+        artist: String,
+        title: String,
+        year: Option<u16>,
+    ) -> Option<Album> {
+        let db = database(ctx).lock().await;
+        db.album(&artist, &title, year).map(Album)
+    }
+
+    /// The server-side play queue.
+    async fn queue(&self, ctx: &Context<'_>) -> Vec<Song> {
+        let db = database(ctx).lock().await;
+        let queue = ctx.data_unchecked::<Arc<Mutex<PlayQueue>>>().lock().await;
+        queue.state(&db).songs.into_iter().map(Song).collect()
+    }
+}
+
+pub struct SearchResults(music_db::SearchResults);
+
+#[Object]
+impl SearchResults {
+    /// Whether there are more results past `limit`
+    async fn has_more(&self) -> bool {
+        self.0.has_more
+    }
+
+    async fn songs(&self) -> Vec<Song> {
+        self.0.results.iter().cloned().map(Song).collect()
+    }
+}
+
+pub struct Song(SongResult);
+
+#[Object]
+impl Song {
+    async fn id(&self) -> ID {
+        ID(self.0.id.clone())
+    }
+
+    async fn title(&self) -> &str {
+        &self.0.title
+    }
+
+    async fn artist(&self) -> Option<Artist> {
+        (!self.0.artist.is_empty()).then(|| Artist {
+            name: self.0.artist.clone(),
+        })
+    }
+
+    async fn album(&self, ctx: &Context<'_>) -> Option<Album> {
+        if self.0.album.is_empty() {
+            return None;
+        }
+        let db = database(ctx).lock().await;
+        db.album(&self.0.artist, &self.0.album, Some(self.0.year))
+            .map(Album)
+    }
+
+    async fn year(&self) -> u16 {
+        self.0.year
+    }
+
+    async fn genre(&self) -> &str {
+        &self.0.genre
+    }
+
+    async fn labels(&self) -> &[String] {
+        &self.0.labels
+    }
+
+    async fn comment(&self) -> &str {
+        &self.0.comment
+    }
+
+    async fn composer(&self) -> &str {
+        &self.0.composer
+    }
+
+    async fn conductor(&self) -> &str {
+        &self.0.conductor
+    }
+
+    async fn work(&self) -> &str {
+        &self.0.work
+    }
+
+    async fn movement(&self) -> &str {
+        &self.0.movement
+    }
+
+    /// eg, "3:45"
+    async fn duration(&self) -> &str {
+        &self.0.duration
+    }
+
+    async fn track(&self) -> Option<u16> {
+        self.0.track
+    }
+
+    async fn track_total(&self) -> Option<u16> {
+        self.0.track_total
+    }
+
+    async fn disc(&self) -> Option<u16> {
+        self.0.disc
+    }
+
+    async fn format(&self) -> &str {
+        &self.0.format
+    }
+
+    async fn codec(&self) -> &str {
+        &self.0.codec
+    }
+
+    /// Average bitrate, in kbps
+    async fn bitrate(&self) -> u16 {
+        self.0.bitrate
+    }
+
+    /// In Hz
+    async fn sample_rate(&self) -> u32 {
+        self.0.sample_rate
+    }
+
+    async fn channels(&self) -> u8 {
+        self.0.channels
+    }
+
+    /// In bytes
+    async fn size(&self) -> u64 {
+        self.0.size
+    }
+
+    async fn unavailable(&self) -> bool {
+        self.0.unavailable
+    }
+
+    /// The average color of its cover art, eg "#3a5f8c"
+    async fn color(&self) -> Option<&str> {
+        self.0.cover.as_ref().map(|c| c.color.as_str())
+    }
+
+    /// A BlurHash of its cover art, for a placeholder while it loads
+    async fn blurhash(&self) -> Option<&str> {
+        self.0.cover.as_ref().map(|c| c.blurhash.as_str())
+    }
+
+    /// Where to stream it from
+    async fn url(&self) -> String {
+        format!("/listen?id={}", self.0.id)
+    }
+}
+
+pub struct Artist {
+    name: String,
+}
+
+#[Object]
+impl Artist {
+    async fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Ordered by year
+    async fn albums(&self, ctx: &Context<'_>) -> Vec<Album> {
+        let db = database(ctx).lock().await;
+        let artist = match db.artist(&self.name) {
+            Some(a) => a,
+            None => return Vec::new(),
+        };
+
+        artist
+            .albums
+            .iter()
+            .filter(|a| !a.album.is_empty())
+            .filter_map(|a| db.album(&self.name, &a.album, Some(a.year)))
+            .map(Album)
+            .collect()
+    }
+}
+
+pub struct Album(music_db::AlbumDetails);
+
+#[Object]
+impl Album {
+    async fn title(&self) -> &str {
+        &self.0.album
+    }
+
+    async fn artist(&self) -> Artist {
+        Artist {
+            name: self.0.artist.clone(),
+        }
+    }
+
+    async fn year(&self) -> u16 {
+        self.0.year
+    }
+
+    async fn duration(&self) -> &str {
+        &self.0.duration
+    }
+
+    /// URL of the album art, if any was found
+    async fn art(&self) -> Option<&str> {
+        self.0.art.as_deref()
+    }
+
+    /// The average color of the art, eg "#3a5f8c"
+    async fn color(&self) -> Option<&str> {
+        self.0.cover.as_ref().map(|c| c.color.as_str())
+    }
+
+    /// A BlurHash of the art, for a placeholder while it loads
+    async fn blurhash(&self) -> Option<&str> {
+        self.0.cover.as_ref().map(|c| c.blurhash.as_str())
+    }
+
+    /// In disc and track order
+    async fn tracks(&self) -> Vec<Song> {
+        self.0.tracks.iter().cloned().map(Song).collect()
+    }
+
+    /// Tracks that the tags' track totals say should exist, but don't
+    async fn missing_tracks(&self) -> Vec<MissingTrack> {
+        self.0
+            .missing_tracks
+            .iter()
+            .map(|m| MissingTrack {
+                disc: m.disc,
+                track: m.track,
+            })
+            .collect()
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct MissingTrack {
+    disc: Option<u16>,
+    track: u16,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn refuses_queries_that_go_too_deep() {
+        let schema = schema(Arc::default(), Arc::default());
+        let shallow = schema
+            .execute("{ artists { name albums { title tracks { title } } } }")
+            .await;
+        assert!(shallow.errors.is_empty(), "{:?}", shallow.errors);
+
+        let deep = "{ artists { albums { tracks { album { tracks { album { tracks { album { \
+            tracks { title } } } } } } } } } }";
+        let errors = schema.execute(deep).await.errors;
+        assert!(errors[0].message.contains("too deep"), "{:?}", errors);
+    }
+
+    #[tokio::test]
+    async fn refuses_queries_that_ask_for_too_much() {
+        let schema = schema(Arc::default(), Arc::default());
+        let fields = (0..MAX_COMPLEXITY)
+            .map(|i| format!("s{}: song(id: \"{}\") {{ title }}", i, i))
+            .collect::<Vec<_>>();
+        let query = format!("{{ {} }}", fields.join(" "));
+        let errors = schema.execute(query).await.errors;
+        assert!(errors[0].message.contains("too complex"), "{:?}", errors);
+    }
+}
@@ -0,0 +1,281 @@
+//! Short-lived numeric codes for party guests, saved in `guests.json`. A guest enters one at
+//! `/guest` and can then browse the library and add songs to the queue, but not play, download, or
+//! change anything else, until it expires or is expired from `/admin/guests`.
+//!
+//! The codes are short, so a client that enters too many wrong ones is turned away for a while, and
+//! a code is only ever entered at `/guest`: entering it starts a guest session, and the guest's
+//! cookie holds that session's random token rather than the code.
+
+use crate::attempts::Attempts;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, BufWriter},
+    net::IpAddr,
+};
+
+pub(crate) const GUESTS_FILE: &str = "guests.json";
+
+/// How many digits a code has
+const DIGITS: u32 = 6;
+
+/// How long a code lasts if no time is given, in hours
+pub const DEFAULT_HOURS: u64 = 6;
+
+/// How many wrong codes a client may enter before it's turned away
+const MAX_FAILURES: u32 = 5;
+/// How long a client is turned away for, in seconds, from its first wrong code
+const FAILURE_WINDOW: u64 = 15 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GuestCode {
+    /// What it's for, eg "Saturday's party"
+    pub name: String,
+    /// In seconds since the Unix epoch
+    pub created: u64,
+    /// In seconds since the Unix epoch
+    pub expires: u64,
+    /// The tokens of the guest sessions started with it; they end with it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    sessions: Vec<String>,
+}
+
+/// A code, as listed by `/admin/guests`.
+#[derive(Serialize)]
+pub struct GuestCodeSummary {
+    pub code: String,
+    pub name: String,
+    pub created: u64,
+    pub expires: u64,
+}
+
+impl GuestCodeSummary {
+    fn new(code: &str, guest: &GuestCode) -> Self {
+        GuestCodeSummary {
+            code: code.to_string(),
+            name: guest.name.clone(),
+            created: guest.created,
+            expires: guest.expires,
+        }
+    }
+}
+
+/// A guest session, started by entering a code at `/guest`.
+#[derive(Debug)]
+pub struct Entered {
+    /// For the guest's cookie
+    pub token: String,
+    /// When the code, and so the session, expires, in seconds since the Unix epoch
+    pub expires: u64,
+}
+
+/// Why a code entered at `/guest` wasn't taken.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Refused {
+    /// It isn't a code, or it's expired
+    Invalid,
+    /// The client has entered too many wrong codes lately
+    TooManyAttempts,
+}
+
+/// The current guest codes, by code. Made with `default()`, eg in tests, they're only kept in
+/// memory.
+pub struct GuestCodes {
+    codes: BTreeMap<String, GuestCode>,
+    /// Whether changes are saved to `guests.json`
+    saved: bool,
+    /// Wrong codes entered lately
+    attempts: Attempts,
+}
+
+impl Default for GuestCodes {
+    fn default() -> Self {
+        GuestCodes {
+            codes: BTreeMap::new(),
+            saved: false,
+            attempts: Attempts::new(MAX_FAILURES, FAILURE_WINDOW),
+        }
+    }
+}
+
+impl GuestCodes {
+    /// Loads the codes, dropping any that have expired.
+    pub fn load() -> Self {
+        let mut codes: BTreeMap<String, GuestCode> = File::open(GUESTS_FILE)
+            .ok()
+            .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+            .unwrap_or_default();
+        let now = crate::history::now();
+        codes.retain(|_, c| c.expires > now);
+
+        Self {
+            codes,
+            saved: true,
+            ..Self::default()
+        }
+    }
+
+    fn save(&self) {
+        if !self.saved {
+            return;
+        }
+        let saved = File::create(GUESTS_FILE).and_then(|file| {
+            serde_json::to_writer_pretty(BufWriter::new(file), &self.codes)?;
+            Ok(())
+        });
+        if let Err(e) = saved {
+            eprintln!("Unable to save guest codes: {:?}", e);
+        }
+    }
+
+    /// Creates a code lasting `hours`.
+    pub fn create(&mut self, name: String, hours: u64) -> GuestCodeSummary {
+        let now = crate::history::now();
+        self.codes.retain(|_, c| c.expires > now);
+
+        let mut rng = rand::thread_rng();
+        let code = loop {
+            let code = format!(
+                "{:0width$}",
+                rng.gen_range(0..10u32.pow(DIGITS)),
+                width = DIGITS as usize
+            );
+            if !self.codes.contains_key(&code) {
+                break code;
+            }
+        };
+
+        let guest = GuestCode {
+            name,
+            created: now,
+            expires: now + hours * 60 * 60,
+            sessions: Vec::new(),
+        };
+        let summary = GuestCodeSummary::new(&code, &guest);
+        self.codes.insert(code, guest);
+        self.save();
+
+        summary
+    }
+
+    /// Lists the codes that haven't expired, soonest to expire first.
+    pub fn list(&self) -> Vec<GuestCodeSummary> {
+        let now = crate::history::now();
+        let mut codes = self
+            .codes
+            .iter()
+            .filter(|(_, guest)| guest.expires > now)
+            .map(|(code, guest)| GuestCodeSummary::new(code, guest))
+            .collect::<Vec<_>>();
+        codes.sort_by_key(|c| c.expires);
+        codes
+    }
+
+    /// Expires a code now, ending its guests' sessions; returns whether there was one.
+    pub fn expire(&mut self, code: &str) -> bool {
+        let expired = self.codes.remove(code).is_some();
+        if expired {
+            self.save();
+        }
+        expired
+    }
+
+    /// The guest whose session `token` is, if their code hasn't expired.
+    pub fn session(&self, token: &str) -> Option<&GuestCode> {
+        let now = crate::history::now();
+        self.codes.values().find(|c| {
+            c.expires > now
+                && c.sessions
+                    .iter()
+                    .any(|session| crate::sessions::same(session, token))
+        })
+    }
+
+    /// Starts a guest session with `code`, as entered by `client` at `/guest`. Once a client has
+    /// entered too many wrong codes, even a right one is refused until a while after the first.
+    pub fn enter(&mut self, client: IpAddr, code: &str) -> Result<Entered, Refused> {
+        if self.attempts.refused(client) {
+            return Err(Refused::TooManyAttempts);
+        }
+
+        let now = crate::history::now();
+        let Some(guest) = self.codes.get_mut(code).filter(|c| c.expires > now) else {
+            self.attempts.failed(client);
+            return Err(Refused::Invalid);
+        };
+        let token = crate::sessions::random(32);
+        guest.sessions.push(token.clone());
+        let expires = guest.expires;
+        self.save();
+
+        Ok(Entered { token, expires })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_last_until_they_expire_or_are_expired() {
+        let mut guests = GuestCodes::default();
+        let party = guests.create("party".to_string(), 6);
+        let over = guests.create("over".to_string(), 0);
+        assert_eq!(party.code.len(), DIGITS as usize);
+        let client: IpAddr = "192.168.1.2".parse().unwrap();
+
+        let entered = guests.enter(client, &party.code).unwrap();
+        assert_eq!(guests.session(&entered.token).unwrap().name, "party");
+        assert_eq!(
+            guests.enter(client, &over.code).unwrap_err(),
+            Refused::Invalid
+        );
+        let listed = guests.list();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].code, party.code);
+
+        assert!(guests.expire(&party.code));
+        assert!(!guests.expire(&party.code));
+        assert!(guests.session(&entered.token).is_none());
+    }
+
+    #[test]
+    fn sessions_arent_codes() {
+        let mut guests = GuestCodes::default();
+        let party = guests.create("party".to_string(), 6);
+        let client: IpAddr = "192.168.1.2".parse().unwrap();
+        let first = guests.enter(client, &party.code).unwrap();
+        let second = guests.enter(client, &party.code).unwrap();
+
+        assert_ne!(first.token, second.token);
+        assert!(!first.token.contains(&party.code));
+        assert!(guests.session(&party.code).is_none());
+        assert!(guests.session("").is_none());
+        assert_eq!(guests.session(&second.token).unwrap().name, "party");
+    }
+
+    #[test]
+    fn guessing_is_limited_per_client() {
+        let mut guests = GuestCodes::default();
+        let party = guests.create("party".to_string(), 6);
+        let wrong = if party.code == "000000" {
+            "000001"
+        } else {
+            "000000"
+        };
+        let guesser: IpAddr = "192.168.1.2".parse().unwrap();
+        let guest: IpAddr = "192.168.1.3".parse().unwrap();
+
+        for _ in 0..MAX_FAILURES {
+            assert_eq!(guests.enter(guesser, wrong).unwrap_err(), Refused::Invalid);
+        }
+        assert_eq!(
+            guests.enter(guesser, &party.code).unwrap_err(),
+            Refused::TooManyAttempts
+        );
+        // Others aren't affected
+        assert!(guests.enter(guest, &party.code).is_ok());
+    }
+}
@@ -0,0 +1,125 @@
+//! Handing playback from one device to another, eg from a phone to the desktop on getting home.
+//!
+//! The queue is the server's, so every device already shares it; what's handed off is the song
+//! the first device is playing and how far into it it's got, as its player last reported to
+//! `/now-playing`. Browsers can't be pushed to, so both devices find out by polling
+//! `GET /handoff`: the one taking over is told to play from there, and the other to stop.
+
+use crate::devices::Devices;
+use crate::music_db::MusicDB;
+use crate::song::SongResult;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// How long a handoff waits for a device to pick it up, in seconds
+const EXPIRES_AFTER: u64 = 120;
+
+/// What `GET /handoff` tells a device to do.
+#[derive(Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Instruction {
+    /// Take over playing a song, from `position` seconds in
+    Play {
+        song: Box<SongResult>,
+        position: f64,
+        paused: bool,
+        /// The name of the device it was handed off from
+        from: Option<String>,
+    },
+    /// Stop playing, since it's been handed off
+    Stop {
+        /// The name of the device it was handed off to
+        to: Option<String>,
+    },
+}
+
+struct Pending {
+    song: u64,
+    position: f64,
+    paused: bool,
+    from: String,
+    /// In seconds since the Unix epoch
+    at: u64,
+}
+
+/// The handoffs that haven't been picked up yet, by device id.
+#[derive(Default)]
+pub struct Handoffs {
+    /// Songs to play, by the device to play them
+    plays: HashMap<String, Pending>,
+    /// Devices to stop, and the device they handed off to, and when
+    stops: HashMap<String, (String, u64)>,
+}
+
+impl Handoffs {
+    /// Hands `song`, `position` seconds in, off from one device to another, replacing any
+    /// handoff either hasn't picked up yet.
+    pub fn start(&mut self, from: &str, to: &str, song: u64, position: f64, paused: bool) {
+        let at = crate::history::now();
+        self.plays.remove(from);
+        self.stops.remove(to);
+        self.plays.insert(
+            to.to_string(),
+            Pending {
+                song,
+                position,
+                paused,
+                from: from.to_string(),
+                at,
+            },
+        );
+        self.stops.insert(from.to_string(), (to.to_string(), at));
+    }
+
+    /// Takes what a device has been told to do, if anything.
+    pub fn take(&mut self, device: &str, db: &MusicDB, devices: &Devices) -> Option<Instruction> {
+        let now = crate::history::now();
+        self.plays.retain(|_, p| now < p.at + EXPIRES_AFTER);
+        self.stops.retain(|_, (_, at)| now < *at + EXPIRES_AFTER);
+
+        let name = |id: &str| devices.name(id).map(str::to_string);
+        if let Some(pending) = self.plays.remove(device) {
+            return Some(Instruction::Play {
+                song: Box::new(db.records.get(&pending.song)?.into()),
+                position: pending.position,
+                paused: pending.paused,
+                from: name(&pending.from),
+            });
+        }
+        let (to, _) = self.stops.remove(device)?;
+        Some(Instruction::Stop { to: name(&to) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::Song;
+
+    #[test]
+    fn each_device_picks_up_its_part_once() {
+        let mut db = MusicDB::default();
+        db.records.insert(
+            7,
+            Song {
+                id: 7,
+                ..Default::default()
+            },
+        );
+        let devices = Devices::default();
+
+        let mut handoffs = Handoffs::default();
+        handoffs.start("phone", "desktop", 7, 42.0, false);
+        assert!(handoffs.take("laptop", &db, &devices).is_none());
+        assert!(matches!(
+            handoffs.take("desktop", &db, &devices),
+            Some(Instruction::Play { position, .. }) if position == 42.0
+        ));
+        assert!(matches!(
+            handoffs.take("phone", &db, &devices),
+            Some(Instruction::Stop { .. })
+        ));
+        assert!(handoffs.take("desktop", &db, &devices).is_none());
+        assert!(handoffs.take("phone", &db, &devices).is_none());
+    }
+}
@@ -0,0 +1,300 @@
+use crate::devices::Devices;
+use crate::music_db::MusicDB;
+use crate::song::{Song, SongResult};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub(crate) const HISTORY_FILE: &str = "history.json";
+
+/// A single play of a song.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Play {
+    pub id: u64,
+    /// Seconds since the Unix epoch
+    pub at: u64,
+    /// The registered device it was played on, if any; see `devices`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+}
+
+/// One entry of `/history`.
+#[derive(Serialize)]
+pub struct RecentPlay {
+    pub song: SongResult,
+    pub at: u64,
+    /// The name of the device it was played on, if it was registered
+    pub device: Option<String>,
+}
+
+/// Every play, oldest first. Plays are appended to `history.json` as they happen. Made with
+/// `default()`, eg in tests, they're only kept in memory.
+#[derive(Default)]
+pub struct PlayHistory {
+    pub plays: Vec<Play>,
+    /// Whether plays are saved to `history.json`
+    saved: bool,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl PlayHistory {
+    pub fn load() -> Self {
+        let plays = match File::open(HISTORY_FILE) {
+            Ok(file) => BufReader::new(file)
+                .lines()
+                .map_while(Result::ok)
+                .filter_map(|line| serde_json::from_str(&line).ok())
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+
+        Self { plays, saved: true }
+    }
+
+    pub fn record(&mut self, id: u64, device: Option<String>) {
+        let play = Play {
+            id,
+            at: now(),
+            device,
+        };
+
+        if self.saved {
+            let appended = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(HISTORY_FILE)
+                .and_then(|mut file| writeln!(file, "{}", serde_json::to_string(&play)?));
+            if let Err(e) = appended {
+                eprintln!("Unable to save play history: {:?}", e);
+            }
+        }
+        self.plays.push(play);
+    }
+
+    /// The `limit` most recent plays of songs still in the library, newest first.
+    pub fn recent(&self, db: &MusicDB, devices: &Devices, limit: usize) -> Vec<RecentPlay> {
+        self.plays
+            .iter()
+            .rev()
+            .filter_map(|play| {
+                Some(RecentPlay {
+                    song: db.records.get(&play.id)?.into(),
+                    at: play.at,
+                    device: play
+                        .device
+                        .as_deref()
+                        .and_then(|id| devices.name(id))
+                        .map(str::to_string),
+                })
+            })
+            .take(limit)
+            .collect()
+    }
+
+    /// Adds plays from elsewhere, eg an export, skipping any already recorded or given twice, and
+    /// rewrites `history.json` to keep it in order. Returns how many were added.
+    pub fn import(&mut self, plays: impl IntoIterator<Item = Play>) -> std::io::Result<usize> {
+        let mut known = self
+            .plays
+            .iter()
+            .map(|p| (p.id, p.at))
+            .collect::<HashSet<_>>();
+        let before = self.plays.len();
+        for play in plays {
+            if known.insert((play.id, play.at)) {
+                self.plays.push(play);
+            }
+        }
+        let added = self.plays.len() - before;
+        if added == 0 {
+            return Ok(0);
+        }
+
+        self.plays.sort_by_key(|p| p.at);
+        self.rewrite()?;
+        Ok(added)
+    }
+
+    /// Moves plays of the songs in `replaced` to the songs they map to, eg when duplicates are
+    /// resolved, and rewrites `history.json`. Returns how many were moved.
+    pub fn repoint(&mut self, replaced: &HashMap<u64, u64>) -> std::io::Result<usize> {
+        let mut moved = 0;
+        for play in &mut self.plays {
+            if let Some(&id) = replaced.get(&play.id) {
+                play.id = id;
+                moved += 1;
+            }
+        }
+        if moved > 0 {
+            self.rewrite()?;
+        }
+        Ok(moved)
+    }
+
+    fn rewrite(&self) -> std::io::Result<()> {
+        if !self.saved {
+            return Ok(());
+        }
+        let mut file = BufWriter::new(File::create(HISTORY_FILE)?);
+        for play in &self.plays {
+            writeln!(file, "{}", serde_json::to_string(play)?)?;
+        }
+        file.flush()
+    }
+
+    /// Plays at or after `since` (in seconds since the epoch).
+    pub fn since(&self, since: u64) -> impl Iterator<Item = &Play> {
+        // Plays are appended in order, so everything from the first match onwards qualifies
+        let start = self.plays.partition_point(|p| p.at < since);
+        self.plays[start..].iter()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum ChartKind {
+    tracks,
+    artists,
+    albums,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum ChartPeriod {
+    week,
+    month,
+    year,
+    all,
+}
+
+impl ChartPeriod {
+    fn start(self) -> u64 {
+        const DAY: u64 = 24 * 60 * 60;
+        let days = match self {
+            ChartPeriod::week => 7,
+            ChartPeriod::month => 30,
+            ChartPeriod::year => 365,
+            ChartPeriod::all => return 0,
+        };
+        now().saturating_sub(days * DAY)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChartTerms {
+    pub kind: Option<ChartKind>,
+    pub period: Option<ChartPeriod>,
+    pub limit: Option<u16>,
+    pub offset: Option<usize>,
+}
+
+#[derive(Serialize)]
+pub struct Chart {
+    pub kind: ChartKind,
+    pub period: ChartPeriod,
+    /// How many entries the chart has in total, for paging through it
+    pub total: usize,
+    pub entries: Vec<ChartEntry>,
+}
+
+#[derive(Serialize)]
+pub struct ChartEntry {
+    pub rank: usize,
+    pub plays: usize,
+    pub last_played: u64,
+    pub artist: String,
+    /// Set for album and track charts
+    pub album: Option<String>,
+    /// Set for track charts
+    pub song: Option<SongResult>,
+}
+
+impl ChartTerms {
+    const DEFAULT_LIMIT: u16 = 25;
+}
+
+/// Play count and most recent play for one chart entry
+struct Tally<'a> {
+    song: &'a Song,
+    plays: usize,
+    last_played: u64,
+}
+
+impl PlayHistory {
+    /// Builds a chart of the most played tracks, artists, or albums.
+    ///
+    /// Entries with the same number of plays are ordered by whichever was played most recently,
+    /// then by name, so that ranks are stable between requests.
+    pub fn chart(&self, db: &MusicDB, terms: &ChartTerms) -> Chart {
+        let kind = terms.kind.unwrap_or(ChartKind::tracks);
+        let period = terms.period.unwrap_or(ChartPeriod::all);
+        let limit = terms.limit.unwrap_or(ChartTerms::DEFAULT_LIMIT) as usize;
+        let offset = terms.offset.unwrap_or_default();
+
+        let mut tallies: HashMap<(String, String, u64), Tally> = HashMap::new();
+        for play in self.since(period.start()) {
+            // Songs no longer in the library can't be charted
+            let song = match db.records.get(&play.id) {
+                Some(s) => s,
+                None => continue,
+            };
+
+            let key = match kind {
+                ChartKind::tracks => (String::new(), String::new(), song.id),
+                ChartKind::artists => (song.artist_lower.clone(), String::new(), 0),
+                ChartKind::albums => (song.artist_lower.clone(), song.album_lower.clone(), 0),
+            };
+
+            let tally = tallies.entry(key).or_insert(Tally {
+                song,
+                plays: 0,
+                last_played: 0,
+            });
+            tally.plays += 1;
+            tally.last_played = tally.last_played.max(play.at);
+        }
+
+        let mut tallies = tallies.into_values().collect::<Vec<_>>();
+        tallies.sort_unstable_by(|a, b| {
+            b.plays
+                .cmp(&a.plays)
+                .then(b.last_played.cmp(&a.last_played))
+                .then(a.song.artist_lower.cmp(&b.song.artist_lower))
+                .then(a.song.album_lower.cmp(&b.song.album_lower))
+                .then(a.song.title_lower.cmp(&b.song.title_lower))
+        });
+
+        let total = tallies.len();
+        let entries = tallies
+            .into_iter()
+            .enumerate()
+            .skip(offset)
+            .take(limit)
+            .map(|(i, tally)| ChartEntry {
+                rank: i + 1,
+                plays: tally.plays,
+                last_played: tally.last_played,
+                artist: tally.song.artist.clone(),
+                album: (kind != ChartKind::artists).then(|| tally.song.album.clone()),
+                song: (kind == ChartKind::tracks).then(|| tally.song.into()),
+            })
+            .collect();
+
+        Chart {
+            kind,
+            period,
+            total,
+            entries,
+        }
+    }
+}
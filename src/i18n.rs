@@ -0,0 +1,141 @@
+//! Translations of the UI's text, in Fluent files under `locales/<language>/`, embedded in the
+//! binary. The language is negotiated from the request's `Accept-Language` header; anything
+//! missing from a translation falls back to English.
+//!
+//! To add a language, add a directory of `.ftl` files (eg `locales/de/main.ftl`) with the same
+//! message ids as `locales/en`.
+
+use fluent_bundle::{concurrent::FluentBundle, FluentArgs, FluentResource, FluentValue};
+use fluent_langneg::{negotiate_languages, NegotiationStrategy};
+use rust_embed::RustEmbed;
+use std::{collections::BTreeMap, convert::Infallible, sync::OnceLock};
+use unic_langid::LanguageIdentifier;
+use warp::Filter;
+
+#[derive(RustEmbed)]
+#[folder = "locales/"]
+struct Locales;
+
+const FALLBACK: &str = "en";
+
+struct Locale {
+    id: LanguageIdentifier,
+    bundle: FluentBundle<FluentResource>,
+}
+
+/// Every locale, with the fallback first.
+fn locales() -> &'static [Locale] {
+    static LOCALES: OnceLock<Vec<Locale>> = OnceLock::new();
+
+    LOCALES.get_or_init(|| {
+        let mut files: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for path in Locales::iter().filter(|p| p.ends_with(".ftl")) {
+            if let (Some((language, _)), Some(file)) = (path.split_once('/'), Locales::get(&path)) {
+                let source = String::from_utf8_lossy(&file.data).into_owned();
+                files.entry(language.to_string()).or_default().push(source);
+            }
+        }
+
+        let mut locales = files
+            .into_iter()
+            .filter_map(|(language, sources)| {
+                let id = match language.parse::<LanguageIdentifier>() {
+                    Ok(id) => id,
+                    Err(e) => {
+                        eprintln!("Ignoring locales/{}: {}", language, e);
+                        return None;
+                    }
+                };
+
+                let mut bundle = FluentBundle::new_concurrent(vec![id.clone()]);
+                // The Unicode isolation marks around arguments only get in the way in HTML
+                bundle.set_use_isolating(false);
+                for source in sources {
+                    let resource =
+                        FluentResource::try_new(source).unwrap_or_else(|(res, errors)| {
+                            eprintln!("Errors in locales/{}: {:?}", language, errors);
+                            res
+                        });
+                    if let Err(errors) = bundle.add_resource(resource) {
+                        eprintln!("Errors in locales/{}: {:?}", language, errors);
+                    }
+                }
+
+                Some(Locale { id, bundle })
+            })
+            .collect::<Vec<_>>();
+        locales.sort_by_key(|l| l.id.language.as_str() != FALLBACK);
+
+        locales
+    })
+}
+
+/// A language to show the UI in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Lang(usize);
+
+impl Lang {
+    /// The best of the available languages for an `Accept-Language` header.
+    pub fn negotiate(accept_language: Option<&str>) -> Self {
+        let requested = fluent_langneg::accepted_languages::parse(accept_language.unwrap_or(""));
+        let available = locales().iter().map(|l| &l.id).collect::<Vec<_>>();
+        let fallback = &locales()[0].id;
+
+        negotiate_languages(
+            &requested,
+            &available,
+            Some(&fallback),
+            NegotiationStrategy::Lookup,
+        )
+        .first()
+        .and_then(|chosen| available.iter().position(|id| id == *chosen))
+        .map(Lang)
+        .unwrap_or_default()
+    }
+
+    /// The language's code, for the page's `lang` attribute.
+    pub fn code(&self) -> String {
+        locales()[self.0].id.to_string()
+    }
+
+    /// Translates a message.
+    pub fn t(&self, id: &str) -> String {
+        self.format(id, None)
+    }
+
+    /// Translates a message that takes arguments, eg `page-of` with `page` and `pages`.
+    pub fn t_with(&self, id: &str, args: &[(&str, usize)]) -> String {
+        let mut fluent_args = FluentArgs::new();
+        for &(name, value) in args {
+            fluent_args.set(name, FluentValue::from(value));
+        }
+        self.format(id, Some(&fluent_args))
+    }
+
+    fn format(&self, id: &str, args: Option<&FluentArgs>) -> String {
+        let locales = locales();
+        [self.0, 0]
+            .iter()
+            .filter_map(|&i| locales.get(i))
+            .find_map(|locale| {
+                let pattern = locale.bundle.get_message(id)?.value()?;
+                let mut errors = Vec::new();
+                Some(
+                    locale
+                        .bundle
+                        .format_pattern(pattern, args, &mut errors)
+                        .into_owned(),
+                )
+            })
+            // Better the id than nothing at all
+            .unwrap_or_else(|| id.to_string())
+    }
+}
+
+/// Works out the language from the `Accept-Language` header.
+pub fn language() -> impl Filter<Extract = (Lang,), Error = Infallible> + Clone {
+    warp::header::optional::<String>("accept-language")
+        .or(warp::any().map(|| None))
+        .unify()
+        .map(|header: Option<String>| Lang::negotiate(header.as_deref()))
+}
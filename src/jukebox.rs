@@ -0,0 +1,754 @@
+//! Jukebox mode: playing the queue through the server's own audio output, rather than streaming
+//! it to a browser. Needs the `jukebox` feature; without it, `Jukebox::start` always fails.
+//!
+//! Audio devices generally can't be moved between threads, so playback runs on a dedicated
+//! thread driven by commands over a channel. When a song plays to the end, it's sent to the
+//! `finished` channel given to `Jukebox::start`, so the server can line up the next one. With a
+//! crossfade or gapless playback (see [`PlayerSettings`]), it's sent a little before the end as
+//! well, so that the next song can start on time.
+
+use crate::analysis::Silence;
+use crate::dsp::Equalizer;
+use crate::mp3::GaplessInfo;
+use crate::replay_gain::{GainMode, ReplayGain};
+use crate::song::Song;
+use serde::{Deserialize, Serialize};
+use std::{
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
+};
+
+/// How long the sleep timer takes to fade the music out, before it stops
+pub const FADE_OUT: Duration = Duration::from_secs(30);
+
+/// The longest crossfade allowed, in seconds
+pub const MAX_CROSSFADE: f64 = 12.0;
+
+/// The most the ReplayGain pre-amp can turn songs up or down, in dB
+pub const MAX_PREAMP: f32 = 15.0;
+
+/// How songs sound, and the transitions between them. Can be changed while playing; changes apply
+/// from the next song on.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct PlayerSettings {
+    /// How long each song fades into the next, in seconds; 0 for no crossfade
+    pub crossfade: f64,
+    /// Whether to trim the silence encoders add to the start and end of MP3s, and start the next
+    /// song without a gap. Ignored while crossfading.
+    pub gapless: bool,
+    /// Which of a song's stored ReplayGain gains to apply, to even out loudness
+    pub replay_gain: GainMode,
+    /// Added to the gain, in dB. ReplayGain's reference level is fairly quiet, so a few dB is
+    /// common; songs without gains aren't affected.
+    pub preamp: f32,
+    /// Whether to hold the gain down to what keeps a song's peak from clipping
+    pub prevent_clipping: bool,
+    /// Whether to skip the silence analysis found at the start and end of songs (see
+    /// `analysis`), so that crossfades don't fade between dead air
+    #[serde(default)]
+    pub trim_silence: bool,
+}
+
+impl Default for PlayerSettings {
+    fn default() -> Self {
+        PlayerSettings {
+            crossfade: 0.0,
+            gapless: false,
+            replay_gain: GainMode::default(),
+            preamp: 0.0,
+            prevent_clipping: true,
+            trim_silence: false,
+        }
+    }
+}
+
+/// Sent by the audio thread when it's ready for the next song.
+#[derive(Debug, Clone, Copy)]
+pub struct Finished {
+    pub id: u64,
+    /// Whether `id` is still playing the last of it, for the next song to crossfade with or follow
+    /// on from. If nothing's next, let it finish; it's sent again once it has.
+    pub early: bool,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Stopped,
+    Playing,
+    Paused,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct JukeboxState {
+    pub status: Status,
+    /// From 0.0 (silent) to 1.0 (full volume)
+    pub volume: f32,
+    /// The song playing (or paused), if any
+    pub current: Option<u64>,
+    /// How far into `current` playback is, in seconds
+    pub position: f64,
+    /// How long until the sleep timer stops playback, in seconds, if it's set
+    pub sleep_in: Option<f64>,
+    /// Whether playback stops once `current` finishes, rather than going on to the next song
+    pub stop_after_current: bool,
+    pub settings: PlayerSettings,
+    /// The name of the audio output, if it has one
+    pub device: Option<String>,
+    /// The equalizer in use, applied as songs play
+    pub equalizer: Equalizer,
+}
+
+impl Default for JukeboxState {
+    fn default() -> Self {
+        JukeboxState {
+            status: Status::Stopped,
+            volume: 1.0,
+            current: None,
+            position: 0.0,
+            sleep_in: None,
+            stop_after_current: false,
+            settings: PlayerSettings::default(),
+            device: None,
+            equalizer: Equalizer::default(),
+        }
+    }
+}
+
+/// What the audio thread needs to know about a song.
+// Only read by the audio thread, which needs the `jukebox` feature
+#[cfg_attr(not(feature = "jukebox"), allow(dead_code))]
+struct Track {
+    id: u64,
+    path: PathBuf,
+    duration: Duration,
+    gapless: Option<GaplessInfo>,
+    replay_gain: Option<ReplayGain>,
+    silence: Option<Silence>,
+}
+
+// Only read by the audio thread, which needs the `jukebox` feature
+#[cfg_attr(not(feature = "jukebox"), allow(dead_code))]
+enum Command {
+    Play(Track),
+    Pause,
+    Resume,
+    Stop,
+    Volume(f32),
+    Sleep(Option<Duration>),
+    StopAfterCurrent(bool),
+    Settings(PlayerSettings),
+    Equalizer(Equalizer),
+    /// Switches to the named audio output, replying with whether that worked
+    Output(String, mpsc::SyncSender<Result<(), String>>),
+}
+
+/// A handle to the audio thread.
+pub struct Jukebox {
+    commands: mpsc::Sender<Command>,
+    state: Arc<Mutex<JukeboxState>>,
+}
+
+impl Jukebox {
+    /// Opens the default audio output and starts the audio thread.
+    #[cfg(feature = "jukebox")]
+    pub fn start(finished: tokio::sync::mpsc::UnboundedSender<Finished>) -> Result<Self, String> {
+        let (commands, receiver) = mpsc::channel();
+        let (ready, started) = mpsc::sync_channel(1);
+        let state = Arc::new(Mutex::new(JukeboxState::default()));
+
+        let thread_state = Arc::clone(&state);
+        std::thread::Builder::new()
+            .name("jukebox".to_string())
+            .spawn(move || output::run(receiver, thread_state, finished, ready))
+            .map_err(|e| e.to_string())?;
+        started
+            .recv()
+            .map_err(|_| "The audio thread exited".to_string())??;
+
+        Ok(Jukebox { commands, state })
+    }
+
+    /// The names of the audio outputs that could be played through.
+    #[cfg(feature = "jukebox")]
+    pub fn outputs(&self) -> Result<Vec<String>, String> {
+        output::names()
+    }
+
+    #[cfg(not(feature = "jukebox"))]
+    pub fn outputs(&self) -> Result<Vec<String>, String> {
+        Ok(Vec::new())
+    }
+
+    #[cfg(not(feature = "jukebox"))]
+    pub fn start(_finished: tokio::sync::mpsc::UnboundedSender<Finished>) -> Result<Self, String> {
+        Err("this server was built without the `jukebox` feature".to_string())
+    }
+
+    fn send(&self, command: Command) {
+        // The audio thread only exits if the output fails, in which case there's nothing to do
+        self.commands.send(command).ok();
+    }
+
+    /// Starts playing `song`. If the last song sent early to `finished` is still playing, it's
+    /// crossfaded or followed on from, as the settings say; otherwise, it's replaced.
+    pub fn play(&self, song: &Song) {
+        self.send(Command::Play(Track {
+            id: song.id,
+            path: song.path.clone(),
+            duration: song.duration,
+            gapless: song.gapless,
+            replay_gain: song.replay_gain,
+            silence: song.analysis.map(|a| a.silence),
+        }));
+    }
+
+    pub fn pause(&self) {
+        self.send(Command::Pause);
+    }
+
+    pub fn resume(&self) {
+        self.send(Command::Resume);
+    }
+
+    pub fn stop(&self) {
+        self.send(Command::Stop);
+    }
+
+    /// Sets the volume, clamped to 0.0 through 1.0.
+    pub fn set_volume(&self, volume: f32) {
+        self.send(Command::Volume(volume.clamp(0.0, 1.0)));
+    }
+
+    /// Stops playback after `after`, fading out over the last `FADE_OUT` of it; `None` cancels the
+    /// timer.
+    pub fn sleep(&self, after: Option<Duration>) {
+        self.send(Command::Sleep(after));
+    }
+
+    /// Stops playback once the current song finishes, rather than going on to the next.
+    pub fn stop_after_current(&self, stop: bool) {
+        self.send(Command::StopAfterCurrent(stop));
+    }
+
+    /// Changes how songs sound, from the next one on. The crossfade is clamped to
+    /// `MAX_CROSSFADE`, and the pre-amp to `MAX_PREAMP`.
+    pub fn set_settings(&self, settings: PlayerSettings) {
+        self.send(Command::Settings(PlayerSettings {
+            crossfade: settings.crossfade.clamp(0.0, MAX_CROSSFADE),
+            preamp: settings.preamp.clamp(-MAX_PREAMP, MAX_PREAMP),
+            ..settings
+        }));
+    }
+
+    /// Changes the equalizer, straight away. It isn't saved; see `dsp::Equalizers` for that.
+    pub fn set_equalizer(&self, equalizer: Equalizer) {
+        self.send(Command::Equalizer(equalizer.clamped()));
+    }
+
+    /// Switches to another audio output, by name, carrying on with whatever's playing. Blocks until
+    /// the output's been opened, which can take a moment.
+    pub fn set_output(&self, name: &str) -> Result<(), String> {
+        let (reply, replied) = mpsc::sync_channel(1);
+        self.send(Command::Output(name.to_string(), reply));
+        replied
+            .recv()
+            .map_err(|_| "The audio thread exited".to_string())?
+    }
+
+    pub fn state(&self) -> JukeboxState {
+        self.state.lock().unwrap().clone()
+    }
+}
+
+#[cfg(feature = "jukebox")]
+mod output {
+    use super::{Command, Finished, JukeboxState, PlayerSettings, Status, Track, FADE_OUT};
+    use crate::dsp::{Equalizer, Processor};
+    use rodio::{cpal::traits::HostTrait, Decoder, DeviceTrait, OutputStream, Sink, Source};
+    use std::{
+        fs::File,
+        io::BufReader,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Arc, Mutex,
+        },
+        time::{Duration, Instant},
+    };
+
+    /// How often to check whether the current song has finished
+    const TICK: Duration = Duration::from_millis(200);
+
+    /// How long before the end of a song to ask for the next, to play it gaplessly
+    const GAPLESS_LOOKAHEAD: Duration = Duration::from_secs(5);
+
+    /// The samples an MP3 decoder adds to the start, on top of the encoder's delay
+    const DECODER_DELAY: u64 = 529;
+
+    type Audio = Box<dyn Source<Item = i16> + Send>;
+
+    /// The equalizer, shared with the songs playing so that changes to it apply straight away.
+    #[derive(Default)]
+    struct SharedEqualizer {
+        /// Bumped on every change
+        version: AtomicUsize,
+        equalizer: Mutex<Equalizer>,
+    }
+
+    impl SharedEqualizer {
+        fn set(&self, equalizer: Equalizer) {
+            *self.equalizer.lock().unwrap() = equalizer;
+            self.version.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    /// Runs a song through the equalizer, a frame at a time.
+    struct Dsp {
+        source: Audio,
+        shared: Arc<SharedEqualizer>,
+        /// `shared`'s version when `processor` was made, and the format it was made for
+        version: usize,
+        format: (u32, u16),
+        processor: Processor,
+        frame: Vec<f32>,
+        /// How much of `frame` has been returned
+        position: usize,
+    }
+
+    impl Dsp {
+        fn new(source: Audio, shared: Arc<SharedEqualizer>) -> Self {
+            let format = (source.sample_rate(), source.channels());
+            let version = shared.version.load(Ordering::Acquire);
+            let processor = Processor::new(*shared.equalizer.lock().unwrap(), format.0, format.1);
+            Dsp {
+                source,
+                shared,
+                version,
+                format,
+                processor,
+                frame: Vec::new(),
+                position: 0,
+            }
+        }
+    }
+
+    impl Iterator for Dsp {
+        type Item = i16;
+
+        fn next(&mut self) -> Option<i16> {
+            if self.position == self.frame.len() {
+                let format = (self.source.sample_rate(), self.source.channels());
+                let version = self.shared.version.load(Ordering::Acquire);
+                if (version, format) != (self.version, self.format) {
+                    let equalizer = *self.shared.equalizer.lock().unwrap();
+                    self.processor = Processor::new(equalizer, format.0, format.1);
+                    (self.version, self.format) = (version, format);
+                }
+
+                self.frame.clear();
+                self.frame.extend(
+                    self.source
+                        .by_ref()
+                        .take(usize::from(format.1))
+                        .map(|sample| f32::from(sample) / 32768.0),
+                );
+                if self.frame.is_empty() {
+                    return None;
+                }
+                self.processor.process(&mut self.frame);
+                self.position = 0;
+            }
+
+            let sample = self.frame[self.position];
+            self.position += 1;
+            Some((sample * 32768.0).clamp(-32768.0, 32767.0) as i16)
+        }
+    }
+
+    impl Source for Dsp {
+        fn current_frame_len(&self) -> Option<usize> {
+            let buffered = self.frame.len() - self.position;
+            self.source.current_frame_len().map(|len| len + buffered)
+        }
+
+        fn channels(&self) -> u16 {
+            self.format.1
+        }
+
+        fn sample_rate(&self) -> u32 {
+            self.format.0
+        }
+
+        fn total_duration(&self) -> Option<Duration> {
+            self.source.total_duration()
+        }
+    }
+
+    /// The names of the audio outputs.
+    pub fn names() -> Result<Vec<String>, String> {
+        let devices = rodio::cpal::default_host()
+            .output_devices()
+            .map_err(|e| e.to_string())?;
+        Ok(devices.filter_map(|device| device.name().ok()).collect())
+    }
+
+    /// Opens the named audio output, or the default one, returning its name too.
+    fn output(
+        name: Option<&str>,
+    ) -> Result<(OutputStream, rodio::OutputStreamHandle, Option<String>), String> {
+        let host = rodio::cpal::default_host();
+        let device = match name {
+            Some(name) => host
+                .output_devices()
+                .map_err(|e| e.to_string())?
+                .find(|device| device.name().is_ok_and(|n| n == name))
+                .ok_or_else(|| format!("there's no audio output called {}", name))?,
+            None => host
+                .default_output_device()
+                .ok_or("there's no audio output")?,
+        };
+        let (stream, handle) = OutputStream::try_from_device(&device).map_err(|e| e.to_string())?;
+        Ok((stream, handle, device.name().ok()))
+    }
+
+    /// Opens a song, trimming its encoder delay and padding for gapless playback, trimming its
+    /// silence, and applying its ReplayGain, as `settings` say, and skipping to `start`. Returns how long it plays for in
+    /// all, too.
+    fn open(
+        track: &Track,
+        settings: &PlayerSettings,
+        equalizer: &Arc<SharedEqualizer>,
+        start: Duration,
+    ) -> Result<(Audio, Duration), String> {
+        let file = File::open(&track.path).map_err(|e| e.to_string())?;
+        let decoder = Decoder::new(BufReader::new(file)).map_err(|e| e.to_string())?;
+
+        // Gapless playback is pointless while crossfading
+        let gapless = settings.gapless && settings.crossfade == 0.0;
+        let (source, duration): (Audio, Duration) = match track.gapless.filter(|_| gapless) {
+            None => (Box::new(decoder), track.duration),
+            Some(info) => {
+                let rate = f64::from(decoder.sample_rate());
+                let skip = (u64::from(info.encoder_delay) + DECODER_DELAY) as f64 / rate;
+                let trimmed = decoder.skip_duration(Duration::from_secs_f64(skip));
+                match info.total_samples {
+                    Some(samples) => {
+                        let length = Duration::from_secs_f64(samples as f64 / rate);
+                        (Box::new(trimmed.take_duration(length)), length)
+                    }
+                    None => (Box::new(trimmed), track.duration),
+                }
+            }
+        };
+
+        let silence = track.silence.filter(|_| settings.trim_silence);
+        let (source, duration): (Audio, Duration) = match silence {
+            Some(silence) if silence.leading() + silence.trailing() < duration => {
+                let length = duration - silence.leading() - silence.trailing();
+                let trimmed = source.skip_duration(silence.leading());
+                (Box::new(trimmed.take_duration(length)), length)
+            }
+            _ => (source, duration),
+        };
+
+        let source: Audio = match start {
+            Duration::ZERO => source,
+            start => Box::new(source.skip_duration(start)),
+        };
+
+        let factor = track.replay_gain.and_then(|gain| {
+            gain.factor(
+                settings.replay_gain,
+                settings.preamp,
+                settings.prevent_clipping,
+            )
+        });
+        let source: Audio = match factor {
+            Some(factor) => Box::new(source.amplify(factor)),
+            None => source,
+        };
+        Ok((Box::new(Dsp::new(source, Arc::clone(equalizer))), duration))
+    }
+
+    /// Starts `track` again on another output, from `position`, with `next` queued up behind it
+    /// if it was. The sink starts out paused, to be played once its volume's set.
+    fn move_to(
+        handle: &rodio::OutputStreamHandle,
+        (track, position): (&Track, Duration),
+        next: Option<&Track>,
+        settings: &PlayerSettings,
+        equalizer: &Arc<SharedEqualizer>,
+    ) -> Result<Sink, String> {
+        let sink = Sink::try_new(handle).map_err(|e| e.to_string())?;
+        sink.pause();
+        let (source, _) = open(track, settings, equalizer, position)?;
+        sink.append(source);
+        if let Some(next) = next {
+            let (source, _) = open(next, settings, equalizer, Duration::ZERO)?;
+            sink.append(source);
+        }
+        Ok(sink)
+    }
+
+    pub fn run(
+        commands: mpsc::Receiver<Command>,
+        state: Arc<Mutex<JukeboxState>>,
+        finished: tokio::sync::mpsc::UnboundedSender<Finished>,
+        ready: mpsc::SyncSender<Result<(), String>>,
+    ) {
+        let (mut _stream, mut handle) = match output(None) {
+            Ok((stream, handle, device)) => {
+                state.lock().unwrap().device = device;
+                ready.send(Ok(())).ok();
+                (stream, handle)
+            }
+            Err(e) => {
+                ready.send(Err(e)).ok();
+                return;
+            }
+        };
+
+        let equalizer = Arc::new(SharedEqualizer::default());
+        let mut sink: Option<Sink> = None;
+        // The last song, fading out under `sink` during a crossfade, and when it started to
+        let mut fading: Option<(Sink, Instant)> = None;
+        // The song playing, and the next, queued up in `sink` behind it for gapless playback
+        let mut playing: Option<Track> = None;
+        let mut pending: Option<(Track, Duration)> = None;
+        // How long the current song plays for
+        let mut duration = Duration::ZERO;
+        // Whether the next song has been asked for early
+        let mut requested = false;
+        // Time played before the last resume, and when that was
+        let mut played = Duration::ZERO;
+        let mut resumed: Option<Instant> = None;
+        // When the sleep timer goes off
+        let mut sleep: Option<Instant> = None;
+
+        loop {
+            let command = match commands.recv_timeout(TICK) {
+                Ok(command) => Some(command),
+                Err(mpsc::RecvTimeoutError::Timeout) => None,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            };
+
+            let mut state = state.lock().unwrap();
+            let settings = state.settings;
+            match command {
+                Some(Command::Play(track)) => {
+                    // Following on from the current song, if it asked for this one
+                    let following = requested
+                        && state.status == Status::Playing
+                        && sink.as_ref().is_some_and(|s| !s.empty());
+                    requested = false;
+
+                    let crossfade = Duration::from_secs_f64(settings.crossfade);
+                    let opened = open(&track, &settings, &equalizer, Duration::ZERO);
+                    let new_sink = Sink::try_new(&handle).map_err(|e| e.to_string());
+                    match opened.and_then(|opened| Ok((opened, new_sink?))) {
+                        Ok(((source, length), _)) if following && crossfade.is_zero() => {
+                            if let Some(sink) = &sink {
+                                sink.append(source);
+                            }
+                            pending = Some((track, length));
+                        }
+                        Ok(((source, length), new_sink)) => {
+                            new_sink.set_volume(state.volume);
+                            if following {
+                                new_sink.append(source.fade_in(crossfade));
+                                fading = sink.take().map(|old| (old, Instant::now()));
+                            } else {
+                                new_sink.append(source);
+                                fading = None;
+                            }
+                            sink = Some(new_sink);
+                            pending = None;
+                            duration = length;
+                            played = Duration::ZERO;
+                            resumed = Some(Instant::now());
+                            state.status = Status::Playing;
+                            state.current = Some(track.id);
+                            playing = Some(track);
+                        }
+                        Err(e) => {
+                            // Skip it, as though it had played
+                            eprintln!("Unable to play {}: {}", track.path.display(), e);
+                            finished
+                                .send(Finished {
+                                    id: track.id,
+                                    early: following,
+                                })
+                                .ok();
+                        }
+                    }
+                }
+                Some(Command::Pause) if state.status == Status::Playing => {
+                    for sink in sink.iter().chain(fading.as_ref().map(|(f, _)| f)) {
+                        sink.pause();
+                    }
+                    played += resumed.take().map(|r| r.elapsed()).unwrap_or_default();
+                    state.status = Status::Paused;
+                }
+                Some(Command::Resume) if state.status == Status::Paused => {
+                    for sink in sink.iter().chain(fading.as_ref().map(|(f, _)| f)) {
+                        sink.play();
+                    }
+                    resumed = Some(Instant::now());
+                    state.status = Status::Playing;
+                }
+                Some(Command::Stop) => {
+                    sink = None;
+                    fading = None;
+                    pending = None;
+                    requested = false;
+                    played = Duration::ZERO;
+                    resumed = None;
+                    state.status = Status::Stopped;
+                    state.current = None;
+                }
+                Some(Command::Volume(volume)) => {
+                    if let Some(sink) = &sink {
+                        sink.set_volume(volume);
+                    }
+                    state.volume = volume;
+                }
+                Some(Command::Sleep(after)) => {
+                    sleep = after.map(|after| Instant::now() + after);
+                    // Undo any fading out so far
+                    if let Some(sink) = &sink {
+                        sink.set_volume(state.volume);
+                    }
+                }
+                Some(Command::StopAfterCurrent(stop)) => state.stop_after_current = stop,
+                Some(Command::Settings(settings)) => state.settings = settings,
+                Some(Command::Equalizer(changed)) => {
+                    equalizer.set(changed);
+                    state.equalizer = changed;
+                }
+                Some(Command::Output(name, reply)) => match output(Some(&name)) {
+                    Ok((stream, new_handle, device)) => {
+                        // Pick up where the song was, on the new output
+                        let position = played + resumed.map(|r| r.elapsed()).unwrap_or_default();
+                        let moved = match (&playing, state.status) {
+                            (Some(track), Status::Playing | Status::Paused) => {
+                                let track = (track, position);
+                                let next = pending.as_ref().map(|(next, _)| next);
+                                Some(move_to(&new_handle, track, next, &settings, &equalizer))
+                            }
+                            _ => None,
+                        };
+
+                        fading = None;
+                        sink = match moved {
+                            Some(Ok(moved)) => {
+                                moved.set_volume(state.volume);
+                                if state.status == Status::Playing {
+                                    moved.play();
+                                }
+                                Some(moved)
+                            }
+                            Some(Err(e)) => {
+                                eprintln!("Unable to carry on playing on {}: {}", name, e);
+                                pending = None;
+                                requested = false;
+                                played = Duration::ZERO;
+                                resumed = None;
+                                state.status = Status::Stopped;
+                                state.current = None;
+                                None
+                            }
+                            None => None,
+                        };
+                        (_stream, handle) = (stream, new_handle);
+                        state.device = device;
+                        reply.send(Ok(())).ok();
+                    }
+                    Err(e) => {
+                        reply.send(Err(e)).ok();
+                    }
+                },
+                Some(_) | None => {}
+            }
+
+            if let Some((old, started)) = &fading {
+                let crossfade = settings.crossfade.max(f64::EPSILON);
+                let faded = started.elapsed().as_secs_f64() / crossfade;
+                if faded >= 1.0 {
+                    fading = None;
+                } else {
+                    old.set_volume(state.volume * (1.0 - faded) as f32);
+                }
+            }
+
+            if let Some(at) = sleep {
+                let remaining = at.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    sleep = None;
+                    sink = None;
+                    fading = None;
+                    pending = None;
+                    requested = false;
+                    played = Duration::ZERO;
+                    resumed = None;
+                    state.status = Status::Stopped;
+                    state.current = None;
+                } else if let Some(sink) = sink.as_ref().filter(|_| remaining < FADE_OUT) {
+                    let faded = remaining.as_secs_f32() / FADE_OUT.as_secs_f32();
+                    sink.set_volume(state.volume * faded);
+                }
+            }
+            state.sleep_in =
+                sleep.map(|at| at.saturating_duration_since(Instant::now()).as_secs_f64());
+
+            // The current song's finished, and the one queued up behind it has started
+            if sink.as_ref().is_some_and(|s| s.len() <= 1) {
+                if let Some((track, length)) = pending.take() {
+                    duration = length;
+                    played = Duration::ZERO;
+                    resumed = Some(Instant::now());
+                    state.current = Some(track.id);
+                    playing = Some(track);
+                }
+            }
+
+            if state.status == Status::Playing && sink.as_ref().is_some_and(|s| s.empty()) {
+                sink = None;
+                requested = false;
+                played = Duration::ZERO;
+                resumed = None;
+                state.status = Status::Stopped;
+                let id = state.current.take();
+                if state.stop_after_current {
+                    // Just this once
+                    state.stop_after_current = false;
+                } else if let Some(id) = id {
+                    finished.send(Finished { id, early: false }).ok();
+                }
+            }
+
+            let position = played + resumed.map(|r| r.elapsed()).unwrap_or_default();
+            state.position = position.as_secs_f64();
+
+            // Ask for the next song in time to crossfade with it, or to queue it up
+            let lookahead = match settings {
+                PlayerSettings { crossfade, .. } if crossfade > 0.0 => {
+                    Some(Duration::from_secs_f64(crossfade))
+                }
+                PlayerSettings { gapless: true, .. } => Some(GAPLESS_LOOKAHEAD),
+                _ => None,
+            };
+            if let (Some(lookahead), Some(id)) = (lookahead, state.current) {
+                if !requested
+                    && pending.is_none()
+                    && !state.stop_after_current
+                    && state.status == Status::Playing
+                    && duration.saturating_sub(position) <= lookahead
+                {
+                    requested = true;
+                    finished.send(Finished { id, early: true }).ok();
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Labels: the user's own tags for songs, eg "workout", "kids-ok", or "vinyl-owned". They're kept
+//! with the songs in `library.json`, survive rescans, and can be searched for with
+//! `SearchTerms::label`. Labelling an album labels each of its songs.
+//!
+//! Songs can also be hidden, so that a Christmas album or a kids' audiobook stays out of searches,
+//! shuffles, and radio without being deleted; `SearchTerms::include_hidden` brings them back.
+
+use crate::music_db::MusicDB;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Labels to add to songs, and to take off them.
+#[derive(Deserialize, Debug, Default)]
+pub struct LabelChanges {
+    #[serde(default)]
+    pub add: Vec<String>,
+    #[serde(default)]
+    pub remove: Vec<String>,
+}
+
+/// One label, as listed by `/labels`.
+#[derive(Serialize)]
+pub struct LabelCount {
+    pub label: String,
+    pub songs: usize,
+}
+
+/// Labels are compared ignoring case and surrounding whitespace, so they're kept in lowercase.
+pub fn normalize(label: &str) -> String {
+    label.trim().to_lowercase()
+}
+
+impl LabelChanges {
+    /// Applies the changes to one song's labels, keeping them sorted. Returns whether they changed.
+    fn apply(&self, labels: &mut Vec<String>) -> bool {
+        let before = labels.clone();
+        let removed = self.remove.iter().map(|l| normalize(l)).collect::<Vec<_>>();
+        labels.retain(|label| !removed.contains(label));
+        for label in self.add.iter().map(|l| normalize(l)) {
+            if !label.is_empty() && !labels.contains(&label) {
+                labels.push(label);
+            }
+        }
+        labels.sort();
+        *labels != before
+    }
+}
+
+impl MusicDB {
+    /// Changes the labels of the songs in `ids`, saving the library if any changed. Returns how
+    /// many of them are in the library.
+    pub fn label(&mut self, ids: &[u64], changes: &LabelChanges) -> usize {
+        let mut found = 0;
+        let mut changed = false;
+        for id in ids {
+            if let Some(song) = self.records.get_mut(id) {
+                found += 1;
+                changed |= changes.apply(&mut song.labels);
+            }
+        }
+        if changed {
+            self.mark_changed();
+            self.save();
+        }
+        found
+    }
+
+    /// Hides the songs in `ids`, or shows them again, saving the library if any changed. Returns
+    /// how many of them are in the library.
+    pub fn hide(&mut self, ids: &[u64], hidden: bool) -> usize {
+        let mut found = 0;
+        let mut changed = false;
+        for id in ids {
+            if let Some(song) = self.records.get_mut(id) {
+                found += 1;
+                changed |= song.hidden != hidden;
+                song.hidden = hidden;
+            }
+        }
+        if changed {
+            self.mark_changed();
+            self.save();
+        }
+        found
+    }
+
+    /// The ids of an album's songs, matching the artist and album ignoring case.
+    pub fn album_ids(&self, artist: &str, album: &str) -> Vec<u64> {
+        let (artist, album) = (artist.to_lowercase(), album.to_lowercase());
+        self.records
+            .values()
+            .filter(|s| s.artist_lower == artist && s.album_lower == album)
+            .map(|s| s.id)
+            .collect()
+    }
+
+    /// Every label in use, and how many songs have it, by label.
+    pub fn labels(&self) -> Vec<LabelCount> {
+        let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+        for label in self.records.values().flat_map(|s| &s.labels) {
+            *counts.entry(label).or_default() += 1;
+        }
+        counts
+            .into_iter()
+            .map(|(label, songs)| LabelCount {
+                label: label.to_string(),
+                songs,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_are_normalized_and_deduplicated() {
+        let changes = LabelChanges {
+            add: vec![
+                " Workout ".to_string(),
+                "kids-ok".to_string(),
+                "".to_string(),
+            ],
+            remove: vec!["VINYL-OWNED".to_string()],
+        };
+        let mut labels = vec!["vinyl-owned".to_string(), "workout".to_string()];
+        assert!(changes.apply(&mut labels));
+        assert_eq!(labels, vec!["kids-ok", "workout"]);
+        assert!(!changes.apply(&mut labels));
+    }
+}
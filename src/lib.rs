@@ -0,0 +1,81 @@
+//! The library behind the bwaa-bwaa music server: scanning directories of MP3s into a
+//! [`MusicDB`](music_db::MusicDB), and searching, sorting, and reporting on it.
+//!
+//! ```no_run
+//! use bwaabwaa::music_db::{load_db, ScanOptions, SearchTerms};
+//!
+//! let db = load_db(vec![("/music".into(), false)], ScanOptions::default()).unwrap();
+//! let terms = SearchTerms {
+//!     term: Some("everlong".to_string()),
+//!     ..Default::default()
+//! };
+//! for song in db.query(terms).results {
+//!     println!("{} - {}", song.artist, song.title);
+//! }
+//! ```
+//!
+//! The `bwaabwaa` binary serves all of this over HTTP.
+
+pub mod admin;
+pub mod analysis;
+pub mod api_keys;
+pub mod art;
+pub mod attempts;
+pub mod audio_cache;
+pub mod audit;
+pub mod backup;
+pub mod browse;
+pub mod bundle;
+pub mod camelot;
+pub mod capabilities;
+pub mod devices;
+pub mod dsd;
+pub mod dsp;
+pub mod duplicates;
+pub mod events;
+pub mod explicit;
+pub mod feed;
+pub mod genres;
+pub mod guest_codes;
+pub mod handoff;
+pub mod history;
+pub mod jukebox;
+pub mod labels;
+pub mod lossless;
+pub mod loudness;
+pub mod memories;
+pub mod metadata;
+pub mod mp3;
+pub mod music_db;
+pub mod musicbrainz;
+pub mod now_playing;
+pub mod overrides;
+pub mod paths;
+pub mod playlist_import;
+pub mod playlists;
+pub mod preview;
+pub mod progress;
+pub mod queue;
+pub mod radio;
+pub mod random;
+pub mod relevance;
+pub mod remote;
+pub mod replay_gain;
+pub mod resume;
+pub mod roots;
+pub mod scan_filter;
+pub mod scan_schedule;
+pub mod scrobble;
+pub mod sections;
+pub mod sessions;
+pub mod shuffle;
+pub mod song;
+pub mod sort_key;
+pub mod stats;
+pub mod telegram;
+pub mod tracker;
+pub mod transcode;
+pub mod user_data;
+pub mod users;
+pub mod webhooks;
+pub mod wishlist;
@@ -0,0 +1,28 @@
+use crate::themes::Theme;
+use askama::Template;
+
+#[derive(Template)]
+#[template(path = "login.html")]
+pub struct LoginPage {
+    pub theme: Theme,
+    pub error: String,
+    /// Where to go once signed in
+    pub next: String,
+    /// The form's CSRF token
+    pub csrf: String,
+}
+
+#[derive(Template)]
+#[template(path = "guest.html")]
+pub struct GuestPage {
+    pub theme: Theme,
+    pub error: String,
+}
+
+#[derive(Template)]
+#[template(path = "logout.html")]
+pub struct LogoutPage {
+    pub theme: Theme,
+    pub user: String,
+    pub csrf: String,
+}
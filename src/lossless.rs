@@ -0,0 +1,312 @@
+//! WavPack and Monkey's Audio (APE) files: their stream info from the headers at their start, and
+//! their tags from the APEv2 tag both keep at their end. Browsers can't play either, so `/listen`
+//! serves them transcoded; see `transcode`.
+
+use crate::explicit;
+use crate::metadata::MetadataReader;
+use crate::replay_gain::ReplayGain;
+use crate::song::Song;
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+    time::Duration,
+};
+
+/// The extensions of WavPack and Monkey's Audio files
+pub const FORMATS: &[&str] = &["wv", "ape"];
+
+/// How much of the start of a file to read for its header
+const HEADER_LEN: u64 = 1024;
+
+/// The length of an APEv2 tag's footer, and of an ID3v1 tag, which may come after it
+const APE_FOOTER_LEN: u64 = 32;
+const ID3V1_LEN: u64 = 128;
+
+/// WavPack's standard sample rates, by the index in a block header's flags
+const WAVPACK_RATES: [u32; 15] = [
+    6000, 8000, 9600, 11025, 12000, 16000, 22050, 24000, 32000, 44100, 48000, 64000, 88200, 96000,
+    192000,
+];
+
+fn u16_le(buf: &[u8], pos: usize) -> Option<u16> {
+    let b = buf.get(pos..pos + 2)?;
+    Some(u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_le(buf: &[u8], pos: usize) -> Option<u32> {
+    let b = buf.get(pos..pos + 4)?;
+    Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// What a header gives: samples per channel, sample rate, channels, and codec.
+struct StreamInfo {
+    samples: u64,
+    sample_rate: u32,
+    channels: u8,
+    codec: &'static str,
+}
+
+/// Reads the header of a WavPack file's first block.
+fn wavpack_header(buf: &[u8]) -> Option<StreamInfo> {
+    if buf.get(..4)? != b"wvpk" {
+        return None;
+    }
+    // The total is 40 bits, the top 8 kept apart; all ones in the bottom 32 means it's unknown
+    let total = u32_le(buf, 12)?;
+    if total == u32::MAX {
+        return None;
+    }
+    let samples = (*buf.get(11)? as u64) << 32 | total as u64;
+    let flags = u32_le(buf, 24)?;
+    let sample_rate = *WAVPACK_RATES.get((flags >> 23 & 0xF) as usize)?;
+    Some(StreamInfo {
+        samples,
+        sample_rate,
+        // Mono, or otherwise stereo; files with more channels say so in a sub-block
+        channels: if flags & 4 != 0 { 1 } else { 2 },
+        codec: "WavPack",
+    })
+}
+
+/// Reads a Monkey's Audio header, which moved into a descriptor in version 3.98.
+fn ape_header(buf: &[u8]) -> Option<StreamInfo> {
+    if buf.get(..4)? != b"MAC " {
+        return None;
+    }
+    let version = u16_le(buf, 4)?;
+    let (blocks_per_frame, final_frame_blocks, total_frames, channels, sample_rate) =
+        if version >= 3980 {
+            let header = u32_le(buf, 8)? as usize;
+            (
+                u32_le(buf, header + 4)?,
+                u32_le(buf, header + 8)?,
+                u32_le(buf, header + 12)?,
+                u16_le(buf, header + 18)?,
+                u32_le(buf, header + 20)?,
+            )
+        } else {
+            let compression = u16_le(buf, 6)?;
+            let blocks_per_frame = if version >= 3950 {
+                73728 * 4
+            } else if version >= 3900 || (version >= 3800 && compression == 4000) {
+                73728
+            } else {
+                9216
+            };
+            (
+                blocks_per_frame,
+                u32_le(buf, 28)?,
+                u32_le(buf, 24)?,
+                u16_le(buf, 10)?,
+                u32_le(buf, 12)?,
+            )
+        };
+    if total_frames == 0 {
+        return None;
+    }
+    Some(StreamInfo {
+        samples: (total_frames as u64 - 1) * blocks_per_frame as u64 + final_frame_blocks as u64,
+        sample_rate,
+        channels: channels.min(u8::MAX as u16) as u8,
+        codec: "Monkey's Audio",
+    })
+}
+
+/// Reads the text items of the APEv2 tag at the end of a file, as (key, value).
+fn ape_tag(file: &mut (impl Read + Seek), size: u64) -> io::Result<Vec<(String, String)>> {
+    // The footer is last, unless there's an ID3v1 tag after it
+    let mut footer = [0u8; APE_FOOTER_LEN as usize];
+    let mut end = size;
+    for skip in [0, ID3V1_LEN] {
+        if size < skip + APE_FOOTER_LEN {
+            return Ok(Vec::new());
+        }
+        file.seek(SeekFrom::Start(size - skip - APE_FOOTER_LEN))?;
+        file.read_exact(&mut footer)?;
+        if footer.starts_with(b"APETAGEX") {
+            end = size - skip;
+            break;
+        }
+    }
+    if !footer.starts_with(b"APETAGEX") {
+        return Ok(Vec::new());
+    }
+
+    // The tag's size takes in its items and footer, but not its header
+    let tag_size = u32_le(&footer, 12).unwrap_or_default() as u64;
+    let count = u32_le(&footer, 16).unwrap_or_default();
+    if tag_size < APE_FOOTER_LEN || tag_size > end {
+        return Ok(Vec::new());
+    }
+    let mut items = vec![0u8; (tag_size - APE_FOOTER_LEN) as usize];
+    file.seek(SeekFrom::Start(end - tag_size))?;
+    file.read_exact(&mut items)?;
+
+    let mut tags = Vec::new();
+    let mut pos = 0;
+    for _ in 0..count {
+        let (Some(len), Some(flags)) = (u32_le(&items, pos), u32_le(&items, pos + 4)) else {
+            break;
+        };
+        let key_start = pos + 8;
+        let Some(key_len) = items
+            .get(key_start..)
+            .and_then(|rest| rest.iter().position(|&b| b == 0))
+        else {
+            break;
+        };
+        let value_start = key_start + key_len + 1;
+        let Some(value) = items.get(value_start..value_start + len as usize) else {
+            break;
+        };
+        // Bits 1 and 2 mark binary items (eg cover art) and links, rather than text
+        if flags & 0b110 == 0 {
+            let key = String::from_utf8_lossy(&items[key_start..key_start + key_len]);
+            // Several values are separated by NULs; the first will do
+            let value = String::from_utf8_lossy(value);
+            let value = value.split('\0').next().unwrap_or_default();
+            tags.push((key.to_string(), value.trim().to_string()));
+        }
+        pos = value_start + len as usize;
+    }
+    Ok(tags)
+}
+
+/// Parses a track (or disc) number such as "3" or "3/12".
+fn number(value: &str) -> (Option<u16>, Option<u16>) {
+    match value.split_once('/') {
+        Some((n, total)) => (n.trim().parse().ok(), total.trim().parse().ok()),
+        None => (value.trim().parse().ok(), None),
+    }
+}
+
+/// Fills in a song's fields from its APEv2 tag.
+fn apply_tags(song: &mut Song, tags: &[(String, String)]) {
+    for (key, value) in tags {
+        let value = value.clone();
+        match key.to_lowercase().as_str() {
+            "title" => song.title = value,
+            "artist" => song.artist = value,
+            "album" => song.album = value,
+            "album artist" | "albumartist" => song.album_artist = value,
+            "genre" => song.genre = value,
+            "composer" => song.composer = value,
+            "conductor" => song.conductor = value,
+            "comment" => song.comment = value,
+            // Years are sometimes full dates, eg "1997-05-21"
+            "year" => song.year = value.get(..4).and_then(|y| y.parse().ok()).unwrap_or(0),
+            "track" => (song.track, song.track_total) = number(&value),
+            "disc" => song.disc = number(&value).0,
+            "artistsort" => song.artist_sort_tag = value,
+            "albumsort" => song.album_sort_tag = value,
+            "itunesadvisory" => song.explicit = explicit::advisory(&value).unwrap_or(false),
+            _ => {}
+        }
+    }
+    song.replay_gain = ReplayGain::from_texts(tags.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+}
+
+/// Reads a file whose header `header` understands, and its APEv2 tag.
+fn read(
+    path: &Path,
+    header: fn(&[u8]) -> Option<StreamInfo>,
+    name: &str,
+) -> Result<Song, io::Error> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut start = Vec::new();
+    (&mut file).take(HEADER_LEN).read_to_end(&mut start)?;
+    let info = header(&start).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Can't read {} header", name),
+        )
+    })?;
+
+    let duration = if info.sample_rate > 0 {
+        Duration::from_secs_f64(info.samples as f64 / info.sample_rate as f64)
+    } else {
+        Duration::ZERO
+    };
+    let mut song = Song {
+        duration,
+        sample_rate: info.sample_rate,
+        channels: info.channels,
+        codec: info.codec.to_string(),
+        ..Default::default()
+    };
+    if !duration.is_zero() {
+        let kbps = size as f64 * 8.0 / duration.as_secs_f64() / 1000.0;
+        song.bitrate = kbps.min(u16::MAX as f64) as u16;
+    }
+    apply_tags(&mut song, &ape_tag(&mut file, size)?);
+    Ok(song)
+}
+
+/// Reads WavPack files.
+pub struct WavPackReader;
+
+impl MetadataReader for WavPackReader {
+    fn read(&self, path: &Path) -> Result<Song, io::Error> {
+        read(path, wavpack_header, "WavPack")
+    }
+}
+
+/// Reads Monkey's Audio files.
+pub struct ApeReader;
+
+impl MetadataReader for ApeReader {
+    fn read(&self, path: &Path) -> Result<Song, io::Error> {
+        read(path, ape_header, "Monkey's Audio")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_wavpack_headers() {
+        let mut header = vec![0u8; 32];
+        header[..4].copy_from_slice(b"wvpk");
+        header[12..16].copy_from_slice(&441000u32.to_le_bytes());
+        // 44.1kHz, mono
+        header[24..28].copy_from_slice(&(9u32 << 23 | 4).to_le_bytes());
+        let info = wavpack_header(&header).unwrap();
+        assert_eq!(info.samples, 441000);
+        assert_eq!(info.sample_rate, 44100);
+        assert_eq!(info.channels, 1);
+    }
+
+    #[test]
+    fn reads_ape_tags() {
+        let items: &[(&str, &str)] = &[
+            ("Title", "Everlong"),
+            ("Track", "11/13"),
+            ("REPLAYGAIN_TRACK_GAIN", "-7.5 dB"),
+        ];
+        let mut tag = Vec::new();
+        for (key, value) in items {
+            tag.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            tag.extend_from_slice(&0u32.to_le_bytes());
+            tag.extend_from_slice(key.as_bytes());
+            tag.push(0);
+            tag.extend_from_slice(value.as_bytes());
+        }
+        let size = (tag.len() + 32) as u32;
+        tag.extend_from_slice(b"APETAGEX");
+        for field in [2000, size, items.len() as u32, 0, 0, 0] {
+            tag.extend_from_slice(&field.to_le_bytes());
+        }
+
+        let size = tag.len() as u64;
+        let tags = ape_tag(&mut io::Cursor::new(tag), size).unwrap();
+
+        let mut song = Song::default();
+        apply_tags(&mut song, &tags);
+        assert_eq!(song.title, "Everlong");
+        assert_eq!((song.track, song.track_total), (Some(11), Some(13)));
+        assert_eq!(song.replay_gain.unwrap().track_gain, Some(-7.5));
+    }
+}
@@ -0,0 +1,100 @@
+//! Loudness graphs: how loud a song is over its length, for the web player to draw as an overview
+//! of its dynamics. A brickwalled remaster is a flat band near the top; the original pressing
+//! rises and falls.
+//!
+//! Levels are short-term loudness, measured over three seconds of the song every second, in dBFS.
+//! They're worked out the first time they're asked for and kept in `loudness/`, by song id, like
+//! previews.
+
+use crate::analysis;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+pub const LOUDNESS_DIR: &str = "loudness";
+
+/// How much of the song each level is measured over, in seconds
+const WINDOW: u32 = 3;
+
+/// How far apart the levels are, in seconds
+const STEP: u32 = 1;
+
+/// A song's loudness over its length.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Loudness {
+    /// How far apart `levels` are, in seconds
+    pub step: u32,
+    /// How much of the song each level is measured over, in seconds
+    pub window: u32,
+    /// The song's short-term loudness every `step` seconds, in dBFS
+    pub levels: Vec<f32>,
+    /// The difference between its quiet and loud parts, in dB, as in its analysis
+    pub range: f32,
+}
+
+/// Where a song's loudness is kept.
+fn path(id: u64) -> PathBuf {
+    Path::new(LOUDNESS_DIR).join(format!("{:016x}.json", id))
+}
+
+/// Measures audio's loudness over its length.
+fn measure(audio: &analysis::Audio) -> Loudness {
+    let window = (audio.rate * WINDOW) as usize;
+    let step = (audio.rate * STEP) as usize;
+    let levels = (0..audio.samples.len())
+        .step_by(step)
+        .map(|start| {
+            let end = (start + window).min(audio.samples.len());
+            // Rounded, so the graph's saved compactly
+            (analysis::power_db(&audio.samples[start..end]) * 10.0).round() / 10.0
+        })
+        .collect();
+    Loudness {
+        step: STEP,
+        window: WINDOW,
+        levels,
+        range: (analysis::dynamics(audio) * 10.0).round() / 10.0,
+    }
+}
+
+/// A song's loudness graph, measuring it first if it hasn't been yet.
+pub fn loudness(id: u64, song: &Path) -> Result<Loudness, String> {
+    let path = path(id);
+    if let Ok(saved) = fs::read(&path) {
+        if let Ok(loudness) = serde_json::from_slice(&saved) {
+            return Ok(loudness);
+        }
+    }
+
+    let loudness = measure(&analysis::decode(song, Default::default(), None)?);
+    // It's only a cache, so failing to save it isn't worth failing the request over
+    let saved = serde_json::to_vec(&loudness)
+        .map_err(|e| e.to_string())
+        .and_then(|json| {
+            fs::create_dir_all(LOUDNESS_DIR)
+                .and_then(|_| fs::write(&path, json))
+                .map_err(|e| e.to_string())
+        });
+    if let Err(e) = saved {
+        eprintln!("Unable to save the loudness of id={}: {}", id, e);
+    }
+    Ok(loudness)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::Audio;
+
+    #[test]
+    fn measures_each_second() {
+        // Two seconds at half volume, then two silent
+        let rate = 100;
+        let mut samples = vec![0.5; 2 * rate as usize];
+        samples.extend(vec![0.0; 2 * rate as usize]);
+        let loudness = measure(&Audio { samples, rate });
+        assert_eq!(loudness.levels, vec![-7.8, -10.8, -100.0, -100.0]);
+    }
+}
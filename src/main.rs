@@ -1,186 +1,528 @@
-use crate::song::SongResult;
-use askama::Template;
-use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use bwaabwaa::{
+    analysis,
+    audio_cache::{self, AudioCache},
+    audit::{self, Action},
+    backup,
+    devices::Devices,
+    events::{EventBus, Subscriber},
+    explicit::KidMode,
+    handoff::Handoffs,
+    history::{self, PlayHistory},
+    music_db::{self, MusicDB},
+    musicbrainz::MusicBrainz,
+    now_playing::{Discord, Progress},
+    paths,
+    playlists::Playlists,
+    progress::Listening,
+    queue::PlayQueue,
+    remote::RemoteSources,
+    resume::ResumePositions,
+    scan_schedule::ScanSchedule,
+    scrobble::{self, ListenBrainz},
+    telegram::{self, Telegram},
+    user_data,
+    users::Users,
+    webhooks::Webhooks,
+    wishlist::Wishlist,
+};
+use std::{path::PathBuf, sync::Arc};
 use tokio::sync::Mutex;
-use warp::{http::Response, Filter};
+use warp::Filter;
 
-mod music_db;
-use music_db::{MusicDB, SearchTerms};
+mod album;
+mod api;
+mod assets;
+mod auth;
+use auth::Auth;
+mod artist;
+mod cache;
+mod client;
+mod config;
+use config::Problems;
+mod error;
+mod feed_page;
+#[cfg(feature = "graphql")]
+mod graphql;
+mod i18n;
+mod login_page;
+mod pwa;
+mod qr;
+mod range;
+mod room_page;
+mod rooms;
+mod routes;
+use rooms::Rooms;
+use routes::Server;
 mod search;
-use search::SearchResults;
-mod song;
+use search::LibraryPageCache;
+mod stats_page;
+mod streams;
+mod sync;
+mod themes;
+mod throttle;
+mod tui;
+use streams::Streams;
+use throttle::Throttle;
 
-/// BWAA-BWAA! WHAT'S NEW, PUSSYCAT?
-/// https://www.youtube.com/watch?v=Mw7Gryt-rcc
-const WHATS_NEW_PUSSYCAT: &[u8; 28797] = include_bytes!("../What's new pussycat.mp3");
-
-const FAVICON: &[u8; 15406] = include_bytes!("../favicon.ico");
 const DEFAULT_PORT: u16 = 8081;
 
+const USAGE: &str = "Usage: bwaabwaa [options]
+       bwaabwaa remote|sync|tui ...
+
+Scanning:
+    --scan=<dir>, --rescan=<dir>    scans a directory, or rescans it from scratch
+    --include=<glob>, --exclude=<glob>, --scan-hidden, --follow-symlinks, --accurate-durations
+    --scan-schedule=<cron>          rescans on a schedule
+    --s3=<url>, --s3-proxy          scans an S3 bucket (needs the `s3` feature)
+    --analyze                       analyzes songs' tempo, key, and loudness in the background
+
+Serving (on $PORT, or 8081):
+    --trust-local                   lets this machine in without signing in
+    --jukebox                       plays the queue through this machine (needs the `jukebox` feature)
+    --max-kbps=<kbps>, --unthrottled[=<client>], --max-streams=<n>, --audio-cache=<size>
+    --discord-webhook=<url>, --telegram-chat=<id>, --listenbrainz-token=<token>
+
+Data:
+    --set-password=<name>           reads a password from standard input
+    --export=<path>, --import=<path> plays and resume positions (no labels; back up for those)
+    --backup=<path>, --restore=<path>";
+
+/// The usage, and which cargo features this build has; `jukebox`, `s3`, and `tui` are only built
+/// on request, eg `cargo build --release --features jukebox,tui`.
+fn help() {
+    println!("{USAGE}\n\nFeatures (see Cargo.toml):");
+    for (feature, built) in [
+        ("graphql", cfg!(feature = "graphql")),
+        ("transcode", cfg!(feature = "transcode")),
+        ("jukebox", cfg!(feature = "jukebox")),
+        ("s3", cfg!(feature = "s3")),
+        ("tui", cfg!(feature = "tui")),
+    ] {
+        if built {
+            println!("    {feature:<10} built");
+        } else {
+            println!("    {feature:<10} not built; rebuild with `--features {feature}`");
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.get(1).map(String::as_str) == Some("remote") {
+        if let Err(e) = client::run(&args[2..]).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("sync") {
+        if let Err(e) = sync::run(&args[2..]).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("tui") {
+        if let Err(e) = tui::run(&args[2..]).await {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        help();
+        return;
+    }
+    if let Some(name) =
+        std::env::args().find_map(|arg| arg.strip_prefix("--set-password=").map(str::to_string))
+    {
+        set_password(&name);
+        return;
+    }
+    if let Some(path) =
+        std::env::args().find_map(|arg| arg.strip_prefix("--export=").map(str::to_string))
+    {
+        export_data(&path);
+        return;
+    }
+    if let Some(path) =
+        std::env::args().find_map(|arg| arg.strip_prefix("--import=").map(str::to_string))
+    {
+        import_data(&path);
+        return;
+    }
+    if let Some(path) =
+        std::env::args().find_map(|arg| arg.strip_prefix("--backup=").map(str::to_string))
+    {
+        backup_to(&path);
+        return;
+    }
+    if let Some(path) =
+        std::env::args().find_map(|arg| arg.strip_prefix("--restore=").map(str::to_string))
+    {
+        restore_from(&path);
+        return;
+    }
+
+    // Problems are collected as they're found, to report them all at once
+    let mut problems = Problems::default();
+
     let port = match std::env::var("PORT") {
-        Ok(s) => s.parse().expect("Invalid port number specified"),
+        Ok(s) => problems
+            .check(format!("PORT={}", s), s.parse())
+            .unwrap_or(DEFAULT_PORT),
         Err(_) => DEFAULT_PORT,
     };
 
     let to_scan = std::env::args()
         .filter_map(|arg| {
             if let Some(d) = arg.strip_prefix("--scan=") {
-                Some((PathBuf::from(d), false))
+                Some((arg.clone(), PathBuf::from(d), false))
             } else {
-                arg.strip_prefix("--rescan")
-                    .map(|d| (PathBuf::from(d), true))
+                arg.strip_prefix("--rescan=")
+                    .map(|d| (arg.clone(), PathBuf::from(d), true))
             }
         })
-        .filter(|(path, _)| path.exists())
-        .collect();
-    let database = music_db::load_db(to_scan).expect("Failed to load database");
-    let database = Arc::new(Mutex::new(database));
-    let database = warp::any().map(move || Arc::clone(&database));
-
-    let library = warp::path::end()
-        .and(database.clone())
-        .and_then(handle_library);
-
-    let listen = warp::path!("listen")
-        .and(warp::query().map(|map: HashMap<String, String>| map.get("id").unwrap().to_string()))
-        .and(database.clone())
-        .and_then(handle_listen);
-
-    let search = warp::path!("search")
-        .and(warp::query())
-        .and(database.clone())
-        .and_then(handle_search);
-
-    let details = warp::path!("details")
-        .and(warp::query().map(|map: HashMap<String, String>| map.get("id").unwrap().to_string()))
-        .and(database.clone())
-        .and_then(handle_details);
-
-    let favicon = warp::path!("favicon.ico").map(|| {
-        Response::builder()
-            .header("content-type", "image/x-icon")
-            .body(FAVICON.to_vec())
-    });
+        .filter_map(|(arg, path, rescan)| {
+            let path = problems.check(arg, paths::canonicalize(&path))?;
+            Some((path, rescan))
+        })
+        .collect::<Vec<_>>();
+    let patterns = |prefix: &str| {
+        std::env::args()
+            .filter_map(|arg| arg.strip_prefix(prefix).map(str::to_string))
+            .collect::<Vec<_>>()
+    };
+    let options = config::scan_options(&args, &mut problems);
 
-    let whats_new = warp::path!("whatsnew").and_then(handle_whats_new);
+    let scan_schedule = patterns("--scan-schedule=").last().and_then(|expression| {
+        problems.check(
+            format!("--scan-schedule={}", expression),
+            ScanSchedule::new(expression),
+        )
+    });
 
-    let cors = warp::cors().allow_any_origin();
+    let audio_cache = match patterns("--audio-cache=").last() {
+        Some(size) => match audio_cache::parse_size(size) {
+            Some(size) => AudioCache::new(size),
+            None => {
+                problems.note(format!("--audio-cache={}", size), "not a size, eg 512M");
+                AudioCache::default()
+            }
+        },
+        None => AudioCache::default(),
+    };
 
-    let routes = library
-        .or(listen)
-        .or(search)
-        .or(whats_new)
-        .or(details)
-        .or(favicon)
-        .with(cors);
+    let max_kbps = patterns("--max-kbps=")
+        .last()
+        .and_then(|kbps| problems.check(format!("--max-kbps={}", kbps), kbps.parse::<u64>()));
+    let throttle = problems
+        .check(
+            "--max-kbps or --unthrottled",
+            Throttle::new(max_kbps, &patterns("--unthrottled=")),
+        )
+        .unwrap_or_default();
 
-    warp::serve(routes).run(([0, 0, 0, 0], port)).await;
-}
+    let max_streams = patterns("--max-streams=")
+        .last()
+        .and_then(|max| problems.check(format!("--max-streams={}", max), max.parse()));
 
-async fn handle_library(
-    database: Arc<Mutex<MusicDB>>,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    let guard = database.lock().await;
-    let results = guard.records.values().collect();
+    let remote = problems
+        .check(
+            "--s3",
+            RemoteSources::new(
+                &patterns("--s3="),
+                std::env::args().any(|arg| arg == "--s3-proxy"),
+            ),
+        )
+        .unwrap_or_default();
 
-    let body = SearchResults { results }.render().unwrap();
-    Ok(warp::reply::html(body))
-}
+    let discord = patterns("--discord-webhook=")
+        .last()
+        .cloned()
+        .map(Discord::new);
+    let telegram = std::env::var("TELEGRAM_BOT_TOKEN").ok().map(|token| {
+        let chats = patterns("--telegram-chat=")
+            .iter()
+            .filter_map(|chat| problems.check(format!("--telegram-chat={}", chat), chat.parse()))
+            .collect();
+        let api = std::env::var("TELEGRAM_API_URL").unwrap_or_else(|_| telegram::API.to_string());
+        Telegram::new(&api, &token, chats)
+    });
+    let listenbrainz = patterns("--listenbrainz-token=").last().map(|token| {
+        let api =
+            std::env::var("LISTENBRAINZ_API_URL").unwrap_or_else(|_| scrobble::API.to_string());
+        ListenBrainz::new(&api, token.clone())
+    });
+    problems.report();
 
-async fn handle_listen(
-    id: String,
-    database: Arc<Mutex<MusicDB>>,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    let db = database.lock().await;
-
-    if id == "whatsnew" {
-        return Ok(Box::new(
-            Response::builder()
-                .header("content-type", "audio/mpeg")
-                .body(WHATS_NEW_PUSSYCAT.to_vec())
-                .unwrap(),
-        ));
+    let mut subscribers: Vec<Box<dyn Subscriber>> = vec![Box::new(Webhooks::load())];
+    if let Some(listenbrainz) = listenbrainz {
+        subscribers.push(Box::new(listenbrainz));
     }
+    if let Some(discord) = discord {
+        subscribers.push(Box::new(discord));
+    }
+    if let Some(telegram) = &telegram {
+        subscribers.push(Box::new(telegram.clone()));
+    }
+    let events = EventBus::new(subscribers);
+    let scanning = !to_scan.is_empty() || !remote.is_empty();
+    let mut scanned = to_scan
+        .iter()
+        .map(|(path, _)| path.display().to_string())
+        .collect::<Vec<_>>();
+    if !remote.is_empty() {
+        scanned.push("remote sources".to_string());
+    }
+    let scan_started = (history::now(), std::time::Instant::now());
 
-    let id = id.parse::<u64>().unwrap();
-
-    let song = match db.records.get(&id) {
-        Some(s) => s,
+    let mut database = match music_db::load_db(to_scan, options.clone()) {
+        Some(database) => database,
+        // With only remote sources, there may be no library yet
+        None if !remote.is_empty() => MusicDB::default(),
         None => {
-            let msg = format!("id={} not found", id);
-            return Ok(Box::new(
-                Response::builder()
-                    .header("content-type", "text/plain")
-                    .body(msg.into())
-                    .unwrap(),
-            ));
+            eprintln!(
+                "No directories were specified for scanning, and library.json wasn't present."
+            );
+            eprintln!("Start this server with --scan=path/to/directory or --rescan=path/to/directory to scan for music.");
+            std::process::exit(1);
         }
     };
+    if !remote.is_empty() {
+        remote.scan(&mut database).await;
+        database.save();
+    }
+    if scanning {
+        audit::record(
+            audit::SERVER,
+            Action::Scan,
+            format!(
+                "{}: {} songs in the library",
+                scanned.join(", "),
+                database.records.len()
+            ),
+        );
+        events.scan_complete(&database, scan_started.0, scan_started.1.elapsed());
+    }
+
+    database.kid_mode = KidMode::load().kid_mode;
 
-    let response = match std::fs::read(&song.path) {
-        Ok(f) => Box::new(
-            Response::builder()
-                .header("content-type", "audio/mpeg")
-                .body(f)
-                .unwrap(),
-        ),
+    let mut playlists = Playlists::load();
+    let mut wishlist = Wishlist::load();
+    if scanning {
+        let found = wishlist.resolve(&database, &mut playlists);
+        if found > 0 {
+            println!("Found {} tracks on the wishlist", found);
+        }
+    }
+
+    let database = Arc::new(Mutex::new(database));
+    let history = Arc::new(Mutex::new(PlayHistory::load()));
+    let queue = Arc::new(Mutex::new(PlayQueue::default()));
+    let resume = Arc::new(Mutex::new(ResumePositions::load()));
+    let playlists = Arc::new(Mutex::new(playlists));
+    let wishlist = Arc::new(Mutex::new(wishlist));
+    let auth = match Auth::load(std::env::args().any(|arg| arg == "--trust-local")) {
+        Ok(auth) => auth,
         Err(e) => {
-            eprintln!("Error with file {}: {:?}", song.path, e);
-            let msg = format!("Unable to load file: {}", id);
-            let b = msg.bytes().collect::<Vec<_>>();
-            let _x = warp::reply::html(b);
-            todo!()
+            eprintln!("{}", e);
+            eprintln!("Fix or remove it, or restore it from a backup, and start again.");
+            std::process::exit(1);
         }
     };
+    let remote = Arc::new(remote);
+    let audio_cache = Arc::new(Mutex::new(audio_cache));
+    let throttle = Arc::new(throttle);
+    let streams = Arc::new(Streams::new(max_streams));
+    let devices = Arc::new(Mutex::new(Devices::load()));
+
+    let jukebox = if std::env::args().any(|arg| arg == "--jukebox") {
+        routes::start_jukebox(&database, &queue, &history, &events)
+    } else {
+        None
+    };
 
-    Ok(response)
+    if let Some(telegram) = &telegram {
+        tokio::spawn(
+            telegram
+                .clone()
+                .run(Arc::clone(&database), Arc::clone(&queue)),
+        );
+    }
+
+    if std::env::args().any(|arg| arg == "--analyze") {
+        tokio::spawn(analysis::run(Arc::clone(&database)));
+    }
+
+    if let Some(scan_schedule) = scan_schedule {
+        tokio::spawn(scan_schedule.run(
+            Arc::clone(&database),
+            options,
+            events.clone(),
+            Arc::clone(&wishlist),
+            Arc::clone(&playlists),
+        ));
+    }
+
+    #[cfg(feature = "graphql")]
+    let schema = graphql::schema(Arc::clone(&database), Arc::clone(&queue));
+
+    let server = Server {
+        database,
+        history,
+        queue,
+        resume,
+        playlists,
+        wishlist,
+        auth: auth.clone(),
+        remote,
+        audio_cache,
+        throttle,
+        streams,
+        devices,
+        handoffs: Arc::new(Mutex::new(Handoffs::default())),
+        progress: Arc::new(Mutex::new(Progress::default())),
+        listening: Arc::new(Mutex::new(Listening::default())),
+        rooms: Arc::new(Mutex::new(Rooms::new())),
+        musicbrainz: MusicBrainz::default(),
+        events,
+        jukebox,
+        library_page: Arc::new(Mutex::new(LibraryPageCache::default())),
+        #[cfg(feature = "graphql")]
+        schema,
+        port,
+    };
+
+    let cors = warp::cors().allow_any_origin();
+    let routes = auth::guard(auth)
+        .and(routes::routes(&server))
+        .recover(error::handle_rejection);
+    let routes = error::request_id()
+        .and(warp::method())
+        .and(warp::path::full())
+        .and(routes)
+        .then(error::tag_response)
+        .with(cors);
+
+    warp::serve(routes).run(([0, 0, 0, 0], port)).await;
 }
 
-async fn handle_search(
-    terms: SearchTerms,
-    database: Arc<Mutex<MusicDB>>,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    let db = database.lock().await;
-    let results = db.query(terms);
+/// Adds an account, or changes its password, reading the password from standard input.
+fn set_password(name: &str) {
+    let mut password = String::new();
+    let read = std::io::stdin().read_line(&mut password);
+    let password = password.trim_end_matches(['\r', '\n']);
+    if read.is_err() || password.is_empty() {
+        eprintln!(
+            "Give the password on standard input, eg echo 'hunter2' | bwaabwaa --set-password={}",
+            name
+        );
+        std::process::exit(1);
+    }
 
-    Ok(warp::reply::json(&results))
+    let mut users = Users::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    match users.set_password(name, password) {
+        Ok(()) => println!("Set the password for {}", name),
+        Err(e) => {
+            eprintln!("Unable to save the password: {:?}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// The library, for `--export=` and `--import=`.
+fn saved_library() -> MusicDB {
+    MusicDB::from_file(music_db::LIBRARY_FILE).unwrap_or_else(|e| {
+        eprintln!("Unable to read {}: {}", music_db::LIBRARY_FILE, e);
+        std::process::exit(1);
+    })
+}
+
+/// Writes the play history and resume positions to `path`.
+fn export_data(path: &str) {
+    let export = user_data::export(
+        &saved_library(),
+        &PlayHistory::load(),
+        &ResumePositions::load(),
+    );
+    let written = std::fs::File::create(path).and_then(|file| {
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), &export)?;
+        Ok(())
+    });
+    match written {
+        Ok(()) => println!("Exported {} songs to {}", export.songs.len(), path),
+        Err(e) => {
+            eprintln!("Unable to write {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Adds the play history and resume positions in `path`, as written by `--export=`. Best done
+/// while the server isn't running, since it keeps its own copy.
+fn import_data(path: &str) {
+    let export = std::fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| serde_json::from_slice(&bytes).map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| {
+            eprintln!("Unable to read {}: {}", path, e);
+            std::process::exit(1);
+        });
+
+    let imported = user_data::import(
+        export,
+        &saved_library(),
+        &mut PlayHistory::load(),
+        &mut ResumePositions::load(),
+    );
+    match imported {
+        Ok(imported) => {
+            println!(
+                "Imported {} plays and {} resume positions; {} songs weren't in the library",
+                imported.plays, imported.resume_positions, imported.unmatched
+            );
+            audit::record(audit::SERVER, Action::DataImport, path);
+            audit::flush();
+        }
+        Err(e) => {
+            eprintln!("Unable to import {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
 }
 
-async fn handle_details(
-    id: String,
-    database: Arc<Mutex<MusicDB>>,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    let db = database.lock().await;
-
-    if id == "whatsnew" {
-        let song = SongResult {
-            id: "whatsnew".to_string(),
-            title: "The best meal I've ever had in my life".to_string(),
-            artist: "John Mulaney".to_string(),
-            album: "Comedy Central Stand-Up".to_string(),
-            year: 2019,
-            comment: "https://www.youtube.com/watch?v=Mw7Gryt-rcc".to_string(),
-            duration: "21 instances of \"What's New, Pussycat?\"".to_string(),
-            track: None,
-        };
-        return Ok(warp::reply::json(&song));
-    }
-
-    let id = id.parse::<u64>().unwrap();
-    match db.records.get(&id) {
-        Some(s) => {
-            let song: SongResult = s.into();
-            Ok(warp::reply::json(&song))
+/// Writes a backup of the server's files to `path`.
+fn backup_to(path: &str) {
+    match backup::create().and_then(|archive| std::fs::write(path, archive)) {
+        Ok(()) => println!("Backed up to {}", path),
+        Err(e) => {
+            eprintln!("Unable to back up to {}: {}", path, e);
+            std::process::exit(1);
         }
-        None => Ok(warp::reply::json(&"?")),
     }
 }
 
-async fn handle_whats_new() -> Result<impl warp::Reply, warp::Rejection> {
-    Ok(Response::builder()
-        .header("content-type", "audio/mpeg")
-        .body(WHATS_NEW_PUSSYCAT.to_vec())
-        .unwrap())
+/// Restores the server's files from a backup at `path`, as written by `--backup=` or
+/// `/admin/backup`. Best done while the server isn't running, since it keeps its own copies.
+fn restore_from(path: &str) {
+    let restored = std::fs::read(path)
+        .map_err(|e| e.to_string())
+        .and_then(|archive| backup::restore(&archive));
+    match restored {
+        Ok(files) => {
+            println!("Restored {}", files.join(", "));
+            audit::record(audit::SERVER, Action::Restore, path);
+            audit::flush();
+        }
+        Err(e) => {
+            eprintln!("Unable to restore {}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,121 @@
+use crate::history::PlayHistory;
+use crate::music_db::MusicDB;
+use crate::song::SongResult;
+use chrono::{DateTime, Datelike, Local, NaiveDate, TimeZone};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+/// What happened on this date in previous years, as returned by `/memories`.
+#[derive(Serialize)]
+pub struct Memories {
+    /// Today's date, eg "2021-10-16"
+    pub date: String,
+    /// Songs played on this date, most recent year first
+    pub played: Vec<YearOfPlays>,
+    /// Albums that were added to the library around this date, most recent first
+    pub added: Vec<AddedAlbum>,
+}
+
+#[derive(Serialize)]
+pub struct YearOfPlays {
+    pub year: i32,
+    pub songs: Vec<SongResult>,
+}
+
+#[derive(Serialize)]
+pub struct AddedAlbum {
+    pub artist: String,
+    pub album: String,
+    /// The date the album was added, eg "2019-10-14"
+    pub added: String,
+}
+
+/// How close (in days) to today an album must have been added to be remembered
+const ADDED_WINDOW_DAYS: u32 = 3;
+
+fn local_date(timestamp: u64) -> Option<NaiveDate> {
+    let time: DateTime<Local> = Local.timestamp_opt(timestamp as i64, 0).single()?;
+    Some(time.date_naive())
+}
+
+/// Whether two dates are within `window` days of each other, ignoring the year.
+fn near_in_year(a: NaiveDate, b: NaiveDate, window: u32) -> bool {
+    // Ordinals are 1..=366, so the difference is at most 365
+    let diff = a.ordinal().abs_diff(b.ordinal());
+    diff.min(365 - diff) <= window
+}
+
+impl PlayHistory {
+    pub fn memories(&self, db: &MusicDB) -> Memories {
+        let today = Local::now().date_naive();
+
+        let mut played: BTreeMap<i32, Vec<u64>> = BTreeMap::new();
+        for play in &self.plays {
+            let date = match local_date(play.at) {
+                Some(d) => d,
+                None => continue,
+            };
+
+            if date.year() < today.year()
+                && date.month() == today.month()
+                && date.day() == today.day()
+            {
+                let songs = played.entry(date.year()).or_default();
+                if !songs.contains(&play.id) {
+                    songs.push(play.id);
+                }
+            }
+        }
+
+        let played = played
+            .into_iter()
+            .rev()
+            .map(|(year, ids)| YearOfPlays {
+                year,
+                songs: ids
+                    .iter()
+                    .filter_map(|id| db.records.get(id))
+                    .map(|s| s.into())
+                    .collect(),
+            })
+            .filter(|y| !y.songs.is_empty())
+            .collect();
+
+        // An album's added date is when its first song was added
+        let mut albums: HashMap<(&str, &str), (u64, &str, &str)> = HashMap::new();
+        for song in db
+            .records
+            .values()
+            .filter(|s| !s.album.is_empty() && s.added > 0)
+        {
+            let entry = albums
+                .entry((&song.artist_lower, &song.album_lower))
+                .or_insert((song.added, &song.artist, &song.album));
+            entry.0 = entry.0.min(song.added);
+        }
+
+        let mut added = albums
+            .into_values()
+            .filter_map(|(added, artist, album)| {
+                let date = local_date(added)?;
+                let remembered =
+                    date.year() < today.year() && near_in_year(date, today, ADDED_WINDOW_DAYS);
+                remembered.then_some((added, artist, album, date))
+            })
+            .collect::<Vec<_>>();
+        added.sort_unstable_by_key(|a| std::cmp::Reverse(a.0));
+
+        Memories {
+            date: today.to_string(),
+            played,
+            added: added
+                .into_iter()
+                .map(|(_, artist, album, date)| AddedAlbum {
+                    artist: artist.to_string(),
+                    album: album.to_string(),
+                    added: date.to_string(),
+                })
+                .collect(),
+        }
+    }
+}
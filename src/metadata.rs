@@ -0,0 +1,536 @@
+//! Reading songs' tags and stream info, with a reader per file format.
+//!
+//! `Song::new` picks a reader by the file's extension from [`readers`]; supporting another
+//! format means implementing [`MetadataReader`] and registering it there.
+
+use crate::explicit;
+use crate::replay_gain::ReplayGain;
+use crate::song::{MediaKind, Song};
+use id3::TagLike;
+use mp3_metadata::Genre;
+use std::{collections::HashMap, fs::File, io, path::Path, sync::OnceLock, time::Duration};
+use symphonia::core::{
+    codecs::{self, CodecType},
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::{MetadataOptions, StandardTagKey, Tag},
+    probe::Hint,
+};
+
+/// Reads a song's metadata from a file.
+pub trait MetadataReader: Send + Sync {
+    /// Reads the tags and stream info of the file at `path`.
+    ///
+    /// Only what's in the file need be filled in; `Song::with_reader` takes care of the path, size,
+    /// id, and the derived search and sort fields.
+    fn read(&self, path: &Path) -> Result<Song, io::Error>;
+}
+
+/// Metadata readers, by (lowercase) file extension.
+pub struct Readers {
+    readers: HashMap<String, Box<dyn MetadataReader>>,
+}
+
+impl Readers {
+    /// No readers at all.
+    pub fn empty() -> Self {
+        Readers {
+            readers: HashMap::new(),
+        }
+    }
+
+    /// Uses `reader` for files ending in `.extension`, replacing any reader already registered.
+    pub fn register(&mut self, extension: &str, reader: impl MetadataReader + 'static) {
+        self.readers
+            .insert(extension.to_lowercase(), Box::new(reader));
+    }
+
+    /// The reader for `path`'s extension, if there is one.
+    pub fn for_path(&self, path: &Path) -> Option<&dyn MetadataReader> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        self.readers.get(&extension).map(|r| r.as_ref())
+    }
+
+    /// The extensions there are readers for, lowercased.
+    pub fn extensions(&self) -> impl Iterator<Item = &str> {
+        self.readers.keys().map(String::as_str)
+    }
+}
+
+impl Default for Readers {
+    /// Every built-in reader.
+    fn default() -> Self {
+        let mut readers = Readers::empty();
+        readers.register("mp3", Mp3Reader);
+        readers.register("m4a", M4aReader);
+        readers.register("wv", crate::lossless::WavPackReader);
+        readers.register("ape", crate::lossless::ApeReader);
+        readers.register("dsf", crate::dsd::DsdReader);
+        readers.register("dff", crate::dsd::DsdReader);
+        readers.register("mp4", VideoReader);
+        readers.register("m4v", VideoReader);
+        for extension in crate::tracker::FORMATS {
+            readers.register(extension, crate::tracker::ModuleReader);
+        }
+        readers
+    }
+}
+
+/// The readers `Song::new` uses.
+pub fn readers() -> &'static Readers {
+    static READERS: OnceLock<Readers> = OnceLock::new();
+    READERS.get_or_init(Readers::default)
+}
+
+/// Reads MP3s: ID3 tags and MPEG frame info via `mp3_metadata`, sort tags and ReplayGain via
+/// `id3`, and encoder delay/padding from the LAME header.
+pub struct Mp3Reader;
+
+impl MetadataReader for Mp3Reader {
+    fn read(&self, path: &Path) -> Result<Song, io::Error> {
+        let mut song = mp3_metadata::read_from_file(path)
+            .map_err(|e| e.to_string())
+            .and_then(read_mp3)
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Can't read MP3 metadata: {}", e),
+                )
+            })?;
+
+        song.gapless = crate::mp3::gapless_info(path);
+
+        if let Ok(tag) = id3::Tag::read_from_path(path) {
+            read_sort_tags(&mut song, &tag);
+            song.replay_gain = ReplayGain::from_id3(&tag);
+            song.explicit = id3_explicit(&tag);
+        }
+
+        Ok(song)
+    }
+}
+
+impl Mp3Reader {
+    /// Reads an MP3 from its first few frames (and its tags) rather than a whole file, as when
+    /// it's somewhere remote. Its duration is estimated from the bitrate and `size`, the full
+    /// file's size.
+    pub fn read_start(&self, start: &[u8], size: u64) -> Result<Song, io::Error> {
+        let mut song = mp3_metadata::read_from_slice(start)
+            .map_err(|e| e.to_string())
+            .and_then(read_mp3)
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Can't read MP3 metadata: {}", e),
+                )
+            })?;
+
+        if song.bitrate > 0 {
+            song.duration =
+                Duration::from_secs_f64(size as f64 * 8.0 / (song.bitrate as f64 * 1000.0));
+        }
+
+        if let Ok(tag) = id3::Tag::read_from2(io::Cursor::new(start)) {
+            read_sort_tags(&mut song, &tag);
+            song.replay_gain = ReplayGain::from_id3(&tag);
+            song.explicit = id3_explicit(&tag);
+        }
+
+        Ok(song)
+    }
+}
+
+/// Reads MP4 audio files, whether AAC or ALAC: their iTunes-style tags and stream info, via
+/// `symphonia`.
+pub struct M4aReader;
+
+impl MetadataReader for M4aReader {
+    fn read(&self, path: &Path) -> Result<Song, io::Error> {
+        let mut song = read_container(path).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Can't read M4A metadata: {}", e),
+            )
+        })?;
+        read_mp4_advisory(&mut song, path);
+        Ok(song)
+    }
+}
+
+/// Reads MP4 music videos: their iTunes-style tags, and the length and stream info of their
+/// soundtrack, via `symphonia`.
+pub struct VideoReader;
+
+impl MetadataReader for VideoReader {
+    fn read(&self, path: &Path) -> Result<Song, io::Error> {
+        let mut song = read_container(path).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Can't read video metadata: {}", e),
+            )
+        })?;
+        song.kind = MediaKind::Video;
+        read_mp4_advisory(&mut song, path);
+        Ok(song)
+    }
+}
+
+/// Reads the tags and stream info of a file in any container `symphonia` can open.
+fn read_container(path: &Path) -> Result<Song, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+    let mut probed = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .map_err(|e| e.to_string())?;
+
+    let mut song = Song::default();
+    // Tags may come before the container (eg ID3) or inside it; those inside win
+    let mut tags = probed
+        .metadata
+        .get()
+        .and_then(|m| m.current().map(|r| r.tags().to_vec()))
+        .unwrap_or_default();
+    if let Some(revision) = probed.format.metadata().current() {
+        tags.extend_from_slice(revision.tags());
+    }
+    for tag in &tags {
+        read_tag(&mut song, tag);
+    }
+
+    // The longest track is the length of the whole thing
+    for track in probed.format.tracks() {
+        let params = &track.codec_params;
+        if let (Some(time_base), Some(frames)) = (params.time_base, params.n_frames) {
+            let time = time_base.calc_time(frames);
+            let duration = Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac);
+            song.duration = song.duration.max(duration);
+        }
+    }
+    // Stream info is the first audio track's, for videos as much as songs
+    let audio = probed
+        .format
+        .tracks()
+        .iter()
+        .map(|t| &t.codec_params)
+        .find(|p| p.sample_rate.is_some());
+    if let Some(params) = audio {
+        song.sample_rate = params.sample_rate.unwrap_or_default();
+        song.channels = params.channels.map_or(0, |c| c.count() as u8);
+        song.codec = codec_name(params.codec).to_string();
+    }
+    if !song.duration.is_zero() {
+        // The average over the whole file: near enough, since the tags and headers are small
+        let size = std::fs::metadata(path).map_err(|e| e.to_string())?.len();
+        let kbps = size as f64 * 8.0 / song.duration.as_secs_f64() / 1000.0;
+        song.bitrate = kbps.min(u16::MAX as f64) as u16;
+    }
+
+    Ok(song)
+}
+
+/// Fills in whatever of a song's fields a tag read by `symphonia` gives.
+fn read_tag(song: &mut Song, tag: &Tag) {
+    // Its advisory has no standard key, and ID3's TXXX frames keep their description
+    if tag.key.to_uppercase().ends_with(explicit::ADVISORY_TAG) {
+        if let Some(explicit) = explicit::advisory(&tag.value.to_string()) {
+            song.explicit = explicit;
+        }
+    }
+    let Some(key) = tag.std_key else {
+        return;
+    };
+    let value = tag.value.to_string().trim().to_string();
+    match key {
+        StandardTagKey::TrackTitle => song.title = value,
+        StandardTagKey::Artist => song.artist = value,
+        StandardTagKey::Album => song.album = value,
+        StandardTagKey::AlbumArtist => song.album_artist = value,
+        StandardTagKey::Genre => song.genre = value,
+        StandardTagKey::Composer => song.composer = value,
+        StandardTagKey::Conductor => song.conductor = value,
+        StandardTagKey::Comment => song.comment = value,
+        StandardTagKey::ContentGroup => song.work = value,
+        StandardTagKey::SortArtist => song.artist_sort_tag = value,
+        StandardTagKey::SortAlbum => song.album_sort_tag = value,
+        StandardTagKey::Date | StandardTagKey::OriginalDate if song.year == 0 => {
+            song.year = get_year(Some(&value)).unwrap_or_default();
+        }
+        StandardTagKey::TrackNumber => {
+            let (track, total) = get_track(Some(&value));
+            song.track = track;
+            song.track_total = song.track_total.or(total);
+        }
+        StandardTagKey::TrackTotal => song.track_total = value.trim().parse().ok(),
+        StandardTagKey::DiscNumber => song.disc = get_track(Some(&value)).0,
+        _ => {}
+    }
+}
+
+/// A display name for a codec, eg "AAC".
+fn codec_name(codec: CodecType) -> &'static str {
+    match codec {
+        codecs::CODEC_TYPE_AAC => "AAC",
+        codecs::CODEC_TYPE_ALAC => "ALAC",
+        codecs::CODEC_TYPE_FLAC => "FLAC",
+        codecs::CODEC_TYPE_MP3 => "MPEG-1 Layer 3",
+        codecs::CODEC_TYPE_OPUS => "Opus",
+        codecs::CODEC_TYPE_VORBIS => "Vorbis",
+        _ => "",
+    }
+}
+
+/// Reads a song's fields from an ID3v2 tag alone, for formats that carry one without being MP3s.
+pub(crate) fn song_from_id3(tag: &id3::Tag) -> Song {
+    let text = |id| {
+        tag.get(id)
+            .and_then(|f| f.content().text())
+            .unwrap_or_default()
+            .trim()
+            .to_string()
+    };
+    let year = tag
+        .year()
+        .or_else(|| tag.date_recorded().map(|d| d.year))
+        .and_then(|y| u16::try_from(y).ok())
+        .unwrap_or_default();
+    let mut song = Song {
+        title: tag.title().unwrap_or_default().trim().to_string(),
+        artist: tag.artist().unwrap_or_default().trim().to_string(),
+        album: tag.album().unwrap_or_default().trim().to_string(),
+        album_artist: tag.album_artist().unwrap_or_default().trim().to_string(),
+        year,
+        genre: tag.genre_parsed().unwrap_or_default().trim().to_string(),
+        composer: text("TCOM"),
+        conductor: text("TPE3"),
+        work: text("TIT1"),
+        movement: text("TIT3"),
+        comment: tag
+            .comments()
+            .next()
+            .map(|c| c.text.trim().to_string())
+            .unwrap_or_default(),
+        track: tag.track().and_then(|t| u16::try_from(t).ok()),
+        track_total: tag.total_tracks().and_then(|t| u16::try_from(t).ok()),
+        disc: tag.disc().and_then(|d| u16::try_from(d).ok()),
+        ..Default::default()
+    };
+    read_sort_tags(&mut song, tag);
+    song.replay_gain = ReplayGain::from_id3(tag);
+    song.explicit = id3_explicit(tag);
+    song
+}
+
+/// Whether an ID3 tag's content advisory, in a TXXX frame, says it's explicit.
+fn id3_explicit(tag: &id3::Tag) -> bool {
+    tag.extended_texts()
+        .find(|text| {
+            text.description
+                .eq_ignore_ascii_case(explicit::ADVISORY_TAG)
+        })
+        .and_then(|text| explicit::advisory(&text.value))
+        .unwrap_or(false)
+}
+
+/// Reads the content advisory of an MP4 file, which `symphonia` doesn't, into `song`.
+fn read_mp4_advisory(song: &mut Song, path: &Path) {
+    let advisory = File::open(path).and_then(|mut file| {
+        let size = file.metadata()?.len();
+        explicit::mp4_advisory(&mut file, size)
+    });
+    if let Ok(Some(explicit)) = advisory {
+        song.explicit = explicit;
+    }
+}
+
+/// Explicit sort names (TSOP/TSOA)
+fn read_sort_tags(song: &mut Song, tag: &id3::Tag) {
+    let text = |id| {
+        tag.get(id)
+            .and_then(|f| f.content().text())
+            .unwrap_or_default()
+            .to_string()
+    };
+    song.artist_sort_tag = text("TSOP");
+    song.album_sort_tag = text("TSOA");
+}
+
+fn read_mp3(metadata: mp3_metadata::MP3Metadata) -> Result<Song, String> {
+    let bitrate = average_bitrate(&metadata.frames);
+    let (sample_rate, channels, codec) =
+        metadata.frames.first().map(stream_info).unwrap_or_default();
+
+    let song = if metadata.optional_info.is_empty() {
+        let tags = metadata.tag.ok_or("it has no ID3 tags")?;
+
+        Song {
+            title: tags.title,
+            artist: tags.artist,
+            album: tags.album,
+            year: tags.year,
+            genre: genre_name(&tags.genre),
+            duration: metadata.duration,
+            bitrate,
+            sample_rate,
+            channels,
+            codec,
+            ..Default::default()
+        }
+    } else {
+        let info = metadata
+            .optional_info
+            .into_iter()
+            .next()
+            .unwrap_or_default();
+        let (track, track_total) = get_track(info.track_number.as_ref());
+        let (disc, _) = get_track(info.part_of_a_set.as_ref());
+        let year = get_year(info.year.as_ref())
+            .or_else(|| metadata.tag.as_ref().map(|t| t.year))
+            .unwrap_or_default();
+        let genre = info
+            .content_type
+            .first()
+            .or_else(|| metadata.tag.as_ref().map(|t| &t.genre))
+            .map(genre_name)
+            .unwrap_or_default();
+        Song {
+            title: info.title.unwrap_or_default(),
+            artist: if info.performers.is_empty() {
+                "".to_string()
+            } else {
+                info.performers[0].to_string()
+            },
+            album: info.album_movie_show.unwrap_or_default(),
+            album_artist: info.band.unwrap_or_default(),
+            year,
+            genre,
+            composer: info.composers.join(", "),
+            conductor: info.conductor.unwrap_or_default(),
+            work: info.content_group_description.unwrap_or_default(),
+            movement: info.subtitle_refinement_description.unwrap_or_default(),
+            duration: metadata.duration,
+            track,
+            track_total,
+            disc,
+            bitrate,
+            sample_rate,
+            channels,
+            codec,
+            ..Default::default()
+        }
+    };
+
+    Ok(song)
+}
+
+/// The mean bitrate across all frames, which accounts for VBR files.
+fn average_bitrate(frames: &[mp3_metadata::Frame]) -> u16 {
+    if frames.is_empty() {
+        return 0;
+    }
+
+    let total = frames.iter().map(|f| f.bitrate as u64).sum::<u64>();
+    (total / frames.len() as u64) as u16
+}
+
+/// The sample rate, channel count, and codec name described by an MPEG frame header.
+fn stream_info(frame: &mp3_metadata::Frame) -> (u32, u8, String) {
+    use mp3_metadata::{ChannelType, Layer, Version};
+
+    let channels = match frame.chan_type {
+        ChannelType::SingleChannel => 1,
+        ChannelType::Unknown => 0,
+        _ => 2,
+    };
+
+    let version = match frame.version {
+        Version::MPEG1 => "MPEG-1",
+        Version::MPEG2 => "MPEG-2",
+        Version::MPEG2_5 => "MPEG-2.5",
+        _ => "MPEG",
+    };
+    let layer = match frame.layer {
+        Layer::Layer1 => " Layer 1",
+        Layer::Layer2 => " Layer 2",
+        Layer::Layer3 => " Layer 3",
+        _ => "",
+    };
+
+    (
+        frame.sampling_freq as u32,
+        channels,
+        format!("{}{}", version, layer),
+    )
+}
+
+/// Parses a track (or disc) number such as "3" or "3/12" into the number and, if present, the
+/// total.
+fn get_track(track_info: Option<&String>) -> (Option<u16>, Option<u16>) {
+    let s = match track_info {
+        Some(s) => s.trim(),
+        None => return (None, None),
+    };
+
+    match s.split_once('/') {
+        Some((n, total)) => (n.trim().parse().ok(), total.trim().parse().ok()),
+        None => (s.parse().ok(), None),
+    }
+}
+
+fn get_year(year_info: Option<&String>) -> Option<u16> {
+    // Years are sometimes full dates, eg "1997-05-21"
+    let s = year_info?;
+    s.trim().get(..4)?.parse().ok()
+}
+
+/// Converts an ID3 genre to display form, eg `ClassicRock` becomes "Classic Rock".
+fn genre_name(genre: &Genre) -> String {
+    match genre {
+        Genre::Something(s) => s.trim().to_string(),
+        Genre::Unknown => String::new(),
+        known => {
+            let debug = format!("{:?}", known);
+            let mut name = String::with_capacity(debug.len() + 4);
+            for (i, c) in debug.char_indices() {
+                if i > 0 && c.is_uppercase() {
+                    name.push(' ');
+                }
+                name.push(c);
+            }
+            name
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_track_numbers() {
+        for (tag, parsed) in [
+            ("7", (Some(7), None)),
+            ("07", (Some(7), None)),
+            ("3/12", (Some(3), Some(12))),
+            (" 3 / 12 ", (Some(3), Some(12))),
+            ("3/", (Some(3), None)),
+            ("/12", (None, Some(12))),
+            ("", (None, None)),
+            ("A1", (None, None)),
+            ("-1", (None, None)),
+            ("70000", (None, None)),
+            ("1/2/3", (Some(1), None)),
+        ] {
+            assert_eq!(get_track(Some(&tag.to_string())), parsed, "{:?}", tag);
+        }
+        assert_eq!(get_track(None), (None, None));
+    }
+}
@@ -0,0 +1,433 @@
+//! Low-level MPEG audio frame parsing, for when `mp3_metadata`'s estimates aren't good enough.
+//!
+//! `mp3_metadata` derives a file's duration from the frames it happens to find, which can be
+//! thrown off by VBR info frames and by false frame syncs inside tags or junk data. Here, the
+//! duration comes from the Xing/Info or VBRI header that VBR encoders write into the first frame,
+//! falling back to a careful walk of every frame (where each frame must be followed by another
+//! valid frame header).
+
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Version {
+    Mpeg1,
+    Mpeg2,
+    Mpeg25,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FrameHeader {
+    version: Version,
+    /// 1, 2, or 3
+    layer: u8,
+    /// kbps
+    bitrate: u32,
+    /// Hz
+    sample_rate: u32,
+    padding: bool,
+    mono: bool,
+}
+
+const BITRATES_V1: [[u32; 14]; 3] = [
+    [
+        32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+    ],
+    [
+        32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+    ],
+    [
+        32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ],
+];
+const BITRATES_V2: [[u32; 14]; 2] = [
+    [
+        32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+    ],
+    [8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+const SAMPLE_RATES_V1: [u32; 3] = [44100, 48000, 32000];
+
+impl FrameHeader {
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        let b = bytes.get(..4)?;
+        if b[0] != 0xFF || b[1] & 0xE0 != 0xE0 {
+            return None;
+        }
+
+        let version = match (b[1] >> 3) & 0b11 {
+            0 => Version::Mpeg25,
+            2 => Version::Mpeg2,
+            3 => Version::Mpeg1,
+            _ => return None,
+        };
+        let layer = match (b[1] >> 1) & 0b11 {
+            1 => 3,
+            2 => 2,
+            3 => 1,
+            _ => return None,
+        };
+
+        // Index 0 is "free format" and 15 is invalid; neither can be walked
+        let bitrate_index = (b[2] >> 4) as usize;
+        if bitrate_index == 0 || bitrate_index == 15 {
+            return None;
+        }
+        let bitrate = match version {
+            Version::Mpeg1 => BITRATES_V1[layer as usize - 1][bitrate_index - 1],
+            _ => BITRATES_V2[if layer == 1 { 0 } else { 1 }][bitrate_index - 1],
+        };
+
+        let sample_rate = *SAMPLE_RATES_V1.get(((b[2] >> 2) & 0b11) as usize)?;
+        let sample_rate = match version {
+            Version::Mpeg1 => sample_rate,
+            Version::Mpeg2 => sample_rate / 2,
+            Version::Mpeg25 => sample_rate / 4,
+        };
+
+        Some(FrameHeader {
+            version,
+            layer,
+            bitrate,
+            sample_rate,
+            padding: (b[2] >> 1) & 1 == 1,
+            mono: b[3] >> 6 == 0b11,
+        })
+    }
+
+    fn samples(&self) -> u32 {
+        match (self.layer, self.version) {
+            (1, _) => 384,
+            (3, Version::Mpeg2 | Version::Mpeg25) => 576,
+            _ => 1152,
+        }
+    }
+
+    /// The length of the frame in bytes, including the header.
+    fn len(&self) -> usize {
+        let padding = self.padding as u32;
+        let len = if self.layer == 1 {
+            (12 * self.bitrate * 1000 / self.sample_rate + padding) * 4
+        } else {
+            self.samples() / 8 * self.bitrate * 1000 / self.sample_rate + padding
+        };
+        len as usize
+    }
+
+    /// Where a Xing/Info header would begin, relative to the start of the frame.
+    fn xing_offset(&self) -> usize {
+        // The header is followed by the layer 3 side information, whose size varies
+        4 + match (self.version, self.mono) {
+            (Version::Mpeg1, false) => 32,
+            (Version::Mpeg1, true) => 17,
+            (_, false) => 17,
+            (_, true) => 9,
+        }
+    }
+}
+
+/// Skips over an ID3v2 tag at the start of the file, if there is one.
+fn audio_start(buf: &[u8]) -> usize {
+    if buf.len() >= 10 && &buf[..3] == b"ID3" {
+        let size = buf[6..10]
+            .iter()
+            .fold(0usize, |acc, &b| (acc << 7) | (b & 0x7F) as usize);
+        let footer = if buf[5] & 0x10 != 0 { 10 } else { 0 };
+        10 + size + footer
+    } else {
+        0
+    }
+}
+
+/// Finds the first frame at or after `start` that's followed by another valid frame (or the end
+/// of the file), so that stray 0xFF bytes aren't mistaken for audio.
+fn first_frame(buf: &[u8], start: usize) -> Option<(usize, FrameHeader)> {
+    (start..buf.len().saturating_sub(4)).find_map(|pos| {
+        let header = FrameHeader::parse(&buf[pos..])?;
+        let next = pos + header.len();
+        if next >= buf.len() || FrameHeader::parse(&buf[next..]).is_some() {
+            Some((pos, header))
+        } else {
+            None
+        }
+    })
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> Option<u32> {
+    let b = buf.get(pos..pos + 4)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Where the Xing/Info header's optional fields end (and a LAME tag would begin), if the frame at
+/// `pos` has one.
+fn xing_end(buf: &[u8], pos: usize, header: &FrameHeader) -> Option<usize> {
+    let xing = pos + header.xing_offset();
+    if !matches!(buf.get(xing..xing + 4), Some(b"Xing" | b"Info")) {
+        return None;
+    }
+
+    let flags = read_u32(buf, xing + 4)?;
+    // Frame count, byte count, seek table, and quality, each present if its flag bit is set
+    let optional = [(1, 4), (2, 4), (4, 100), (8, 4)]
+        .iter()
+        .filter(|(flag, _)| flags & flag != 0)
+        .map(|(_, len)| len)
+        .sum::<usize>();
+
+    Some(xing + 8 + optional)
+}
+
+/// Looks for a Xing/Info or VBRI header in the frame at `pos`.
+///
+/// The outer `Option` is whether there's a header at all (in which case the frame holds no
+/// audio); the inner one is the number of audio frames it records, which is optional for Xing.
+fn vbr_header(buf: &[u8], pos: usize, header: &FrameHeader) -> Option<Option<u32>> {
+    let xing = pos + header.xing_offset();
+    if let Some(b"Xing" | b"Info") = buf.get(xing..xing + 4) {
+        let flags = read_u32(buf, xing + 4)?;
+        // Bit 0 indicates that the frame count is present
+        return Some(if flags & 1 == 1 {
+            read_u32(buf, xing + 8)
+        } else {
+            None
+        });
+    }
+
+    // VBRI headers always sit 32 bytes after the frame header
+    let vbri = pos + 4 + 32;
+    if buf.get(vbri..vbri + 4) == Some(b"VBRI") {
+        return Some(read_u32(buf, vbri + 14));
+    }
+
+    None
+}
+
+/// Counts samples by walking every frame from `pos`, resynchronizing past any garbage.
+fn walk_frames(buf: &[u8], mut pos: usize) -> u64 {
+    let mut samples = 0u64;
+
+    while pos + 4 <= buf.len() {
+        // A trailing ID3v1 or APE tag marks the end of the audio
+        if buf[pos..].starts_with(b"TAG") || buf[pos..].starts_with(b"APETAGEX") {
+            break;
+        }
+
+        match FrameHeader::parse(&buf[pos..]) {
+            // A frame cut short, eg by a truncated download, isn't counted
+            Some(header) if header.len() > 0 && pos + header.len() <= buf.len() => {
+                samples += header.samples() as u64;
+                pos += header.len();
+            }
+            _ => match first_frame(buf, pos + 1) {
+                Some((next, _)) => pos = next,
+                None => break,
+            },
+        }
+    }
+
+    samples
+}
+
+/// Encoder delay and padding, which a player must trim for gapless playback.
+///
+/// Encoders prepend `delay` samples of silence (plus the decoder's own delay) and append `padding`
+/// samples to fill out the last frame.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct GaplessInfo {
+    pub encoder_delay: u16,
+    pub encoder_padding: u16,
+    /// The number of audio samples (per channel) after trimming, when the encoder recorded it
+    pub total_samples: Option<u64>,
+}
+
+/// How much of the file to read when looking for the LAME tag in the first frame
+const HEAD_LEN: u64 = 16 * 1024;
+
+/// Reads the LAME tag's gapless information, if the file has one.
+pub fn gapless_info(path: &Path) -> Option<GaplessInfo> {
+    let mut file = std::fs::File::open(path).ok()?;
+
+    // Only the start of the audio is needed, but that may be after a large ID3 tag
+    let mut id3 = [0u8; 10];
+    file.read_exact(&mut id3).ok()?;
+    let start = audio_start(&id3) as u64;
+    let mut buf = Vec::new();
+    std::io::Seek::seek(&mut file, std::io::SeekFrom::Start(start)).ok()?;
+    file.take(HEAD_LEN).read_to_end(&mut buf).ok()?;
+
+    lame_tag(&buf)
+}
+
+/// Reads the gapless information from the LAME tag in the first frame of `buf`, the start of the
+/// audio.
+fn lame_tag(buf: &[u8]) -> Option<GaplessInfo> {
+    let (pos, header) = first_frame(buf, 0)?;
+    let lame = xing_end(buf, pos, &header)?;
+    if buf.get(lame..lame + 4) != Some(b"LAME") && buf.get(lame..lame + 4) != Some(b"Lavf") {
+        return None;
+    }
+
+    // 12 bits of delay followed by 12 bits of padding
+    let b = buf.get(lame + 21..lame + 24)?;
+    let encoder_delay = ((b[0] as u16) << 4) | (b[1] as u16 >> 4);
+    let encoder_padding = (((b[1] & 0x0F) as u16) << 8) | b[2] as u16;
+
+    let total_samples = match vbr_header(buf, pos, &header) {
+        Some(Some(frames)) => (frames as u64 * header.samples() as u64)
+            .checked_sub(encoder_delay as u64 + encoder_padding as u64),
+        _ => None,
+    };
+
+    Some(GaplessInfo {
+        encoder_delay,
+        encoder_padding,
+        total_samples,
+    })
+}
+
+/// Computes an MP3's duration precisely, using its VBR header if present or by walking every
+/// frame otherwise.
+pub fn accurate_duration(path: &Path) -> Option<Duration> {
+    duration(&std::fs::read(path).ok()?)
+}
+
+/// The duration of the MP3 in `buf`, a whole file.
+fn duration(buf: &[u8]) -> Option<Duration> {
+    let (pos, header) = first_frame(buf, audio_start(buf))?;
+
+    let samples = match vbr_header(buf, pos, &header) {
+        Some(Some(frames)) => frames as u64 * header.samples() as u64,
+        Some(None) => walk_frames(buf, pos + header.len()),
+        // Without a VBR header, every frame (including the first) is audio
+        None => walk_frames(buf, pos),
+    };
+
+    Some(Duration::from_secs_f64(
+        samples as f64 / header.sample_rate as f64,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encoded by LAME 3.100: 99 frames of MPEG-1 layer 3 at 48kHz, after an ID3v2 tag
+    const LAME_FILE: &[u8] = include_bytes!("../What's new pussycat.mp3");
+
+    /// An MPEG-1 layer 3 frame at 44.1kHz, in stereo, of silence.
+    fn frame(bitrate_index: u8, padding: bool) -> Vec<u8> {
+        let header = [
+            0xFF,
+            0xFB,
+            (bitrate_index << 4) | ((padding as u8) << 1),
+            0x00,
+        ];
+        let len = FrameHeader::parse(&header).unwrap().len();
+        let mut frame = header.to_vec();
+        frame.resize(len, 0);
+        frame
+    }
+
+    #[test]
+    fn parses_frame_headers() {
+        for (bytes, parsed) in [
+            // MPEG-1 layer 3, 128kbps, 44.1kHz
+            (
+                [0xFF, 0xFB, 0x90, 0x00],
+                Some((Version::Mpeg1, 3, 128, 44100, 417)),
+            ),
+            // With padding
+            (
+                [0xFF, 0xFB, 0x92, 0x00],
+                Some((Version::Mpeg1, 3, 128, 44100, 418)),
+            ),
+            // MPEG-2 layer 3, 64kbps, 22.05kHz
+            (
+                [0xFF, 0xF3, 0x80, 0x00],
+                Some((Version::Mpeg2, 3, 64, 22050, 208)),
+            ),
+            // MPEG-1 layer 1, 384kbps, 48kHz
+            (
+                [0xFF, 0xFF, 0xC4, 0x00],
+                Some((Version::Mpeg1, 1, 384, 48000, 384)),
+            ),
+            // Bad sync words
+            ([0xFE, 0xFB, 0x90, 0x00], None),
+            ([0xFF, 0x1B, 0x90, 0x00], None),
+            // The reserved version, and layer
+            ([0xFF, 0xEB, 0x90, 0x00], None),
+            ([0xFF, 0xF9, 0x90, 0x00], None),
+            // Free format, and the invalid bitrate
+            ([0xFF, 0xFB, 0x00, 0x00], None),
+            ([0xFF, 0xFB, 0xF0, 0x00], None),
+            // The reserved sample rate
+            ([0xFF, 0xFB, 0x9C, 0x00], None),
+        ] {
+            let header = FrameHeader::parse(&bytes);
+            let header = header.map(|h| (h.version, h.layer, h.bitrate, h.sample_rate, h.len()));
+            assert_eq!(header, parsed, "{:02X?}", bytes);
+        }
+        assert!(FrameHeader::parse(&[0xFF, 0xFB, 0x90]).is_none());
+    }
+
+    #[test]
+    fn walks_whole_frames() {
+        let frames = [frame(9, false), frame(9, true), frame(11, false)].concat();
+        let tag = [&b"TAG"[..], &[0; 125]].concat();
+        for (buf, expected) in [
+            (frames.clone(), 3),
+            // Up to a trailing ID3v1 tag
+            ([&frames[..], &tag].concat(), 3),
+            // Past junk between frames
+            (
+                [&frame(9, false)[..], &[0xFF, 0x00, 0x12], &frames].concat(),
+                4,
+            ),
+            // Not the last frame, cut short
+            (frames[..frames.len() - 1].to_vec(), 2),
+            (frames[..frames.len() - 400].to_vec(), 2),
+            // Nor headers on their own
+            (frames[..3].to_vec(), 0),
+            (Vec::new(), 0),
+        ] {
+            assert_eq!(walk_frames(&buf, 0), expected * 1152, "{} bytes", buf.len());
+        }
+    }
+
+    #[test]
+    fn skips_stray_syncs_before_the_first_frame() {
+        let buf = [
+            &[0xFF, 0xFB, 0x90, 0x00, 0x00][..],
+            &frame(9, false),
+            &frame(9, false),
+        ]
+        .concat();
+        assert_eq!(first_frame(&buf, 0).map(|(pos, _)| pos), Some(5));
+        assert!(first_frame(&[0xFF, 0xFB], 0).is_none());
+    }
+
+    #[test]
+    fn reads_lames_gapless_info() {
+        let audio = &LAME_FILE[audio_start(LAME_FILE)..];
+        let info = lame_tag(audio).unwrap();
+        assert_eq!(
+            info,
+            GaplessInfo {
+                encoder_delay: 576,
+                encoder_padding: 907,
+                total_samples: Some(99 * 1152 - 576 - 907),
+            }
+        );
+        assert_eq!(duration(LAME_FILE), Some(Duration::from_millis(2376)));
+
+        // Nor from a tag that's cut short
+        let (pos, header) = first_frame(audio, 0).unwrap();
+        let lame = xing_end(audio, pos, &header).unwrap();
+        assert!(lame_tag(&audio[..lame + 23]).is_none());
+        assert!(lame_tag(&audio[..pos + header.xing_offset() + 6]).is_none());
+        // Nor from plain frames
+        assert!(lame_tag(&frame(9, false)).is_none());
+    }
+}
@@ -1,27 +1,57 @@
-use crate::song::{Song, SongResult};
+use crate::analysis::Mood;
+use crate::art::CoverColors;
+use crate::camelot::Key;
+use crate::explicit::KidMode;
+use crate::genres::Genres;
+use crate::labels;
+use crate::overrides::Overrides;
+use crate::paths;
+use crate::relevance;
+use crate::roots::{self, RescanPolicy, Root};
+use crate::scan_filter::ScanFilter;
+use crate::sections::Section;
+use crate::song::{format_duration, AlbumKey, Song, SongResult};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fs::File,
     io::{BufRead, BufReader, BufWriter, Write},
     path::{Path, PathBuf},
 };
 
-const LIBRARY_FILE: &str = "library.json";
+pub const LIBRARY_FILE: &str = "library.json";
+pub(crate) const ROOTS_FILE: &str = "roots.json";
+pub(crate) const SCAN_ERRORS_FILE: &str = "scan_errors.json";
 
+/// The music library: every song scanned, keyed by id.
 #[derive(Default)]
-pub(crate) struct MusicDB {
+pub struct MusicDB {
     pub records: HashMap<u64, Song>,
+
+    /// The directories that have been scanned
+    pub roots: Vec<Root>,
+
+    /// Files and directories that couldn't be read when last scanned, keyed by path
+    pub scan_errors: BTreeMap<PathBuf, ScanError>,
+
+    /// Bumped by `mark_changed` whenever `records` changes, so that anything cached from them
+    /// (eg, the rendered library page) knows to refresh.
+    generation: u64,
+
+    /// How genres are tidied as songs are scanned, and matched when searching
+    pub genres: Genres,
+
+    /// Whether explicit songs are left out of searches, shuffles, and radio; see `explicit`
+    pub kid_mode: bool,
+
+    /// Corrections to albums' tags and merged artists, applied as songs are scanned; see
+    /// `overrides`
+    pub overrides: Overrides,
 }
 
 impl MusicDB {
     pub fn new(filename: &str) -> Self {
-        match Self::from_file(filename) {
-            Ok(s) => s,
-            Err(_) => Self {
-                records: HashMap::new(),
-            },
-        }
+        Self::from_file(filename).unwrap_or_default()
     }
 
     pub fn from_file(filename: &str) -> Result<Self, std::io::Error> {
@@ -29,14 +59,135 @@ impl MusicDB {
         let buf = BufReader::new(file);
         let records = buf
             .lines()
-            .filter_map(|line| line.ok())
+            .map_while(Result::ok)
             .filter_map(|line| serde_json::from_str::<Song>(&line).ok())
-            // Check that the song referenced exists
-            .filter(|song| Path::new(&song.path).exists())
+            // Check that the song referenced exists, filling in fields it may predate
+            .filter_map(|mut song| {
+                // The sort locale may have changed since the library was saved
+                song.update_sort_keys();
+                // Remote songs are checked when their source is scanned
+                if paths::is_remote(&song.path) {
+                    return Some(song);
+                }
+
+                let metadata = std::fs::metadata(&song.path).ok()?;
+                if song.size == 0 {
+                    song.size = metadata.len();
+                }
+                if song.added == 0 {
+                    song.added = metadata
+                        .modified()
+                        .ok()
+                        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                        .map(|d| d.as_secs())
+                        .unwrap_or_default();
+                }
+                Some(song)
+            })
             .map(|s| (s.id, s))
             .collect();
 
-        Ok(Self { records })
+        Ok(Self {
+            records,
+            roots: Vec::new(),
+            scan_errors: BTreeMap::new(),
+            generation: 0,
+            genres: Genres::default(),
+            kid_mode: false,
+            overrides: Overrides::default(),
+        })
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Notes that `records` has changed.
+    pub fn mark_changed(&mut self) {
+        self.generation += 1;
+    }
+
+    /// Loads the previously-scanned roots saved by `roots::save`.
+    fn load_roots_from(&mut self, filename: &str) {
+        self.roots = roots::load(filename);
+        self.apply_roots();
+    }
+
+    /// Notes which root each song is from (if the library predates keeping track), and so which
+    /// section it's in.
+    fn apply_roots(&mut self) {
+        for song in self.records.values_mut() {
+            let root = if song.root.as_os_str().is_empty() {
+                roots::find(&self.roots, &song.path)
+            } else {
+                self.roots.iter().find(|r| r.path == song.root)
+            };
+
+            if let Some(root) = root {
+                song.root = root.path.clone();
+                song.section = root.section;
+            }
+        }
+    }
+
+    fn add_roots(&mut self, paths: impl IntoIterator<Item = PathBuf>) {
+        for path in paths {
+            if !self.roots.iter().any(|r| r.path == path) {
+                self.roots.push(Root::new(path));
+            }
+        }
+    }
+
+    /// Loads the errors saved by `save_scan_errors_to`, forgetting any for files that have since
+    /// been deleted.
+    fn load_scan_errors_from(&mut self, filename: &str) {
+        if let Ok(file) = File::open(filename) {
+            if let Ok(errors) = serde_json::from_reader::<_, Vec<ScanError>>(BufReader::new(file)) {
+                self.scan_errors = errors
+                    .into_iter()
+                    .filter(|e| e.path.exists())
+                    .map(|e| (e.path.clone(), e))
+                    .collect();
+            }
+        }
+    }
+
+    fn save_scan_errors_to(&self, filename: &str) -> Result<(), std::io::Error> {
+        let file = File::create(filename)?;
+        let errors = self.scan_errors.values().collect::<Vec<_>>();
+        serde_json::to_writer(BufWriter::new(file), &errors)?;
+        Ok(())
+    }
+
+    pub(crate) fn scan_error(&mut self, path: &Path, error: &std::io::Error) {
+        let error = ScanError {
+            path: path.to_path_buf(),
+            error: error.to_string(),
+            at: crate::history::now(),
+        };
+        self.scan_errors.insert(path.to_path_buf(), error);
+    }
+
+    /// Reads a song while scanning, noting in `scan_errors` whether it could be.
+    fn read_song(&mut self, path: &Path) -> Option<Song> {
+        match Song::new(path) {
+            Ok(song) => {
+                self.scan_errors.remove(path);
+                Some(song)
+            }
+            Err(e) => {
+                // Files that aren't music at all (eg cover art) are expected
+                if crate::metadata::readers().for_path(path).is_some() {
+                    self.scan_error(path, &e);
+                }
+                None
+            }
+        }
+    }
+
+    /// Finds a root by its name (see `Root::name`).
+    pub fn root(&self, name: &str) -> Option<&Root> {
+        self.roots.iter().find(|r| r.name() == name)
     }
 
     /// Scans `directory` for music.
@@ -47,26 +198,77 @@ impl MusicDB {
     /// A note on perf:
     /// On a moderate (~4000 file) input, avoiding the rescan drops load time from about 7m to 1m.
     ///
-    /// Keeping track of the known files as a HashSet instead of searching `self.records` further
-    /// drops the time from 1m to 30s.
+    /// Keeping track of the known files (as a map of path to id) instead of searching
+    /// `self.records` further drops the time from 1m to 30s.
     fn scan_directory(
         &mut self,
-        known_files: &mut HashSet<String>,
+        known_files: &mut HashMap<PathBuf, u64>,
+        visited: &mut HashSet<PathBuf>,
+        root: &Path,
         directory: &Path,
         rescan_files: bool,
+        options: &ScanOptions,
     ) -> Result<(), std::io::Error> {
+        // Every song of an album in a directory shares its cover art, so only work out its colors
+        // once per album
+        let mut covers: HashMap<(String, String, u16), Option<CoverColors>> = HashMap::new();
+
+        let entries = match std::fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(e) => {
+                self.scan_error(directory, &e);
+                return Ok(());
+            }
+        };
+
         // Recursively search a directory
-        for entry in std::fs::read_dir(directory)?.flatten() {
+        for entry in entries.flatten() {
             let path = entry.path();
+            if !options.follow_symlinks && entry.file_type().is_ok_and(|t| t.is_symlink()) {
+                continue;
+            }
+
+            // Symlinks can lead back to a directory already being scanned, or to a file already
+            // scanned through another path; anything that doesn't resolve is a broken link
+            let Ok(canonical) = paths::canonicalize(&path) else {
+                continue;
+            };
+
             if path.is_dir() {
-                self.scan_directory(known_files, &path, rescan_files)?;
-            } else if let Some(s) = path.to_str() {
-                if !rescan_files && known_files.contains(s) {
-                    //if !rescan_files && self.contains_file(s) {
-                    // no need to scan this file
-                } else if let Ok(s) = Song::new(s) {
-                    known_files.insert(s.path.clone());
-                    self.records.insert(s.id, s);
+                if options.filter.wants_directory(root, &path) && visited.insert(canonical) {
+                    self.scan_directory(known_files, visited, root, &path, rescan_files, options)?;
+                }
+            } else if !options.filter.wants_file(root, &path) || !visited.insert(canonical) {
+                continue;
+            } else {
+                let key = paths::key(&path);
+                if !rescan_files && known_files.contains_key(&key) {
+                    // no need to scan this file, but it may still need its duration fixed
+                    if options.accurate_durations {
+                        if let Some(song) = self.records.get_mut(&known_files[&key]) {
+                            song.fix_duration();
+                            self.mark_changed();
+                        }
+                    }
+                } else if let Some(mut s) = self.read_song(&path) {
+                    if options.accurate_durations {
+                        s.fix_duration();
+                    }
+                    s.root = root.to_path_buf();
+                    let album = s.album_key();
+                    s.cover = covers
+                        .entry((
+                            album.artist.to_string(),
+                            album.album.to_string(),
+                            album.year,
+                        ))
+                        .or_insert_with(|| {
+                            crate::art::find_cover(&path).and_then(|c| crate::art::cover_colors(&c))
+                        })
+                        .clone();
+
+                    let old_id = known_files.insert(key, s.id);
+                    self.add_scanned(old_id, s);
                 }
             }
         }
@@ -74,6 +276,139 @@ impl MusicDB {
         Ok(())
     }
 
+    /// The files in the library, as `paths::key`s, and their songs' ids.
+    pub fn known_files(&self) -> HashMap<PathBuf, u64> {
+        self.records
+            .values()
+            .map(|s| (paths::key(&s.path), s.id))
+            .collect()
+    }
+
+    /// Scans `roots` for files that aren't among `known_files`, giving a library of just the new
+    /// songs, for `merge`. It doesn't need the library itself, which can go on being used meanwhile.
+    pub fn scan_new(
+        roots: &[Root],
+        mut known_files: HashMap<PathBuf, u64>,
+        options: &ScanOptions,
+    ) -> MusicDB {
+        let mut found = MusicDB {
+            roots: roots.to_vec(),
+            genres: options.genres.clone(),
+            ..Default::default()
+        };
+        let mut visited = HashSet::new();
+
+        for root in roots {
+            if visited.insert(root.path.clone()) {
+                found
+                    .scan_directory(
+                        &mut known_files,
+                        &mut visited,
+                        &root.path,
+                        &root.path,
+                        false,
+                        options,
+                    )
+                    .ok();
+            }
+        }
+
+        found.apply_roots();
+        found
+    }
+
+    /// Adds the songs (and scan errors) found by `scan_new`; returns how many songs were added.
+    pub fn merge(&mut self, found: MusicDB) -> usize {
+        self.scan_errors.extend(found.scan_errors);
+
+        let mut added = 0;
+        for (id, song) in found.records {
+            self.scan_errors.remove(&song.path);
+            // It may have been added some other way during the scan
+            if !self.records.contains_key(&id) {
+                self.add_scanned(None, song);
+                added += 1;
+            }
+        }
+        added
+    }
+
+    /// Adds a song that's just been scanned, replacing `old_id` if it was already known.
+    pub(crate) fn add_scanned(&mut self, old_id: Option<u64>, mut song: Song) {
+        if let Some(old_id) = old_id {
+            // Rescanning doesn't change when the song was added, how it's labelled, or whether
+            // it's been hidden or marked explicit
+            if let Some(old) = self.records.get(&old_id) {
+                song.added = old.added;
+                song.labels = old.labels.clone();
+                song.hidden = old.hidden;
+                song.marked_explicit = old.marked_explicit;
+                // Nor what analysis found, unless the file itself changed
+                if old_id == song.id {
+                    song.analysis = old.analysis;
+                }
+            }
+
+            // A rescanned file may hash to a new id; drop the stale record
+            if old_id != song.id {
+                self.records.remove(&old_id);
+            }
+        }
+        song.genre = self.genres.canonical(&song.genre);
+        self.overrides.apply(&mut song);
+        self.records.insert(song.id, song);
+        self.mark_changed();
+    }
+
+    /// Loads the library, its roots, any scan errors saved by `save`, and its overrides,
+    /// without scanning.
+    pub fn load_saved() -> Result<Self, std::io::Error> {
+        let mut db = MusicDB::from_file(LIBRARY_FILE)?;
+        db.load_roots_from(ROOTS_FILE);
+        db.load_scan_errors_from(SCAN_ERRORS_FILE);
+        db.set_overrides(Overrides::load());
+        Ok(db)
+    }
+
+    /// Replaces the library with the one saved in the working directory, eg after it's been
+    /// restored from a backup.
+    pub fn reload(&mut self) -> Result<(), std::io::Error> {
+        let generation = self.generation;
+        let genres = std::mem::take(&mut self.genres);
+        *self = MusicDB::load_saved()?;
+        self.generation = generation;
+        self.set_genres(genres);
+        // A restored backup may have turned it on or off
+        self.kid_mode = KidMode::load().kid_mode;
+        self.mark_changed();
+        Ok(())
+    }
+
+    /// Sets how genres are tidied, and tidies those of the songs already in the library.
+    pub fn set_genres(&mut self, genres: Genres) {
+        for song in self.records.values_mut() {
+            song.genre = genres.canonical(&song.genre);
+        }
+        self.genres = genres;
+        self.mark_changed();
+    }
+
+    /// Sets the overrides, and applies them to the songs already in the library.
+    pub fn set_overrides(&mut self, overrides: Overrides) {
+        for song in self.records.values_mut() {
+            overrides.apply(song);
+        }
+        self.overrides = overrides;
+        self.mark_changed();
+    }
+
+    /// Saves the library, its roots, and any scan errors to the working directory.
+    pub fn save(&self) {
+        self.save_to(LIBRARY_FILE).ok();
+        roots::save(&self.roots, ROOTS_FILE).ok();
+        self.save_scan_errors_to(SCAN_ERRORS_FILE).ok();
+    }
+
     pub fn save_to(&self, filename: &str) -> Result<(), std::io::Error> {
         let file = File::create(filename)?;
         let mut buf = BufWriter::new(file);
@@ -87,59 +422,193 @@ impl MusicDB {
         Ok(())
     }
 
-    pub fn query(&self, search_terms: SearchTerms) -> SearchResults {
-        let SearchTerms {
-            artist,
-            album,
-            term,
-            limit,
-            sort_by,
-            after,
-        } = search_terms.clone();
-
-        let limit = limit.unwrap_or(SearchTerms::DEFAULT_LIMIT) as usize;
-        let artist = artist.unwrap_or_default().to_lowercase();
-        let album = album.unwrap_or_default().to_lowercase();
-        let term = term.unwrap_or_default().to_lowercase();
-        let sort_by = sort_by.unwrap_or(SortBy::track);
+    /// Finds the songs matching the filters in `search_terms`, in no particular order.
+    ///
+    /// Only the filtering fields (`artist`, `album`, `genre`, `label`, `mood`, `bpm_min`,
+    /// `bpm_max`, `key`, `harmonic`, `term`, `decade`, `root`, `section`, `include_hidden`, and the
+    /// classical fields) are considered; sorting, pagination, and limits are up to the caller.
+    /// In kid mode, explicit songs are left out.
+    pub fn matching<'a>(
+        &'a self,
+        search_terms: &SearchTerms,
+    ) -> Box<dyn Iterator<Item = &'a Song> + 'a> {
+        let artist = search_terms
+            .artist
+            .clone()
+            .unwrap_or_default()
+            .to_lowercase();
+        // Artists merged into another are found under either name
+        let artist = match self.overrides.merged_into(&artist) {
+            Some(into) => into.to_lowercase(),
+            None => artist,
+        };
+        let album = search_terms
+            .album
+            .clone()
+            .unwrap_or_default()
+            .to_lowercase();
+        let term = search_terms.term.clone().unwrap_or_default().to_lowercase();
 
         let mut results: Box<dyn Iterator<Item = _>> = Box::new(self.records.values());
 
+        if !search_terms.include_hidden.unwrap_or(false) {
+            results = Box::new(results.filter(|song| !song.hidden));
+        }
+
+        results = Box::new(results.filter(|song| self.kid_safe(song)));
+
         if !artist.is_empty() {
-            results = Box::new(results.filter(|song| song.artist_lower == artist));
+            results = Box::new(results.filter(move |song| song.artist_lower == artist));
         }
 
         if !album.is_empty() {
-            results = Box::new(results.filter(|song| song.album_lower == album));
+            results = Box::new(results.filter(move |song| song.album_lower == album));
+        }
+
+        let composer = search_terms
+            .composer
+            .clone()
+            .unwrap_or_default()
+            .to_lowercase();
+        if !composer.is_empty() {
+            results = Box::new(results.filter(move |song| song.composer_lower == composer));
+        }
+
+        let conductor = search_terms.conductor.clone().unwrap_or_default();
+        if !conductor.is_empty() {
+            results = Box::new(
+                results.filter(move |song| song.conductor.eq_ignore_ascii_case(&conductor)),
+            );
+        }
+
+        let work = search_terms.work.clone().unwrap_or_default().to_lowercase();
+        if !work.is_empty() {
+            results = Box::new(results.filter(move |song| song.work_lower == work));
+        }
+
+        let genre = search_terms.genre.clone().unwrap_or_default();
+        if !genre.trim().is_empty() {
+            let genres = &self.genres;
+            results = Box::new(results.filter(move |song| genres.is_under(&song.genre, &genre)));
+        }
+
+        let label = labels::normalize(search_terms.label.as_deref().unwrap_or_default());
+        if !label.is_empty() {
+            results = Box::new(results.filter(move |song| song.labels.contains(&label)));
+        }
+
+        if let Some(mood) = search_terms.mood {
+            results =
+                Box::new(results.filter(move |song| song.analysis.is_some_and(|a| a.mood == mood)));
+        }
+
+        if search_terms.bpm_min.is_some() || search_terms.bpm_max.is_some() {
+            let min = search_terms.bpm_min.unwrap_or(0.0);
+            let max = search_terms.bpm_max.unwrap_or(f32::MAX);
+            results = Box::new(results.filter(move |song| {
+                song.analysis
+                    .and_then(|a| a.bpm)
+                    .is_some_and(|bpm| (min..=max).contains(&bpm))
+            }));
+        }
+
+        if let Some(key) = search_terms.key {
+            results = Box::new(results.filter(move |song| song.key() == Some(key)));
+        }
+
+        if let Some(key) = search_terms.harmonic {
+            results =
+                Box::new(results.filter(move |song| song.key().is_some_and(|k| k.mixes_with(key))));
+        }
+
+        if let Some(name) = &search_terms.root {
+            // An unknown root matches nothing, rather than being ignored
+            let root = self.root(name).map(|r| r.path.clone());
+            results = Box::new(results.filter(move |song| root.as_ref() == Some(&song.root)));
+        }
+
+        // Sections are kept apart, but a root's songs are all in its section anyway
+        if search_terms.section.is_some() || search_terms.root.is_none() {
+            let section = search_terms.section.unwrap_or_default();
+            results = Box::new(results.filter(move |song| song.section == section));
+        }
+
+        if let Some(decade) = search_terms.decade {
+            let decade = decade - decade % 10;
+            results = Box::new(results.filter(move |song| song.year / 10 * 10 == decade));
         }
 
         if !term.is_empty() {
-            results = Box::new(results.filter(|song| {
+            results = Box::new(results.filter(move |song| {
                 song.title_lower.contains(&term[..])
                     || song.artist_lower.contains(&term[..])
                     || song.album_lower.contains(&term[..])
                     || song.stem_lower.contains(&term[..])
+                    || song.composer_lower.contains(&term[..])
+                    || song.work_lower.contains(&term[..])
             }));
         }
 
+        results
+    }
+
+    /// Searches the library, returning one sorted page of results.
+    pub fn query(&self, search_terms: SearchTerms) -> SearchResults {
+        let SearchTerms {
+            artist,
+            album,
+            limit,
+            sort_by,
+            after,
+            ..
+        } = search_terms.clone();
+
+        let limit = limit.unwrap_or(SearchTerms::DEFAULT_LIMIT) as usize;
+        let artist = artist.unwrap_or_default().to_lowercase();
+        let album = album.unwrap_or_default().to_lowercase();
+        let section = search_terms
+            .section
+            .or_else(|| self.root(search_terms.root.as_deref()?).map(|r| r.section))
+            .unwrap_or_default();
+        let term = search_terms.term.clone().unwrap_or_default().to_lowercase();
+        // Searching by term, the best matches come first unless asked otherwise
+        let by_relevance = !term.is_empty() && sort_by.is_none();
+        let sort_by = sort_by.unwrap_or(section.default_sort());
+        let score = |song: &Song| relevance::score(song, &term);
+        let order = |(a_score, a): &(u32, &Song), (b_score, b): &(u32, &Song)| {
+            let by_score = if by_relevance {
+                b_score.cmp(a_score)
+            } else {
+                std::cmp::Ordering::Equal
+            };
+            by_score.then_with(|| a.cmp(b, sort_by))
+        };
+
+        let mut results = self
+            .matching(&search_terms)
+            .map(|song| (score(song), song))
+            .collect::<Vec<_>>();
+
         // Sorting results: First, _everything_ is sorted. By default, it'll be by title.
         // If there's an `after` (ie, we've paginated to next), we will know how to filter before sorting
         if let Some(after) = after {
             if let Some(after) = self.records.get(&after) {
                 // Keep only those records that are > `after`, depending on the filtering scheme
-                results = Box::new(
-                    results.filter(|song| song.cmp(after, sort_by) == std::cmp::Ordering::Greater),
-                );
+                let after = (score(after), after);
+                results.retain(|song| order(song, &after) == std::cmp::Ordering::Greater);
             }
         }
 
         // After filtering, we can sort and take the first n:
-        let mut results = results.collect::<Vec<_>>();
-        results.sort_unstable_by(|&a, &b| a.cmp(b, sort_by));
+        results.sort_unstable_by(order);
+        let has_more = results.len() > limit;
         let results = results
             .into_iter()
             .take(limit)
-            .map(|s| s.into())
+            .map(|(score, song)| SongResult {
+                score: (!term.is_empty()).then_some(score),
+                ..song.into()
+            })
             .collect::<Vec<_>>();
 
         let other_albums = if !artist.is_empty() {
@@ -153,21 +622,23 @@ impl MusicDB {
                     .collect(),
             )
         } else if !album.is_empty() {
-            // Find all artists associated with this album name
+            // Find every album of this name, and their artists; same-named albums by different
+            // artists are different albums
             let album_lower = album.to_lowercase();
-            let artists = self
+            let albums = self
                 .records
                 .values()
                 .filter(|&s| s.album_lower == album_lower)
-                .map(|s| s.artist.to_lowercase())
+                .map(|s| s.album_key())
                 .collect::<HashSet<_>>();
+            let artists = albums.iter().map(|a| a.artist).collect::<HashSet<_>>();
 
-            // Then all albums for these artists except the one specified
+            // Then all the other albums by these artists
             Some(
                 self.records
                     .values()
-                    .filter(|&s| *s.album_lower != album_lower)
-                    .filter(|&s| artists.contains(&s.artist_lower))
+                    .filter(|&s| artists.contains(s.album_key().artist))
+                    .filter(|&s| !albums.contains(&s.album_key()))
                     .map(|s| s.album.clone())
                     .collect(),
             )
@@ -176,7 +647,7 @@ impl MusicDB {
         };
 
         SearchResults {
-            has_more: results.len() > limit,
+            has_more,
             search_terms,
             results,
             other_albums,
@@ -184,13 +655,195 @@ impl MusicDB {
     }
 }
 
+impl MusicDB {
+    /// Collects everything by `name`, grouped into albums. Albums are ordered by year (then name)
+    /// and each album's tracks are in track order.
+    pub fn artist(&self, name: &str) -> Option<ArtistDetails> {
+        let name_lower = name.to_lowercase();
+
+        let mut albums: HashMap<AlbumKey, Vec<&Song>> = HashMap::new();
+        for song in self.records.values() {
+            if song.artist_lower == name_lower {
+                albums.entry(song.album_key()).or_default().push(song);
+            }
+        }
+
+        let name = albums.values().flatten().next()?.artist.clone();
+
+        let mut albums = albums
+            .into_values()
+            .map(|mut songs| {
+                songs.sort_unstable_by(|&a, &b| a.cmp(b, SortBy::track));
+                AlbumSummary {
+                    album: songs[0].album.clone(),
+                    year: songs.iter().map(|s| s.year).max().unwrap_or_default(),
+                    duration: format_duration(songs.iter().map(|s| s.duration).sum()),
+                    tracks: songs.into_iter().map(|s| s.into()).collect(),
+                }
+            })
+            .collect::<Vec<_>>();
+        albums.sort_unstable_by(|a, b| {
+            a.year
+                .cmp(&b.year)
+                .then_with(|| a.album.to_lowercase().cmp(&b.album.to_lowercase()))
+        });
+
+        Some(ArtistDetails { name, albums })
+    }
+
+    /// Lists every artist, in sort-key order (so "The Beatles" files under B).
+    pub fn artists(&self) -> Vec<ArtistSummary> {
+        let mut artists: HashMap<&str, (&Song, HashSet<AlbumKey>, usize)> = HashMap::new();
+        for song in self
+            .records
+            .values()
+            .filter(|s| !s.artist.is_empty() && s.section == Section::Music)
+        {
+            let (_, albums, tracks) =
+                artists
+                    .entry(&song.artist_lower)
+                    .or_insert((song, HashSet::new(), 0));
+            albums.insert(song.album_key());
+            *tracks += 1;
+        }
+
+        let mut artists = artists
+            .into_values()
+            .map(|(song, albums, tracks)| ArtistSummary {
+                name: song.artist.clone(),
+                sort_name: song.sort_artist.clone(),
+                albums: albums.len(),
+                tracks,
+            })
+            .collect::<Vec<_>>();
+        artists.sort_unstable_by(|a, b| {
+            a.sort_name
+                .cmp(&b.sort_name)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+
+        artists
+    }
+
+    /// Finds the tracks of `album` by `artist`, in disc and track order. Given a year, only the
+    /// album of that year, eg rather than its re-release; otherwise, the newest, with the years of
+    /// the others in `other_years`.
+    ///
+    /// `artist` may be the album's artist or any of its tracks' artists; either way, it's every
+    /// track of the album, so all of a compilation rather than just one artist's tracks on it.
+    pub fn album(&self, artist: &str, album: &str, year: Option<u16>) -> Option<AlbumDetails> {
+        let artist_lower = artist.to_lowercase();
+        let album_lower = album.to_lowercase();
+
+        let mut albums = self
+            .records
+            .values()
+            .filter(|s| s.album_lower == album_lower)
+            .filter(|s| year.is_none_or(|year| s.year == year))
+            .filter(|s| s.artist_lower == artist_lower || s.album_key().artist == artist_lower)
+            .map(|s| s.album_key())
+            .collect::<HashSet<_>>();
+
+        // Albums of different years are different albums, so they're never merged
+        let newest = albums.iter().map(|a| a.year).max()?;
+        let mut other_years = albums
+            .iter()
+            .map(|a| a.year)
+            .filter(|&year| year != newest)
+            .collect::<Vec<_>>();
+        other_years.sort_unstable_by(|a, b| b.cmp(a));
+        other_years.dedup();
+        albums.retain(|a| a.year == newest);
+
+        let mut songs = self
+            .records
+            .values()
+            .filter(|s| albums.contains(&s.album_key()))
+            .collect::<Vec<_>>();
+        songs.sort_unstable_by(|&a, &b| a.cmp(b, SortBy::track));
+
+        let first = songs.first()?;
+        let art = crate::art::find_cover(&first.path).map(|_| format!("/art?id={}", first.id));
+
+        let missing_tracks = missing_tracks(&songs);
+
+        Some(AlbumDetails {
+            artist: if first.album_artist.is_empty() {
+                first.artist.clone()
+            } else {
+                first.album_artist.clone()
+            },
+            album: first.album.clone(),
+            year: newest,
+            duration: format_duration(songs.iter().map(|s| s.duration).sum()),
+            art,
+            cover: songs.iter().find_map(|s| s.cover.clone()),
+            missing_tracks,
+            tracks: songs.into_iter().map(|s| s.into()).collect(),
+            other_years,
+        })
+    }
+}
+
+impl MusicDB {
+    /// Counts songs per year and per decade. Songs without a year are counted separately.
+    pub fn years(&self) -> YearCounts {
+        let mut years = BTreeMap::new();
+        let mut decades = BTreeMap::new();
+        let mut unknown = 0;
+
+        for song in self.records.values() {
+            if song.year == 0 {
+                unknown += 1;
+            } else {
+                *years.entry(song.year).or_insert(0) += 1;
+                *decades.entry(song.year / 10 * 10).or_insert(0) += 1;
+            }
+        }
+
+        YearCounts {
+            years: years
+                .into_iter()
+                .map(|(year, count)| YearCount { year, count })
+                .collect(),
+            decades: decades
+                .into_iter()
+                .map(|(year, count)| YearCount { year, count })
+                .collect(),
+            unknown,
+        }
+    }
+}
+
 impl std::ops::Add for MusicDB {
     type Output = MusicDB;
 
     fn add(self, rhs: Self) -> Self::Output {
-        let MusicDB { mut records } = self;
+        let MusicDB {
+            mut records,
+            mut roots,
+            mut scan_errors,
+            generation,
+            genres,
+            kid_mode,
+            overrides,
+        } = self;
         records.extend(rhs.records);
-        MusicDB { records }
+        scan_errors.extend(rhs.scan_errors);
+        for root in rhs.roots {
+            if !roots.iter().any(|r| r.path == root.path) {
+                roots.push(root);
+            }
+        }
+        MusicDB {
+            records,
+            roots,
+            scan_errors,
+            generation: generation.max(rhs.generation) + 1,
+            genres,
+            kid_mode: kid_mode || rhs.kid_mode,
+            overrides,
+        }
     }
 }
 
@@ -202,37 +855,211 @@ pub enum SortBy {
     album,
     duration,
     track,
+    composer,
+    /// Round the Camelot wheel, as analyzed
+    key,
+    /// Newest first
+    added,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// What to search for, and how to sort and page the results. Every field is optional; the
+/// default matches the whole library.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct SearchTerms {
+    /// Matches the artist exactly (ignoring case)
     pub artist: Option<String>,
+    /// Matches the album exactly (ignoring case)
     pub album: Option<String>,
+    /// Matches the genre, or any genre under it (see `genres`)
+    pub genre: Option<String>,
+    /// Matches songs with this label (see `labels`)
+    pub label: Option<String>,
+    /// Matches songs analyzed as having this mood (see `analysis`)
+    pub mood: Option<Mood>,
+    /// Restricts results to songs analyzed as having at least this tempo, in beats per minute
+    pub bpm_min: Option<f32>,
+    /// Restricts results to songs analyzed as having at most this tempo, in beats per minute
+    pub bpm_max: Option<f32>,
+    /// Matches songs analyzed as being in this key, in Camelot notation, eg `8A`
+    pub key: Option<Key>,
+    /// Matches songs analyzed as being in a key that mixes harmonically with this one (see
+    /// `camelot`)
+    pub harmonic: Option<Key>,
+    /// Matches anywhere in the title, artist, album, file name, composer, or work
     pub term: Option<String>,
 
+    /// The most results to return; 100 by default
     pub limit: Option<u16>,
+    /// By track by default, or when searching by `term`, by relevance (see `relevance`)
     pub sort_by: Option<SortBy>,
+    /// Continues from the song with this id, as given by the last result of the previous page
     pub after: Option<u64>,
+
+    /// Restricts results to a decade, eg `1980` for 1980-1989
+    pub decade: Option<u16>,
+    /// Restricts results to the songs from one root, by its name
+    pub root: Option<String>,
+    /// Music by default, or all of a root's songs when searching by `root`
+    pub section: Option<Section>,
+
+    pub composer: Option<String>,
+    pub conductor: Option<String>,
+    pub work: Option<String>,
+
+    /// Includes songs the user has hidden, which are otherwise left out
+    pub include_hidden: Option<bool>,
 }
 
+/// The results of `MusicDB::query`.
 #[derive(Serialize)]
 pub struct SearchResults {
-    has_more: bool,
-    search_terms: SearchTerms,
-    results: Vec<SongResult>,
+    /// Whether more results exist past `limit`
+    pub has_more: bool,
+    pub search_terms: SearchTerms,
+    pub results: Vec<SongResult>,
+
+    /// When searching by artist, their albums; when searching by album, other albums by the same
+    /// artists
+    pub other_albums: Option<HashSet<String>>,
+}
+
+/// One entry of the `/artists` index.
+#[derive(Serialize)]
+pub struct ArtistSummary {
+    pub name: String,
+    pub sort_name: String,
+    pub albums: usize,
+    pub tracks: usize,
+}
+
+/// An artist's discography, as returned by `/artist`.
+#[derive(Serialize)]
+pub struct ArtistDetails {
+    pub name: String,
+    pub albums: Vec<AlbumSummary>,
+}
+
+#[derive(Serialize)]
+pub struct AlbumSummary {
+    pub album: String,
+    pub year: u16,
+    pub duration: String,
+    pub tracks: Vec<SongResult>,
+}
+
+/// A single album, as returned by `/album`.
+#[derive(Serialize)]
+pub struct AlbumDetails {
+    pub artist: String,
+    pub album: String,
+    pub year: u16,
+    pub duration: String,
+    /// URL of the album art, if any was found
+    pub art: Option<String>,
+    /// Colors to show while the art loads
+    pub cover: Option<CoverColors>,
+    /// Track numbers that the tags' track totals say should exist, but don't
+    pub missing_tracks: Vec<MissingTrack>,
+    pub tracks: Vec<SongResult>,
+    /// The years of other albums by the same name and artist, eg the original of a re-release,
+    /// newest first; each is found by giving its year
+    pub other_years: Vec<u16>,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct MissingTrack {
+    pub disc: Option<u16>,
+    pub track: u16,
+}
+
+/// Finds gaps in an album's track numbering, disc by disc, using the largest track total tagged on
+/// each disc. Discs without a track total can't be checked.
+pub fn missing_tracks(songs: &[&Song]) -> Vec<MissingTrack> {
+    let mut discs: BTreeMap<Option<u16>, (u16, HashSet<u16>)> = BTreeMap::new();
+    for song in songs {
+        let (total, present) = discs.entry(song.disc).or_default();
+        *total = (*total).max(song.track_total.unwrap_or_default());
+        if let Some(track) = song.track {
+            present.insert(track);
+        }
+    }
 
-    other_albums: Option<HashSet<String>>,
+    discs
+        .into_iter()
+        .flat_map(|(disc, (total, present))| {
+            (1..=total)
+                .filter(move |t| !present.contains(t))
+                .map(move |track| MissingTrack { disc, track })
+        })
+        .collect()
+}
+
+/// Song counts by year, as returned by `/years`.
+#[derive(Serialize)]
+pub struct YearCounts {
+    pub years: Vec<YearCount>,
+    /// Counts per decade; `year` is the first year of the decade
+    pub decades: Vec<YearCount>,
+    /// Songs with no year in their metadata
+    pub unknown: usize,
+}
+
+#[derive(Serialize)]
+pub struct YearCount {
+    pub year: u16,
+    pub count: usize,
 }
 
 impl SearchTerms {
     const DEFAULT_LIMIT: u16 = 100;
 }
 
-pub(crate) fn load_db(directories: Vec<(PathBuf, bool)>) -> Option<MusicDB> {
+/// A file or directory that couldn't be read while scanning, eg a truncated download.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScanError {
+    #[serde(with = "crate::paths::serde_path")]
+    pub path: PathBuf,
+    pub error: String,
+    /// When it was last tried, in seconds since the Unix epoch
+    pub at: u64,
+}
+
+/// Settings that apply to every directory scanned in a run.
+#[derive(Debug, Default, Clone)]
+pub struct ScanOptions {
+    /// Compute MP3 durations from their VBR headers or by walking every frame, rather than
+    /// trusting `mp3_metadata`. Slower, but VBR files otherwise come out several percent off.
+    pub accurate_durations: bool,
+    /// Which files and directories to look at; by default, everything but hidden ones
+    pub filter: ScanFilter,
+    /// Follow symlinked files and directories, rather than skipping them
+    pub follow_symlinks: bool,
+    /// How to tidy the songs' genres
+    pub genres: Genres,
+}
+
+/// Loads the library from `library.json` in the working directory, then scans each of
+/// `directories` (rescanning files already known if its flag is set), along with any saved roots
+/// set to be scanned at startup, and saves the result.
+///
+/// With nothing to scan, just loads the library; returns `None` if there's nothing to load.
+pub fn load_db(mut directories: Vec<(PathBuf, bool)>, options: ScanOptions) -> Option<MusicDB> {
+    for root in roots::load(ROOTS_FILE) {
+        let rescan = match root.rescan {
+            RescanPolicy::Manual => continue,
+            RescanPolicy::Startup => false,
+            RescanPolicy::Full => true,
+        };
+        if !directories.iter().any(|(d, _)| *d == root.path) {
+            directories.push((root.path, rescan));
+        }
+    }
+
     if directories.is_empty() {
         // Nothing to scan - just load the library file if possible.
         let start = std::time::Instant::now();
-        if let Ok(db) = MusicDB::from_file(LIBRARY_FILE) {
+        if let Ok(mut db) = MusicDB::load_saved() {
+            db.set_genres(options.genres);
             println!(
                 "Loaded {} files from {LIBRARY_FILE} in {:.2?}",
                 db.records.len(),
@@ -241,29 +1068,389 @@ pub(crate) fn load_db(directories: Vec<(PathBuf, bool)>) -> Option<MusicDB> {
 
             Some(db)
         } else {
-            eprintln!(
-                "No directories were specified for scanning, and {LIBRARY_FILE} wasn't present."
-            );
-            eprintln!("Start this server with --scan=path/to/directory or --rescan=path/to/directory to scan for music.");
             None
         }
     } else {
         println!("Scanning for MP3s...");
         let start = std::time::Instant::now();
         let mut db = MusicDB::new(LIBRARY_FILE);
+        db.set_genres(options.genres.clone());
+        db.set_overrides(Overrides::load());
+        db.load_roots_from(ROOTS_FILE);
+        db.load_scan_errors_from(SCAN_ERRORS_FILE);
+        db.add_roots(directories.iter().map(|(d, _)| d.clone()));
 
-        let mut known_files = db.records.values().map(|s| s.path.to_string()).collect();
+        let mut known_files = db.known_files();
+        // Canonical paths of every directory and file scanned so far, so nothing is scanned twice
+        let mut visited = HashSet::new();
 
         for (directory, rescan_files) in directories {
-            db.scan_directory(&mut known_files, &directory, rescan_files)
-                .ok();
+            if !visited.insert(directory.clone()) {
+                continue;
+            }
+
+            db.scan_directory(
+                &mut known_files,
+                &mut visited,
+                &directory,
+                &directory,
+                rescan_files,
+                &options,
+            )
+            .ok();
         }
 
+        db.apply_roots();
+
         let elapsed = start.elapsed();
         println!("Scanned {} files in {:.2?}", db.records.len(), elapsed);
+        if !db.scan_errors.is_empty() {
+            println!(
+                "{} files couldn't be read; see /admin/scan-errors",
+                db.scan_errors.len()
+            );
+        }
 
-        db.save_to(LIBRARY_FILE).ok();
+        db.save();
 
         Some(db)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(id: u64, artist: &str, album: &str, track: u16, title: &str, year: u16) -> Song {
+        let mut song = Song {
+            id,
+            path: format!("/music/{artist}/{album}/{track:02} {title}.mp3").into(),
+            title: title.to_string(),
+            artist: artist.to_string(),
+            album: album.to_string(),
+            year,
+            track: Some(track),
+            title_lower: title.to_lowercase(),
+            artist_lower: artist.to_lowercase(),
+            album_lower: album.to_lowercase(),
+            stem_lower: format!("{track:02} {title}"),
+            ..Default::default()
+        };
+        song.update_sort_keys();
+        song
+    }
+
+    fn library() -> MusicDB {
+        let songs = vec![
+            song(1, "The Beatles", "Abbey Road", 1, "Come Together", 1969),
+            song(2, "The Beatles", "Abbey Road", 2, "Something", 1969),
+            song(3, "The Beatles", "Help!", 1, "Help!", 1965),
+            song(4, "ABBA", "Arrival", 2, "Dancing Queen", 1976),
+            song(
+                5,
+                "Foo Fighters",
+                "The Colour and the Shape",
+                11,
+                "Everlong",
+                1997,
+            ),
+            song(
+                6,
+                "Foo Fighters",
+                "The Colour and the Shape",
+                2,
+                "Track 2",
+                1997,
+            ),
+            song(
+                7,
+                "Foo Fighters",
+                "The Colour and the Shape",
+                10,
+                "Track 10",
+                1997,
+            ),
+        ];
+
+        MusicDB {
+            records: songs.into_iter().map(|s| (s.id, s)).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn ids(results: &SearchResults) -> Vec<String> {
+        results.results.iter().map(|s| s.id.clone()).collect()
+    }
+
+    #[test]
+    fn query_everything() {
+        let results = library().query(SearchTerms::default());
+
+        assert_eq!(results.results.len(), 7);
+        assert!(!results.has_more);
+        assert!(results.other_albums.is_none());
+    }
+
+    #[test]
+    fn query_by_artist_ignores_case() {
+        let terms = SearchTerms {
+            artist: Some("the beatles".to_string()),
+            sort_by: Some(SortBy::title),
+            ..Default::default()
+        };
+        let results = library().query(terms);
+
+        assert_eq!(ids(&results), ["1", "3", "2"]);
+        let albums = results.other_albums.unwrap();
+        assert_eq!(albums.len(), 2);
+        assert!(albums.contains("Abbey Road") && albums.contains("Help!"));
+    }
+
+    #[test]
+    fn query_by_album_lists_other_albums() {
+        let terms = SearchTerms {
+            album: Some("Abbey Road".to_string()),
+            ..Default::default()
+        };
+        let results = library().query(terms);
+
+        assert_eq!(ids(&results), ["1", "2"]);
+        assert_eq!(
+            results
+                .other_albums
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            ["Help!"]
+        );
+    }
+
+    #[test]
+    fn same_named_albums_are_kept_apart() {
+        let mut db = library();
+        for song in [
+            song(8, "ABBA", "Greatest Hits", 1, "SOS", 1975),
+            song(9, "ABBA", "Greatest Hits", 2, "Waterloo", 1975),
+            song(10, "The Beatles", "Greatest Hits", 1, "Yesterday", 1982),
+            // A re-release
+            song(11, "ABBA", "Greatest Hits", 1, "SOS", 1992),
+        ] {
+            db.records.insert(song.id, song);
+        }
+
+        let terms = SearchTerms {
+            album: Some("greatest hits".to_string()),
+            sort_by: Some(SortBy::album),
+            ..Default::default()
+        };
+        let results = db.query(terms);
+        // One album's tracks, then the other's, rather than both albums' first tracks first
+        assert_eq!(ids(&results), ["8", "9", "11", "10"]);
+        let mut others = results
+            .other_albums
+            .unwrap()
+            .into_iter()
+            .collect::<Vec<_>>();
+        others.sort();
+        assert_eq!(others, ["Abbey Road", "Arrival", "Help!"]);
+
+        let abba = db.artists().into_iter().find(|a| a.name == "ABBA").unwrap();
+        assert_eq!(abba.albums, 3);
+        assert_eq!(
+            db.album("abba", "greatest hits", Some(1992))
+                .unwrap()
+                .tracks
+                .len(),
+            1
+        );
+
+        // Without a year, the newest, rather than both merged
+        let newest = db.album("abba", "greatest hits", None).unwrap();
+        assert_eq!(newest.year, 1992);
+        assert_eq!(newest.tracks.len(), 1);
+        assert_eq!(newest.other_years, [1975]);
+        let original = db.album("abba", "greatest hits", Some(1975)).unwrap();
+        assert_eq!(original.tracks.len(), 2);
+        assert!(original.other_years.is_empty());
+    }
+
+    #[test]
+    fn compilations_are_one_album() {
+        let mut db = library();
+        for (id, artist, track, title) in [
+            (20, "Blondie", 1, "Call Me"),
+            (21, "Kenny Loggins", 2, "Footloose"),
+            (22, "Berlin", 3, "Take My Breath Away"),
+        ] {
+            let mut song = song(id, artist, "80s Hits", track, title, 1999);
+            song.album_artist = "Various Artists".to_string();
+            song.album_artist_lower = "various artists".to_string();
+            db.records.insert(id, song);
+        }
+
+        // By the album's artist, or any one track's
+        for artist in ["Various Artists", "Kenny Loggins"] {
+            let album = db.album(artist, "80s hits", None).unwrap();
+            assert_eq!(album.artist, "Various Artists");
+            assert_eq!(album.tracks.len(), 3);
+        }
+    }
+
+    #[test]
+    fn query_by_term() {
+        let terms = SearchTerms {
+            term: Some("EVER".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(ids(&library().query(terms)), ["5"]);
+
+        // Matches the album, too
+        let terms = SearchTerms {
+            term: Some("abbey".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(library().query(terms).results.len(), 2);
+
+        let terms = SearchTerms {
+            term: Some("nothing matches this".to_string()),
+            ..Default::default()
+        };
+        assert!(library().query(terms).results.is_empty());
+    }
+
+    #[test]
+    fn query_by_term_ranks_by_relevance() {
+        let terms = SearchTerms {
+            term: Some("the".to_string()),
+            ..Default::default()
+        };
+        let results = library().query(terms);
+        // The Beatles' songs, led by "Come Together", then "The Colour and the Shape" by track
+        assert_eq!(ids(&results), ["1", "3", "2", "6", "7", "5"]);
+        assert!(results.results[0].score > results.results[1].score);
+
+        // Unless asked to sort otherwise
+        let terms = SearchTerms {
+            term: Some("the".to_string()),
+            sort_by: Some(SortBy::title),
+            ..Default::default()
+        };
+        assert_eq!(ids(&library().query(terms)), ["1", "5", "3", "2", "6", "7"]);
+    }
+
+    #[test]
+    fn query_by_decade() {
+        let terms = SearchTerms {
+            decade: Some(1965),
+            sort_by: Some(SortBy::title),
+            ..Default::default()
+        };
+        assert_eq!(ids(&library().query(terms)), ["1", "3", "2"]);
+    }
+
+    #[test]
+    fn query_sorts_by_artist_without_articles() {
+        let terms = SearchTerms {
+            sort_by: Some(SortBy::artist),
+            ..Default::default()
+        };
+        let results = library().query(terms);
+        let artists = results
+            .results
+            .iter()
+            .map(|s| s.artist.as_str())
+            .collect::<Vec<_>>();
+
+        // "The Beatles" files under B
+        assert_eq!(
+            artists,
+            [
+                "ABBA",
+                "The Beatles",
+                "The Beatles",
+                "The Beatles",
+                "Foo Fighters",
+                "Foo Fighters",
+                "Foo Fighters"
+            ]
+        );
+    }
+
+    #[test]
+    fn query_sorts_titles_naturally() {
+        let terms = SearchTerms {
+            term: Some("track".to_string()),
+            sort_by: Some(SortBy::title),
+            ..Default::default()
+        };
+        assert_eq!(ids(&library().query(terms)), ["6", "7"]);
+    }
+
+    #[test]
+    fn query_pages_with_limit_and_after() {
+        let db = library();
+        let terms = SearchTerms {
+            artist: Some("Foo Fighters".to_string()),
+            limit: Some(2),
+            ..Default::default()
+        };
+        let first = db.query(terms.clone());
+        assert_eq!(ids(&first), ["6", "7"]);
+        assert!(first.has_more);
+
+        let terms = SearchTerms {
+            after: Some(7),
+            ..terms
+        };
+        let second = db.query(terms);
+        assert_eq!(ids(&second), ["5"]);
+        assert!(!second.has_more);
+    }
+
+    #[test]
+    fn query_leaves_out_hidden_songs() {
+        let mut db = library();
+        db.records.get_mut(&3).unwrap().hidden = true;
+        let terms = SearchTerms {
+            artist: Some("The Beatles".to_string()),
+            sort_by: Some(SortBy::title),
+            ..Default::default()
+        };
+        assert_eq!(ids(&db.query(terms.clone())), ["1", "2"]);
+
+        let terms = SearchTerms {
+            include_hidden: Some(true),
+            ..terms
+        };
+        assert_eq!(ids(&db.query(terms)), ["1", "3", "2"]);
+    }
+
+    #[test]
+    fn keeps_scan_errors_for_files_still_there() {
+        let directory =
+            std::env::temp_dir().join(format!("bwaabwaa-scan-errors-{:x}", rand::random::<u64>()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let broken = directory.join("broken.mp3");
+        std::fs::write(&broken, b"not an mp3").unwrap();
+        std::fs::write(directory.join("cover.jpg"), b"not music").unwrap();
+
+        let root = Root::new(paths::canonicalize(&directory).unwrap());
+        let scanned = MusicDB::scan_new(&[root], HashMap::new(), &ScanOptions::default());
+        let errors = scanned.scan_errors.keys().collect::<Vec<_>>();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        assert!(errors[0].ends_with("broken.mp3"));
+
+        let saved = directory.join("scan_errors.json");
+        let saved = saved.to_str().unwrap();
+        scanned.save_scan_errors_to(saved).unwrap();
+        let mut loaded = MusicDB::default();
+        loaded.load_scan_errors_from(saved);
+        assert_eq!(loaded.scan_errors.len(), 1);
+
+        // Once it's gone, there's nothing to fix
+        std::fs::remove_file(&broken).unwrap();
+        loaded.load_scan_errors_from(saved);
+        assert!(loaded.scan_errors.is_empty());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,130 @@
+//! Looking albums up on MusicBrainz, for how many tracks they ought to have when their tags don't
+//! say. MusicBrainz asks for no more than one request a second, so lookups are spaced out, and
+//! each album's answer is remembered until the server restarts.
+
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+const API: &str = "https://musicbrainz.org/ws/2/release";
+
+/// MusicBrainz turns away requests without a meaningful user agent
+const USER_AGENT: &str = concat!(
+    "bwaabwaa/",
+    env!("CARGO_PKG_VERSION"),
+    " ( https://github.com/aeshirey/bwaa-bwaa )"
+);
+
+/// The least time between requests
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait on MusicBrainz before giving up
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How sure MusicBrainz must be that a release is the album, out of 100
+const MIN_SCORE: u8 = 90;
+
+#[derive(Deserialize)]
+struct Releases {
+    releases: Vec<Release>,
+}
+
+#[derive(Deserialize)]
+struct Release {
+    score: u8,
+    #[serde(default)]
+    media: Vec<Medium>,
+}
+
+#[derive(Deserialize)]
+struct Medium {
+    #[serde(rename = "track-count")]
+    track_count: u16,
+}
+
+/// Each album's track count per disc, by lowercase artist and album; `None` if it wasn't found
+type Known = HashMap<(String, String), Option<Vec<u16>>>;
+
+/// A MusicBrainz client. Cheap to clone.
+#[derive(Clone)]
+pub struct MusicBrainz {
+    client: reqwest::Client,
+    known: Arc<Mutex<Known>>,
+    /// When the last request was sent
+    last: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Default for MusicBrainz {
+    fn default() -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .user_agent(USER_AGENT)
+            .build()
+            .unwrap_or_default();
+        MusicBrainz {
+            client,
+            known: Arc::default(),
+            last: Arc::default(),
+        }
+    }
+}
+
+/// Quotes a phrase for a Lucene query.
+fn quote(phrase: &str) -> String {
+    format!("\"{}\"", phrase.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl MusicBrainz {
+    /// How many tracks are on each disc of `album`, if it's been looked up already.
+    pub async fn known(&self, artist: &str, album: &str) -> Option<Option<Vec<u16>>> {
+        let key = (artist.to_lowercase(), album.to_lowercase());
+        self.known.lock().await.get(&key).cloned()
+    }
+
+    /// Looks up how many tracks are on each disc of `album`. Returns `None` if MusicBrainz doesn't
+    /// know it well enough to say.
+    pub async fn track_counts(
+        &self,
+        artist: &str,
+        album: &str,
+    ) -> Result<Option<Vec<u16>>, String> {
+        if let Some(known) = self.known(artist, album).await {
+            return Ok(known);
+        }
+
+        {
+            let mut last = self.last.lock().await;
+            if let Some(wait) = last.map(|at| INTERVAL.saturating_sub(at.elapsed())) {
+                tokio::time::sleep(wait).await;
+            }
+            *last = Some(Instant::now());
+        }
+
+        let query = format!("release:{} AND artist:{}", quote(album), quote(artist));
+        let releases: Releases = self
+            .client
+            .get(API)
+            .query(&[("query", query.as_str()), ("fmt", "json"), ("limit", "1")])
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let counts = releases
+            .releases
+            .into_iter()
+            .find(|r| r.score >= MIN_SCORE)
+            .map(|r| r.media.iter().map(|m| m.track_count).collect::<Vec<_>>())
+            .filter(|counts| !counts.is_empty());
+        let key = (artist.to_lowercase(), album.to_lowercase());
+        self.known.lock().await.insert(key, counts.clone());
+        Ok(counts)
+    }
+}
@@ -0,0 +1,238 @@
+//! What's playing in the web UI, as its player reports it every so often (`POST /now-playing`),
+//! for Discord. `GET /now-playing` gives it to a rich presence helper to poll; and with
+//! `--discord-webhook=<url>`, each song that starts playing is posted to a Discord channel, as a
+//! subscriber to the event bus.
+//!
+//! Songs get skipped, so posts are debounced: none goes out sooner than `MIN_INTERVAL` after the
+//! last. A song that starts before then waits, and is posted then if it's still playing; one
+//! skipped meanwhile never is.
+//!
+//! Reports from registered devices are also kept by device, so that what one is playing can be
+//! handed off to another; see `handoff`.
+
+use crate::events::{Published, ServerEvent, Subscriber};
+use crate::music_db::MusicDB;
+use crate::song::SongResult;
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// How long without a report before nothing is considered playing, in seconds
+const STALE_AFTER: u64 = 60;
+
+/// The least time between posts to Discord, in seconds
+const MIN_INTERVAL: u64 = 30;
+
+/// How long to wait on Discord before giving up.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The song playing, as given by `GET /now-playing`.
+#[derive(Serialize)]
+pub struct NowPlaying {
+    pub song: SongResult,
+    /// Seconds into the song, as of the last report
+    pub position: f64,
+    pub paused: bool,
+    /// When the song started, in seconds since the Unix epoch, as Discord's rich presence wants
+    pub started: u64,
+    /// When it was last reported, in seconds since the Unix epoch
+    pub reported: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Report {
+    song: u64,
+    position: f64,
+    paused: bool,
+    at: u64,
+}
+
+/// The latest progress report.
+#[derive(Default)]
+pub struct Progress {
+    latest: Option<Report>,
+    /// The latest report from each registered device, by its id
+    by_device: HashMap<String, Report>,
+}
+
+impl Progress {
+    /// Records a report that `song` is `position` seconds in, on `device` if it's registered.
+    pub fn report(&mut self, song: u64, position: f64, paused: bool, device: Option<String>) {
+        let report = Report {
+            song,
+            position,
+            paused,
+            at: crate::history::now(),
+        };
+        if let Some(device) = device {
+            self.by_device.insert(device, report);
+        }
+        self.latest = Some(report);
+    }
+
+    /// The song playing on a device, how far into it it's got by now, and whether it's paused,
+    /// unless it hasn't reported for a while.
+    pub fn playing_on(&self, device: &str) -> Option<(u64, f64, bool)> {
+        let now = crate::history::now();
+        let report = self
+            .by_device
+            .get(device)
+            .filter(|r| now < r.at + STALE_AFTER)?;
+        let position = if report.paused {
+            report.position
+        } else {
+            report.position + now.saturating_sub(report.at) as f64
+        };
+        Some((report.song, position, report.paused))
+    }
+
+    /// What's playing, unless nothing's been reported for a while.
+    pub fn current(&self, db: &MusicDB) -> Option<NowPlaying> {
+        let report = self
+            .latest
+            .as_ref()
+            .filter(|r| crate::history::now() < r.at + STALE_AFTER)?;
+        let song = db.records.get(&report.song)?;
+
+        Some(NowPlaying {
+            song: song.into(),
+            position: report.position,
+            paused: report.paused,
+            started: report.at.saturating_sub(report.position as u64),
+            reported: report.at,
+        })
+    }
+}
+
+/// What's been posted, and what's playing.
+#[derive(Default)]
+struct Posts {
+    /// The song last posted, and when
+    posted: Option<(String, u64)>,
+    /// Counts up with every song that starts, so a wait can tell whether it's been skipped
+    started: u64,
+}
+
+impl Posts {
+    /// Notes that `song` started at `at`, giving its number and how long to wait before posting
+    /// it, unless it was just posted. Played again later, it's posted again.
+    fn start(&mut self, song: &str, at: u64) -> Option<(u64, u64)> {
+        // Whatever was waiting has been skipped
+        self.started += 1;
+        let wait = match &self.posted {
+            Some((posted, posted_at)) if posted == song && at < posted_at + MIN_INTERVAL => {
+                return None
+            }
+            Some((_, posted_at)) => (posted_at + MIN_INTERVAL).saturating_sub(at),
+            None => 0,
+        };
+        Some((self.started, wait))
+    }
+}
+
+/// A Discord webhook to post new songs to. Cheap to clone.
+#[derive(Clone)]
+pub struct Discord {
+    url: String,
+    client: reqwest::Client,
+    posts: Arc<Mutex<Posts>>,
+}
+
+impl Discord {
+    pub fn new(url: String) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        Discord {
+            url,
+            client,
+            posts: Default::default(),
+        }
+    }
+
+    /// Posts `song` in the background. Failures are logged and otherwise ignored.
+    ///
+    /// Must be called from within a Tokio runtime.
+    fn post(&self, song: &SongResult) {
+        let mut description = Vec::new();
+        if !song.artist.is_empty() {
+            description.push(format!("by {}", song.artist));
+        }
+        if !song.album.is_empty() {
+            description.push(format!("from {}", song.album));
+        }
+
+        let body = serde_json::json!({
+            "embeds": [{
+                "author": { "name": "Now playing" },
+                "title": song.title,
+                "description": description.join(", "),
+            }],
+            // Don't let tags ping anyone
+            "allowed_mentions": { "parse": [] },
+        });
+        let request = self.client.post(&self.url).json(&body);
+
+        tokio::spawn(async move {
+            if let Err(e) = request.send().await.and_then(|r| r.error_for_status()) {
+                eprintln!("Posting to Discord failed: {}", e);
+            }
+        });
+    }
+}
+
+impl Subscriber for Discord {
+    /// Posts each song that starts playing, once `MIN_INTERVAL` has passed since the last post.
+    fn notify(&self, published: &Published) {
+        let ServerEvent::NowPlaying { song } = &published.event else {
+            return;
+        };
+
+        let started = self
+            .posts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .start(&song.id, published.at);
+        let Some((started, wait)) = started else {
+            return;
+        };
+
+        let discord = self.clone();
+        let song = song.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(wait)).await;
+            {
+                let mut posts = discord.posts.lock().unwrap_or_else(|e| e.into_inner());
+                // Skipped while it waited
+                if posts.started != started {
+                    return;
+                }
+                posts.posted = Some((song.id.clone(), crate::history::now()));
+            }
+            discord.post(&song);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn posts_songs_again_when_theyre_played_again() {
+        let mut posts = Posts::default();
+        assert_eq!(posts.start("1", 1000), Some((1, 0)));
+        posts.posted = Some(("1".into(), 1000));
+
+        // Restarted straight away, it's the same play
+        assert_eq!(posts.start("1", 1010), None);
+        // Another song waits its turn
+        assert_eq!(posts.start("2", 1010), Some((3, MIN_INTERVAL - 10)));
+        // On repeat, it's posted again
+        assert_eq!(posts.start("1", 1000 + 240), Some((4, 0)));
+    }
+}
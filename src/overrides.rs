@@ -0,0 +1,400 @@
+//! Overrides of what the tags say, for when they're wrong but the files can't be fixed, eg being
+//! read-only on a shared NAS. They're kept alongside the library and applied over the tags as
+//! songs are scanned, so that searching, browsing, stats, and everything else sees them; the files
+//! themselves are never touched. There are two kinds:
+//!
+//! - Album overrides, in `album_overrides.json`. Each is for an album as it's tagged, by its artist
+//!   and title, and sets any of its artist, title, year, and genre.
+//! - Artist aliases, in `artist_aliases.json`, which merge artists that are really the same, eg
+//!   "REM" into "R.E.M.". Each names an artist and who it's really by, and applies after any album
+//!   override.
+//!
+//! Songs keep what their tags said, so removing an override, or splitting a merged artist, puts
+//! them back without a rescan.
+
+use crate::music_db::MusicDB;
+use crate::song::Song;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::{BufReader, BufWriter},
+};
+
+pub(crate) const ALBUM_OVERRIDES_FILE: &str = "album_overrides.json";
+pub(crate) const ARTIST_ALIASES_FILE: &str = "artist_aliases.json";
+
+/// What to show for an album in place of its tags; anything unset is left as tagged.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct AlbumOverride {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub year: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub genre: Option<String>,
+}
+
+/// An album's override, as saved and as `/album/overrides` lists it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Overridden {
+    /// The album's artist, as tagged
+    pub artist: String,
+    /// The album's title, as tagged
+    pub album: String,
+    pub set: AlbumOverride,
+}
+
+/// An artist merged into another, as saved and as `/admin/artists/aliases` lists it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Alias {
+    /// The artist as tagged, or as an album override has it
+    pub alias: String,
+    /// Who it's really by
+    pub artist: String,
+}
+
+/// What `POST /admin/artists/merge` takes.
+#[derive(Deserialize, Debug)]
+pub struct ArtistMerge {
+    /// The artists to merge, as shown
+    pub artists: Vec<String>,
+    /// Who they're really by
+    pub into: String,
+}
+
+/// What `POST /admin/artists/split` takes.
+#[derive(Deserialize, Debug)]
+pub struct ArtistSplit {
+    /// The artist others were merged into
+    pub artist: String,
+}
+
+/// What an overridden song's tags said.
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, PartialEq, Eq)]
+pub struct Tagged {
+    pub artist: String,
+    pub album: String,
+    #[serde(default)]
+    pub album_artist: String,
+    pub year: u16,
+    pub genre: String,
+}
+
+/// Every override.
+#[derive(Debug, Default, Clone)]
+pub struct Overrides {
+    /// By the album's artist and title as tagged, lowercased
+    albums: BTreeMap<(String, String), Overridden>,
+    /// By the alias, lowercased
+    artists: BTreeMap<String, Alias>,
+}
+
+fn key(artist: &str, album: &str) -> (String, String) {
+    (artist.to_lowercase(), album.to_lowercase())
+}
+
+fn load<T: for<'de> Deserialize<'de>>(filename: &str) -> Vec<T> {
+    File::open(filename)
+        .ok()
+        .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+        .unwrap_or_default()
+}
+
+fn save<T: Serialize>(filename: &str, overrides: &[T]) {
+    let saved = File::create(filename).and_then(|file| {
+        serde_json::to_writer_pretty(BufWriter::new(file), overrides)?;
+        Ok(())
+    });
+    if let Err(e) = saved {
+        eprintln!("Unable to save {}: {:?}", filename, e);
+    }
+}
+
+impl Overrides {
+    pub fn load() -> Self {
+        Self {
+            albums: load::<Overridden>(ALBUM_OVERRIDES_FILE)
+                .into_iter()
+                .map(|o| (key(&o.artist, &o.album), o))
+                .collect(),
+            artists: load::<Alias>(ARTIST_ALIASES_FILE)
+                .into_iter()
+                .map(|a| (a.alias.to_lowercase(), a))
+                .collect(),
+        }
+    }
+
+    /// Every album override, by artist and then album, as tagged.
+    pub fn albums(&self) -> Vec<&Overridden> {
+        self.albums.values().collect()
+    }
+
+    /// Every artist alias, by alias.
+    pub fn aliases(&self) -> Vec<&Alias> {
+        self.artists.values().collect()
+    }
+
+    /// Who `artist` is really by, if it's been merged into another artist.
+    pub fn merged_into(&self, artist: &str) -> Option<&str> {
+        self.artists
+            .get(&artist.to_lowercase())
+            .map(|a| a.artist.as_str())
+    }
+
+    /// Puts `song` back as it's tagged, then applies its overrides, if it has any.
+    pub fn apply(&self, song: &mut Song) {
+        if let Some(tagged) = song.tagged.take() {
+            song.artist = tagged.artist;
+            song.album = tagged.album;
+            song.album_artist = tagged.album_artist;
+            song.year = tagged.year;
+            song.genre = tagged.genre;
+        }
+        let tagged = Tagged {
+            artist: song.artist.clone(),
+            album: song.album.clone(),
+            album_artist: song.album_artist.clone(),
+            year: song.year,
+            genre: song.genre.clone(),
+        };
+
+        if let Some(Overridden { set, .. }) = self.albums.get(&key(&song.artist, &song.album)) {
+            if let Some(artist) = &set.artist {
+                song.artist = artist.clone();
+                if !song.album_artist.is_empty() {
+                    song.album_artist = artist.clone();
+                }
+            }
+            if let Some(album) = &set.album {
+                song.album = album.clone();
+            }
+            if let Some(year) = set.year {
+                song.year = year;
+            }
+            if let Some(genre) = &set.genre {
+                song.genre = genre.clone();
+            }
+        }
+        if let Some(artist) = self.merged_into(&song.artist) {
+            song.artist = artist.to_string();
+        }
+        if let Some(artist) = self.merged_into(&song.album_artist) {
+            song.album_artist = artist.to_string();
+        }
+
+        let overridden = Tagged {
+            artist: song.artist.clone(),
+            album: song.album.clone(),
+            album_artist: song.album_artist.clone(),
+            year: song.year,
+            genre: song.genre.clone(),
+        };
+        if overridden != tagged {
+            song.tagged = Some(tagged);
+        }
+        song.artist_lower = song.artist.to_lowercase();
+        song.album_lower = song.album.to_lowercase();
+        song.album_artist_lower = song.album_artist.to_lowercase();
+        song.update_sort_keys();
+    }
+
+    /// Merges `artists` into `into`; gives whether anything changed.
+    fn merge(&mut self, artists: &[String], into: &str) -> bool {
+        let names = artists
+            .iter()
+            .map(|a| a.to_lowercase())
+            .collect::<BTreeSet<_>>();
+        // Whatever was merged into them is merged into `into` too
+        let mut aliases = self
+            .artists
+            .values()
+            .filter(|a| names.contains(&a.artist.to_lowercase()))
+            .map(|a| a.alias.clone())
+            .collect::<Vec<_>>();
+        aliases.extend(artists.iter().cloned());
+
+        let mut changed = self.artists.remove(&into.to_lowercase()).is_some();
+        for alias in aliases {
+            if alias.to_lowercase() == into.to_lowercase() {
+                continue;
+            }
+            let merged = Alias {
+                alias,
+                artist: into.to_string(),
+            };
+            changed |= self.artists.get(&merged.alias.to_lowercase()) != Some(&merged);
+            self.artists.insert(merged.alias.to_lowercase(), merged);
+        }
+        changed
+    }
+
+    /// Splits the artists merged into `artist` back out; gives their names.
+    fn split(&mut self, artist: &str) -> Vec<String> {
+        let artist = artist.to_lowercase();
+        let mut split = Vec::new();
+        self.artists.retain(|_, a| {
+            let merged = a.artist.to_lowercase() == artist;
+            if merged {
+                split.push(a.alias.clone());
+            }
+            !merged
+        });
+        split
+    }
+}
+
+impl MusicDB {
+    /// Overrides the tags of the album shown as `album` by `artist` (which may already be
+    /// overridden), or with `None`, removes its override; saves both. Returns the album's songs'
+    /// ids, which are none if there's no such album.
+    pub fn override_album(
+        &mut self,
+        artist: &str,
+        album: &str,
+        set: Option<AlbumOverride>,
+    ) -> Vec<u64> {
+        let ids = self.album_ids(artist, album);
+        // Albums tagged differently may have been overridden to look the same
+        let tagged = ids
+            .iter()
+            .map(|id| {
+                let song = &self.records[id];
+                match &song.tagged {
+                    Some(tagged) => (tagged.artist.clone(), tagged.album.clone()),
+                    None => (song.artist.clone(), song.album.clone()),
+                }
+            })
+            .collect::<BTreeSet<_>>();
+        if tagged.is_empty() {
+            return ids;
+        }
+
+        for (artist, album) in tagged {
+            let key = key(&artist, &album);
+            match set.clone().filter(|set| *set != AlbumOverride::default()) {
+                Some(set) => {
+                    self.overrides
+                        .albums
+                        .insert(key, Overridden { artist, album, set });
+                }
+                None => {
+                    self.overrides.albums.remove(&key);
+                }
+            }
+        }
+        save(ALBUM_OVERRIDES_FILE, &self.overrides.albums());
+
+        for id in &ids {
+            if let Some(song) = self.records.get_mut(id) {
+                self.overrides.apply(song);
+            }
+        }
+        self.mark_changed();
+        self.save();
+        ids
+    }
+
+    /// Merges `artists` into `into`, so their songs are all shown as by `into`, and saves. Returns
+    /// the ids of `into`'s songs.
+    pub fn merge_artists(&mut self, artists: &[String], into: &str) -> Vec<u64> {
+        if self.overrides.merge(artists, into) {
+            save(ARTIST_ALIASES_FILE, &self.overrides.aliases());
+            self.reapply_overrides();
+        }
+        let into = into.to_lowercase();
+        self.records
+            .values()
+            .filter(|s| s.artist_lower == into)
+            .map(|s| s.id)
+            .collect()
+    }
+
+    /// Splits the artists merged into `artist` back out, as they were before, and saves. Returns
+    /// their names, which are none if nothing was merged into it.
+    pub fn split_artist(&mut self, artist: &str) -> Vec<String> {
+        let split = self.overrides.split(artist);
+        if !split.is_empty() {
+            save(ARTIST_ALIASES_FILE, &self.overrides.aliases());
+            self.reapply_overrides();
+        }
+        split
+    }
+
+    fn reapply_overrides(&mut self) {
+        for song in self.records.values_mut() {
+            self.overrides.apply(song);
+        }
+        self.mark_changed();
+        self.save();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(artist: &str, album: &str, year: u16) -> Song {
+        Song {
+            artist: artist.to_string(),
+            album: album.to_string(),
+            year,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn overrides_apply_over_the_tags_and_come_off_again() {
+        let mut song = song("Beatles", "Abbey Rd", 1970);
+        let mut overrides = Overrides::default();
+        overrides.albums.insert(
+            key("beatles", "abbey rd"),
+            Overridden {
+                artist: "Beatles".to_string(),
+                album: "Abbey Rd".to_string(),
+                set: AlbumOverride {
+                    artist: Some("The Beatles".to_string()),
+                    album: Some("Abbey Road".to_string()),
+                    year: Some(1969),
+                    genre: None,
+                },
+            },
+        );
+
+        overrides.apply(&mut song);
+        assert_eq!(
+            (song.artist.as_str(), song.album_lower.as_str(), song.year),
+            ("The Beatles", "abbey road", 1969)
+        );
+        // Applying again changes nothing
+        overrides.apply(&mut song);
+        assert_eq!(song.album, "Abbey Road");
+
+        Overrides::default().apply(&mut song);
+        assert_eq!((song.album.as_str(), song.year), ("Abbey Rd", 1970));
+        assert!(song.tagged.is_none());
+    }
+
+    #[test]
+    fn merged_artists_split_back_out() {
+        let mut overrides = Overrides::default();
+        assert!(overrides.merge(&["REM".to_string()], "R.E.M."));
+        // Merging what was merged merges the lot
+        assert!(overrides.merge(&["R.E.M.".to_string()], "R.E.M"));
+        assert_eq!(overrides.merged_into("rem"), Some("R.E.M"));
+        assert!(!overrides.merge(&["REM".to_string()], "R.E.M"));
+
+        let mut rem = song("REM", "Murmur", 1983);
+        overrides.apply(&mut rem);
+        assert_eq!(rem.artist_lower, "r.e.m");
+
+        let mut split = overrides.split("R.E.M");
+        split.sort();
+        assert_eq!(split, ["R.E.M.", "REM"]);
+        overrides.apply(&mut rem);
+        assert_eq!(rem.artist, "REM");
+        assert!(rem.tagged.is_none());
+    }
+}
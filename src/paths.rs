@@ -0,0 +1,171 @@
+//! Handling file paths the same way on every platform: canonicalizing without Windows' `\\?\`
+//! prefix, comparing them case-insensitively on Windows, and saving names that aren't valid
+//! UTF-8. Also replacing files whole, so a crash mid-write doesn't leave half of one.
+
+use std::path::{Path, PathBuf};
+
+/// Canonicalizes a path, but leaves Windows paths in their familiar form (eg `\\nas\music`
+/// rather than `\\?\UNC\nas\music`) where that means the same thing.
+pub fn canonicalize(path: &Path) -> std::io::Result<PathBuf> {
+    dunce::canonicalize(path)
+}
+
+/// What to compare paths by: as-is, except that Windows file names are case-insensitive.
+pub fn key(path: &Path) -> PathBuf {
+    if cfg!(windows) {
+        PathBuf::from(path.to_string_lossy().to_lowercase())
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// Whether `path` is `base` or somewhere under it.
+pub fn is_under(path: &Path, base: &Path) -> bool {
+    key(path).starts_with(key(base))
+}
+
+/// Whether a song's path names something other than a local file, eg `s3://bucket/key` (see
+/// `remote`).
+pub fn is_remote(path: &Path) -> bool {
+    path.to_str()
+        .and_then(|p| p.split_once("://"))
+        .is_some_and(|(scheme, _)| {
+            !scheme.is_empty()
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '+')
+        })
+}
+
+/// Replaces the file at `path` with `contents`: they're written alongside and then moved into
+/// place, so the file is never left half-written.
+pub fn replace(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = PathBuf::from(partial);
+    std::fs::write(&partial, contents)
+        .and_then(|()| std::fs::rename(&partial, path))
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(&partial);
+        })
+}
+
+/// Makes a tag, eg an album's name, safe to use as a file or directory name on any platform.
+pub fn file_name(name: &str) -> String {
+    let name = name
+        .chars()
+        .map(|c| {
+            if c.is_control() || "/\\:*?\"<>|".contains(c) {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect::<String>();
+    match name.trim().trim_matches('.') {
+        "" => "_".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// (De)serializes a path as a string when it's valid UTF-8, and otherwise as its raw bytes (or on
+/// Windows, UTF-16 code units), so that nothing is lost. Use with `#[serde(with = "...")]`.
+pub mod serde_path {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::path::{Path, PathBuf};
+
+    #[cfg(unix)]
+    type Unit = u8;
+    #[cfg(windows)]
+    type Unit = u16;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Saved {
+        Utf8(String),
+        #[cfg(any(unix, windows))]
+        Raw(Vec<Unit>),
+    }
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        if let Some(s) = path.to_str() {
+            return s.serialize(serializer);
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            path.as_os_str().as_bytes().serialize(serializer)
+        }
+        #[cfg(windows)]
+        {
+            use std::os::windows::ffi::OsStrExt;
+            let wide = path.as_os_str().encode_wide().collect::<Vec<_>>();
+            wide.serialize(serializer)
+        }
+        #[cfg(not(any(unix, windows)))]
+        path.to_string_lossy().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        Ok(match Saved::deserialize(deserializer)? {
+            Saved::Utf8(s) => PathBuf::from(s),
+            #[cfg(unix)]
+            Saved::Raw(bytes) => {
+                use std::os::unix::ffi::OsStringExt;
+                PathBuf::from(std::ffi::OsString::from_vec(bytes))
+            }
+            #[cfg(windows)]
+            Saved::Raw(wide) => {
+                use std::os::windows::ffi::OsStringExt;
+                PathBuf::from(std::ffi::OsString::from_wide(&wide))
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct Saved(#[serde(with = "serde_path")] PathBuf);
+
+    fn round_trip(path: PathBuf) -> (String, PathBuf) {
+        let json = serde_json::to_string(&Saved(path)).unwrap();
+        let Saved(path) = serde_json::from_str(&json).unwrap();
+        (json, path)
+    }
+
+    #[test]
+    fn replaces_files_whole() {
+        let path = std::env::temp_dir().join(format!("bwaabwaa-paths-{:x}", rand::random::<u64>()));
+        std::fs::write(&path, "old").unwrap();
+        replace(&path, b"new").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+
+        let mut partial = path.clone().into_os_string();
+        partial.push(".partial");
+        assert!(!PathBuf::from(partial).exists());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn utf8_paths_are_strings() {
+        let (json, path) = round_trip(PathBuf::from("/music/Björk/Homogenic"));
+        assert_eq!(json, r#""/music/Björk/Homogenic""#);
+        assert_eq!(path, PathBuf::from("/music/Björk/Homogenic"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn other_paths_survive() {
+        use std::os::unix::ffi::OsStringExt;
+
+        // "café" in Latin-1
+        let latin1 = PathBuf::from(std::ffi::OsString::from_vec(b"/music/caf\xe9".to_vec()));
+        let (json, path) = round_trip(latin1.clone());
+        assert!(json.starts_with('['));
+        assert_eq!(path, latin1);
+    }
+}
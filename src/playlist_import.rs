@@ -0,0 +1,432 @@
+//! Importing playlists exported from other services (eg Spotify, via Exportify, or last.fm) by
+//! finding each track in the library by its artist and title.
+//!
+//! Tags rarely agree exactly between services, so names are compared loosely: case, punctuation,
+//! a leading "The", and anything in brackets ("(Remastered 2011)", "[Live]", "(feat. ...)") are
+//! ignored, as is a " - " suffix on the title ("- 2011 Remaster"), and small differences in
+//! spelling are forgiven. Tracks that still aren't found are reported, rather than guessed at, and
+//! go on the `wishlist`.
+
+use crate::music_db::MusicDB;
+use crate::playlists::PlaylistSummary;
+use crate::song::Song;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// How alike two normalized names must be to match, from 0.0 to 1.0
+const THRESHOLD: f64 = 0.8;
+
+/// A track from another service.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TrackRef {
+    pub artist: String,
+    pub title: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub album: String,
+}
+
+/// The tracks found in the library, and those that weren't.
+#[derive(Debug, Default)]
+pub struct Resolved {
+    /// The matching songs' ids, in the tracks' order
+    pub songs: Vec<u64>,
+    pub unmatched: Vec<TrackRef>,
+}
+
+/// What an import made of the tracks it was given.
+#[derive(Serialize, Debug)]
+pub struct Imported {
+    pub playlist: PlaylistSummary,
+    /// The tracks that weren't found in the library, and were left out
+    pub unmatched: Vec<TrackRef>,
+}
+
+/// Reads a list of tracks, as CSV or JSON (whichever it looks like).
+///
+/// CSV needs a header row naming the artist and title columns (eg Exportify's "Artist Name(s)"
+/// and "Track Name"), or no header and the columns artist, album, title, as last.fm exports have
+/// them; just artist and title works too. JSON is an array of tracks, or an object with one under
+/// `tracks` or `items`; each track has an `artist` (or `artistName`) and a `title` (or
+/// `trackName` or `name`), possibly nested under `track`.
+pub fn parse(text: &str) -> Result<Vec<TrackRef>, String> {
+    let text = text.trim_start_matches('\u{feff}').trim();
+    if text.starts_with('[') || text.starts_with('{') {
+        let json = serde_json::from_str(text).map_err(|e| format!("invalid JSON: {}", e))?;
+        parse_json(json)
+    } else {
+        parse_csv(text)
+    }
+}
+
+fn parse_json(json: Value) -> Result<Vec<TrackRef>, String> {
+    let tracks = match json {
+        Value::Array(tracks) => tracks,
+        Value::Object(mut object) => {
+            match object.remove("tracks").or_else(|| object.remove("items")) {
+                Some(Value::Array(tracks)) => tracks,
+                _ => return Err("expected a `tracks` array".to_string()),
+            }
+        }
+        _ => return Err("expected an array of tracks".to_string()),
+    };
+
+    // Names are strings, or objects with one, eg last.fm's `{"#text": "..."}`
+    fn field(track: &Value, keys: &[&str]) -> String {
+        let value = keys.iter().find_map(|&key| match track.get(key)? {
+            Value::String(s) => Some(s.clone()),
+            Value::Object(o) => o
+                .get("#text")
+                .or_else(|| o.get("name"))?
+                .as_str()
+                .map(str::to_string),
+            _ => None,
+        });
+        value.unwrap_or_default()
+    }
+
+    Ok(tracks
+        .iter()
+        .map(|item| {
+            let track = item.get("track").filter(|t| t.is_object()).unwrap_or(item);
+            TrackRef {
+                artist: field(track, &["artist", "artistName", "artist_name"]),
+                title: field(track, &["title", "trackName", "track_name", "name"]),
+                album: field(track, &["album", "albumName", "album_name"]),
+            }
+        })
+        .filter(|track| !track.title.is_empty())
+        .collect())
+}
+
+fn parse_csv(text: &str) -> Result<Vec<TrackRef>, String> {
+    let mut rows = csv_rows(text).into_iter();
+    let Some(first) = rows.next() else {
+        return Ok(Vec::new());
+    };
+
+    let header = first.iter().map(|h| h.to_lowercase()).collect::<Vec<_>>();
+    // Exportify also has columns like "Artist URI(s)", which aren't names
+    let column = |names: &[&str]| {
+        header
+            .iter()
+            .position(|h| !h.contains("uri") && names.iter().any(|name| h.contains(name)))
+    };
+    let title = ["track name", "title", "song"];
+    let (columns, first) = match (column(&["artist"]), column(&title), column(&["album"])) {
+        (Some(artist), Some(title), album) => ((artist, title, album), None),
+        _ if first.len() >= 3 => ((0, 2, Some(1)), Some(first)),
+        _ if first.len() == 2 => ((0, 1, None), Some(first)),
+        _ => return Err("expected a header row with artist and title columns".to_string()),
+    };
+
+    let (artist, title, album) = columns;
+    let cell = |row: &[String], i: usize| row.get(i).map(|s| s.trim().to_string());
+    Ok(first
+        .into_iter()
+        .chain(rows)
+        .filter_map(|row| {
+            Some(TrackRef {
+                artist: cell(&row, artist).unwrap_or_default(),
+                title: cell(&row, title).filter(|t| !t.is_empty())?,
+                album: album.and_then(|i| cell(&row, i)).unwrap_or_default(),
+            })
+        })
+        .collect())
+}
+
+/// Splits CSV into rows of fields, allowing for quoted fields with commas, quotes (doubled), and
+/// line breaks in them. Blank lines are skipped.
+fn csv_rows(text: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => row.push(std::mem::take(&mut field)),
+            '\r' if !quoted => {}
+            '\n' if !quoted => {
+                row.push(std::mem::take(&mut field));
+                if row.iter().any(|f| !f.is_empty()) {
+                    rows.push(std::mem::take(&mut row));
+                }
+                row.clear();
+            }
+            c => field.push(c),
+        }
+    }
+    row.push(field);
+    if row.iter().any(|f| !f.is_empty()) {
+        rows.push(row);
+    }
+    rows
+}
+
+/// Lowercases a name and strips it down to its words, leaving out anything in brackets and a
+/// leading "the".
+pub(crate) fn normalize(name: &str) -> String {
+    let mut stripped = String::new();
+    let mut depth = 0;
+    for c in name.chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth = (depth - 1).max(0),
+            '&' if depth == 0 => stripped.push_str(" and "),
+            c if depth == 0 && c.is_alphanumeric() => stripped.extend(c.to_lowercase()),
+            _ if depth == 0 => stripped.push(' '),
+            _ => {}
+        }
+    }
+
+    let words = stripped.split_whitespace().collect::<Vec<_>>();
+    let words = match words.as_slice() {
+        ["the", rest @ ..] if !rest.is_empty() => rest,
+        words => words,
+    };
+    words.join(" ")
+}
+
+/// The ways a title might be written in the library: as it is, and without a " - " suffix.
+fn title_variants(title: &str) -> Vec<String> {
+    let mut variants = vec![normalize(title)];
+    if let Some((main, _)) = title.split_once(" - ") {
+        variants.push(normalize(main));
+    }
+    variants.retain(|v| !v.is_empty());
+    variants
+}
+
+/// The artists credited, since other services often list several, eg "Queen, David Bowie".
+fn artist_variants(artist: &str) -> Vec<String> {
+    let mut variants = vec![normalize(artist)];
+    // ASCII only, so that positions in it are positions in `artist` too
+    let lower = artist.to_ascii_lowercase();
+    let main = [" feat. ", " feat ", " ft. ", " featuring "]
+        .iter()
+        .filter_map(|sep| lower.find(sep))
+        .min()
+        .map_or(artist, |at| &artist[..at]);
+    variants.extend(
+        main.split([',', ';', '/'])
+            .chain(std::iter::once(main))
+            .map(normalize),
+    );
+    variants.retain(|v| !v.is_empty());
+    variants.sort();
+    variants.dedup();
+    variants
+}
+
+/// How alike two strings are, from 0.0 (nothing in common) to 1.0 (the same), by their edit
+/// distance.
+fn similarity(a: &str, b: &str) -> f64 {
+    if a == b {
+        return 1.0;
+    }
+    let (a, b) = (a.chars().collect::<Vec<_>>(), b.chars().collect::<Vec<_>>());
+    let longest = a.len().max(b.len());
+    if longest == 0 {
+        return 1.0;
+    }
+
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.iter().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    1.0 - previous[b.len()] as f64 / longest as f64
+}
+
+fn best(variants: &[String], name: &str) -> f64 {
+    variants
+        .iter()
+        .map(|v| similarity(v, name))
+        .fold(0.0, f64::max)
+}
+
+/// Finds tracks in the library. When several songs match equally well (eg the same song on an
+/// album and a compilation), one on the track's album is preferred, then the best quality.
+pub struct Matcher<'a> {
+    /// The songs by their normalized artist. Comparing artists first keeps this from comparing
+    /// every track with every song.
+    by_artist: HashMap<String, Vec<&'a Song>>,
+}
+
+impl<'a> Matcher<'a> {
+    pub fn new(db: &'a MusicDB) -> Self {
+        let mut by_artist: HashMap<String, Vec<&Song>> = HashMap::new();
+        for song in db.records.values() {
+            by_artist
+                .entry(normalize(&song.artist))
+                .or_default()
+                .push(song);
+        }
+        Matcher { by_artist }
+    }
+
+    /// The song that best matches `track`, if any's close enough.
+    pub fn find(&self, track: &TrackRef) -> Option<&'a Song> {
+        let artists = artist_variants(&track.artist);
+        let titles = &title_variants(&track.title);
+        let album = normalize(&track.album);
+
+        let found = self
+            .by_artist
+            .iter()
+            .filter_map(|(name, songs)| {
+                let score = best(&artists, name);
+                (score >= THRESHOLD).then_some((score, songs))
+            })
+            .flat_map(|(artist_score, songs)| {
+                songs.iter().filter_map(move |song| {
+                    let score = best(titles, &normalize(&song.title));
+                    (score >= THRESHOLD).then_some((score * artist_score, *song))
+                })
+            })
+            .max_by(|(a, x), (b, y)| {
+                let on_album = |song: &Song| !album.is_empty() && normalize(&song.album) == album;
+                a.total_cmp(b)
+                    .then_with(|| on_album(x).cmp(&on_album(y)))
+                    .then_with(|| x.bitrate.cmp(&y.bitrate))
+                    // Otherwise, consistently
+                    .then_with(|| y.id.cmp(&x.id))
+            });
+        found.map(|(_, song)| song)
+    }
+}
+
+/// Finds each track in the library.
+pub fn resolve(db: &MusicDB, tracks: &[TrackRef]) -> Resolved {
+    let matcher = Matcher::new(db);
+    let mut resolved = Resolved::default();
+    for track in tracks {
+        match matcher.find(track) {
+            Some(song) => resolved.songs.push(song.id),
+            None => resolved.unmatched.push(track.clone()),
+        }
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(id: u64, artist: &str, album: &str, title: &str, bitrate: u16) -> Song {
+        Song {
+            id,
+            title: title.to_string(),
+            artist: artist.to_string(),
+            album: album.to_string(),
+            bitrate,
+            ..Default::default()
+        }
+    }
+
+    fn library() -> MusicDB {
+        let mut db = MusicDB::default();
+        for song in [
+            song(1, "The Beatles", "Abbey Road", "Come Together", 320),
+            song(2, "Queen", "Hot Space", "Under Pressure", 192),
+            song(3, "Queen", "Greatest Hits II", "Under Pressure", 320),
+            song(4, "Simon & Garfunkel", "Bookends", "Mrs. Robinson", 256),
+        ] {
+            db.records.insert(song.id, song);
+        }
+        db
+    }
+
+    fn track(artist: &str, title: &str, album: &str) -> TrackRef {
+        TrackRef {
+            artist: artist.to_string(),
+            title: title.to_string(),
+            album: album.to_string(),
+        }
+    }
+
+    #[test]
+    fn parses_exportify_csv() {
+        let csv = "\"Track URI\",\"Track Name\",\"Artist URI(s)\",\"Artist Name(s)\",\"Album Name\"\n\
+                   \"t:1\",\"Come Together - Remastered 2009\",\"a:1\",\"The Beatles\",\"Abbey Road\"\n\
+                   \"t:2\",\"Say \"\"Hello\"\", Wave Goodbye\",\"a:2\",\"Soft Cell\",\"\"\n";
+        assert_eq!(
+            parse(csv).unwrap(),
+            vec![
+                track(
+                    "The Beatles",
+                    "Come Together - Remastered 2009",
+                    "Abbey Road"
+                ),
+                track("Soft Cell", "Say \"Hello\", Wave Goodbye", ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_headerless_lastfm_csv() {
+        let csv = "Queen,Hot Space,Under Pressure,01 Jan 2020 12:00\r\n\r\nABBA,,Waterloo,\r\n";
+        assert_eq!(
+            parse(csv).unwrap(),
+            vec![
+                track("Queen", "Under Pressure", "Hot Space"),
+                track("ABBA", "Waterloo", ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_json() {
+        let json = r##"{"items": [
+            {"track": {"trackName": "Mrs. Robinson", "artistName": "Simon & Garfunkel"}},
+            {"artist": {"#text": "Queen"}, "name": "Under Pressure", "album": {"#text": ""}}
+        ]}"##;
+        assert_eq!(
+            parse(json).unwrap(),
+            vec![
+                track("Simon & Garfunkel", "Mrs. Robinson", ""),
+                track("Queen", "Under Pressure", ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn normalizes_names() {
+        assert_eq!(normalize("The Beatles"), "beatles");
+        assert_eq!(
+            normalize("Mrs. Robinson (From \"The Graduate\")"),
+            "mrs robinson"
+        );
+        assert_eq!(normalize("Simon & Garfunkel"), "simon and garfunkel");
+        assert_eq!(normalize("The The"), "the");
+    }
+
+    #[test]
+    fn matches_loosely() {
+        let tracks = [
+            track("Beatles", "Come Together - Remastered 2009", ""),
+            track("Simon and Garfunkel", "Mrs Robinson", ""),
+            track("Queen, David Bowie", "Under Presure", "Hot Space"),
+            track("Queen", "Bohemian Rhapsody", ""),
+        ];
+        let resolved = resolve(&library(), &tracks);
+        assert_eq!(resolved.songs, vec![1, 4, 2]);
+        assert_eq!(resolved.unmatched, vec![tracks[3].clone()]);
+    }
+
+    #[test]
+    fn prefers_better_copies() {
+        let resolved = resolve(&library(), &[track("Queen", "Under Pressure", "")]);
+        assert_eq!(resolved.songs, vec![3]);
+    }
+}
@@ -0,0 +1,147 @@
+//! Saved playlists, kept in `playlists.json`. For now they're made by importing a list of tracks
+//! from another service (see `playlist_import`); `/playlists` lists them. Tracks that weren't in
+//! the library go on the `wishlist`, and are added once they are.
+
+use crate::music_db::MusicDB;
+use crate::song::SongResult;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufReader, BufWriter},
+};
+
+pub(crate) const PLAYLISTS_FILE: &str = "playlists.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Playlist {
+    pub name: String,
+    /// In seconds since the Unix epoch
+    pub created: u64,
+    /// The songs' ids, in order
+    pub songs: Vec<u64>,
+}
+
+/// A playlist, as listed by `/playlists`.
+#[derive(Serialize, Debug)]
+pub struct PlaylistSummary {
+    pub id: String,
+    pub name: String,
+    pub created: u64,
+    pub songs: usize,
+}
+
+/// A playlist with its songs, for `/playlists/{id}`.
+#[derive(Serialize)]
+pub struct PlaylistDetails {
+    #[serde(flatten)]
+    pub summary: PlaylistSummary,
+    /// Those of its songs still in the library
+    pub songs: Vec<SongResult>,
+}
+
+/// Every playlist, by id.
+#[derive(Default)]
+pub struct Playlists {
+    playlists: BTreeMap<u64, Playlist>,
+}
+
+impl Playlists {
+    pub fn load() -> Self {
+        let playlists = File::open(PLAYLISTS_FILE)
+            .ok()
+            .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+            .unwrap_or_default();
+
+        Self { playlists }
+    }
+
+    fn save(&self) {
+        let saved = File::create(PLAYLISTS_FILE).and_then(|file| {
+            serde_json::to_writer_pretty(BufWriter::new(file), &self.playlists)?;
+            Ok(())
+        });
+        if let Err(e) = saved {
+            eprintln!("Unable to save playlists: {:?}", e);
+        }
+    }
+
+    fn summary(id: u64, playlist: &Playlist) -> PlaylistSummary {
+        PlaylistSummary {
+            id: id.to_string(),
+            name: playlist.name.clone(),
+            created: playlist.created,
+            songs: playlist.songs.len(),
+        }
+    }
+
+    /// Saves a new playlist of `songs`.
+    pub fn create(&mut self, name: String, songs: Vec<u64>) -> PlaylistSummary {
+        let id = self.playlists.keys().next_back().map_or(1, |last| last + 1);
+        let playlist = Playlist {
+            name,
+            created: crate::history::now(),
+            songs,
+        };
+        let summary = Self::summary(id, &playlist);
+        self.playlists.insert(id, playlist);
+        self.save();
+        summary
+    }
+
+    /// Every playlist, oldest first.
+    pub fn list(&self) -> Vec<PlaylistSummary> {
+        self.playlists
+            .iter()
+            .map(|(&id, playlist)| Self::summary(id, playlist))
+            .collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Playlist> {
+        self.playlists.get(&id)
+    }
+
+    /// Adds songs to the end of a playlist, if it's still there.
+    pub fn append(&mut self, id: u64, songs: &[u64]) {
+        if let Some(playlist) = self.playlists.get_mut(&id) {
+            playlist.songs.extend_from_slice(songs);
+            self.save();
+        }
+    }
+
+    /// How many entries in all the playlists are songs in `ids`.
+    pub fn count(&self, ids: &HashMap<u64, u64>) -> usize {
+        let songs = self.playlists.values().flat_map(|p| &p.songs);
+        songs.filter(|id| ids.contains_key(id)).count()
+    }
+
+    /// Replaces the songs in `replaced` with the songs they map to, in every playlist. Returns how
+    /// many entries were changed.
+    pub fn repoint(&mut self, replaced: &HashMap<u64, u64>) -> usize {
+        let mut changed = 0;
+        for id in self.playlists.values_mut().flat_map(|p| &mut p.songs) {
+            if let Some(&new) = replaced.get(id) {
+                *id = new;
+                changed += 1;
+            }
+        }
+        if changed > 0 {
+            self.save();
+        }
+        changed
+    }
+
+    /// A playlist and its songs, leaving out any no longer in the library.
+    pub fn details(&self, id: u64, db: &MusicDB) -> Option<PlaylistDetails> {
+        let playlist = self.playlists.get(&id)?;
+        Some(PlaylistDetails {
+            summary: Self::summary(id, playlist),
+            songs: playlist
+                .songs
+                .iter()
+                .filter_map(|id| db.records.get(id))
+                .map(SongResult::from)
+                .collect(),
+        })
+    }
+}
@@ -0,0 +1,110 @@
+//! Previews: thirty seconds of a song, from about a third of the way in, for hover-previews in
+//! search results. They're 8-bit mono WAVs at about 11kHz (under 100kbps), which sound rough but
+//! play in any browser without the server needing an encoder.
+//!
+//! Each preview is made the first time it's asked for and kept in `previews/`, by song id; since
+//! a changed file gets a new id, a stale preview is never served.
+
+use crate::analysis::{self, Audio};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+pub const PREVIEWS_DIR: &str = "previews";
+
+/// How long previews are
+const LENGTH: Duration = Duration::from_secs(30);
+
+/// How long previews take to fade in and out, so they don't start and stop with a click
+const FADE: Duration = Duration::from_secs(1);
+
+/// Where a song's preview is kept.
+fn path(id: u64) -> PathBuf {
+    Path::new(PREVIEWS_DIR).join(format!("{:016x}.wav", id))
+}
+
+/// Where in a song its preview starts.
+fn start(duration: Duration) -> Duration {
+    if duration <= LENGTH {
+        Duration::ZERO
+    } else {
+        (duration / 3).min(duration - LENGTH)
+    }
+}
+
+/// Writes audio out as an 8-bit mono WAV, fading it in and out.
+fn wav(audio: &Audio) -> Vec<u8> {
+    let fade = (FADE.as_secs_f32() * audio.rate as f32) as usize;
+    let len = audio.samples.len();
+    let data = audio.samples.iter().enumerate().map(|(i, sample)| {
+        let gain = (i.min(len - 1 - i) as f32 / fade.max(1) as f32).min(1.0);
+        // 8-bit WAV samples are unsigned, centered on 128
+        ((sample * gain).clamp(-1.0, 1.0) * 127.0).round() as i8 as u8 ^ 0x80
+    });
+
+    let mut wav = Vec::with_capacity(44 + len);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + len as u32).to_le_bytes());
+    wav.extend_from_slice(b"WAVEfmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, in one channel
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&audio.rate.to_le_bytes());
+    // Bytes per second, bytes per sample, and bits per sample
+    wav.extend_from_slice(&audio.rate.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes());
+    wav.extend_from_slice(&8u16.to_le_bytes());
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&(len as u32).to_le_bytes());
+    wav.extend(data);
+    wav
+}
+
+/// The path to a song's preview, making it first if it hasn't been made yet.
+pub fn preview(id: u64, song: &Path, duration: Duration) -> Result<PathBuf, String> {
+    let path = path(id);
+    if path.exists() {
+        return Ok(path);
+    }
+
+    let audio = analysis::decode(song, start(duration), Some(LENGTH))?;
+    if audio.samples.is_empty() {
+        return Err("it has no audio".to_string());
+    }
+    // Written alongside and then moved into place, so a preview being made is never served
+    let partial = path.with_extension("partial");
+    fs::create_dir_all(PREVIEWS_DIR)
+        .and_then(|_| fs::write(&partial, wav(&audio)))
+        .and_then(|_| fs::rename(&partial, &path))
+        .map_err(|e| format!("unable to save the preview: {}", e))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starts_a_third_of_the_way_in() {
+        assert_eq!(start(Duration::from_secs(20)), Duration::ZERO);
+        assert_eq!(start(Duration::from_secs(40)), Duration::from_secs(10));
+        assert_eq!(start(Duration::from_secs(300)), Duration::from_secs(100));
+    }
+
+    #[test]
+    fn writes_wavs() {
+        let audio = Audio {
+            samples: vec![0.0, 1.0, -1.0, 0.0],
+            rate: 11025,
+        };
+        let wav = wav(&audio);
+        assert_eq!(wav.len(), 48);
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(&wav[36..40], b"data");
+        // Silence is 128; the rest are faded right down, being within the fade
+        assert_eq!(&wav[44..], &[128, 128, 128, 128]);
+    }
+}
@@ -0,0 +1,212 @@
+//! Clients' progress through what they're playing, as they report it every so often to
+//! `POST /progress`. The one report drives several things: what's now playing (see
+//! `now_playing`), audiobook and podcast resume positions, play counts, and scrobbling (see
+//! `scrobble`).
+//!
+//! A play is counted once half the song has actually been played, rather than when it's
+//! requested, so skipping a song or seeking past most of it doesn't count. Clients that report
+//! their progress have their plays counted this way; `/listen` goes on counting plays as they
+//! start for those that don't.
+
+use std::{collections::HashMap, net::SocketAddr};
+
+/// How much further a song may get between reports than the time between them, in seconds,
+/// before it's taken for a seek rather than listening
+const SLACK: f64 = 5.0;
+
+/// How near its end a song can stop and have finished, in seconds, since tags' durations aren't
+/// always exact
+const NEAR_END: f64 = 2.0;
+
+/// How long a listener that's stopped reporting is still taken to report, in seconds, eg while
+/// paused
+const FORGET_AFTER: u64 = 600;
+
+/// Who's listening: a registered device, or failing that, an address.
+#[derive(Debug, Clone)]
+pub struct Listener {
+    /// The registered device, if any; see `devices`
+    pub device: Option<String>,
+    key: String,
+}
+
+impl Listener {
+    pub fn new(device: Option<String>, client: Option<SocketAddr>) -> Self {
+        let key = match (&device, client) {
+            (Some(device), _) => device.clone(),
+            (None, Some(client)) => client.ip().to_string(),
+            (None, None) => String::new(),
+        };
+        Listener { device, key }
+    }
+}
+
+/// What a report changed.
+#[derive(Debug, Default, PartialEq)]
+pub struct Update {
+    /// A song started playing, and when, in seconds since the Unix epoch
+    pub started: Option<u64>,
+    /// The song's play was counted, having started when given, in seconds since the Unix epoch
+    pub counted: Option<u64>,
+    /// The song finished playing
+    pub finished: bool,
+    /// Whether `/listen` already counted the play, since the listener hadn't reported before
+    pub by_listen: bool,
+}
+
+/// One listener's progress through a song.
+#[derive(Default)]
+struct Session {
+    song: u64,
+    /// When the song started, in seconds since the Unix epoch
+    started: u64,
+    /// Seconds of it actually listened to
+    played: f64,
+    position: f64,
+    playing: bool,
+    /// When it was last reported, in seconds since the Unix epoch
+    at: u64,
+    reported: bool,
+    announced: bool,
+    counted: bool,
+    finished: bool,
+    by_listen: bool,
+}
+
+/// Each listener's progress through the song they're playing.
+#[derive(Default)]
+pub struct Listening {
+    sessions: HashMap<String, Session>,
+}
+
+impl Listening {
+    /// Records that `listener` is `position` seconds into `song`, which is `duration` long.
+    pub fn report(
+        &mut self,
+        listener: &Listener,
+        song: u64,
+        position: f64,
+        playing: bool,
+        duration: f64,
+    ) -> Update {
+        self.report_at(
+            listener,
+            song,
+            position,
+            playing,
+            duration,
+            crate::history::now(),
+        )
+    }
+
+    fn report_at(
+        &mut self,
+        listener: &Listener,
+        song: u64,
+        position: f64,
+        playing: bool,
+        duration: f64,
+        now: u64,
+    ) -> Update {
+        self.sessions.retain(|_, s| now < s.at + FORGET_AFTER);
+        let session = self
+            .sessions
+            .entry(listener.key.clone())
+            .and_modify(|s| {
+                if s.song != song {
+                    *s = Session::default();
+                }
+            })
+            .or_default();
+        if !session.reported && !session.by_listen {
+            *session = Session {
+                song,
+                started: now.saturating_sub(position as u64),
+                position,
+                at: now,
+                ..Default::default()
+            };
+        }
+
+        // Only time spent playing counts, and not skipping ahead
+        let advanced = position - session.position;
+        let elapsed = now.saturating_sub(session.at) as f64;
+        if session.playing && advanced > 0.0 && advanced <= elapsed + SLACK {
+            session.played += advanced;
+        }
+        session.position = position;
+        session.playing = playing;
+        session.at = now;
+        session.reported = true;
+
+        let mut update = Update {
+            by_listen: session.by_listen,
+            ..Default::default()
+        };
+        if playing && !session.announced {
+            session.announced = true;
+            update.started = Some(session.started);
+        }
+        if !session.counted && duration > 0.0 && session.played >= duration / 2.0 {
+            session.counted = true;
+            update.counted = Some(session.started);
+        }
+        // Players stop at the end, so the last report is of it paused there
+        if !session.finished && !playing && duration > 0.0 && position >= duration - NEAR_END {
+            session.finished = true;
+            update.finished = true;
+        }
+        update
+    }
+
+    /// Notes that `/listen` counted a play of `song` by `listener`, which doesn't report its
+    /// progress yet, so that it isn't counted again if it starts to.
+    pub fn listened(&mut self, listener: &Listener, song: u64) {
+        let now = crate::history::now();
+        self.sessions.insert(
+            listener.key.clone(),
+            Session {
+                song,
+                started: now,
+                at: now,
+                by_listen: true,
+                ..Default::default()
+            },
+        );
+    }
+
+    /// Whether `listener` reports its progress, so that its plays are counted from that.
+    pub fn reports(&self, listener: &Listener) -> bool {
+        let now = crate::history::now();
+        self.sessions
+            .get(&listener.key)
+            .is_some_and(|s| s.reported && now < s.at + FORGET_AFTER)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_plays_half_way_through() {
+        let listener = Listener::new(Some("phone".to_string()), None);
+        let mut listening = Listening::default();
+        let mut report =
+            |position, at| listening.report_at(&listener, 1, position, true, 100.0, at);
+
+        assert_eq!(report(0.0, 1000).started, Some(1000));
+        assert_eq!(report(15.0, 1015), Update::default());
+        // Seeking ahead doesn't count
+        assert_eq!(report(45.0, 1016), Update::default());
+        assert_eq!(report(60.0, 1031), Update::default());
+        assert_eq!(report(80.0, 1051).counted, Some(1000));
+        // Only once
+        assert_eq!(report(95.0, 1066), Update::default());
+        assert!(
+            listening
+                .report_at(&listener, 1, 99.5, false, 100.0, 1071)
+                .finished
+        );
+    }
+}
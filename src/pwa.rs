@@ -0,0 +1,81 @@
+//! What the web UI needs to be installed as a Progressive Web App: a manifest, icons in the sizes
+//! phones ask for (scaled up from the favicon), and a service worker that caches the page's shell.
+
+use image::{imageops::FilterType, ImageFormat};
+use serde::Serialize;
+use std::{collections::HashMap, io::Cursor, sync::OnceLock};
+
+/// The icon sizes listed in the manifest. Android wants at least 192 and 512.
+pub const ICON_SIZES: [u32; 2] = [192, 512];
+
+#[derive(Serialize)]
+struct Manifest {
+    name: &'static str,
+    short_name: &'static str,
+    start_url: &'static str,
+    display: &'static str,
+    background_color: &'static str,
+    theme_color: &'static str,
+    icons: Vec<Icon>,
+}
+
+#[derive(Serialize)]
+struct Icon {
+    src: String,
+    sizes: String,
+    #[serde(rename = "type")]
+    content_type: &'static str,
+}
+
+/// The web app manifest, as JSON.
+pub fn manifest() -> String {
+    let manifest = Manifest {
+        name: "bwaa-bwaa",
+        short_name: "bwaa-bwaa",
+        start_url: "/",
+        display: "standalone",
+        background_color: "#ffffff",
+        theme_color: "#CEDFF2",
+        icons: ICON_SIZES
+            .iter()
+            .map(|size| Icon {
+                src: format!("/icons/{}.png", size),
+                sizes: format!("{0}x{0}", size),
+                content_type: "image/png",
+            })
+            .collect(),
+    };
+
+    serde_json::to_string(&manifest).unwrap()
+}
+
+/// The favicon scaled to `size` pixels square, as a PNG; `None` for sizes not in `ICON_SIZES`.
+/// Each size is only rendered once.
+pub fn icon(favicon: &[u8], size: u32) -> Option<&'static [u8]> {
+    static ICONS: OnceLock<HashMap<u32, Vec<u8>>> = OnceLock::new();
+
+    let icons = ICONS.get_or_init(|| {
+        // Decoding picks the largest image in the .ico
+        let image = match image::load_from_memory_with_format(favicon, ImageFormat::Ico) {
+            Ok(image) => image,
+            Err(e) => {
+                eprintln!("Unable to decode the favicon: {}", e);
+                return HashMap::new();
+            }
+        };
+
+        ICON_SIZES
+            .iter()
+            .filter_map(|&size| {
+                let mut png = Cursor::new(Vec::new());
+                image
+                    .resize_exact(size, size, FilterType::Lanczos3)
+                    .write_to(&mut png, ImageFormat::Png)
+                    .ok()?;
+                Some((size, png.into_inner()))
+            })
+            .collect()
+    });
+
+    icons.get(&size).map(Vec::as_slice)
+}
@@ -0,0 +1,86 @@
+//! `/qr`: a QR code of the server's address, to put up on the host's screen so that guests can
+//! point their phone's camera at it to join.
+
+use image::{ImageFormat, Luma};
+use qrcode::{render::svg, QrCode};
+use serde::Deserialize;
+use std::{io::Cursor, net::UdpSocket};
+
+#[derive(Deserialize, Debug, Default)]
+pub struct QrQuery {
+    /// `svg` (the default) or `png`
+    pub format: Option<String>,
+    /// Passed along in the URL as `?token=`, eg to let a guest in
+    pub token: Option<String>,
+    /// Width and height of the code in pixels; 256 by default
+    pub size: Option<u32>,
+}
+
+impl QrQuery {
+    const DEFAULT_SIZE: u32 = 256;
+    const MAX_SIZE: u32 = 2048;
+}
+
+/// The URL a phone on the same network can reach the server at. `host` is the request's `Host`
+/// header; when that's a loopback address (the host browsing its own server), the machine's LAN
+/// address is used instead, since `localhost` means nothing to a phone.
+pub fn server_url(host: Option<&str>, port: u16, token: Option<&str>) -> String {
+    let host = match host {
+        Some(h) if !is_loopback(h) => h.to_string(),
+        _ => match lan_address() {
+            Some(ip) => format!("{}:{}", ip, port),
+            None => format!("localhost:{}", port),
+        },
+    };
+
+    match token {
+        Some(token) => format!("http://{}/?token={}", host, token),
+        None => format!("http://{}/", host),
+    }
+}
+
+fn is_loopback(host: &str) -> bool {
+    let name = match host.rsplit_once(':') {
+        // Leave IPv6 addresses (which have colons of their own) alone
+        Some((name, port)) if port.parse::<u16>().is_ok() && !name.ends_with(':') => name,
+        _ => host,
+    };
+    name == "localhost" || name.starts_with("127.") || name == "[::1]"
+}
+
+/// The address this machine uses for outgoing traffic, found by "connecting" a UDP socket (which
+/// sends nothing).
+fn lan_address() -> Option<std::net::IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("192.0.2.1:80").ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    (!ip.is_unspecified()).then_some(ip)
+}
+
+/// Renders `url` as a QR code, returning the content type and body.
+pub fn render(url: &str, query: &QrQuery) -> Result<(&'static str, Vec<u8>), String> {
+    let code = QrCode::new(url.as_bytes()).map_err(|e| e.to_string())?;
+    let size = query
+        .size
+        .unwrap_or(QrQuery::DEFAULT_SIZE)
+        .clamp(64, QrQuery::MAX_SIZE);
+
+    match query.format.as_deref().unwrap_or("svg") {
+        "svg" => {
+            let svg = code
+                .render::<svg::Color>()
+                .min_dimensions(size, size)
+                .build();
+            Ok(("image/svg+xml", svg.into_bytes()))
+        }
+        "png" => {
+            let image = code.render::<Luma<u8>>().min_dimensions(size, size).build();
+            let mut png = Cursor::new(Vec::new());
+            image
+                .write_to(&mut png, ImageFormat::Png)
+                .map_err(|e| e.to_string())?;
+            Ok(("image/png", png.into_inner()))
+        }
+        other => Err(format!("unknown format: {}", other)),
+    }
+}
@@ -0,0 +1,94 @@
+use crate::music_db::MusicDB;
+use crate::radio::RadioSeed;
+use crate::song::SongResult;
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
+
+/// The server-side play queue.
+///
+/// When seeded as a radio station, the queue tops itself up with similar songs as it's consumed,
+/// so it never runs dry.
+#[derive(Default)]
+pub struct PlayQueue {
+    songs: VecDeque<u64>,
+    radio: Option<RadioSeed>,
+    /// Recently played songs, so that the radio doesn't repeat itself
+    recent: VecDeque<u64>,
+}
+
+#[derive(Serialize)]
+pub struct QueueState {
+    pub songs: Vec<SongResult>,
+    /// The song the radio station was seeded with, if any
+    pub radio: Option<String>,
+}
+
+impl PlayQueue {
+    /// How many songs a radio queue keeps lined up
+    const RADIO_LOOKAHEAD: usize = 10;
+    /// How many recently played songs the radio avoids repeating
+    const RECENT_LIMIT: usize = 200;
+
+    pub fn enqueue(&mut self, id: u64) {
+        self.songs.push_back(id);
+    }
+
+    /// Replaces the queue's contents (and stops any radio station).
+    pub fn replace(&mut self, ids: impl IntoIterator<Item = u64>) {
+        self.songs = ids.into_iter().collect();
+        self.radio = None;
+    }
+
+    pub fn clear(&mut self) {
+        self.songs.clear();
+        self.radio = None;
+    }
+
+    /// Replaces the queue with a radio station seeded by `seed`.
+    pub fn start_radio(&mut self, seed: RadioSeed, db: &MusicDB) {
+        self.songs.clear();
+        self.radio = Some(seed);
+        self.refill(db);
+    }
+
+    /// Takes the next song off the queue.
+    pub fn next(&mut self, db: &MusicDB) -> Option<u64> {
+        let id = self.songs.pop_front()?;
+
+        self.recent.push_back(id);
+        if self.recent.len() > Self::RECENT_LIMIT {
+            self.recent.pop_front();
+        }
+
+        self.refill(db);
+        Some(id)
+    }
+
+    fn refill(&mut self, db: &MusicDB) {
+        let seed = match &self.radio {
+            Some(seed) if self.songs.len() < Self::RADIO_LOOKAHEAD => seed,
+            _ => return,
+        };
+
+        let exclude = self
+            .songs
+            .iter()
+            .chain(self.recent.iter())
+            .copied()
+            .collect::<HashSet<_>>();
+        let more = db.radio_songs(seed, Self::RADIO_LOOKAHEAD - self.songs.len(), &exclude);
+        self.songs.extend(more);
+    }
+
+    pub fn state(&self, db: &MusicDB) -> QueueState {
+        QueueState {
+            songs: self
+                .songs
+                .iter()
+                .filter_map(|id| db.records.get(id))
+                .map(|s| s.into())
+                .collect(),
+            radio: self.radio.as_ref().map(|r| r.id.to_string()),
+        }
+    }
+}
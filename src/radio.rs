@@ -0,0 +1,120 @@
+use crate::music_db::MusicDB;
+use crate::song::Song;
+use rand::seq::SliceRandom;
+use std::collections::HashSet;
+
+/// What a radio station is "about", derived from the song it was seeded with.
+#[derive(Debug, Clone)]
+pub struct RadioSeed {
+    pub id: u64,
+    genre: String,
+    artist_lower: String,
+    year: u16,
+    /// Genres that other songs by the seed's artist are tagged with
+    related_genres: HashSet<String>,
+    /// Artists that share an album (eg, a compilation or split) with the seed's artist
+    related_artists: HashSet<String>,
+}
+
+impl MusicDB {
+    pub fn radio_seed(&self, id: u64) -> Option<RadioSeed> {
+        let seed = self.records.get(&id)?;
+
+        let by_artist = self
+            .records
+            .values()
+            .filter(|s| s.artist_lower == seed.artist_lower)
+            .collect::<Vec<_>>();
+
+        let related_genres = by_artist
+            .iter()
+            .filter(|s| !s.genre.is_empty())
+            .map(|s| s.genre.to_lowercase())
+            .collect();
+
+        let albums = by_artist
+            .iter()
+            .filter(|s| !s.album_lower.is_empty())
+            .map(|s| &s.album_lower)
+            .collect::<HashSet<_>>();
+        let related_artists = self
+            .records
+            .values()
+            .filter(|s| s.artist_lower != seed.artist_lower && albums.contains(&s.album_lower))
+            .map(|s| s.artist_lower.clone())
+            .collect();
+
+        Some(RadioSeed {
+            id,
+            genre: seed.genre.to_lowercase(),
+            artist_lower: seed.artist_lower.clone(),
+            year: seed.year,
+            related_genres,
+            related_artists,
+        })
+    }
+
+    /// Picks up to `count` songs similar to `seed`, skipping anything in `exclude`, anything
+    /// hidden, and in kid mode, anything explicit.
+    ///
+    /// Every song is scored by how much it has in common with the seed; the best-scoring
+    /// candidates are then shuffled so that the same seed doesn't always produce the same station.
+    pub fn radio_songs(&self, seed: &RadioSeed, count: usize, exclude: &HashSet<u64>) -> Vec<u64> {
+        let mut candidates = self
+            .records
+            .values()
+            .filter(|s| {
+                s.id != seed.id
+                    && !exclude.contains(&s.id)
+                    && !s.hidden
+                    && self.kid_safe(s)
+                    && s.section.shuffles()
+            })
+            .map(|s| (seed.score(s), s.id))
+            .filter(|(score, _)| *score > 0)
+            .collect::<Vec<_>>();
+
+        // Consider a pool a few times larger than needed, so there's some variety
+        candidates.sort_unstable_by_key(|&(score, _)| std::cmp::Reverse(score));
+        candidates.truncate(count * 4);
+        candidates.shuffle(&mut rand::thread_rng());
+
+        candidates
+            .into_iter()
+            .take(count)
+            .map(|(_, id)| id)
+            .collect()
+    }
+}
+
+impl RadioSeed {
+    fn score(&self, song: &Song) -> u32 {
+        let mut score = 0;
+
+        let genre = song.genre.to_lowercase();
+        if !genre.is_empty() {
+            if genre == self.genre {
+                score += 4;
+            } else if self.related_genres.contains(&genre) {
+                score += 2;
+            }
+        }
+
+        if song.artist_lower == self.artist_lower {
+            // Some of the same artist is good, but it shouldn't take over the station
+            score += 2;
+        } else if self.related_artists.contains(&song.artist_lower) {
+            score += 3;
+        }
+
+        if self.year != 0 && song.year != 0 {
+            match self.year.abs_diff(song.year) {
+                0..=2 => score += 2,
+                3..=5 => score += 1,
+                _ => {}
+            }
+        }
+
+        score
+    }
+}
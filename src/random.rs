@@ -0,0 +1,76 @@
+use crate::analysis::Mood;
+use crate::genres::Genres;
+use crate::music_db::{AlbumDetails, MusicDB};
+use crate::song::{Song, SongResult};
+use rand::seq::IteratorRandom;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct RandomTerms {
+    pub count: Option<u16>,
+    pub genre: Option<String>,
+    pub artist: Option<String>,
+    pub mood: Option<Mood>,
+    /// Includes songs the user has hidden, which are otherwise left out
+    pub include_hidden: Option<bool>,
+}
+
+impl RandomTerms {
+    const DEFAULT_COUNT: u16 = 25;
+    const MAX_COUNT: u16 = 1000;
+
+    fn matches(&self, song: &Song, genres: &Genres) -> bool {
+        let genre_ok = match &self.genre {
+            Some(g) if !g.is_empty() => genres.is_under(&song.genre, g),
+            _ => true,
+        };
+        let artist_ok = match &self.artist {
+            Some(a) if !a.is_empty() => song.artist_lower == a.to_lowercase(),
+            _ => true,
+        };
+
+        let mood_ok = match self.mood {
+            Some(mood) => song.analysis.is_some_and(|a| a.mood == mood),
+            None => true,
+        };
+
+        let hidden_ok = !song.hidden || self.include_hidden.unwrap_or(false);
+
+        genre_ok && artist_ok && mood_ok && hidden_ok && song.section.shuffles()
+    }
+}
+
+impl MusicDB {
+    /// Picks up to `count` distinct songs uniformly at random from those matching `terms`.
+    pub fn random_songs(&self, terms: &RandomTerms) -> Vec<SongResult> {
+        let count = terms
+            .count
+            .unwrap_or(RandomTerms::DEFAULT_COUNT)
+            .min(RandomTerms::MAX_COUNT) as usize;
+
+        self.records
+            .values()
+            .filter(|song| terms.matches(song, &self.genres) && self.kid_safe(song))
+            .choose_multiple(&mut rand::thread_rng(), count)
+            .into_iter()
+            .map(|s| s.into())
+            .collect()
+    }
+
+    /// Picks an album uniformly at random (rather than weighted by track count) from those with a
+    /// song matching `terms`.
+    pub fn random_album(&self, terms: &RandomTerms) -> Option<AlbumDetails> {
+        let albums = self
+            .records
+            .values()
+            .filter(|song| {
+                !song.album.is_empty() && terms.matches(song, &self.genres) && self.kid_safe(song)
+            })
+            .map(|song| (song.album_key(), song))
+            .collect::<HashMap<_, _>>();
+
+        let song = albums.into_values().choose(&mut rand::thread_rng())?;
+        self.album(&song.artist, &song.album, Some(song.year))
+    }
+}
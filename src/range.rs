@@ -0,0 +1,82 @@
+//! HTTP range requests (`Range: bytes=...`), so that browsers can seek in a song or music video
+//! without downloading all of it first. Only a single range is honored; a request for several is
+//! answered with the whole file, which the spec allows.
+
+use std::ops::Range;
+
+/// What part of a file a request asked for.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Requested {
+    /// No range, or one that can't be parsed, which is ignored
+    Whole,
+    /// The bytes in the range, which is within the file
+    Part(Range<u64>),
+    /// A range starting past the end of the file
+    Unsatisfiable,
+}
+
+impl Requested {
+    /// Works out the part of a file `len` bytes long asked for by a `Range` header.
+    pub fn from_header(header: Option<&str>, len: u64) -> Self {
+        let Some(spec) = header.and_then(|h| h.trim().strip_prefix("bytes=")) else {
+            return Requested::Whole;
+        };
+        let Some((start, end)) = spec.trim().split_once('-') else {
+            return Requested::Whole;
+        };
+        if spec.contains(',') {
+            return Requested::Whole;
+        }
+
+        let (start, end) = (start.trim(), end.trim());
+        let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+            // The first bytes, eg `bytes=0-499`; an end past the file means to its end
+            (Ok(start), Ok(end)) if start <= end => start..end.saturating_add(1).min(len),
+            // From a byte to the end, eg `bytes=500-`
+            (Ok(start), Err(_)) if end.is_empty() => start..len,
+            // The last bytes, eg `bytes=-500`
+            (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+                len.saturating_sub(suffix)..len
+            }
+            _ => return Requested::Whole,
+        };
+        if range.start >= len {
+            Requested::Unsatisfiable
+        } else {
+            Requested::Part(range)
+        }
+    }
+
+    /// Whether this is the start of the file, so a play rather than a seek.
+    pub fn is_start(&self) -> bool {
+        match self {
+            Requested::Whole => true,
+            Requested::Part(range) => range.start == 0,
+            Requested::Unsatisfiable => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_single_ranges() {
+        let requested = |header| Requested::from_header(Some(header), 1000);
+        assert_eq!(Requested::from_header(None, 1000), Requested::Whole);
+        assert_eq!(requested("bytes=0-499"), Requested::Part(0..500));
+        assert_eq!(requested("bytes=500-"), Requested::Part(500..1000));
+        assert_eq!(requested("bytes=-100"), Requested::Part(900..1000));
+        assert_eq!(requested("bytes=900-5000"), Requested::Part(900..1000));
+        assert_eq!(requested("bytes=-5000"), Requested::Part(0..1000));
+        assert_eq!(
+            requested("bytes=0-18446744073709551615"),
+            Requested::Part(0..1000)
+        );
+        assert_eq!(requested("bytes=1000-"), Requested::Unsatisfiable);
+        assert_eq!(requested("bytes=0-1,5-9"), Requested::Whole);
+        assert_eq!(requested("bytes=9-5"), Requested::Whole);
+        assert_eq!(requested("items=0-5"), Requested::Whole);
+    }
+}
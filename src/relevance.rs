@@ -0,0 +1,86 @@
+//! How well a song matches a search term, so that searches can give the best matches first rather
+//! than in track order.
+//!
+//! Each field the term is found in scores by how well it matches there (the whole field, the
+//! start of it, the start of a word in it, or anywhere else) and by how much the field counts (the
+//! title most, then the artist, then the album, then the rest). A song's score is the sum of its
+//! fields', so an exact title match outranks the term turning up in an artist's name, and a
+//! prefix outranks the middle of a word.
+
+use crate::song::Song;
+
+/// How much each field counts, most first
+const TITLE: u32 = 4;
+const ARTIST: u32 = 3;
+const ALBUM: u32 = 2;
+const OTHER: u32 = 1;
+
+/// How well `term` matches `field`, both lowercased: 4 for all of it, 3 for its start, 2 for the
+/// start of a later word, 1 for anywhere else, and 0 if it's not there at all.
+fn quality(field: &str, term: &str) -> u32 {
+    if field == term {
+        return 4;
+    }
+    if field.starts_with(term) {
+        return 3;
+    }
+
+    let mut found = 0;
+    for (i, _) in field.match_indices(term) {
+        let word_start = field[..i]
+            .chars()
+            .next_back()
+            .is_some_and(|c| !c.is_alphanumeric());
+        if word_start {
+            return 2;
+        }
+        found = 1;
+    }
+    found
+}
+
+/// How well `song` matches `term`, which is lowercased; higher is better, and 0 is no match.
+pub fn score(song: &Song, term: &str) -> u32 {
+    if term.is_empty() {
+        return 0;
+    }
+
+    [
+        (&song.title_lower, TITLE),
+        (&song.artist_lower, ARTIST),
+        (&song.album_lower, ALBUM),
+        (&song.stem_lower, OTHER),
+        (&song.composer_lower, OTHER),
+        (&song.work_lower, OTHER),
+    ]
+    .into_iter()
+    .map(|(field, weight)| quality(field, term) * weight)
+    .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(title: &str, artist: &str) -> Song {
+        Song {
+            title_lower: title.to_lowercase(),
+            artist_lower: artist.to_lowercase(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn ranks_better_matches_higher() {
+        let exact_title = score(&song("Help", "The Beatles"), "help");
+        let in_artist = score(&song("Run", "Helpers"), "help");
+        assert!(exact_title > in_artist);
+
+        let prefix = score(&song("Helplessly Hoping", ""), "help");
+        let word = score(&song("Don't Help", ""), "help");
+        let mid_word = score(&song("Unhelpful", ""), "help");
+        assert!(prefix > word && word > mid_word && mid_word > 0);
+
+        assert_eq!(score(&song("Yesterday", ""), "help"), 0);
+    }
+}
@@ -0,0 +1,765 @@
+//! Songs kept somewhere other than local disk. Each source lists its files, reads parts of them
+//! (enough to get at an MP3's tags and first frames, without downloading the whole thing), and
+//! hands them to clients, either through the server or by pointing clients at them directly.
+//! Through the server, files are streamed as they come, and a client's range goes to the source,
+//! so seeking doesn't download everything before it.
+//!
+//! Remote songs' paths are URLs, eg `s3://bucket/album/song.mp3`. Only MP3s are read.
+//!
+//! # S3
+//!
+//! With the `s3` feature, `--s3=s3://bucket/prefix` scans a bucket (MinIO and other S3-alikes
+//! work too). Credentials, the region, and the endpoint come from the usual `AWS_*` environment
+//! variables, eg `AWS_ENDPOINT=http://minio.local:9000`. `/listen` redirects clients to a
+//! presigned URL, or with `--s3-proxy`, fetches the file itself.
+//!
+//! # WebDAV
+//!
+//! Shares (eg Nextcloud's) are listed in `webdav.json`, with their credentials:
+//!
+//! ```json
+//! [
+//!     {
+//!         "url": "https://cloud.example.com/remote.php/dav/files/me/Music",
+//!         "username": "me",
+//!         "password": "an app password"
+//!     }
+//! ]
+//! ```
+//!
+//! Since clients don't have the credentials, files are always fetched through the server.
+
+use crate::metadata::Mp3Reader;
+use crate::music_db::MusicDB;
+use crate::song::Song;
+use bytes::Bytes;
+use futures::{stream::BoxStream, StreamExt};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, BufReader},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+pub(crate) const WEBDAV_FILE: &str = "webdav.json";
+
+/// How much of an MP3 past its ID3 tag is read, to find its bitrate and such
+const AUDIO_BYTES: u64 = 64 * 1024;
+
+/// A file in a remote source.
+pub struct Object {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// How to get a remote song to a client.
+pub enum Fetch {
+    /// Send the client to this URL, which it can fetch itself
+    Redirect(String),
+    /// Send the file through the server; see `RemoteSources::read`
+    Proxy,
+}
+
+/// A file, or part of one, as it comes from its source.
+pub type ByteStream = BoxStream<'static, io::Result<Bytes>>;
+
+enum Source {
+    #[cfg(feature = "s3")]
+    S3(s3::Bucket),
+    WebDav(webdav::Share),
+}
+
+impl Source {
+    fn owns(&self, path: &Path) -> bool {
+        match *self {
+            #[cfg(feature = "s3")]
+            Source::S3(ref bucket) => bucket.key(path).is_some(),
+            Source::WebDav(ref share) => share.owns(path),
+        }
+    }
+
+    fn name(&self) -> String {
+        match *self {
+            #[cfg(feature = "s3")]
+            Source::S3(ref bucket) => bucket.url(),
+            Source::WebDav(ref share) => share.url(),
+        }
+    }
+
+    async fn list(&self) -> io::Result<Vec<Object>> {
+        match *self {
+            #[cfg(feature = "s3")]
+            Source::S3(ref bucket) => bucket.list().await,
+            Source::WebDav(ref share) => share.list().await,
+        }
+    }
+
+    async fn read_range(&self, path: &Path, range: Range<u64>) -> io::Result<Vec<u8>> {
+        match *self {
+            #[cfg(feature = "s3")]
+            Source::S3(ref bucket) => bucket.get_range(path, range).await,
+            Source::WebDav(ref share) => share.get_range(path, range).await,
+        }
+    }
+
+    #[cfg_attr(not(feature = "s3"), allow(unused_variables))]
+    async fn fetch(&self, path: &Path, proxy: bool) -> io::Result<Fetch> {
+        match *self {
+            #[cfg(feature = "s3")]
+            Source::S3(ref bucket) if !proxy => {
+                bucket.presigned_url(path).await.map(Fetch::Redirect)
+            }
+            _ => Ok(Fetch::Proxy),
+        }
+    }
+
+    async fn read(&self, path: &Path, range: Option<Range<u64>>) -> io::Result<ByteStream> {
+        match *self {
+            #[cfg(feature = "s3")]
+            Source::S3(ref bucket) => bucket.get(path, range).await,
+            Source::WebDav(ref share) => share.get(path, range).await,
+        }
+    }
+
+    /// Reads an MP3's tags from the start of the file.
+    async fn read_song(&self, object: &Object) -> io::Result<Song> {
+        let header = self
+            .read_range(&object.path, 0..object.size.min(10))
+            .await?;
+        let end = (id3_len(&header) + AUDIO_BYTES).min(object.size);
+        let start = self.read_range(&object.path, 0..end).await?;
+
+        let song = Mp3Reader.read_start(&start, object.size)?;
+        Ok(Song::from_tags(song, object.path.clone(), object.size))
+    }
+
+    /// Adds new and changed songs to the library, and drops those that are gone from the source.
+    async fn scan(&self, db: &mut MusicDB) -> io::Result<()> {
+        let objects = self.list().await?;
+
+        let known = db
+            .records
+            .values()
+            .filter(|s| self.owns(&s.path))
+            .map(|s| (s.path.clone(), (s.id, s.size)))
+            .collect::<HashMap<_, _>>();
+
+        for object in objects.iter().filter(|o| is_mp3(&o.path)) {
+            let old = known.get(&object.path);
+            if old.is_some_and(|&(_, size)| size == object.size) {
+                continue;
+            }
+
+            match self.read_song(object).await {
+                Ok(song) => db.add_scanned(old.map(|&(id, _)| id), song),
+                Err(e) => db.scan_error(&object.path, &e),
+            }
+        }
+
+        let listed = objects.iter().map(|o| &o.path).collect::<HashSet<_>>();
+        let before = db.records.len();
+        db.records
+            .retain(|_, s| !self.owns(&s.path) || listed.contains(&s.path));
+        if db.records.len() != before {
+            db.mark_changed();
+        }
+
+        Ok(())
+    }
+}
+
+/// Every remote source the server was started with.
+#[derive(Default)]
+pub struct RemoteSources {
+    sources: Vec<Source>,
+    /// Send files through the server, rather than redirecting clients to them
+    proxy: bool,
+}
+
+impl RemoteSources {
+    /// Sets up the buckets given by `--s3=` URLs, and the shares in `webdav.json`.
+    pub fn new(s3: &[String], proxy: bool) -> Result<Self, String> {
+        #[cfg(feature = "s3")]
+        let mut sources = s3
+            .iter()
+            .map(|url| s3::Bucket::new(url).map(Source::S3))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        #[cfg(not(feature = "s3"))]
+        let mut sources = match s3.first() {
+            Some(_) => return Err("this server was built without the `s3` feature".to_string()),
+            None => Vec::new(),
+        };
+
+        let shares: Vec<webdav::Config> = match File::open(WEBDAV_FILE) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))
+                .map_err(|e| format!("{WEBDAV_FILE}: {}", e))?,
+            Err(_) => Vec::new(),
+        };
+        for share in shares {
+            sources.push(Source::WebDav(webdav::Share::new(share)?));
+        }
+
+        Ok(RemoteSources { sources, proxy })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Scans every source. One that can't be listed is left as it was.
+    pub async fn scan(&self, db: &mut MusicDB) {
+        for source in &self.sources {
+            if let Err(e) = source.scan(db).await {
+                eprintln!("Unable to scan {}: {}", source.name(), e);
+            }
+        }
+    }
+
+    fn source(&self, path: &Path) -> io::Result<&Source> {
+        self.sources
+            .iter()
+            .find(|s| s.owns(path))
+            .ok_or_else(|| io::Error::other("its source wasn't given when starting the server"))
+    }
+
+    /// How to get a remote song to a client. Nothing is downloaded yet.
+    pub async fn fetch(&self, path: &Path) -> io::Result<Fetch> {
+        self.source(path)?.fetch(path, self.proxy).await
+    }
+
+    /// Streams a remote song, or with a range, only that part of it, to send through the server.
+    pub async fn read(&self, path: &Path, range: Option<Range<u64>>) -> io::Result<ByteStream> {
+        self.source(path)?.read(path, range).await
+    }
+}
+
+/// The bytes of `range` from `stream`, which has the whole file, for sources that send all of it
+/// whatever was asked for.
+fn slice(stream: ByteStream, range: Range<u64>) -> ByteStream {
+    let (mut skip, mut take) = (range.start, range.end - range.start);
+    stream
+        .map(move |chunk| {
+            chunk.map(|mut chunk| {
+                let skipped = skip.min(chunk.len() as u64);
+                skip -= skipped;
+                let chunk = chunk.split_off(skipped as usize);
+                let taken = take.min(chunk.len() as u64);
+                take -= taken;
+                chunk.slice(..taken as usize)
+            })
+        })
+        .filter(|chunk| std::future::ready(chunk.as_ref().map_or(true, |c| !c.is_empty())))
+        .boxed()
+}
+
+fn is_mp3(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("mp3"))
+}
+
+/// The length of the ID3v2 tag at the start of a file, from its 10-byte header; 0 if it has none.
+fn id3_len(header: &[u8]) -> u64 {
+    match *header {
+        [b'I', b'D', b'3', _, _, flags, a, b, c, d] => {
+            // The size is "synchsafe": 7 bits per byte
+            let size = [a, b, c, d]
+                .iter()
+                .fold(0, |size, &byte| (size << 7) | (byte & 0x7f) as u64);
+            let footer = if flags & 0x10 != 0 { 10 } else { 0 };
+            10 + size + footer
+        }
+        _ => 0,
+    }
+}
+
+#[cfg(feature = "s3")]
+mod s3 {
+    use super::{ByteStream, Object};
+    use futures::{StreamExt, TryStreamExt};
+    use object_store::{
+        aws::{AmazonS3, AmazonS3Builder},
+        path::Path as Key,
+        signer::Signer,
+        GetOptions, GetRange, ObjectStore,
+    };
+    use std::{
+        io,
+        ops::Range,
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    /// How long a presigned URL works for; long enough to get through a long track that's paused
+    const PRESIGNED_FOR: Duration = Duration::from_secs(6 * 60 * 60);
+
+    pub struct Bucket {
+        store: AmazonS3,
+        name: String,
+        prefix: Option<Key>,
+    }
+
+    fn io_error(e: object_store::Error) -> io::Error {
+        match e {
+            object_store::Error::NotFound { .. } => io::Error::new(io::ErrorKind::NotFound, e),
+            e => io::Error::other(e),
+        }
+    }
+
+    impl Bucket {
+        /// Sets up a bucket from a URL like `s3://bucket/prefix`.
+        pub fn new(url: &str) -> Result<Self, String> {
+            let rest = url
+                .strip_prefix("s3://")
+                .ok_or_else(|| format!("{} isn't an s3:// URL", url))?;
+            let (name, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+
+            let store = AmazonS3Builder::from_env()
+                .with_bucket_name(name)
+                .build()
+                .map_err(|e| format!("{}: {}", url, e))?;
+            let prefix = match prefix.trim_matches('/') {
+                "" => None,
+                prefix => Some(Key::parse(prefix).map_err(|e| format!("{}: {}", url, e))?),
+            };
+
+            Ok(Bucket {
+                store,
+                name: name.to_string(),
+                prefix,
+            })
+        }
+
+        pub fn url(&self) -> String {
+            match &self.prefix {
+                Some(prefix) => format!("s3://{}/{}", self.name, prefix),
+                None => format!("s3://{}", self.name),
+            }
+        }
+
+        fn path(&self, key: &Key) -> PathBuf {
+            PathBuf::from(format!("s3://{}/{}", self.name, key))
+        }
+
+        /// The object key of a song in this bucket, eg `album/song.mp3` for
+        /// `s3://bucket/album/song.mp3`.
+        pub fn key(&self, path: &Path) -> Option<Key> {
+            let key = path
+                .to_str()?
+                .strip_prefix("s3://")?
+                .strip_prefix(self.name.as_str())?
+                .strip_prefix('/')?;
+            Key::parse(key).ok()
+        }
+
+        fn key_of(&self, path: &Path) -> io::Result<Key> {
+            self.key(path)
+                .ok_or_else(|| io::Error::other(format!("{} isn't in this bucket", path.display())))
+        }
+
+        pub async fn list(&self) -> io::Result<Vec<Object>> {
+            self.store
+                .list(self.prefix.as_ref())
+                .map_ok(|meta| Object {
+                    path: self.path(&meta.location),
+                    size: meta.size,
+                })
+                .try_collect()
+                .await
+                .map_err(io_error)
+        }
+
+        pub async fn get_range(&self, path: &Path, range: Range<u64>) -> io::Result<Vec<u8>> {
+            let bytes = self
+                .store
+                .get_range(&self.key_of(path)?, range)
+                .await
+                .map_err(io_error)?;
+            Ok(bytes.to_vec())
+        }
+
+        pub async fn get(&self, path: &Path, range: Option<Range<u64>>) -> io::Result<ByteStream> {
+            let options = GetOptions {
+                range: range.map(GetRange::from),
+                ..Default::default()
+            };
+            let result = self
+                .store
+                .get_opts(&self.key_of(path)?, options)
+                .await
+                .map_err(io_error)?;
+            Ok(result.into_stream().map_err(io_error).boxed())
+        }
+
+        pub async fn presigned_url(&self, path: &Path) -> io::Result<String> {
+            let url = self
+                .store
+                .signed_url(reqwest::Method::GET, &self.key_of(path)?, PRESIGNED_FOR)
+                .await
+                .map_err(io_error)?;
+            Ok(url.to_string())
+        }
+    }
+}
+
+pub(crate) mod webdav {
+    use super::{ByteStream, Object};
+    use futures::StreamExt;
+    use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+    use reqwest::{header, Client, Method, RequestBuilder, StatusCode, Url};
+    use serde::Deserialize;
+    use std::{
+        collections::HashSet,
+        io,
+        ops::Range,
+        path::{Path, PathBuf},
+        time::Duration,
+    };
+
+    /// What's escaped in each segment of a path
+    const SEGMENT: &AsciiSet = &CONTROLS
+        .add(b' ')
+        .add(b'"')
+        .add(b'#')
+        .add(b'%')
+        .add(b'/')
+        .add(b'<')
+        .add(b'>')
+        .add(b'?')
+        .add(b'[')
+        .add(b'\\')
+        .add(b']')
+        .add(b'^')
+        .add(b'`')
+        .add(b'{')
+        .add(b'|')
+        .add(b'}');
+
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Asks for only what a scan needs to know about each file
+    const PROPFIND: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/><d:getcontentlength/></d:prop></d:propfind>"#;
+
+    /// One share in `webdav.json`.
+    #[derive(Deserialize)]
+    pub struct Config {
+        /// The directory to scan, eg `https://cloud.example.com/remote.php/dav/files/me/Music`
+        url: String,
+        #[serde(default)]
+        username: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    }
+
+    pub struct Share {
+        client: Client,
+        /// eg `https://cloud.example.com`
+        origin: String,
+        /// The directory's path, unescaped and ending in `/`, eg `/remote.php/dav/files/me/Music/`
+        directory: String,
+        username: Option<String>,
+        password: Option<String>,
+    }
+
+    /// A file or directory in a `PROPFIND` response.
+    #[derive(Debug, PartialEq, Eq)]
+    struct Entry {
+        /// Unescaped
+        path: String,
+        size: u64,
+        directory: bool,
+    }
+
+    fn io_error(e: reqwest::Error) -> io::Error {
+        match e.status() {
+            Some(StatusCode::NOT_FOUND) => io::Error::new(io::ErrorKind::NotFound, e),
+            _ => io::Error::other(e),
+        }
+    }
+
+    fn decode(path: &str) -> String {
+        percent_decode_str(path).decode_utf8_lossy().into_owned()
+    }
+
+    fn encode(path: &str) -> String {
+        path.split('/')
+            .map(|segment| utf8_percent_encode(segment, SEGMENT).to_string())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    impl Share {
+        pub fn new(config: Config) -> Result<Self, String> {
+            let url = Url::parse(&config.url).map_err(|e| format!("{}: {}", config.url, e))?;
+            if !matches!(url.scheme(), "http" | "https") {
+                return Err(format!("{} isn't an http(s) URL", config.url));
+            }
+
+            let mut directory = decode(url.path());
+            if !directory.ends_with('/') {
+                directory.push('/');
+            }
+            let client = Client::builder()
+                .connect_timeout(CONNECT_TIMEOUT)
+                .build()
+                .map_err(|e| e.to_string())?;
+
+            Ok(Share {
+                client,
+                origin: url.origin().ascii_serialization(),
+                directory,
+                username: config.username,
+                password: config.password,
+            })
+        }
+
+        pub fn url(&self) -> String {
+            format!("{}{}", self.origin, self.directory)
+        }
+
+        /// The unescaped path of a song in this share, eg `/dav/Music/Björk/Jóga.mp3` for
+        /// `https://cloud.example.com/dav/Music/Björk/Jóga.mp3`.
+        fn path_of<'a>(&self, path: &'a Path) -> Option<&'a str> {
+            path.to_str()?
+                .strip_prefix(self.origin.as_str())
+                .filter(|p| p.starts_with(&self.directory))
+        }
+
+        pub fn owns(&self, path: &Path) -> bool {
+            self.path_of(path).is_some()
+        }
+
+        fn request(&self, method: Method, path: &str) -> RequestBuilder {
+            let request = self
+                .client
+                .request(method, format!("{}{}", self.origin, encode(path)));
+            match &self.username {
+                Some(username) => request.basic_auth(username, self.password.as_ref()),
+                None => request,
+            }
+        }
+
+        fn get_request(&self, path: &Path) -> io::Result<RequestBuilder> {
+            let path = self.path_of(path).ok_or_else(|| {
+                io::Error::other(format!("{} isn't in this share", path.display()))
+            })?;
+            Ok(self.request(Method::GET, path))
+        }
+
+        /// Lists every file in the share, a directory at a time, since servers often refuse to
+        /// list a whole tree at once (`Depth: infinity`).
+        pub async fn list(&self) -> io::Result<Vec<Object>> {
+            let propfind = Method::from_bytes(b"PROPFIND").expect("PROPFIND is a valid method");
+
+            let mut objects = Vec::new();
+            let mut pending = vec![self.directory.clone()];
+            let mut listed = HashSet::new();
+            while let Some(directory) = pending.pop() {
+                if !listed.insert(directory.clone()) {
+                    continue;
+                }
+
+                let response = self
+                    .request(propfind.clone(), &directory)
+                    .header("depth", "1")
+                    .header(header::CONTENT_TYPE, "application/xml")
+                    .body(PROPFIND)
+                    .send()
+                    .await
+                    .and_then(|r| r.error_for_status())
+                    .map_err(io_error)?;
+                let body = response.text().await.map_err(io_error)?;
+
+                for entry in parse_multistatus(&body)? {
+                    // The directory itself is listed too
+                    if !entry.path.starts_with(&directory) || entry.path.len() <= directory.len() {
+                        continue;
+                    }
+
+                    if entry.directory {
+                        pending.push(format!("{}/", entry.path.trim_end_matches('/')));
+                    } else {
+                        objects.push(Object {
+                            path: PathBuf::from(format!("{}{}", self.origin, entry.path)),
+                            size: entry.size,
+                        });
+                    }
+                }
+            }
+
+            Ok(objects)
+        }
+
+        pub async fn get_range(&self, path: &Path, range: Range<u64>) -> io::Result<Vec<u8>> {
+            if range.is_empty() {
+                return Ok(Vec::new());
+            }
+
+            let response = self
+                .get_request(path)?
+                .header(
+                    header::RANGE,
+                    format!("bytes={}-{}", range.start, range.end - 1),
+                )
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(io_error)?;
+            let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+            let bytes = response.bytes().await.map_err(io_error)?;
+
+            if partial {
+                Ok(bytes.to_vec())
+            } else {
+                // The server ignored the range and sent the whole file
+                let end = (range.end as usize).min(bytes.len());
+                let start = (range.start as usize).min(end);
+                Ok(bytes[start..end].to_vec())
+            }
+        }
+
+        pub async fn get(&self, path: &Path, range: Option<Range<u64>>) -> io::Result<ByteStream> {
+            let mut request = self.get_request(path)?;
+            if let Some(range) = &range {
+                request = request.header(
+                    header::RANGE,
+                    format!("bytes={}-{}", range.start, range.end.saturating_sub(1)),
+                );
+            }
+            let response = request
+                .send()
+                .await
+                .and_then(|r| r.error_for_status())
+                .map_err(io_error)?;
+            let partial = response.status() == StatusCode::PARTIAL_CONTENT;
+            let stream = response.bytes_stream().map(|b| b.map_err(io_error)).boxed();
+
+            Ok(match range {
+                // The server ignored the range and is sending the whole file
+                Some(range) if !partial => super::slice(stream, range),
+                _ => stream,
+            })
+        }
+    }
+
+    fn is_dav(node: roxmltree::Node, name: &str) -> bool {
+        node.tag_name().namespace() == Some("DAV:") && node.tag_name().name() == name
+    }
+
+    /// Reads the files and directories out of a `PROPFIND` response.
+    fn parse_multistatus(xml: &str) -> io::Result<Vec<Entry>> {
+        let document = roxmltree::Document::parse(xml)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let entries = document
+            .descendants()
+            .filter(|n| is_dav(*n, "response"))
+            .filter_map(|response| {
+                let href = response.children().find(|n| is_dav(*n, "href"))?.text()?;
+                // Hrefs may be whole URLs or just paths
+                let path = match Url::parse(href.trim()) {
+                    Ok(url) => decode(url.path()),
+                    Err(_) => decode(href.trim()),
+                };
+
+                // Only the properties the server has, not those it says are missing
+                let props = response
+                    .children()
+                    .filter(|n| is_dav(*n, "propstat"))
+                    .filter(|propstat| {
+                        propstat
+                            .children()
+                            .find(|n| is_dav(*n, "status"))
+                            .and_then(|s| s.text())
+                            .is_none_or(|s| s.contains(" 200 "))
+                    })
+                    .flat_map(|propstat| propstat.children().filter(|n| is_dav(*n, "prop")))
+                    .flat_map(|prop| prop.children())
+                    .collect::<Vec<_>>();
+
+                let directory = props.iter().any(|p| {
+                    is_dav(*p, "resourcetype") && p.children().any(|n| is_dav(n, "collection"))
+                });
+                let size = props
+                    .iter()
+                    .find(|p| is_dav(**p, "getcontentlength"))
+                    .and_then(|p| p.text())
+                    .and_then(|s| s.trim().parse().ok())
+                    .unwrap_or(0);
+
+                Some(Entry {
+                    path,
+                    size,
+                    directory,
+                })
+            })
+            .collect();
+
+        Ok(entries)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn reads_nextcloud_listing() {
+            let xml = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:oc="http://owncloud.org/ns">
+  <d:response>
+    <d:href>/remote.php/dav/files/me/Music/</d:href>
+    <d:propstat>
+      <d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+    <d:propstat>
+      <d:prop><d:getcontentlength/></d:prop>
+      <d:status>HTTP/1.1 404 Not Found</d:status>
+    </d:propstat>
+  </d:response>
+  <d:response>
+    <d:href>/remote.php/dav/files/me/Music/Bj%c3%b6rk%20%231.mp3</d:href>
+    <d:propstat>
+      <d:prop><d:resourcetype/><d:getcontentlength>4096</d:getcontentlength></d:prop>
+      <d:status>HTTP/1.1 200 OK</d:status>
+    </d:propstat>
+  </d:response>
+</d:multistatus>"#;
+
+            assert_eq!(
+                parse_multistatus(xml).unwrap(),
+                vec![
+                    Entry {
+                        path: "/remote.php/dav/files/me/Music/".into(),
+                        size: 0,
+                        directory: true,
+                    },
+                    Entry {
+                        path: "/remote.php/dav/files/me/Music/Björk #1.mp3".into(),
+                        size: 4096,
+                        directory: false,
+                    },
+                ]
+            );
+            assert_eq!(
+                encode("/Music/Björk #1.mp3"),
+                "/Music/Bj%C3%B6rk%20%231.mp3"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn slices_whole_files_sent_for_a_range() {
+        let chunks = ["abcd", "efgh", "ij"].map(|c| Ok(Bytes::from(c)));
+        let sliced = slice(futures::stream::iter(chunks).boxed(), 3..9);
+        let sliced: Vec<Bytes> = sliced.map(Result::unwrap).collect().await;
+        assert_eq!(sliced, ["d", "efgh", "i"]);
+    }
+}
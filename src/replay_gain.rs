@@ -0,0 +1,148 @@
+//! ReplayGain: how much to turn each song up or down so that everything plays at about the same
+//! loudness, as measured by a tagger (eg `rsgain` or foobar2000) and stored in the song's tags.
+//!
+//! Gains are read from the `REPLAYGAIN_*` TXXX frames most taggers write, or failing those, from
+//! `R128_TRACK_GAIN`/`R128_ALBUM_GAIN`, which are measured against -23 LUFS rather than
+//! ReplayGain's -18 and so are 5 dB quieter.
+
+use serde::{Deserialize, Serialize};
+use std::hash::{Hash, Hasher};
+
+/// The difference between the R128 and ReplayGain reference levels, in dB
+const R128_OFFSET: f32 = 5.0;
+
+/// A song's stored gains, in dB, and peaks, where 1.0 is full scale.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    /// The same for every song on the album, to keep its quiet and loud songs as they were
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+// Songs are hashed for their ids; floats don't implement `Hash`, but their bits do
+impl Hash for ReplayGain {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for value in [
+            self.track_gain,
+            self.track_peak,
+            self.album_gain,
+            self.album_peak,
+        ] {
+            value.map(f32::to_bits).hash(state);
+        }
+    }
+}
+
+/// Which gain to apply.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum GainMode {
+    Off,
+    /// Each song's own gain, for shuffled or mixed playback
+    #[default]
+    Track,
+    /// The album's gain, falling back to the song's own
+    Album,
+}
+
+impl ReplayGain {
+    /// Reads a song's gains from its ID3 tag, if it has any.
+    pub fn from_id3(tag: &id3::Tag) -> Option<Self> {
+        Self::from_texts(
+            tag.extended_texts()
+                .map(|text| (text.description.as_str(), text.value.as_str())),
+        )
+    }
+
+    /// Reads a song's gains from its tags' keys and values (eg the descriptions and values of
+    /// ID3 TXXX frames, or APEv2 items), if it has any.
+    pub fn from_texts<'a>(texts: impl IntoIterator<Item = (&'a str, &'a str)>) -> Option<Self> {
+        let mut gain = ReplayGain::default();
+        let (mut r128_track, mut r128_album) = (None, None);
+        for (key, value) in texts {
+            let value = value.trim_end_matches('\0');
+            match key.to_uppercase().as_str() {
+                "REPLAYGAIN_TRACK_GAIN" => gain.track_gain = parse_gain(value),
+                "REPLAYGAIN_TRACK_PEAK" => gain.track_peak = value.trim().parse().ok(),
+                "REPLAYGAIN_ALBUM_GAIN" => gain.album_gain = parse_gain(value),
+                "REPLAYGAIN_ALBUM_PEAK" => gain.album_peak = value.trim().parse().ok(),
+                "R128_TRACK_GAIN" => r128_track = parse_r128(value),
+                "R128_ALBUM_GAIN" => r128_album = parse_r128(value),
+                _ => {}
+            }
+        }
+        gain.track_gain = gain.track_gain.or(r128_track);
+        gain.album_gain = gain.album_gain.or(r128_album);
+
+        (gain.track_gain.is_some() || gain.album_gain.is_some()).then_some(gain)
+    }
+
+    /// What to multiply samples by: the gain for `mode` plus `preamp` (in dB), and when
+    /// `prevent_clipping`, no more than keeps the peak at full scale. `None` if there's nothing to
+    /// apply.
+    pub fn factor(&self, mode: GainMode, preamp: f32, prevent_clipping: bool) -> Option<f32> {
+        let (gain, peak) = match mode {
+            GainMode::Off => return None,
+            GainMode::Track => (
+                self.track_gain.or(self.album_gain)?,
+                self.track_peak.or(self.album_peak),
+            ),
+            GainMode::Album => (
+                self.album_gain.or(self.track_gain)?,
+                self.album_peak.or(self.track_peak),
+            ),
+        };
+
+        let factor = 10f32.powf((gain + preamp) / 20.0);
+        Some(match peak.filter(|&peak| prevent_clipping && peak > 0.0) {
+            Some(peak) => factor.min(1.0 / peak),
+            None => factor,
+        })
+    }
+}
+
+/// Parses a ReplayGain value, eg "-6.54 dB".
+fn parse_gain(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let value = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value);
+    value.trim().parse().ok()
+}
+
+/// Parses an R128 gain, in 1/256 dB relative to -23 LUFS, as a ReplayGain value.
+fn parse_r128(value: &str) -> Option<f32> {
+    let q78: i16 = value.trim().parse().ok()?;
+    Some(f32::from(q78) / 256.0 + R128_OFFSET)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_gains() {
+        assert_eq!(parse_gain("-6.54 dB"), Some(-6.54));
+        assert_eq!(parse_gain("+2.10 dB"), Some(2.1));
+        assert_eq!(parse_gain(" 1.5"), Some(1.5));
+        assert_eq!(parse_gain("loud"), None);
+        assert_eq!(parse_r128("-512"), Some(3.0));
+    }
+
+    #[test]
+    fn limits_to_the_peak() {
+        let gain = ReplayGain {
+            track_gain: Some(6.0),
+            track_peak: Some(0.9),
+            ..Default::default()
+        };
+        let factor = gain.factor(GainMode::Track, 0.0, true).unwrap();
+        assert!((factor - 1.0 / 0.9).abs() < 1e-6);
+        let factor = gain.factor(GainMode::Album, 0.0, false).unwrap();
+        assert!((factor - 1.995).abs() < 1e-3);
+        assert_eq!(gain.factor(GainMode::Off, 0.0, true), None);
+    }
+}
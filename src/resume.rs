@@ -0,0 +1,170 @@
+//! Where listening stopped in each audiobook chapter or podcast episode, saved in `resume.json`
+//! so any client can pick up from there.
+
+use crate::devices::Devices;
+use crate::music_db::MusicDB;
+use crate::song::SongResult;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter},
+};
+
+pub(crate) const RESUME_FILE: &str = "resume.json";
+
+/// A position this close to the end, in seconds, counts as finished
+const FINISHED_WITHIN: f64 = 10.0;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Position {
+    /// Seconds into the song
+    pub position: f64,
+    /// When it was saved, in seconds since the Unix epoch
+    pub at: u64,
+    /// The registered device it was saved from, if any; see `devices`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<String>,
+}
+
+/// Resume positions, keyed by song id. Made with `default()`, eg in tests, they're only kept in
+/// memory.
+#[derive(Default)]
+pub struct ResumePositions {
+    positions: HashMap<u64, Position>,
+    /// Whether changes are saved to `resume.json`
+    saved: bool,
+}
+
+/// One entry of `/resume`.
+#[derive(Serialize)]
+pub struct InProgress {
+    pub song: SongResult,
+    #[serde(flatten)]
+    pub position: Position,
+    /// The name of the device it was saved from, if it was registered
+    pub device_name: Option<String>,
+}
+
+impl ResumePositions {
+    pub fn load() -> Self {
+        let positions = File::open(RESUME_FILE)
+            .ok()
+            .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+            .unwrap_or_default();
+
+        Self {
+            positions,
+            saved: true,
+        }
+    }
+
+    fn save(&self) {
+        if !self.saved {
+            return;
+        }
+        let saved = File::create(RESUME_FILE).and_then(|file| {
+            serde_json::to_writer(BufWriter::new(file), &self.positions)?;
+            Ok(())
+        });
+        if let Err(e) = saved {
+            eprintln!("Unable to save resume positions: {:?}", e);
+        }
+    }
+
+    /// Saves how far into a song listening got. Getting to (nearly) the end forgets it, so that
+    /// it starts from the beginning next time.
+    pub fn record(&mut self, id: u64, position: f64, duration: f64, device: Option<String>) {
+        if position <= 0.0 || position >= duration - FINISHED_WITHIN {
+            self.positions.remove(&id);
+        } else {
+            let at = crate::history::now();
+            self.positions.insert(
+                id,
+                Position {
+                    position,
+                    at,
+                    device,
+                },
+            );
+        }
+        self.save();
+    }
+
+    /// Every saved position, by song id.
+    pub fn positions(&self) -> &HashMap<u64, Position> {
+        &self.positions
+    }
+
+    /// Adds positions from elsewhere, eg an export, where they're newer than those saved. Returns
+    /// how many were. Positions that aren't a number of seconds (which JSON can't hold) are
+    /// skipped.
+    pub fn import(&mut self, positions: impl IntoIterator<Item = (u64, Position)>) -> usize {
+        let mut imported = 0;
+        for (id, position) in positions {
+            if !position.position.is_finite() {
+                continue;
+            }
+            if self.positions.get(&id).is_none_or(|p| p.at < position.at) {
+                self.positions.insert(id, position);
+                imported += 1;
+            }
+        }
+        if imported > 0 {
+            self.save();
+        }
+        imported
+    }
+
+    /// The songs listening stopped partway through, most recent first.
+    pub fn in_progress(&self, db: &MusicDB, devices: &Devices) -> Vec<InProgress> {
+        let mut in_progress = self
+            .positions
+            .iter()
+            .filter_map(|(id, position)| {
+                Some(InProgress {
+                    song: db.records.get(id)?.into(),
+                    device_name: position
+                        .device
+                        .as_deref()
+                        .and_then(|d| devices.name(d))
+                        .map(str::to_string),
+                    position: position.clone(),
+                })
+            })
+            .collect::<Vec<_>>();
+        in_progress.sort_unstable_by_key(|p| std::cmp::Reverse(p.position.at));
+
+        in_progress
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn imports_only_newer_positions_in_seconds() {
+        let position = |position, at| Position {
+            position,
+            at,
+            device: None,
+        };
+        let mut resume = ResumePositions::default();
+        resume.positions.insert(1, position(60.0, 100));
+
+        let imported = resume.import([
+            (1, position(30.0, 50)),
+            (2, position(f64::NAN, 100)),
+            (3, position(f64::INFINITY, 100)),
+            (4, position(90.0, 100)),
+        ]);
+        assert_eq!(imported, 1);
+        assert_eq!(resume.positions[&1].position, 60.0);
+        assert!(!resume.positions.contains_key(&2));
+        assert!(!resume.positions.contains_key(&3));
+        // What's saved can be loaded again
+        let saved = serde_json::to_string(&resume.positions).unwrap();
+        assert!(serde_json::from_str::<HashMap<u64, Position>>(&saved).is_ok());
+    }
+}
@@ -0,0 +1,9 @@
+use crate::themes::Theme;
+use askama::Template;
+
+#[derive(Template)]
+#[template(path = "room.html")]
+pub struct RoomPage {
+    pub theme: Theme,
+    pub name: String,
+}
@@ -0,0 +1,343 @@
+//! Listening rooms: a named queue, separate from the server's own, with one now-playing song and
+//! position that everyone in the room follows. Each listener streams the song from `/listen`
+//! themselves; the room's WebSocket (`/rooms/{name}/ws`) tells them what to play and where to be,
+//! and takes their play, pause, seek, skip, and enqueue commands.
+
+use bwaabwaa::{music_db::MusicDB, queue::PlayQueue, song::SongResult};
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+use tokio::sync::{broadcast, Mutex};
+use warp::ws::{Message, WebSocket};
+
+/// How many updates a listener can fall behind before it skips to the latest
+const UPDATE_BACKLOG: usize = 16;
+
+/// Numbers each room made, so one closed and opened again under its name isn't taken for it
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// The rooms, by name.
+pub type Rooms = BTreeMap<String, Room>;
+
+pub struct Room {
+    id: u64,
+    queue: PlayQueue,
+    song: Option<u64>,
+    /// Seconds into the song as of `since`
+    position: f64,
+    since: Instant,
+    playing: bool,
+    listeners: usize,
+    /// The room's state, as JSON, each time it changes
+    updates: broadcast::Sender<String>,
+}
+
+/// A room's state, as sent to its listeners and by `GET /rooms/{name}`.
+#[derive(Serialize)]
+pub struct RoomState {
+    pub name: String,
+    pub song: Option<SongResult>,
+    /// Seconds into the song, as of now
+    pub position: f64,
+    pub playing: bool,
+    pub queue: Vec<SongResult>,
+    pub listeners: usize,
+}
+
+/// A room, as listed by `GET /rooms`.
+#[derive(Serialize)]
+pub struct RoomSummary {
+    pub name: String,
+    pub song: Option<SongResult>,
+    pub listeners: usize,
+}
+
+/// What listeners can send, eg `{"command": "seek", "position": 61.5}`.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Command {
+    Play,
+    Pause,
+    Seek {
+        position: f64,
+    },
+    /// Skips to the next song in the room's queue
+    Next,
+    /// A listener reached the end of `song`. Every listener sends this, so it only moves on if
+    /// `song` is still the one playing.
+    Ended {
+        song: String,
+    },
+    Enqueue {
+        id: String,
+    },
+}
+
+impl Default for Room {
+    fn default() -> Self {
+        let (updates, _) = broadcast::channel(UPDATE_BACKLOG);
+        Room {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            queue: PlayQueue::default(),
+            song: None,
+            position: 0.0,
+            since: Instant::now(),
+            playing: false,
+            listeners: 0,
+            updates,
+        }
+    }
+}
+
+impl Room {
+    fn position(&self) -> f64 {
+        if self.playing {
+            self.position + self.since.elapsed().as_secs_f64()
+        } else {
+            self.position
+        }
+    }
+
+    fn seek(&mut self, position: f64) {
+        self.position = position.max(0.0);
+        self.since = Instant::now();
+    }
+
+    fn advance(&mut self, db: &MusicDB) {
+        self.song = self.queue.next(db);
+        self.playing = self.song.is_some();
+        self.seek(0.0);
+    }
+
+    /// Carries out a listener's command, or says why it can't.
+    pub fn apply(&mut self, command: Command, db: &MusicDB) -> Result<(), String> {
+        match command {
+            Command::Play if self.song.is_none() => self.advance(db),
+            Command::Play => {
+                self.seek(self.position());
+                self.playing = true;
+            }
+            Command::Pause => {
+                self.seek(self.position());
+                self.playing = false;
+            }
+            Command::Seek { position } => self.seek(position),
+            Command::Next => self.advance(db),
+            Command::Ended { song } => {
+                if self.song.is_some_and(|s| s.to_string() == song) {
+                    self.advance(db);
+                }
+            }
+            Command::Enqueue { id } => {
+                let id = id.parse().map_err(|_| format!("invalid id {}", id))?;
+                if !db.records.contains_key(&id) {
+                    return Err(format!("id={} not found", id));
+                }
+                self.queue.enqueue(id);
+                // An idle room starts with the first song it's given
+                if self.song.is_none() {
+                    self.advance(db);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn state(&self, name: &str, db: &MusicDB) -> RoomState {
+        RoomState {
+            name: name.to_string(),
+            song: self
+                .song
+                .and_then(|id| db.records.get(&id))
+                .map(SongResult::from),
+            position: self.position(),
+            playing: self.playing,
+            queue: self.queue.state(db).songs,
+            listeners: self.listeners,
+        }
+    }
+
+    pub fn summary(&self, name: &str, db: &MusicDB) -> RoomSummary {
+        RoomSummary {
+            name: name.to_string(),
+            song: self
+                .song
+                .and_then(|id| db.records.get(&id))
+                .map(SongResult::from),
+            listeners: self.listeners,
+        }
+    }
+
+    /// Sends the room's state to everyone in it.
+    pub fn broadcast(&self, name: &str, db: &MusicDB) {
+        if let Ok(state) = serde_json::to_string(&self.state(name, db)) {
+            // Fails only if no one's listening
+            let _ = self.updates.send(state);
+        }
+    }
+}
+
+/// Runs one listener's connection to a room, until they leave or the room is closed.
+pub async fn listen(
+    socket: WebSocket,
+    name: String,
+    rooms: Arc<Mutex<Rooms>>,
+    database: Arc<Mutex<MusicDB>>,
+) {
+    let (mut tx, mut rx) = socket.split();
+
+    let (id, mut updates) = {
+        let db = database.lock().await;
+        let mut rooms = rooms.lock().await;
+        let Some(room) = rooms.get_mut(&name) else {
+            return;
+        };
+        room.listeners += 1;
+        let updates = room.updates.subscribe();
+        room.broadcast(&name, &db);
+        (room.id, updates)
+    };
+
+    loop {
+        tokio::select! {
+            update = updates.recv() => match update {
+                Ok(state) => {
+                    if tx.send(Message::text(state)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            message = rx.next() => {
+                let Some(Ok(message)) = message else {
+                    break;
+                };
+                if message.is_close() {
+                    break;
+                }
+                let Ok(text) = message.to_str() else {
+                    continue;
+                };
+
+                let applied = match serde_json::from_str::<Command>(text) {
+                    Ok(command) => {
+                        let db = database.lock().await;
+                        let mut rooms = rooms.lock().await;
+                        match rooms.get_mut(&name).filter(|room| room.id == id) {
+                            Some(room) => room.apply(command, &db).map(|_| room.broadcast(&name, &db)),
+                            None => break,
+                        }
+                    }
+                    Err(e) => Err(e.to_string()),
+                };
+                if let Err(error) = applied {
+                    let error = serde_json::json!({ "error": error }).to_string();
+                    if tx.send(Message::text(error)).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    let db = database.lock().await;
+    let mut rooms = rooms.lock().await;
+    // Unless it was closed, and perhaps another opened under its name, which they weren't in
+    if let Some(room) = rooms.get_mut(&name).filter(|room| room.id == id) {
+        room.listeners -= 1;
+        room.broadcast(&name, &db);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bwaabwaa::song::Song;
+
+    fn db() -> MusicDB {
+        let mut db = MusicDB::default();
+        for id in [1, 2] {
+            db.records.insert(
+                id,
+                Song {
+                    id,
+                    ..Default::default()
+                },
+            );
+        }
+        db
+    }
+
+    #[test]
+    fn plays_pauses_and_seeks() {
+        let db = db();
+        let mut room = Room::default();
+        room.apply(Command::Enqueue { id: "1".into() }, &db)
+            .unwrap();
+        assert_eq!(room.song, Some(1));
+        assert!(room.playing);
+
+        room.apply(Command::Seek { position: 61.5 }, &db).unwrap();
+        room.apply(Command::Pause, &db).unwrap();
+        assert!(!room.playing);
+        assert!(room.position() >= 61.5);
+
+        let paused = room.position();
+        room.apply(Command::Play, &db).unwrap();
+        assert!(room.playing);
+        assert!(room.position() >= paused);
+
+        room.apply(Command::Seek { position: -5.0 }, &db).unwrap();
+        assert!(room.position() < 1.0);
+    }
+
+    #[test]
+    fn moves_on_once_the_song_has_ended() {
+        let db = db();
+        let mut room = Room::default();
+        room.apply(Command::Enqueue { id: "1".into() }, &db)
+            .unwrap();
+        room.apply(Command::Enqueue { id: "2".into() }, &db)
+            .unwrap();
+        assert_eq!(room.song, Some(1));
+
+        room.apply(Command::Ended { song: "1".into() }, &db)
+            .unwrap();
+        assert_eq!(room.song, Some(2));
+        // Every listener reports the end, but only the first moves it on
+        room.apply(Command::Ended { song: "1".into() }, &db)
+            .unwrap();
+        assert_eq!(room.song, Some(2));
+
+        room.apply(Command::Ended { song: "2".into() }, &db)
+            .unwrap();
+        assert_eq!(room.song, None);
+        assert!(!room.playing);
+    }
+
+    #[test]
+    fn enqueues_only_songs_in_the_library() {
+        let db = db();
+        let mut room = Room::default();
+        assert!(room
+            .apply(Command::Enqueue { id: "x".into() }, &db)
+            .is_err());
+        assert!(room
+            .apply(Command::Enqueue { id: "3".into() }, &db)
+            .is_err());
+        assert_eq!(room.song, None);
+    }
+
+    #[test]
+    fn rooms_opened_again_are_new() {
+        assert_ne!(Room::default().id, Room::default().id);
+    }
+}
@@ -0,0 +1,144 @@
+//! The directories music is scanned from, and their settings, saved in `roots.json`.
+//!
+//! Roots are added by `--scan=` and `--rescan=`; to change one's settings, edit `roots.json`
+//! while the server isn't running, eg
+//!
+//! ```json
+//! [
+//!     {"path": "/mnt/music", "nickname": "nas", "rescan": "startup", "read_only": true},
+//!     {"path": "/home/me/Audiobooks", "section": "audiobooks"}
+//! ]
+//! ```
+
+use crate::music_db::MusicDB;
+use crate::sections::Section;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Root {
+    /// The (canonicalized) directory
+    #[serde(with = "crate::paths::serde_path")]
+    pub path: PathBuf,
+    /// What to call it rather than its directory name, eg in `/browse` and `?root=`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nickname: Option<String>,
+    #[serde(default)]
+    pub rescan: RescanPolicy,
+    /// What kind of audio it holds
+    #[serde(default)]
+    pub section: Section,
+    /// Nothing should modify the files under it, eg because it's someone else's share
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+/// When a root is scanned, besides when it's given by `--scan=` or `--rescan=`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum RescanPolicy {
+    /// Only when asked to on the command line
+    #[default]
+    Manual,
+    /// For new files at every startup
+    Startup,
+    /// Every file, at every startup
+    Full,
+}
+
+impl Root {
+    pub fn new(path: PathBuf) -> Self {
+        Root {
+            path,
+            nickname: None,
+            rescan: RescanPolicy::default(),
+            section: Section::default(),
+            read_only: false,
+        }
+    }
+
+    /// Its nickname, or failing that, its directory name.
+    pub fn name(&self) -> &str {
+        self.nickname
+            .as_deref()
+            .or_else(|| self.path.file_name().and_then(|n| n.to_str()))
+            .unwrap_or_default()
+    }
+
+    /// Whether `path` is somewhere under it.
+    pub fn contains(&self, path: &Path) -> bool {
+        crate::paths::is_under(path, &self.path)
+    }
+}
+
+/// One entry of `/roots`. Paths are left out, as with `/browse`.
+#[derive(Serialize)]
+pub struct RootSummary {
+    pub name: String,
+    pub rescan: RescanPolicy,
+    pub section: Section,
+    pub read_only: bool,
+    pub songs: usize,
+}
+
+impl MusicDB {
+    /// Lists the roots, with how many songs came from each.
+    pub fn root_summaries(&self) -> Vec<RootSummary> {
+        self.roots
+            .iter()
+            .map(|root| RootSummary {
+                name: root.name().to_string(),
+                rescan: root.rescan,
+                section: root.section,
+                read_only: root.read_only,
+                songs: self
+                    .records
+                    .values()
+                    .filter(|s| s.root == root.path)
+                    .count(),
+            })
+            .collect()
+    }
+}
+
+/// How `roots.json` was saved: originally just a list of paths.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(crate) enum SavedRoot {
+    Path(PathBuf),
+    Root(Root),
+}
+
+/// Loads the roots saved by `save`, or none if there's no file.
+pub fn load(filename: &str) -> Vec<Root> {
+    let saved = File::open(filename)
+        .ok()
+        .and_then(|file| serde_json::from_reader::<_, Vec<SavedRoot>>(BufReader::new(file)).ok())
+        .unwrap_or_default();
+
+    saved
+        .into_iter()
+        .map(|root| match root {
+            SavedRoot::Path(path) => Root::new(path),
+            SavedRoot::Root(root) => root,
+        })
+        .collect()
+}
+
+pub fn save(roots: &[Root], filename: &str) -> Result<(), std::io::Error> {
+    let file = File::create(filename)?;
+    serde_json::to_writer_pretty(BufWriter::new(file), roots)?;
+    Ok(())
+}
+
+/// The root `path` is under. For nested roots, the innermost.
+pub fn find<'a>(roots: &'a [Root], path: &Path) -> Option<&'a Root> {
+    roots
+        .iter()
+        .filter(|root| root.contains(path))
+        .max_by_key(|root| root.path.components().count())
+}
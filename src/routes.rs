@@ -0,0 +1,359 @@
+//! The server's routes, by area, and the state they share.
+//!
+//! Each area has its own module with its handlers and the filters that route to them. The JSON
+//! endpoints are served at their unversioned paths and, for API keys, under `/api/v1`; `routes`
+//! puts them together with the pages and the static files.
+
+use crate::{
+    assets, auth,
+    auth::Auth,
+    cache::{self, Conditional, Validators},
+    error, pwa,
+    rooms::Rooms,
+    search::LibraryPageCache,
+    streams::{Stream, Streams},
+};
+use bwaabwaa::{
+    capabilities::Capabilities,
+    devices::Devices,
+    events::EventBus,
+    handoff::Handoffs,
+    history::PlayHistory,
+    jukebox::Jukebox,
+    music_db::MusicDB,
+    musicbrainz::MusicBrainz,
+    now_playing::Progress,
+    playlists::Playlists,
+    progress::{Listener, Listening},
+    queue::PlayQueue,
+    remote::RemoteSources,
+    resume::ResumePositions,
+    wishlist::Wishlist,
+};
+use serde::Deserialize;
+use std::{convert::Infallible, sync::Arc};
+use tokio::sync::Mutex;
+use warp::{filters::BoxedFilter, http::Response, Filter, Rejection, Reply};
+
+mod accounts;
+mod admin;
+mod devices;
+mod files;
+mod labels;
+mod library;
+mod playback;
+mod player;
+mod playlists;
+mod rooms;
+
+pub use player::start_jukebox;
+
+const JSON: &str = "application/json";
+
+/// What the handlers share.
+pub struct Server {
+    pub database: Arc<Mutex<MusicDB>>,
+    pub history: Arc<Mutex<PlayHistory>>,
+    pub queue: Arc<Mutex<PlayQueue>>,
+    pub resume: Arc<Mutex<ResumePositions>>,
+    pub playlists: Arc<Mutex<Playlists>>,
+    pub wishlist: Arc<Mutex<Wishlist>>,
+    pub auth: Auth,
+    pub remote: Arc<RemoteSources>,
+    pub audio_cache: Arc<Mutex<bwaabwaa::audio_cache::AudioCache>>,
+    pub throttle: Arc<crate::throttle::Throttle>,
+    pub streams: Arc<Streams>,
+    pub devices: Arc<Mutex<Devices>>,
+    pub handoffs: Arc<Mutex<Handoffs>>,
+    pub progress: Arc<Mutex<Progress>>,
+    pub listening: Arc<Mutex<Listening>>,
+    pub rooms: Arc<Mutex<Rooms>>,
+    pub musicbrainz: MusicBrainz,
+    pub events: EventBus,
+    pub jukebox: Option<Arc<Jukebox>>,
+    pub library_page: Arc<Mutex<LibraryPageCache>>,
+    #[cfg(feature = "graphql")]
+    pub schema: crate::graphql::Schema,
+    /// The port served on, for links back to the server
+    pub port: u16,
+}
+
+/// Passes a clone of `value` to each request's handler.
+fn with<T: Clone + Send + Sync + 'static>(
+    value: &T,
+) -> impl Filter<Extract = (T,), Error = Infallible> + Clone {
+    let value = value.clone();
+    warp::any().map(move || value.clone())
+}
+
+impl Server {
+    /// Passes requests that may use `/admin` and `/api/v1`.
+    fn admin(&self) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+        auth::authorized(self.auth.clone(), false)
+    }
+
+    /// Passes requests that may manage API keys, which needs a key even before there are any.
+    fn keys_admin(&self) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+        auth::authorized(self.auth.clone(), true)
+    }
+
+    fn who(&self) -> impl Filter<Extract = (String,), Error = Rejection> + Clone {
+        auth::who(self.auth.clone())
+    }
+
+    /// The registered device a request is from, by its `X-Device` header or `device=` parameter
+    fn device(&self) -> impl Filter<Extract = (Option<String>,), Error = Rejection> + Clone {
+        warp::header::optional::<String>("x-device")
+            .and(warp::query().map(|q: DeviceQuery| q.device))
+            .and(with(&self.devices))
+            .then(
+                |header: Option<String>, query: Option<String>, devices: Arc<Mutex<Devices>>| async move {
+                    let id = header.or(query)?;
+                    devices.lock().await.seen(&id)
+                },
+            )
+    }
+
+    /// Who's listening: the device, or failing that, the address
+    fn listener(&self) -> impl Filter<Extract = (Listener,), Error = Rejection> + Clone {
+        warp::addr::remote()
+            .and(self.device())
+            .map(|client, device| Listener::new(device, client))
+    }
+
+    /// Starts a stream of the song in `?id=`, refusing it if too many are streaming already.
+    fn stream(
+        &self,
+        kind: &'static str,
+    ) -> impl Filter<Extract = (Stream,), Error = Rejection> + Clone {
+        warp::addr::remote()
+            .and(self.device())
+            .and(warp::query().map(|q: IdQuery| q.id))
+            .and(with(&self.streams))
+            .and_then(
+                move |client, device, id, streams: Arc<Streams>| async move {
+                    streams.start(client, device, id, kind).ok_or_else(|| {
+                        error::unavailable("Too many songs are streaming right now; try again soon")
+                    })
+                },
+            )
+    }
+}
+
+/// Every route, at its unversioned path and under `/api/v1`.
+pub fn routes(server: &Server) -> BoxedFilter<(warp::reply::Response,)> {
+    let api = auth::api(server.auth.clone());
+    let events = with(&server.events);
+
+    let event_stream = warp::path!("events")
+        .and(warp::get())
+        .and(events.clone())
+        .map(|events: EventBus| events.sse());
+
+    let event_socket = warp::path!("events" / "ws")
+        .and(warp::ws())
+        .and(events)
+        .map(|ws: warp::ws::Ws, events: EventBus| ws.on_upgrade(|socket| events.stream(socket)));
+
+    #[cfg(feature = "graphql")]
+    let graphql = warp::path!("graphql")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(with(&server.schema))
+        .and_then(handle_graphql);
+    #[cfg(not(feature = "graphql"))]
+    let graphql = warp::path!("graphql").and_then(|| async {
+        Err::<warp::http::StatusCode, _>(error::not_found(
+            "this server was built without the `graphql` feature",
+        ))
+    });
+
+    let capabilities = warp::path!("capabilities")
+        .and(warp::get())
+        .and(with(&server.jukebox))
+        .and_then(handle_capabilities);
+
+    let assets = warp::path("static")
+        .and(warp::path::tail())
+        .and(cache::conditional())
+        .and_then(|tail: warp::path::Tail, conditional| async move {
+            assets::serve(tail.as_str(), &conditional)
+                .ok_or_else(|| error::not_found(format!("no such file: {}", tail.as_str())))
+        });
+
+    // These have to be at the root: browsers look for the favicon there, and a service worker
+    // only controls pages under its own path
+    let favicon = warp::path!("favicon.ico")
+        .and(cache::conditional())
+        .map(|conditional| root_asset("favicon.ico", conditional));
+
+    let service_worker = warp::path!("sw.js")
+        .and(cache::conditional())
+        .map(|conditional| root_asset("sw.js", conditional));
+
+    let manifest = warp::path!("manifest.json")
+        .and(cache::conditional())
+        .map(|conditional| {
+            static_file(
+                conditional,
+                "application/manifest+json",
+                pwa::manifest().as_bytes(),
+            )
+        });
+
+    let icon = warp::path!("icons" / String)
+        .and(cache::conditional())
+        .and_then(|name: String, conditional| async move {
+            name.strip_suffix(".png")
+                .and_then(|size| size.parse().ok())
+                .and_then(|size| pwa::icon(&assets::get("favicon.ico")?.data, size))
+                .map(|png| static_file(conditional, "image/png", png))
+                .ok_or_else(|| error::not_found(format!("no icon {}", name)))
+        });
+
+    let json = library::json(server)
+        .or(playback::json(server))
+        .or(capabilities)
+        .or(player::json(server))
+        .or(playlists::json(server))
+        .or(labels::json(server))
+        .or(rooms::json(server))
+        .or(devices::json(server))
+        .or(admin::json(server))
+        .map(Reply::into_response)
+        .boxed();
+
+    let api_v1 = warp::path!("api" / "v1" / ..).and(server.admin()).and(
+        player::api(server).or(json.clone()).or(pages(
+            server,
+            warp::any().map(|| Some(JSON.to_string())).boxed(),
+        )),
+    );
+
+    // The original, unversioned paths, kept as aliases
+    library::routes(server)
+        .or(accounts::routes(server))
+        .or(api_v1)
+        .or(rooms::socket(server))
+        .or(event_socket)
+        .or(event_stream)
+        .or(files::routes(server))
+        .or(pages(
+            server,
+            warp::header::optional::<String>("accept").boxed(),
+        ))
+        // After the pages, so that their own rejections are the ones reported
+        .or(api.clone().and(json))
+        .or(api.and(graphql))
+        .or(assets)
+        .or(favicon)
+        .or(manifest)
+        .or(icon)
+        .or(service_worker)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+/// The endpoints that render a page unless asked for JSON. Under /api/v1 they always give JSON.
+fn pages(
+    server: &Server,
+    accept: BoxedFilter<(Option<String>,)>,
+) -> BoxedFilter<(warp::reply::Response,)> {
+    library::pages(server, accept.clone())
+        .or(rooms::page(server, accept))
+        .unify()
+        .boxed()
+}
+
+fn root_asset(name: &str, conditional: Conditional) -> Response<Vec<u8>> {
+    let file = assets::get(name).expect("missing from static/");
+    assets::respond(&file, false, &conditional)
+}
+
+/// Serves something compiled into the server, with cache validators.
+fn static_file(conditional: Conditional, content_type: &str, bytes: &[u8]) -> Response<Vec<u8>> {
+    let validators = Validators::for_static(bytes);
+    if conditional.is_fresh(&validators) {
+        return cache::not_modified(&validators);
+    }
+
+    validators
+        .apply(Response::builder())
+        .header("content-type", content_type)
+        .body(bytes.to_vec())
+        .unwrap()
+}
+
+/// A `Content-Disposition` header that saves the response as `filename`: an ASCII fallback for
+/// old clients, and the real name percent-encoded (RFC 6266) for everyone else.
+fn content_disposition(filename: &str) -> String {
+    let fallback = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+
+    let encoded = filename
+        .bytes()
+        .map(|b| {
+            if b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&b) {
+                (b as char).to_string()
+            } else {
+                format!("%{:02X}", b)
+            }
+        })
+        .collect::<String>();
+
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+/// The `?id=` taken by most per-song endpoints. Kept as a string, since some also accept
+/// "whatsnew".
+#[derive(Deserialize)]
+struct IdQuery {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct AlbumQuery {
+    artist: String,
+    album: String,
+    /// Which year's album, when there's more than one of the same name; only for `/album`
+    year: Option<u16>,
+}
+
+#[derive(Deserialize)]
+struct DeviceQuery {
+    device: Option<String>,
+}
+
+/// Whether the client asked for JSON rather than a rendered page.
+fn wants_json(accept: &Option<String>) -> bool {
+    accept.as_deref().is_some_and(|a| a.contains(JSON))
+}
+
+async fn handle_capabilities(
+    jukebox: Option<Arc<Jukebox>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // Looking for `ffmpeg` the first time runs it
+    let capabilities = tokio::task::spawn_blocking(move || Capabilities::detect(jukebox.is_some()))
+        .await
+        .map_err(|e| error::internal(e.to_string()))?;
+    Ok(warp::reply::json(&capabilities))
+}
+
+#[cfg(feature = "graphql")]
+async fn handle_graphql(
+    request: async_graphql::Request,
+    schema: crate::graphql::Schema,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&schema.execute(request).await))
+}
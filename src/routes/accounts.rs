@@ -0,0 +1,400 @@
+//! Signing in and out, and guests signing in with a code.
+
+use askama::Template;
+use bwaabwaa::{
+    guest_codes::{GuestCodes, Refused},
+    history, sessions,
+};
+use serde::Deserialize;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use tokio::sync::Mutex;
+use warp::{
+    filters::BoxedFilter,
+    http::{Response, StatusCode},
+    Filter, Reply,
+};
+
+use super::{with, Server};
+use crate::{
+    auth::{Auth, CSRF_COOKIE, GUEST_COOKIE, LOGIN_COOKIE, SESSION_COOKIE},
+    error,
+    login_page::{GuestPage, LoginPage, LogoutPage},
+    themes::{self, ThemeChoice},
+};
+
+/// The login, logout and guest pages, and their forms.
+pub fn routes(server: &Server) -> BoxedFilter<(warp::reply::Response,)> {
+    let guests = with(&server.auth.guests);
+    let auth = with(&server.auth);
+
+    let login_page = warp::path!("login")
+        .and(warp::get())
+        .and(warp::query())
+        .and(themes::theme())
+        .and(auth.clone())
+        .and_then(handle_login_page);
+
+    let login = warp::path!("login")
+        .and(warp::post())
+        .and(warp::body::form())
+        .and(warp::cookie::optional::<String>(LOGIN_COOKIE))
+        .and(warp::addr::remote())
+        .and(themes::theme())
+        .and(warp::header::optional::<String>("x-forwarded-proto"))
+        .and(auth.clone())
+        .and_then(handle_login);
+
+    let logout_page = warp::path!("logout")
+        .and(warp::get())
+        .and(warp::cookie::optional::<String>(SESSION_COOKIE))
+        .and(themes::theme())
+        .and(auth.clone())
+        .and_then(handle_logout_page);
+
+    let logout = warp::path!("logout")
+        .and(warp::post())
+        .and(warp::body::form())
+        .and(warp::cookie::optional::<String>(SESSION_COOKIE))
+        .and(auth.clone())
+        .and_then(handle_logout);
+
+    let guest_page = warp::path!("guest")
+        .and(warp::get())
+        .and(themes::theme())
+        .map(|theme: ThemeChoice| {
+            let body = GuestPage {
+                theme: theme.theme,
+                error: String::new(),
+            }
+            .render()
+            .unwrap();
+            theme.remember(warp::reply::html(body).into_response())
+        });
+
+    let guest = warp::path!("guest")
+        .and(warp::post())
+        .and(warp::body::form())
+        .and(warp::addr::remote())
+        .and(themes::theme())
+        .and(guests.clone())
+        .and_then(handle_guest);
+
+    login_page
+        .or(login)
+        .or(logout_page)
+        .or(logout)
+        .or(guest_page)
+        .or(guest)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+#[derive(Deserialize)]
+struct LoginQuery {
+    next: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    user: String,
+    password: String,
+    /// Present if "remember me" was checked
+    remember: Option<String>,
+    next: Option<String>,
+    #[serde(default)]
+    csrf: String,
+}
+
+#[derive(Deserialize)]
+struct LogoutForm {
+    csrf: String,
+}
+
+/// Where to go after signing in: somewhere on this server, and by default, the library.
+fn local_path(next: Option<String>) -> String {
+    next.filter(|n| n.starts_with('/') && !n.starts_with("//") && !n.starts_with("/\\"))
+        .unwrap_or_else(|| "/".to_string())
+}
+
+fn see_other(location: &str) -> warp::reply::Response {
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header("location", location)
+        .body(Default::default())
+        .unwrap()
+}
+
+/// The sign-in page, with a new CSRF token for its form.
+async fn login_page(
+    theme: ThemeChoice,
+    error: &str,
+    next: String,
+    status: StatusCode,
+    auth: &Auth,
+) -> warp::reply::Response {
+    let (cookie, csrf) = auth.sessions.lock().await.login_csrf();
+    let body = LoginPage {
+        theme: theme.theme,
+        error: error.to_string(),
+        next,
+        csrf,
+    }
+    .render()
+    .unwrap();
+
+    let mut response = warp::reply::with_status(warp::reply::html(body), status).into_response();
+    let cookie = format!(
+        "{}={}; Path=/login; HttpOnly; SameSite=Strict",
+        LOGIN_COOKIE, cookie
+    );
+    response
+        .headers_mut()
+        .append("set-cookie", cookie.parse().unwrap());
+    theme.remember(response)
+}
+
+async fn handle_login_page(
+    query: LoginQuery,
+    theme: ThemeChoice,
+    auth: Auth,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let next = local_path(query.next);
+    Ok(login_page(theme, "", next, StatusCode::OK, &auth).await)
+}
+
+async fn handle_login(
+    form: LoginForm,
+    login_cookie: Option<String>,
+    client: Option<SocketAddr>,
+    theme: ThemeChoice,
+    forwarded_proto: Option<String>,
+    auth: Auth,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let next = local_path(form.next);
+    let genuine = auth
+        .sessions
+        .lock()
+        .await
+        .check_login_csrf(login_cookie.as_deref().unwrap_or_default(), &form.csrf);
+    if !genuine {
+        let error = "The sign-in form expired; try again";
+        return Ok(login_page(theme, error, next, StatusCode::FORBIDDEN, &auth).await);
+    }
+
+    let client = client.map_or(IpAddr::from([0, 0, 0, 0]), |c| c.ip());
+    if auth.logins.lock().await.refused(client) {
+        let error = "Too many wrong passwords; try again later";
+        return Ok(login_page(theme, error, next, StatusCode::TOO_MANY_REQUESTS, &auth).await);
+    }
+
+    let (user, exists) = auth.users.lock().await.for_sign_in(&form.user);
+    let password = form.password;
+    // Checking a password is slow on purpose, so keep it off the async threads
+    let valid = tokio::task::spawn_blocking(move || user.verify(&password) && exists)
+        .await
+        .unwrap_or(false);
+
+    if !valid {
+        auth.logins.lock().await.failed(client);
+        let error = "Wrong name or password";
+        return Ok(login_page(theme, error, next, StatusCode::UNAUTHORIZED, &auth).await);
+    }
+
+    let remember = form.remember.is_some();
+    let (session, csrf) = {
+        let mut sessions = auth.sessions.lock().await;
+        let session = sessions.start(&form.user, remember);
+        let csrf = sessions.csrf_token(&session);
+        (session, csrf)
+    };
+
+    let mut attributes = "Path=/; SameSite=Lax".to_string();
+    if remember {
+        attributes += &format!("; Max-Age={}", sessions::REMEMBERED_LIFETIME);
+    }
+    // Behind a proxy that terminates TLS, keep the cookies off plain HTTP
+    if forwarded_proto.as_deref() == Some("https") {
+        attributes += "; Secure";
+    }
+
+    let mut response = see_other(&next);
+    let cookies = [
+        format!("{}={}; HttpOnly; {}", SESSION_COOKIE, session, attributes),
+        // Readable by the pages' scripts, which send it back in an X-CSRF-Token header
+        format!("{}={}; {}", CSRF_COOKIE, csrf, attributes),
+        format!("{}=; Path=/login; Max-Age=0", LOGIN_COOKIE),
+    ];
+    for cookie in cookies {
+        response
+            .headers_mut()
+            .append("set-cookie", cookie.parse().unwrap());
+    }
+    Ok(response)
+}
+
+async fn handle_logout_page(
+    cookie: Option<String>,
+    theme: ThemeChoice,
+    auth: Auth,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let sessions = auth.sessions.lock().await;
+    let Some((cookie, session)) = cookie.and_then(|c| Some((c.clone(), sessions.get(&c)?))) else {
+        return Ok(see_other("/login"));
+    };
+
+    let body = LogoutPage {
+        theme: theme.theme,
+        user: session.user.clone(),
+        csrf: sessions.csrf_token(&cookie),
+    }
+    .render()
+    .unwrap();
+    Ok(theme.remember(warp::reply::html(body).into_response()))
+}
+
+async fn handle_logout(
+    form: LogoutForm,
+    cookie: Option<String>,
+    auth: Auth,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    if let Some(cookie) = cookie {
+        let mut sessions = auth.sessions.lock().await;
+        if !sessions.check_csrf(&cookie, &form.csrf) {
+            return Err(error::forbidden("missing or invalid CSRF token"));
+        }
+        sessions.end(&cookie);
+    }
+
+    let mut response = see_other("/login");
+    for name in [SESSION_COOKIE, CSRF_COOKIE] {
+        response.headers_mut().append(
+            "set-cookie",
+            format!("{}=; Path=/; Max-Age=0", name).parse().unwrap(),
+        );
+    }
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct GuestForm {
+    code: String,
+}
+
+async fn handle_guest(
+    form: GuestForm,
+    client: Option<SocketAddr>,
+    theme: ThemeChoice,
+    guests: Arc<Mutex<GuestCodes>>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let client = client.map_or(IpAddr::from([0, 0, 0, 0]), |c| c.ip());
+    let entered = guests.lock().await.enter(client, form.code.trim());
+    let entered = match entered {
+        Ok(entered) => entered,
+        Err(refused) => {
+            // The codes are short, so make guessing them slow
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            let (error, status) = match refused {
+                Refused::Invalid => (
+                    "That code isn't valid, or has expired",
+                    StatusCode::UNAUTHORIZED,
+                ),
+                Refused::TooManyAttempts => (
+                    "Too many wrong codes; try again later",
+                    StatusCode::TOO_MANY_REQUESTS,
+                ),
+            };
+            let body = GuestPage {
+                theme: theme.theme,
+                error: error.to_string(),
+            }
+            .render()
+            .unwrap();
+            return Ok(warp::reply::with_status(warp::reply::html(body), status).into_response());
+        }
+    };
+
+    // Strict, since guests' requests aren't CSRF-checked
+    let cookie = format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}",
+        GUEST_COOKIE,
+        entered.token,
+        entered.expires.saturating_sub(history::now())
+    );
+    let mut response = see_other("/");
+    response
+        .headers_mut()
+        .append("set-cookie", cookie.parse().unwrap());
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bwaabwaa::{attempts::Attempts, users::Users};
+
+    fn auth() -> Auth {
+        let mut users = Users::default();
+        users.set_password("me", "hunter2").unwrap();
+        Auth {
+            keys: Default::default(),
+            users: Arc::new(Mutex::new(users)),
+            sessions: Default::default(),
+            guests: Default::default(),
+            logins: Arc::new(Mutex::new(Attempts::new(2, 60))),
+            trust_local: false,
+        }
+    }
+
+    /// Signs in as `me` with the form's token, unless `cookie` is given in place of the form's own.
+    async fn sign_in(auth: &Auth, password: &str, cookie: Option<&str>) -> StatusCode {
+        let (form_cookie, csrf) = auth.sessions.lock().await.login_csrf();
+        let form = LoginForm {
+            user: "me".to_string(),
+            password: password.to_string(),
+            remember: None,
+            next: None,
+            csrf,
+        };
+        let cookie = cookie.unwrap_or(&form_cookie).to_string();
+        let client = "192.168.1.2:50000".parse().ok();
+        handle_login(
+            form,
+            Some(cookie),
+            client,
+            Default::default(),
+            None,
+            auth.clone(),
+        )
+        .await
+        .unwrap()
+        .status()
+    }
+
+    #[tokio::test]
+    async fn checks_the_forms_token() {
+        let auth = auth();
+        assert_eq!(
+            sign_in(&auth, "hunter2", Some("another")).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(sign_in(&auth, "hunter2", None).await, StatusCode::SEE_OTHER);
+    }
+
+    #[tokio::test]
+    async fn turns_away_clients_that_guess() {
+        let auth = auth();
+        for _ in 0..2 {
+            assert_eq!(
+                sign_in(&auth, "hunter3", None).await,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        assert_eq!(
+            sign_in(&auth, "hunter2", None).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+}
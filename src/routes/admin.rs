@@ -0,0 +1,653 @@
+//! Everything under `/admin`: reports on the library, its data and backups, API keys and guest
+//! codes, and the audit log.
+
+use bwaabwaa::{
+    admin,
+    api_keys::ApiKeys,
+    audit::{self, Action},
+    backup,
+    devices::Devices,
+    duplicates::{self, ResolveTerms},
+    explicit::KidMode,
+    genres::Genres,
+    guest_codes::{self, GuestCodes},
+    history::{self, PlayHistory},
+    music_db::MusicDB,
+    musicbrainz::MusicBrainz,
+    overrides::{ArtistMerge, ArtistSplit},
+    playlists::Playlists,
+    resume::ResumePositions,
+    sessions::Sessions,
+    user_data,
+    users::Users,
+    wishlist::Wishlist,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use warp::{
+    filters::BoxedFilter,
+    http::{Response, StatusCode},
+    Filter, Reply,
+};
+
+use super::{content_disposition, with, Server};
+use crate::{auth::Auth, error, streams::Streams};
+
+/// The `/admin` endpoints. Each checks that the caller may use them itself.
+pub fn json(server: &Server) -> BoxedFilter<(warp::reply::Response,)> {
+    let admin = server.admin();
+    let keys_admin = server.keys_admin();
+    let who = server.who();
+    let api_keys = with(&server.auth.keys);
+    let guests = with(&server.auth.guests);
+    let database = with(&server.database);
+    let history = with(&server.history);
+    let resume = with(&server.resume);
+    let playlists = with(&server.playlists);
+    let wishlist = with(&server.wishlist);
+    let auth = with(&server.auth);
+    let streams = with(&server.streams);
+    let devices = with(&server.devices);
+    let musicbrainz = with(&server.musicbrainz);
+
+    let low_bitrate = warp::path!("admin" / "low-bitrate")
+        .and(admin.clone())
+        .and(warp::query())
+        .and(database.clone())
+        .and_then(handle_low_bitrate);
+
+    let long_silences = warp::path!("admin" / "silence")
+        .and(admin.clone())
+        .and(warp::query())
+        .and(database.clone())
+        .and_then(handle_long_silences);
+
+    let unavailable = warp::path!("admin" / "unavailable")
+        .and(admin.clone())
+        .and(database.clone())
+        .and_then(handle_unavailable);
+
+    let incomplete_albums = warp::path!("admin" / "incomplete-albums")
+        .and(admin.clone())
+        .and(warp::query())
+        .and(database.clone())
+        .and(musicbrainz.clone())
+        .and_then(handle_incomplete_albums);
+
+    let duplicates = warp::path!("admin" / "duplicates")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(database.clone())
+        .and_then(handle_duplicates);
+
+    let duplicates_resolve = warp::path!("admin" / "duplicates" / "resolve")
+        .and(warp::post())
+        .and(admin.clone())
+        .and(warp::query())
+        .and(who.clone())
+        .and(database.clone())
+        .and(history.clone())
+        .and(playlists.clone())
+        .and_then(handle_duplicates_resolve);
+
+    let scan_errors = warp::path!("admin" / "scan-errors")
+        .and(admin.clone())
+        .and(database.clone())
+        .and_then(handle_scan_errors);
+
+    let active_streams = warp::path!("admin" / "streams")
+        .and(admin.clone())
+        .and(database.clone())
+        .and(streams.clone())
+        .and(devices.clone())
+        .and_then(handle_streams);
+
+    let audit_log = warp::path!("admin" / "audit")
+        .and(admin.clone())
+        .and(warp::query())
+        .and_then(handle_audit_log);
+
+    let keys_list = warp::path!("admin" / "keys")
+        .and(warp::get())
+        .and(keys_admin.clone())
+        .and(api_keys.clone())
+        .and_then(handle_keys_list);
+
+    let keys_create = warp::path!("admin" / "keys")
+        .and(warp::post())
+        .and(keys_admin.clone())
+        .and(warp::body::json())
+        .and(who.clone())
+        .and(api_keys.clone())
+        .and_then(handle_keys_create);
+
+    let keys_revoke = warp::path!("admin" / "keys" / String)
+        .and(warp::delete())
+        .and(keys_admin.clone())
+        .and(who.clone())
+        .and(api_keys.clone())
+        .and_then(handle_keys_revoke);
+
+    let guests_list = warp::path!("admin" / "guests")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(guests.clone())
+        .and_then(handle_guests_list);
+
+    let guests_create = warp::path!("admin" / "guests")
+        .and(warp::post())
+        .and(admin.clone())
+        .and(warp::body::json())
+        .and(who.clone())
+        .and(guests.clone())
+        .and_then(handle_guests_create);
+
+    let guests_expire = warp::path!("admin" / "guests" / String)
+        .and(warp::delete())
+        .and(admin.clone())
+        .and(who.clone())
+        .and(guests.clone())
+        .and_then(handle_guests_expire);
+
+    let data_export = warp::path!("admin" / "export")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(database.clone())
+        .and(history.clone())
+        .and(resume.clone())
+        .and_then(handle_data_export);
+
+    let data_import = warp::path!("admin" / "import")
+        .and(warp::post())
+        .and(admin.clone())
+        .and(warp::body::json())
+        .and(who.clone())
+        .and(database.clone())
+        .and(history.clone())
+        .and(resume.clone())
+        .and_then(handle_data_import);
+
+    let backup = warp::path!("admin" / "backup")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(database.clone())
+        .and_then(handle_backup);
+
+    let restore = warp::path!("admin" / "restore")
+        .and(warp::post())
+        // It replaces the accounts and keys
+        .and(keys_admin.clone())
+        .and(warp::body::content_length_limit(backup::MAX_SIZE))
+        .and(warp::body::bytes())
+        .and(who.clone())
+        .and(database.clone())
+        .and(history.clone())
+        .and(resume.clone())
+        .and(playlists.clone())
+        .and(wishlist.clone())
+        .and(devices.clone())
+        .and(auth.clone())
+        .and_then(handle_restore);
+
+    let kid_mode = warp::path!("admin" / "kid-mode")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(database.clone())
+        .and_then(handle_kid_mode);
+
+    let kid_mode_update = warp::path!("admin" / "kid-mode")
+        .and(warp::put())
+        .and(admin.clone())
+        .and(warp::body::json())
+        .and(who.clone())
+        .and(database.clone())
+        .and_then(handle_kid_mode_update);
+
+    let artist_aliases = warp::path!("admin" / "artists" / "aliases")
+        .and(warp::get())
+        .and(admin.clone())
+        .and(database.clone())
+        .and_then(handle_artist_aliases);
+
+    let artists_merge = warp::path!("admin" / "artists" / "merge")
+        .and(warp::post())
+        .and(admin.clone())
+        .and(warp::body::json())
+        .and(who.clone())
+        .and(database.clone())
+        .and_then(handle_artists_merge);
+
+    let artists_split = warp::path!("admin" / "artists" / "split")
+        .and(warp::post())
+        .and(admin.clone())
+        .and(warp::body::json())
+        .and(who.clone())
+        .and(database.clone())
+        .and_then(handle_artists_split);
+
+    low_bitrate
+        .or(long_silences)
+        .or(unavailable)
+        .or(incomplete_albums)
+        .or(duplicates)
+        .or(duplicates_resolve)
+        .or(scan_errors)
+        .or(active_streams)
+        .or(audit_log)
+        .or(keys_list)
+        .or(keys_create)
+        .or(keys_revoke)
+        .or(guests_list)
+        .or(guests_create)
+        .or(guests_expire)
+        .or(data_export)
+        .or(data_import)
+        .or(backup)
+        .or(restore)
+        .or(kid_mode)
+        .or(kid_mode_update)
+        .or(artist_aliases)
+        .or(artists_merge)
+        .or(artists_split)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+/// The albums missing tracks. With `?musicbrainz=true`, albums without track totals are looked up
+/// on MusicBrainz too, a few at a time; `unchecked` says how many are left for the next request.
+async fn handle_incomplete_albums(
+    terms: admin::IncompleteTerms,
+    database: Arc<Mutex<MusicDB>>,
+    musicbrainz: MusicBrainz,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // Don't hold up the library while waiting on MusicBrainz
+    let (mut albums, unchecked_albums) = database.lock().await.incomplete_albums();
+
+    let mut unchecked = 0;
+    if terms.musicbrainz.unwrap_or(false) {
+        let mut lookups = 0;
+        for album in unchecked_albums {
+            let counts = match musicbrainz.known(&album.artist, &album.album).await {
+                Some(counts) => counts,
+                None if lookups < admin::IncompleteTerms::MUSICBRAINZ_LOOKUPS => {
+                    lookups += 1;
+                    match musicbrainz.track_counts(&album.artist, &album.album).await {
+                        Ok(counts) => counts,
+                        Err(e) => {
+                            eprintln!("Unable to look up {} on MusicBrainz: {}", album.album, e);
+                            unchecked += 1;
+                            continue;
+                        }
+                    }
+                }
+                None => {
+                    unchecked += 1;
+                    continue;
+                }
+            };
+            albums.extend(counts.and_then(|counts| album.check(&counts)));
+        }
+        albums.sort_by(|a, b| {
+            let key =
+                |a: &admin::IncompleteAlbum| (a.artist.to_lowercase(), a.album.to_lowercase());
+            key(a).cmp(&key(b))
+        });
+    } else {
+        unchecked = unchecked_albums.len();
+    }
+
+    Ok(warp::reply::json(&admin::IncompleteAlbums {
+        albums,
+        unchecked,
+    }))
+}
+
+async fn handle_duplicates(
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    Ok(warp::reply::json(&duplicates::report(&db)))
+}
+
+/// Keeps the best copy of each duplicated song. Without `?apply=true`, only reports what it
+/// would do.
+async fn handle_duplicates_resolve(
+    terms: ResolveTerms,
+    who: String,
+    database: Arc<Mutex<MusicDB>>,
+    history: Arc<Mutex<PlayHistory>>,
+    playlists: Arc<Mutex<Playlists>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let resolved = duplicates::resolve(
+        &terms,
+        &mut *database.lock().await,
+        &mut *history.lock().await,
+        &mut *playlists.lock().await,
+    );
+
+    if resolved.applied {
+        audit::record(
+            &who,
+            Action::DuplicatesResolve,
+            format!(
+                "{} songs, {} files trashed",
+                resolved.resolutions.len(),
+                resolved.trashed.len()
+            ),
+        );
+    }
+    Ok(warp::reply::json(&resolved))
+}
+
+async fn handle_low_bitrate(
+    terms: admin::LowBitrateTerms,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    Ok(warp::reply::json(&db.low_bitrate(&terms)))
+}
+
+async fn handle_long_silences(
+    terms: admin::SilenceTerms,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    Ok(warp::reply::json(&db.long_silences(&terms)))
+}
+
+async fn handle_unavailable(
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    Ok(warp::reply::json(&db.unavailable()))
+}
+
+async fn handle_scan_errors(
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    Ok(warp::reply::json(&db.scan_errors()))
+}
+
+async fn handle_streams(
+    database: Arc<Mutex<MusicDB>>,
+    streams: Arc<Streams>,
+    devices: Arc<Mutex<Devices>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    let devices = devices.lock().await;
+    Ok(warp::reply::json(&streams.summaries(&db, &devices)))
+}
+
+async fn handle_data_export(
+    database: Arc<Mutex<MusicDB>>,
+    history: Arc<Mutex<PlayHistory>>,
+    resume: Arc<Mutex<ResumePositions>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let export = user_data::export(
+        &*database.lock().await,
+        &*history.lock().await,
+        &*resume.lock().await,
+    );
+    Ok(warp::reply::with_header(
+        warp::reply::json(&export),
+        "content-disposition",
+        content_disposition("bwaabwaa-export.json"),
+    ))
+}
+
+async fn handle_data_import(
+    export: user_data::Export,
+    who: String,
+    database: Arc<Mutex<MusicDB>>,
+    history: Arc<Mutex<PlayHistory>>,
+    resume: Arc<Mutex<ResumePositions>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let imported = user_data::import(
+        export,
+        &*database.lock().await,
+        &mut *history.lock().await,
+        &mut *resume.lock().await,
+    )
+    .map_err(error::bad_request)?;
+
+    audit::record(
+        &who,
+        Action::DataImport,
+        format!(
+            "{} plays, {} resume positions",
+            imported.plays, imported.resume_positions
+        ),
+    );
+    Ok(warp::reply::json(&imported))
+}
+
+async fn handle_backup(database: Arc<Mutex<MusicDB>>) -> Result<impl warp::Reply, warp::Rejection> {
+    // So the archive has anything changed since the last scan, eg songs found to be unavailable
+    database.lock().await.save();
+    let archive = backup::create()
+        .map_err(|e| error::internal(format!("unable to create the backup: {}", e)))?;
+
+    Ok(Response::builder()
+        .header("content-type", "application/x-tar")
+        .header(
+            "content-disposition",
+            content_disposition(&format!("bwaabwaa-backup-{}.tar", history::now())),
+        )
+        .body(archive)
+        .unwrap())
+}
+
+async fn handle_kid_mode(
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let kid_mode = database.lock().await.kid_mode;
+    Ok(warp::reply::json(&KidMode { kid_mode }))
+}
+
+async fn handle_kid_mode_update(
+    update: KidMode,
+    who: String,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    database.lock().await.set_kid_mode(update.kid_mode);
+    let what = if update.kid_mode { "on" } else { "off" };
+    audit::record(&who, Action::KidMode, what);
+    Ok(warp::reply::json(&update))
+}
+
+async fn handle_artist_aliases(
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    Ok(warp::reply::json(&db.overrides.aliases()))
+}
+
+async fn handle_artists_merge(
+    merge: ArtistMerge,
+    who: String,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if merge.into.trim().is_empty() || merge.artists.is_empty() {
+        return Err(error::bad_request(
+            "give the artists to merge, and into whom",
+        ));
+    }
+
+    let mut db = database.lock().await;
+    let names = merge
+        .artists
+        .iter()
+        .chain([&merge.into])
+        .map(|a| a.to_lowercase())
+        .collect::<Vec<_>>();
+    if !db.records.values().any(|s| names.contains(&s.artist_lower)) {
+        return Err(error::not_found(format!(
+            "artists not found: {}",
+            merge.artists.join(", ")
+        )));
+    }
+
+    let songs = db.merge_artists(&merge.artists, &merge.into).len();
+    audit::record(
+        &who,
+        Action::ArtistMerge,
+        format!("{} into {}", merge.artists.join(", "), merge.into),
+    );
+    Ok(warp::reply::json(&serde_json::json!({
+        "artist": merge.into,
+        "songs": songs,
+    })))
+}
+
+async fn handle_artists_split(
+    split: ArtistSplit,
+    who: String,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let artists = database.lock().await.split_artist(&split.artist);
+    if artists.is_empty() {
+        return Err(error::not_found(format!(
+            "no artists were merged into {}",
+            split.artist
+        )));
+    }
+    audit::record(
+        &who,
+        Action::ArtistSplit,
+        format!("{} from {}", artists.join(", "), split.artist),
+    );
+    Ok(warp::reply::json(&artists))
+}
+
+/// Restores from a backup, then reloads everything it replaced but the webhooks and WebDAV
+/// shares, which take effect once the server restarts.
+#[allow(clippy::too_many_arguments)]
+async fn handle_restore(
+    archive: warp::hyper::body::Bytes,
+    who: String,
+    database: Arc<Mutex<MusicDB>>,
+    history: Arc<Mutex<PlayHistory>>,
+    resume: Arc<Mutex<ResumePositions>>,
+    playlists: Arc<Mutex<Playlists>>,
+    wishlist: Arc<Mutex<Wishlist>>,
+    devices: Arc<Mutex<Devices>>,
+    auth: Auth,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    // Hold the library throughout, so nothing saves over the files while they're replaced
+    let mut db = database.lock().await;
+    let files = backup::restore(&archive).map_err(error::bad_request)?;
+
+    db.reload()
+        .map_err(|e| error::internal(format!("unable to reload the library: {}", e)))?;
+    db.set_genres(Genres::load());
+    *history.lock().await = PlayHistory::load();
+    *resume.lock().await = ResumePositions::load();
+    *playlists.lock().await = Playlists::load();
+    *wishlist.lock().await = Wishlist::load();
+    *devices.lock().await = Devices::load();
+    *auth.users.lock().await = Users::load().map_err(error::internal)?;
+    *auth.keys.lock().await = ApiKeys::load().map_err(error::internal)?;
+    *auth.guests.lock().await = GuestCodes::load();
+    *auth.sessions.lock().await = Sessions::load();
+
+    audit::record(&who, Action::Restore, files.join(", "));
+    Ok(warp::reply::json(&files))
+}
+
+/// The body of `POST /admin/keys`.
+#[derive(Deserialize)]
+struct NewKeyRequest {
+    name: String,
+    #[serde(default)]
+    user: String,
+}
+
+async fn handle_keys_list(
+    api_keys: Arc<Mutex<ApiKeys>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let api_keys = api_keys.lock().await;
+    Ok(warp::reply::json(&api_keys.list()))
+}
+
+async fn handle_keys_create(
+    request: NewKeyRequest,
+    who: String,
+    api_keys: Arc<Mutex<ApiKeys>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut api_keys = api_keys.lock().await;
+    let key = api_keys.create(request.name, request.user);
+    audit::record(&who, Action::KeyCreate, &key.id);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&key),
+        StatusCode::CREATED,
+    ))
+}
+
+async fn handle_keys_revoke(
+    id: String,
+    who: String,
+    api_keys: Arc<Mutex<ApiKeys>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut api_keys = api_keys.lock().await;
+    if !api_keys.revoke(&id) {
+        return Err(error::not_found(format!("no key with id {}", id)));
+    }
+    audit::record(&who, Action::KeyRevoke, id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The body of `POST /admin/guests`.
+#[derive(Deserialize)]
+struct NewGuestCodeRequest {
+    name: String,
+    /// How long it lasts
+    hours: Option<u64>,
+}
+
+async fn handle_guests_list(
+    guests: Arc<Mutex<GuestCodes>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let guests = guests.lock().await;
+    Ok(warp::reply::json(&guests.list()))
+}
+
+async fn handle_guests_create(
+    request: NewGuestCodeRequest,
+    who: String,
+    guests: Arc<Mutex<GuestCodes>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let hours = request.hours.unwrap_or(guest_codes::DEFAULT_HOURS);
+    if hours == 0 {
+        return Err(error::bad_request("hours must be at least 1"));
+    }
+
+    let mut guests = guests.lock().await;
+    let guest = guests.create(request.name, hours);
+    audit::record(&who, Action::GuestCodeCreate, &guest.name);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&guest),
+        StatusCode::CREATED,
+    ))
+}
+
+async fn handle_guests_expire(
+    code: String,
+    who: String,
+    guests: Arc<Mutex<GuestCodes>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut guests = guests.lock().await;
+    if !guests.expire(&code) {
+        return Err(error::not_found(format!("no guest code {}", code)));
+    }
+    audit::record(&who, Action::GuestCodeExpire, code);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn handle_audit_log(query: audit::AuditQuery) -> Result<impl warp::Reply, warp::Rejection> {
+    let entries = tokio::task::spawn_blocking(move || audit::read(&query))
+        .await
+        .map_err(|e| error::internal(e.to_string()))?;
+    Ok(warp::reply::json(&entries))
+}
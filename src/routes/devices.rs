@@ -0,0 +1,205 @@
+//! Registered devices, handing playback off between them, and what each has played.
+
+use bwaabwaa::{
+    devices::{DeviceName, Devices},
+    handoff::Handoffs,
+    history::PlayHistory,
+    music_db::MusicDB,
+    now_playing::Progress,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use warp::{filters::BoxedFilter, http::StatusCode, Filter, Reply};
+
+use super::{with, Server};
+use crate::error;
+
+/// Devices, handoffs, and recent plays.
+pub fn json(server: &Server) -> BoxedFilter<(warp::reply::Response,)> {
+    let device = server.device();
+    let database = with(&server.database);
+    let history = with(&server.history);
+    let devices = with(&server.devices);
+    let handoffs = with(&server.handoffs);
+    let progress = with(&server.progress);
+
+    let devices_list = warp::path!("devices")
+        .and(warp::get())
+        .and(devices.clone())
+        .and_then(handle_devices_list);
+
+    let devices_register = warp::path!("devices")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(devices.clone())
+        .and_then(handle_devices_register);
+
+    let devices_rename = warp::path!("devices" / String)
+        .and(warp::put())
+        .and(warp::body::json())
+        .and(devices.clone())
+        .and_then(handle_devices_rename);
+
+    let devices_remove = warp::path!("devices" / String)
+        .and(warp::delete())
+        .and(devices.clone())
+        .and_then(handle_devices_remove);
+
+    let handoff = warp::path!("handoff")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(device.clone())
+        .and(progress.clone())
+        .and(devices.clone())
+        .and(handoffs.clone())
+        .and_then(handle_handoff);
+
+    let handoff_poll = warp::path!("handoff")
+        .and(warp::get())
+        .and(device.clone())
+        .and(database.clone())
+        .and(devices.clone())
+        .and(handoffs.clone())
+        .and_then(handle_handoff_poll);
+
+    let recent_plays = warp::path!("history")
+        .and(warp::get())
+        .and(warp::query())
+        .and(database.clone())
+        .and(history.clone())
+        .and(devices.clone())
+        .and_then(handle_recent_plays);
+
+    devices_list
+        .or(devices_register)
+        .or(devices_rename)
+        .or(devices_remove)
+        .or(handoff)
+        .or(handoff_poll)
+        .or(recent_plays)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+async fn handle_devices_list(
+    devices: Arc<Mutex<Devices>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&devices.lock().await.list()))
+}
+
+async fn handle_devices_register(
+    body: DeviceName,
+    devices: Arc<Mutex<Devices>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if body.name.trim().is_empty() {
+        return Err(error::bad_request("a device needs a name"));
+    }
+    Ok(warp::reply::json(
+        &devices.lock().await.register(&body.name),
+    ))
+}
+
+async fn handle_devices_rename(
+    id: String,
+    body: DeviceName,
+    devices: Arc<Mutex<Devices>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if body.name.trim().is_empty() {
+        return Err(error::bad_request("a device needs a name"));
+    }
+    match devices.lock().await.rename(&id, &body.name) {
+        Some(device) => Ok(warp::reply::json(&device)),
+        None => Err(error::not_found(format!("no device with id {}", id))),
+    }
+}
+
+async fn handle_devices_remove(
+    id: String,
+    devices: Arc<Mutex<Devices>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if !devices.lock().await.remove(&id) {
+        return Err(error::not_found(format!("no device with id {}", id)));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The body of `POST /handoff`.
+#[derive(Deserialize)]
+struct HandoffRequest {
+    /// The device to hand off from; by default, the one asking
+    from: Option<String>,
+    to: String,
+}
+
+async fn handle_handoff(
+    request: HandoffRequest,
+    device: Option<String>,
+    progress: Arc<Mutex<Progress>>,
+    devices: Arc<Mutex<Devices>>,
+    handoffs: Arc<Mutex<Handoffs>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let from = request
+        .from
+        .or(device)
+        .ok_or_else(|| error::bad_request("which device to hand off from?"))?;
+    let devices = devices.lock().await;
+    for id in [&from, &request.to] {
+        if devices.name(id).is_none() {
+            return Err(error::not_found(format!("no device with id {}", id)));
+        }
+    }
+    if from == request.to {
+        return Err(error::bad_request("can't hand off to the same device"));
+    }
+
+    let (song, position, paused) = progress.lock().await.playing_on(&from).ok_or_else(|| {
+        let name = devices.name(&from).unwrap_or(&from);
+        error::not_found(format!("nothing is playing on {}", name))
+    })?;
+    handoffs
+        .lock()
+        .await
+        .start(&from, &request.to, song, position, paused);
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn handle_handoff_poll(
+    device: Option<String>,
+    database: Arc<Mutex<MusicDB>>,
+    devices: Arc<Mutex<Devices>>,
+    handoffs: Arc<Mutex<Handoffs>>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let device =
+        device.ok_or_else(|| error::bad_request("only registered devices can be handed off to"))?;
+    let db = database.lock().await;
+    let instruction = handoffs
+        .lock()
+        .await
+        .take(&device, &db, &*devices.lock().await);
+    Ok(match instruction {
+        Some(instruction) => warp::reply::json(&instruction).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    })
+}
+
+#[derive(Deserialize)]
+struct RecentQuery {
+    limit: Option<usize>,
+}
+
+async fn handle_recent_plays(
+    query: RecentQuery,
+    database: Arc<Mutex<MusicDB>>,
+    history: Arc<Mutex<PlayHistory>>,
+    devices: Arc<Mutex<Devices>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    const DEFAULT_LIMIT: usize = 50;
+    let db = database.lock().await;
+    let plays = history.lock().await.recent(
+        &db,
+        &*devices.lock().await,
+        query.limit.unwrap_or(DEFAULT_LIMIT),
+    );
+    Ok(warp::reply::json(&plays))
+}
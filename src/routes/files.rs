@@ -0,0 +1,620 @@
+//! Song files: streaming and downloading them, bundles of them, their art and previews.
+
+use bwaabwaa::{
+    analysis, art,
+    audio_cache::AudioCache,
+    bundle::{self, BundleRequest},
+    events::{EventBus, ServerEvent},
+    history::PlayHistory,
+    music_db::MusicDB,
+    paths, preview,
+    progress::{Listener, Listening},
+    remote::{ByteStream, Fetch, RemoteSources},
+    song, transcode,
+};
+use std::{path::PathBuf, sync::Arc};
+use tokio::sync::Mutex;
+use warp::{
+    filters::BoxedFilter,
+    http::{Method, Response, StatusCode},
+    Filter, Reply,
+};
+
+use super::{content_disposition, with, IdQuery, Server};
+use crate::{
+    cache::{self, Conditional, Validators},
+    error,
+    range::Requested,
+    throttle::{FileBody, Throttle},
+};
+
+/// BWAA-BWAA! WHAT'S NEW, PUSSYCAT?
+/// https://www.youtube.com/watch?v=Mw7Gryt-rcc
+pub const WHATS_NEW_PUSSYCAT: &[u8; 28797] = include_bytes!("../../What's new pussycat.mp3");
+
+/// Streams, downloads, bundles, art and previews.
+pub fn routes(server: &Server) -> BoxedFilter<(warp::reply::Response,)> {
+    let listener = server.listener();
+    let database = with(&server.database);
+    let history = with(&server.history);
+    let remote = with(&server.remote);
+    let audio_cache = with(&server.audio_cache);
+    let throttle = with(&server.throttle);
+    let listening = with(&server.listening);
+    let events = with(&server.events);
+
+    let listen = warp::path!("listen")
+        .and(server.stream("listen"))
+        .and(
+            warp::any()
+                .map(|| FileRequest::Listen)
+                .and(warp::query().map(|q: IdQuery| q.id))
+                .and(warp::method())
+                .and(cache::conditional())
+                .and(warp::header::optional::<String>("range"))
+                .and(listener.clone())
+                .and(listening.clone())
+                .and(database.clone())
+                .and(history.clone())
+                .and(events.clone())
+                .and(remote.clone())
+                .and(audio_cache.clone())
+                .and_then(handle_listen),
+        )
+        .and(throttle.clone())
+        .map(|stream, response, throttle: Arc<Throttle>| throttle.apply(response, stream));
+
+    let download = warp::path!("download")
+        .and(server.stream("download"))
+        .and(
+            warp::any()
+                .map(|| FileRequest::Download)
+                .and(warp::query().map(|q: IdQuery| q.id))
+                .and(warp::method())
+                .and(cache::conditional())
+                .and(warp::header::optional::<String>("range"))
+                .and(listener.clone())
+                .and(listening.clone())
+                .and(database.clone())
+                .and(history.clone())
+                .and(events.clone())
+                .and(remote.clone())
+                .and(audio_cache.clone())
+                .and_then(handle_listen),
+        )
+        .and(throttle.clone())
+        .map(|stream, response, throttle: Arc<Throttle>| throttle.apply(response, stream));
+
+    let export_bundle = warp::path!("export" / "bundle")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(database.clone())
+        .and_then(handle_export_bundle);
+
+    let whats_new = warp::path!("whatsnew").and_then(handle_whats_new);
+
+    let art = warp::path!("art")
+        .and(warp::query().map(|q: IdQuery| q.id))
+        .and(cache::conditional())
+        .and(database.clone())
+        .and_then(handle_art);
+
+    let preview = warp::path!("preview")
+        .and(warp::query().map(|q: IdQuery| q.id))
+        .and(cache::conditional())
+        .and(database.clone())
+        .and_then(handle_preview);
+
+    listen
+        .or(download)
+        .or(export_bundle)
+        .or(whats_new)
+        .or(art)
+        .or(preview)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+/// Whether a song file is being played or saved.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FileRequest {
+    Listen,
+    Download,
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_listen(
+    kind: FileRequest,
+    id: String,
+    method: Method,
+    conditional: Conditional,
+    range: Option<String>,
+    listener: Listener,
+    listening: Arc<Mutex<Listening>>,
+    database: Arc<Mutex<MusicDB>>,
+    history: Arc<Mutex<PlayHistory>>,
+    events: EventBus,
+    remote: Arc<RemoteSources>,
+    audio_cache: Arc<Mutex<AudioCache>>,
+) -> Result<Response<FileBody>, warp::Rejection> {
+    let mut db = database.lock().await;
+
+    // HEAD lets players probe the length before streaming; it shouldn't read the file or count
+    // as a play
+    let head = method == Method::HEAD;
+
+    if id == "whatsnew" {
+        let validators = Validators::for_static(WHATS_NEW_PUSSYCAT);
+        if conditional.is_fresh(&validators) {
+            return Ok(cache::not_modified(&validators).map(FileBody::from));
+        }
+
+        let mut builder = validators
+            .apply(Response::builder())
+            .header("content-type", "audio/mpeg")
+            .header("content-length", WHATS_NEW_PUSSYCAT.len())
+            .header("accept-ranges", "none");
+        if kind == FileRequest::Download {
+            builder = builder.header(
+                "content-disposition",
+                content_disposition("What's new pussycat.mp3"),
+            );
+        }
+        let body = if head {
+            Vec::new()
+        } else {
+            WHATS_NEW_PUSSYCAT.to_vec()
+        };
+        return Ok(builder.body(body.into()).unwrap());
+    }
+
+    let id = error::parse_id(&id)?;
+
+    let song = db
+        .records
+        .get_mut(&id)
+        .ok_or_else(|| error::not_found(format!("id={} not found", id)))?;
+    // Clients that report their progress have their plays counted from that instead
+    let counts_as_play =
+        kind == FileRequest::Listen && !head && !listening.lock().await.reports(&listener);
+
+    if paths::is_remote(&song.path) {
+        let path = song.path.clone();
+        let len = song.size;
+        let requested = Requested::from_header(range.as_deref(), len);
+        let counts_as_play = counts_as_play && requested.is_start();
+        // Don't hold up the whole library while the file is fetched
+        drop(db);
+        let fetched = remote.fetch(&path).await;
+        // HEAD only needs the headers, and a part is read from the source as it's asked for
+        let body = match &fetched {
+            Ok(Fetch::Proxy) if !head && requested != Requested::Unsatisfiable => {
+                // Sized as the scan saw it, or the range would be out
+                let cached = audio_cache
+                    .lock()
+                    .await
+                    .get(&path, None)
+                    .filter(|data| data.len() as u64 == len);
+                match (cached, &requested) {
+                    (Some(data), Requested::Part(range)) => Ok(FileBody::Whole(
+                        data[range.start as usize..range.end as usize].to_vec(),
+                    )),
+                    (Some(data), _) => Ok(FileBody::Whole(data)),
+                    (None, Requested::Part(range)) => {
+                        let part = range.clone();
+                        remote
+                            .read(&path, Some(part))
+                            .await
+                            .map(|data| FileBody::Streamed {
+                                len: range.end - range.start,
+                                data,
+                            })
+                    }
+                    (None, _) => {
+                        let keep = audio_cache.lock().await.takes(len);
+                        remote
+                            .read(&path, None)
+                            .await
+                            .map(|data| FileBody::Streamed {
+                                len,
+                                data: if keep {
+                                    caching(data, path.clone(), len, audio_cache.clone())
+                                } else {
+                                    data
+                                },
+                            })
+                    }
+                }
+            }
+            _ => Ok(FileBody::Whole(Vec::new())),
+        };
+
+        let mut db = database.lock().await;
+        let song = db
+            .records
+            .get_mut(&id)
+            .ok_or_else(|| error::not_found(format!("id={} not found", id)))?;
+        let (fetched, body) = match (fetched, body) {
+            (Ok(fetched), Ok(body)) => (fetched, body),
+            (Err(e), _) | (_, Err(e)) => return Err(unreadable(song, e)),
+        };
+
+        song.unavailable = None;
+        if counts_as_play {
+            history.lock().await.record(id, listener.device.clone());
+            listening.lock().await.listened(&listener, id);
+            events.publish(ServerEvent::NowPlaying {
+                song: Box::new((&*song).into()),
+            });
+        }
+
+        if let Fetch::Redirect(url) = fetched {
+            return Ok(Response::builder()
+                .status(StatusCode::TEMPORARY_REDIRECT)
+                .header("location", url)
+                .body(FileBody::Whole(Vec::new()))
+                .unwrap());
+        }
+        if requested == Requested::Unsatisfiable {
+            return Ok(Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header("content-range", format!("bytes */{}", len))
+                .body(FileBody::Whole(Vec::new()))
+                .unwrap());
+        }
+
+        let mut builder = Response::builder()
+            .header("content-type", song.content_type())
+            .header("accept-ranges", "bytes");
+        builder = match &requested {
+            Requested::Part(range) => builder
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(
+                    "content-range",
+                    format!("bytes {}-{}/{}", range.start, range.end - 1, len),
+                )
+                .header("content-length", range.end - range.start),
+            _ => builder.header("content-length", len),
+        };
+        if kind == FileRequest::Download {
+            builder = builder.header(
+                "content-disposition",
+                content_disposition(&download_name(song)),
+            );
+        }
+        return Ok(builder.body(body).unwrap());
+    }
+
+    // Formats browsers can't play are listened to transcoded, which takes a while the first time,
+    // so the library isn't held up meanwhile
+    let transcoding = kind == FileRequest::Listen && transcode::needed(song);
+    let original = song.path.clone();
+    let made = if transcoding {
+        drop(db);
+        let made = tokio::task::spawn_blocking(move || transcode::transcoded(id, &original))
+            .await
+            .map_err(|e| error::internal(e.to_string()))?;
+        db = database.lock().await;
+        Some(made)
+    } else {
+        None
+    };
+    let song = db
+        .records
+        .get_mut(&id)
+        .ok_or_else(|| error::not_found(format!("id={} not found", id)))?;
+    let (served, content_type) = match made {
+        Some(Ok(path)) => (path, transcode::CONTENT_TYPE),
+        Some(Err(e)) => return Err(unreadable(song, e)),
+        None => (song.path.clone(), song.content_type()),
+    };
+
+    let metadata = match std::fs::metadata(&served) {
+        Ok(m) => m,
+        Err(e) => return Err(unreadable(song, e)),
+    };
+    let validators = Validators::for_file(&metadata);
+    let requested = Requested::from_header(range.as_deref(), metadata.len());
+    // Seeking, as video players do, asks for a part further in; only the start counts as a play
+    let counts_as_play = counts_as_play && requested.is_start();
+
+    // The client's cached copy still counts as a play
+    if conditional.is_fresh(&validators) {
+        song.unavailable = None;
+        if counts_as_play {
+            history.lock().await.record(id, listener.device.clone());
+            listening.lock().await.listened(&listener, id);
+            events.publish(ServerEvent::NowPlaying {
+                song: Box::new((&*song).into()),
+            });
+        }
+        return Ok(cache::not_modified(&validators).map(FileBody::from));
+    }
+
+    if requested == Requested::Unsatisfiable {
+        return Ok(Response::builder()
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header("content-range", format!("bytes */{}", metadata.len()))
+            .body(Vec::new().into())
+            .unwrap());
+    }
+
+    let body = if head {
+        Vec::new()
+    } else if let Requested::Part(range) = &requested {
+        // Parts are read straight from the file, since a video may well be too big to cache
+        match read_range(&served, range) {
+            Ok(data) => data,
+            Err(e) => return Err(unreadable(song, e)),
+        }
+    } else {
+        let modified = metadata.modified().ok();
+        let cached = audio_cache.lock().await.get(&served, modified);
+        match cached {
+            Some(data) => data,
+            None => match std::fs::read(&served) {
+                Ok(f) => {
+                    audio_cache.lock().await.insert(&served, modified, &f);
+                    f
+                }
+                Err(e) => return Err(unreadable(song, e)),
+            },
+        }
+    };
+
+    song.unavailable = None;
+    if counts_as_play {
+        history.lock().await.record(id, listener.device.clone());
+        listening.lock().await.listened(&listener, id);
+        events.publish(ServerEvent::NowPlaying {
+            song: Box::new((&*song).into()),
+        });
+    }
+
+    let mut builder = validators
+        .apply(Response::builder())
+        .header("content-type", content_type)
+        .header("accept-ranges", "bytes");
+    builder = match &requested {
+        Requested::Part(range) => builder
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(
+                "content-range",
+                format!("bytes {}-{}/{}", range.start, range.end - 1, metadata.len()),
+            )
+            .header("content-length", range.end - range.start),
+        _ => builder.header("content-length", metadata.len()),
+    };
+    if kind == FileRequest::Download {
+        builder = builder.header(
+            "content-disposition",
+            content_disposition(&download_name(song)),
+        );
+    }
+
+    Ok(builder.body(body.into()).unwrap())
+}
+
+/// Passes a remote file on, keeping a copy in the audio cache once it's all come.
+fn caching(
+    data: ByteStream,
+    path: PathBuf,
+    len: u64,
+    audio_cache: Arc<Mutex<AudioCache>>,
+) -> ByteStream {
+    use futures::StreamExt;
+
+    let kept = Vec::with_capacity(len as usize);
+    futures::stream::unfold(
+        (data, kept, Some(audio_cache)),
+        move |(mut data, mut kept, audio_cache)| {
+            let path = path.clone();
+            async move {
+                match data.next().await {
+                    Some(Ok(chunk)) => {
+                        kept.extend_from_slice(&chunk);
+                        Some((Ok(chunk), (data, kept, audio_cache)))
+                    }
+                    // Part of a file isn't worth keeping
+                    Some(Err(e)) => Some((Err(e), (data, Vec::new(), None))),
+                    None => {
+                        if let Some(audio_cache) = audio_cache {
+                            audio_cache.lock().await.insert(&path, None, &kept);
+                        }
+                        None
+                    }
+                }
+            }
+        },
+    )
+    .boxed()
+}
+
+/// Reads part of a file.
+fn read_range(path: &std::path::Path, range: &std::ops::Range<u64>) -> std::io::Result<Vec<u8>> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut file = std::fs::File::open(path)?;
+    file.seek(SeekFrom::Start(range.start))?;
+    let mut data = Vec::with_capacity((range.end - range.start) as usize);
+    file.take(range.end - range.start).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// The file name to save a song as.
+fn download_name(song: &song::Song) -> String {
+    song.path
+        .file_name()
+        .map_or("song.mp3".into(), |f| f.to_string_lossy().into_owned())
+}
+
+/// Marks a song whose file couldn't be read as unavailable, and rejects the request.
+fn unreadable(song: &mut song::Song, e: std::io::Error) -> warp::Rejection {
+    eprintln!("Error with file {}: {:?}", song.path.display(), e);
+    song.unavailable = Some(e.to_string());
+
+    // A missing file is gone; anything else (a NAS that's offline, a permissions change) may
+    // well come back
+    if e.kind() == std::io::ErrorKind::NotFound {
+        error::not_found(format!("file for id={} not found", song.id))
+    } else {
+        error::unavailable(format!("unable to read file for id={}: {}", song.id, e))
+    }
+}
+
+/// Bundles songs into a ZIP for copying onto a device. It's written to a temporary file first,
+/// since it may well be too big to hold in memory.
+async fn handle_export_bundle(
+    request: BundleRequest,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<Response<warp::hyper::Body>, warp::Rejection> {
+    let entries = bundle::select(&*database.lock().await, &request).map_err(error::bad_request)?;
+
+    let path =
+        std::env::temp_dir().join(format!("bwaabwaa-bundle-{:x}.zip", rand::random::<u64>()));
+    let file = std::fs::File::create(&path)
+        .map_err(|e| error::internal(format!("unable to create {}: {}", path.display(), e)))?;
+    let written = tokio::task::spawn_blocking(move || {
+        bundle::write(&entries, request.format, request.kbps, file)
+    })
+    .await
+    .map_err(|e| error::internal(e.to_string()))
+    .and_then(|written| written.map_err(error::internal));
+    let opened = match written {
+        Ok(written) => {
+            println!(
+                "Bundled {} songs ({} left out)",
+                written.songs, written.skipped
+            );
+            tokio::fs::File::open(&path).await
+        }
+        Err(e) => {
+            std::fs::remove_file(&path).ok();
+            return Err(e);
+        }
+    };
+    let (mut file, size) = match opened {
+        Ok(file) => {
+            let size = file.metadata().await.map(|m| m.len()).unwrap_or_default();
+            (file, size)
+        }
+        Err(e) => {
+            std::fs::remove_file(&path).ok();
+            return Err(error::internal(format!("unable to read the bundle: {}", e)));
+        }
+    };
+
+    let (mut sender, body) = warp::hyper::Body::channel();
+    tokio::spawn(async move {
+        use tokio::io::AsyncReadExt;
+        let mut chunk = vec![0; 64 * 1024];
+        while let Ok(read @ 1..) = file.read(&mut chunk).await {
+            let data = warp::hyper::body::Bytes::copy_from_slice(&chunk[..read]);
+            if sender.send_data(data).await.is_err() {
+                // The client went away
+                break;
+            }
+        }
+        drop(file);
+        std::fs::remove_file(&path).ok();
+    });
+
+    Ok(Response::builder()
+        .header("content-type", "application/zip")
+        .header("content-length", size)
+        .header(
+            "content-disposition",
+            content_disposition("bwaabwaa-bundle.zip"),
+        )
+        .body(body)
+        .unwrap())
+}
+
+async fn handle_preview(
+    id: String,
+    conditional: Conditional,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let id = error::parse_id(&id)?;
+    let (path, duration) = {
+        let db = database.lock().await;
+        let song = db
+            .records
+            .get(&id)
+            .ok_or_else(|| error::not_found(format!("id={} not found", id)))?;
+        if !analysis::decodable(song) {
+            return Err(error::bad_request(format!(
+                "previews can only be made of local MP3s, not {}",
+                song.path.display()
+            )));
+        }
+        (song.path.clone(), song.duration)
+    };
+
+    // Making a preview means decoding part of the song, so it's done off the async runtime
+    let made = tokio::task::spawn_blocking(move || preview::preview(id, &path, duration))
+        .await
+        .map_err(|e| error::internal(e.to_string()))?;
+    let preview =
+        made.map_err(|e| error::internal(format!("unable to make a preview of id={}: {}", id, e)))?;
+
+    let validators = std::fs::metadata(&preview)
+        .map(|m| Validators::for_file(&m))
+        .map_err(|e| error::internal(format!("unable to read the preview of id={}: {}", id, e)))?;
+    if conditional.is_fresh(&validators) {
+        return Ok(cache::not_modified(&validators));
+    }
+    match std::fs::read(&preview) {
+        Ok(bytes) => Ok(validators
+            .apply(Response::builder())
+            .header("content-type", "audio/wav")
+            .body(bytes)
+            .unwrap()),
+        Err(e) => Err(error::internal(format!(
+            "unable to read the preview of id={}: {}",
+            id, e
+        ))),
+    }
+}
+
+async fn handle_art(
+    id: String,
+    conditional: Conditional,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+
+    let id = error::parse_id(&id)?;
+    let song = db
+        .records
+        .get(&id)
+        .ok_or_else(|| error::not_found(format!("id={} not found", id)))?;
+    let cover = art::find_cover(std::path::Path::new(&song.path))
+        .ok_or_else(|| error::not_found(format!("no cover art for id={}", id)))?;
+
+    let validators = std::fs::metadata(&cover)
+        .map(|m| Validators::for_file(&m))
+        .map_err(|e| error::internal(format!("unable to read cover art for id={}: {}", id, e)))?;
+    if conditional.is_fresh(&validators) {
+        return Ok(cache::not_modified(&validators));
+    }
+
+    match std::fs::read(&cover) {
+        Ok(bytes) => Ok(validators
+            .apply(Response::builder())
+            .header("content-type", art::content_type(&cover))
+            .body(bytes)
+            .unwrap()),
+        Err(e) => Err(error::internal(format!(
+            "unable to read cover art for id={}: {}",
+            id, e
+        ))),
+    }
+}
+
+async fn handle_whats_new() -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(Response::builder()
+        .header("content-type", "audio/mpeg")
+        .body(WHATS_NEW_PUSSYCAT.to_vec())
+        .unwrap())
+}
@@ -0,0 +1,335 @@
+//! What's said about songs and albums besides their tags: labels, hiding, explicit marks, and
+//! album overrides.
+
+use bwaabwaa::{
+    audit::{self, Action},
+    labels::LabelChanges,
+    music_db::MusicDB,
+    overrides::AlbumOverride,
+    song::SongResult,
+};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use warp::{filters::BoxedFilter, Filter, Reply};
+
+use super::{with, AlbumQuery, Server};
+use crate::error;
+
+/// Labels, moods, hiding, explicit marks, and album overrides.
+pub fn json(server: &Server) -> BoxedFilter<(warp::reply::Response,)> {
+    let who = server.who();
+    let database = with(&server.database);
+
+    let labels_list = warp::path!("labels")
+        .and(warp::get())
+        .and(database.clone())
+        .and_then(handle_labels);
+
+    let moods = warp::path!("moods")
+        .and(warp::get())
+        .and(database.clone())
+        .and_then(handle_moods);
+
+    let song_labels = warp::path!("song" / String / "labels")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(who.clone())
+        .and(database.clone())
+        .and_then(handle_song_labels);
+
+    let album_labels = warp::path!("album" / "labels")
+        .and(warp::post())
+        .and(warp::query())
+        .and(warp::body::json())
+        .and(who.clone())
+        .and(database.clone())
+        .and_then(handle_album_labels);
+
+    // PUT hides, and DELETE shows again
+    let song_hide = warp::path!("song" / String / "hide")
+        .and(
+            warp::put()
+                .map(|| true)
+                .or(warp::delete().map(|| false))
+                .unify(),
+        )
+        .and(who.clone())
+        .and(database.clone())
+        .and_then(handle_song_hide);
+
+    let album_hide = warp::path!("album" / "hide")
+        .and(
+            warp::put()
+                .map(|| true)
+                .or(warp::delete().map(|| false))
+                .unify(),
+        )
+        .and(warp::query())
+        .and(who.clone())
+        .and(database.clone())
+        .and_then(handle_album_hide);
+
+    // PUT marks explicit, and DELETE marks clean, whatever the tags say
+    let song_explicit = warp::path!("song" / String / "explicit")
+        .and(
+            warp::put()
+                .map(|| true)
+                .or(warp::delete().map(|| false))
+                .unify(),
+        )
+        .and(who.clone())
+        .and(database.clone())
+        .and_then(handle_song_explicit);
+
+    let album_explicit = warp::path!("album" / "explicit")
+        .and(
+            warp::put()
+                .map(|| true)
+                .or(warp::delete().map(|| false))
+                .unify(),
+        )
+        .and(warp::query())
+        .and(who.clone())
+        .and(database.clone())
+        .and_then(handle_album_explicit);
+
+    let album_overrides = warp::path!("album" / "overrides")
+        .and(warp::get())
+        .and(database.clone())
+        .and_then(handle_album_overrides);
+
+    // PUT sets what to show in place of the album's tags, and DELETE goes back to them
+    let album_override_set = warp::path!("album" / "override")
+        .and(warp::put())
+        .and(warp::query())
+        .and(warp::body::json())
+        .and(who.clone())
+        .and(database.clone())
+        .and_then(|query, set, who, database| {
+            handle_album_override(query, Some(set), who, database)
+        });
+
+    let album_override_remove = warp::path!("album" / "override")
+        .and(warp::delete())
+        .and(warp::query())
+        .and(who)
+        .and(database.clone())
+        .and_then(|query, who, database| handle_album_override(query, None, who, database));
+
+    labels_list
+        .or(moods)
+        .or(song_labels)
+        .or(album_labels)
+        .or(song_hide)
+        .or(album_hide)
+        .or(song_explicit)
+        .or(album_explicit)
+        .or(album_overrides)
+        .or(album_override_set)
+        .or(album_override_remove)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+/// An album, as the audit log names it.
+fn album(query: &AlbumQuery) -> String {
+    format!("{} by {}", query.album, query.artist)
+}
+
+fn hidden_or_shown(hidden: bool) -> &'static str {
+    if hidden {
+        "hidden"
+    } else {
+        "shown"
+    }
+}
+
+fn explicit_or_clean(explicit: bool) -> &'static str {
+    if explicit {
+        "explicit"
+    } else {
+        "clean"
+    }
+}
+
+/// Records the labels added to and taken off `what`, eg `mellow, road trip on 12`.
+fn record_labels(who: &str, changes: &LabelChanges, what: &str) {
+    for (action, labels) in [
+        (Action::LabelAdd, &changes.add),
+        (Action::LabelRemove, &changes.remove),
+    ] {
+        if !labels.is_empty() {
+            audit::record(who, action, format!("{} on {}", labels.join(", "), what));
+        }
+    }
+}
+
+async fn handle_labels(database: Arc<Mutex<MusicDB>>) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&database.lock().await.labels()))
+}
+
+async fn handle_moods(database: Arc<Mutex<MusicDB>>) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&database.lock().await.moods()))
+}
+
+async fn handle_song_labels(
+    id: String,
+    changes: LabelChanges,
+    who: String,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let id = error::parse_id(&id)?;
+    let mut db = database.lock().await;
+    if db.label(&[id], &changes) == 0 {
+        return Err(error::not_found(format!("song not found: {}", id)));
+    }
+    record_labels(&who, &changes, &id.to_string());
+    Ok(warp::reply::json(&SongResult::from(&db.records[&id])))
+}
+
+async fn handle_album_labels(
+    query: AlbumQuery,
+    changes: LabelChanges,
+    who: String,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut db = database.lock().await;
+    let ids = db.album_ids(&query.artist, &query.album);
+    if db.label(&ids, &changes) == 0 {
+        return Err(error::not_found(format!(
+            "album not found: {} by {}",
+            query.album, query.artist
+        )));
+    }
+    record_labels(&who, &changes, &album(&query));
+    let songs = ids
+        .iter()
+        .map(|id| SongResult::from(&db.records[id]))
+        .collect::<Vec<_>>();
+    Ok(warp::reply::json(&songs))
+}
+
+async fn handle_song_hide(
+    id: String,
+    hidden: bool,
+    who: String,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let id = error::parse_id(&id)?;
+    let mut db = database.lock().await;
+    if db.hide(&[id], hidden) == 0 {
+        return Err(error::not_found(format!("song not found: {}", id)));
+    }
+    audit::record(
+        &who,
+        Action::Hide,
+        format!("{} {}", id, hidden_or_shown(hidden)),
+    );
+    Ok(warp::reply::json(&SongResult::from(&db.records[&id])))
+}
+
+async fn handle_album_hide(
+    hidden: bool,
+    query: AlbumQuery,
+    who: String,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut db = database.lock().await;
+    let ids = db.album_ids(&query.artist, &query.album);
+    if db.hide(&ids, hidden) == 0 {
+        return Err(error::not_found(format!(
+            "album not found: {} by {}",
+            query.album, query.artist
+        )));
+    }
+    audit::record(
+        &who,
+        Action::Hide,
+        format!("{} {}", album(&query), hidden_or_shown(hidden)),
+    );
+    let songs = ids
+        .iter()
+        .map(|id| SongResult::from(&db.records[id]))
+        .collect::<Vec<_>>();
+    Ok(warp::reply::json(&songs))
+}
+
+async fn handle_song_explicit(
+    id: String,
+    explicit: bool,
+    who: String,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let id = error::parse_id(&id)?;
+    let mut db = database.lock().await;
+    if db.mark_explicit(&[id], explicit) == 0 {
+        return Err(error::not_found(format!("song not found: {}", id)));
+    }
+    audit::record(
+        &who,
+        Action::Explicit,
+        format!("{} {}", id, explicit_or_clean(explicit)),
+    );
+    Ok(warp::reply::json(&SongResult::from(&db.records[&id])))
+}
+
+async fn handle_album_explicit(
+    explicit: bool,
+    query: AlbumQuery,
+    who: String,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let mut db = database.lock().await;
+    let ids = db.album_ids(&query.artist, &query.album);
+    if db.mark_explicit(&ids, explicit) == 0 {
+        return Err(error::not_found(format!(
+            "album not found: {} by {}",
+            query.album, query.artist
+        )));
+    }
+    audit::record(
+        &who,
+        Action::Explicit,
+        format!("{} {}", album(&query), explicit_or_clean(explicit)),
+    );
+    let songs = ids
+        .iter()
+        .map(|id| SongResult::from(&db.records[id]))
+        .collect::<Vec<_>>();
+    Ok(warp::reply::json(&songs))
+}
+
+async fn handle_album_overrides(
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    Ok(warp::reply::json(&db.overrides.albums()))
+}
+
+async fn handle_album_override(
+    query: AlbumQuery,
+    set: Option<AlbumOverride>,
+    who: String,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let cleared = set.is_none();
+    let mut db = database.lock().await;
+    let ids = db.override_album(&query.artist, &query.album, set);
+    if ids.is_empty() {
+        return Err(error::not_found(format!(
+            "album not found: {} by {}",
+            query.album, query.artist
+        )));
+    }
+    let what = if cleared {
+        format!("{} back to its tags", album(&query))
+    } else {
+        album(&query)
+    };
+    audit::record(&who, Action::AlbumOverride, what);
+    let songs = ids
+        .iter()
+        .map(|id| SongResult::from(&db.records[id]))
+        .collect::<Vec<_>>();
+    Ok(warp::reply::json(&songs))
+}
@@ -0,0 +1,566 @@
+//! Browsing and searching the library: its page, the song and artist details, and the stats.
+
+use askama::Template;
+use bwaabwaa::{
+    analysis, feed,
+    history::{self, PlayHistory},
+    loudness,
+    music_db::{MusicDB, SearchTerms},
+    random,
+    sections::Section,
+    song::SongResult,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use warp::{filters::BoxedFilter, http::Response, Filter, Reply};
+
+use super::{files::WHATS_NEW_PUSSYCAT, wants_json, with, AlbumQuery, IdQuery, Server};
+use crate::{
+    album::AlbumPage,
+    artist::ArtistPage,
+    cache::{self, Conditional},
+    error,
+    feed_page::FeedPage,
+    i18n, qr,
+    search::{LibraryPageCache, LibraryQuery},
+    stats_page::StatsPage,
+    themes::{self, ThemeChoice},
+};
+
+/// The library page, and links to it: its feed and QR code.
+pub fn routes(server: &Server) -> BoxedFilter<(warp::reply::Response,)> {
+    let database = with(&server.database);
+    let library_page = with(&server.library_page);
+    let port = server.port;
+
+    let library = warp::path::end()
+        .and(warp::query())
+        .and(cache::conditional())
+        .and(themes::theme())
+        .and(i18n::language())
+        .and(database.clone())
+        .and(library_page)
+        .and_then(handle_library);
+
+    let feed_new = warp::path!("feed" / "new")
+        .and(warp::get())
+        .and(warp::query())
+        .and(warp::header::optional::<String>("host"))
+        .and(warp::header::optional::<String>("x-forwarded-proto"))
+        .and(database.clone())
+        .and_then(move |query, host, forwarded_proto, database| {
+            handle_feed_new(query, host, forwarded_proto, port, database)
+        });
+
+    let qr = warp::path!("qr")
+        .and(warp::query())
+        .and(warp::header::optional::<String>("host"))
+        .and_then(move |query, host| handle_qr(query, host, port));
+
+    library
+        .or(feed_new)
+        .or(qr)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+/// The JSON endpoints for browsing and searching.
+pub fn json(server: &Server) -> BoxedFilter<(warp::reply::Response,)> {
+    let database = with(&server.database);
+    let history = with(&server.history);
+
+    let search = warp::path!("search")
+        .and(warp::query())
+        .and(database.clone())
+        .and_then(handle_search);
+
+    let details = warp::path!("details")
+        .and(warp::query().map(|q: IdQuery| q.id))
+        .and(database.clone())
+        .and_then(handle_details);
+
+    let details_batch =
+        warp::path!("details")
+            .and(
+                warp::get()
+                    .and(warp::query().map(|q: IdsQuery| {
+                        q.ids.split(',').map(|id| id.trim().to_string()).collect()
+                    }))
+                    .or(warp::post().and(warp::body::json()))
+                    .unify(),
+            )
+            .and(database.clone())
+            .and_then(handle_details_batch);
+
+    let loudness = warp::path!("loudness")
+        .and(warp::query().map(|q: IdQuery| q.id))
+        .and(database.clone())
+        .and_then(handle_loudness);
+
+    let artists = warp::path!("artists")
+        .and(database.clone())
+        .and_then(handle_artists);
+
+    let browse = warp::path!("browse")
+        .and(warp::query())
+        .and(database.clone())
+        .and_then(handle_browse);
+
+    let roots = warp::path!("roots")
+        .and(database.clone())
+        .and_then(handle_roots);
+
+    let years = warp::path!("years")
+        .and(database.clone())
+        .and_then(handle_years);
+
+    let random = warp::path!("random")
+        .and(warp::query())
+        .and(database.clone())
+        .and_then(handle_random);
+
+    let random_album = warp::path!("random" / "album")
+        .and(warp::query())
+        .and(database.clone())
+        .and_then(handle_random_album);
+
+    let shuffle = warp::path!("shuffle")
+        .and(warp::query())
+        .and(database.clone())
+        .and_then(handle_shuffle);
+
+    let top = warp::path!("stats" / "top")
+        .and(warp::query())
+        .and(database.clone())
+        .and(history.clone())
+        .and_then(handle_top);
+
+    let memories = warp::path!("memories")
+        .and(database.clone())
+        .and(history.clone())
+        .and_then(handle_memories);
+
+    search
+        .or(details)
+        .or(details_batch)
+        .or(loudness)
+        .or(artists)
+        .or(browse)
+        .or(roots)
+        .or(years)
+        .or(random)
+        .or(random_album)
+        .or(shuffle)
+        .or(top)
+        .or(memories)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+/// The artist, album and stats pages, or their JSON when `accept` asks for it.
+pub fn pages(
+    server: &Server,
+    accept: BoxedFilter<(Option<String>,)>,
+) -> BoxedFilter<(warp::reply::Response,)> {
+    let database = with(&server.database);
+
+    let artist = warp::path!("artist")
+        .and(warp::query())
+        .and(accept.clone())
+        .and(themes::theme())
+        .and(database.clone())
+        .and_then(handle_artist);
+
+    let album = warp::path!("album")
+        .and(warp::query())
+        .and(accept.clone())
+        .and(themes::theme())
+        .and(database.clone())
+        .and_then(handle_album);
+
+    let stats = warp::path!("stats")
+        .and(accept)
+        .and(themes::theme())
+        .and(database)
+        .and_then(handle_stats);
+
+    artist.or(album).or(stats).map(Reply::into_response).boxed()
+}
+
+async fn handle_library(
+    query: LibraryQuery,
+    conditional: Conditional,
+    theme: ThemeChoice,
+    lang: i18n::Lang,
+    database: Arc<Mutex<MusicDB>>,
+    library_page: Arc<Mutex<LibraryPageCache>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    let mut library_page = library_page.lock().await;
+    let (html, validators) = library_page.get(&db, &query, theme.theme, lang);
+
+    if conditional.is_fresh(validators) {
+        return Ok(theme.remember(cache::not_modified(validators)));
+    }
+
+    Ok(theme.remember(
+        validators
+            .apply(Response::builder())
+            .header("content-type", "text/html; charset=utf-8")
+            .header("content-language", lang.code())
+            .header("vary", "accept-language")
+            .body(html.as_bytes().to_vec())
+            .unwrap(),
+    ))
+}
+
+async fn handle_search(
+    terms: SearchTerms,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    let results = db.query(terms);
+
+    Ok(warp::reply::json(&results))
+}
+
+async fn handle_details(
+    id: String,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+
+    if id == "whatsnew" {
+        return Ok(warp::reply::json(&whats_new_details()));
+    }
+
+    let id = error::parse_id(&id)?;
+    match db.records.get(&id) {
+        Some(s) => {
+            let song: SongResult = s.into();
+            Ok(warp::reply::json(&song))
+        }
+        None => Err(error::not_found(format!("id={} not found", id))),
+    }
+}
+
+/// The most songs `/details` will look up at once
+const MAX_BATCH: usize = 1000;
+
+/// `?ids=1,2,3`, for looking up several songs at once.
+#[derive(Deserialize)]
+struct IdsQuery {
+    ids: String,
+}
+
+/// Looks up several songs at once, in the order given. Ids that aren't in the library are left
+/// out, rather than failing the whole request.
+async fn handle_details_batch(
+    ids: Vec<String>,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if ids.len() > MAX_BATCH {
+        return Err(error::bad_request(format!(
+            "at most {} ids can be looked up at once",
+            MAX_BATCH
+        )));
+    }
+
+    let db = database.lock().await;
+    let mut songs = Vec::with_capacity(ids.len());
+    for id in ids {
+        if id == "whatsnew" {
+            songs.push(whats_new_details());
+        } else if let Some(song) = db.records.get(&error::parse_id(&id)?) {
+            songs.push(song.into());
+        }
+    }
+
+    Ok(warp::reply::json(&songs))
+}
+
+fn whats_new_details() -> SongResult {
+    SongResult {
+        id: "whatsnew".to_string(),
+        title: "The best meal I've ever had in my life".to_string(),
+        artist: "John Mulaney".to_string(),
+        album: "Comedy Central Stand-Up".to_string(),
+        year: 2019,
+        comment: "https://www.youtube.com/watch?v=Mw7Gryt-rcc".to_string(),
+        genre: "Comedy".to_string(),
+        duration: "21 instances of \"What's New, Pussycat?\"".to_string(),
+        format: "mp3".to_string(),
+        size: WHATS_NEW_PUSSYCAT.len() as u64,
+        ..Default::default()
+    }
+}
+
+#[derive(Deserialize)]
+struct ArtistQuery {
+    name: String,
+}
+
+async fn handle_artist(
+    query: ArtistQuery,
+    accept: Option<String>,
+    theme: ThemeChoice,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let db = database.lock().await;
+
+    let artist = match db.artist(&query.name) {
+        Some(a) => a,
+        None => {
+            return Err(error::not_found(format!(
+                "artist not found: {}",
+                query.name
+            )))
+        }
+    };
+
+    if wants_json(&accept) {
+        Ok(Box::new(warp::reply::json(&artist)))
+    } else {
+        let body = ArtistPage {
+            artist: &artist,
+            theme: theme.theme,
+        }
+        .render()
+        .unwrap();
+        Ok(Box::new(
+            theme.remember(warp::reply::html(body).into_response()),
+        ))
+    }
+}
+
+async fn handle_artists(
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    Ok(warp::reply::json(&db.artists()))
+}
+
+async fn handle_album(
+    query: AlbumQuery,
+    accept: Option<String>,
+    theme: ThemeChoice,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let db = database.lock().await;
+
+    let album = match db.album(&query.artist, &query.album, query.year) {
+        Some(a) => a,
+        None => {
+            return Err(error::not_found(format!(
+                "album not found: {} by {}",
+                query.album, query.artist
+            )))
+        }
+    };
+
+    if wants_json(&accept) {
+        Ok(Box::new(warp::reply::json(&album)))
+    } else {
+        let body = AlbumPage {
+            album: &album,
+            theme: theme.theme,
+        }
+        .render()
+        .unwrap();
+        Ok(Box::new(
+            theme.remember(warp::reply::html(body).into_response()),
+        ))
+    }
+}
+
+async fn handle_loudness(
+    id: String,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let id = error::parse_id(&id)?;
+    let path = {
+        let db = database.lock().await;
+        let song = db
+            .records
+            .get(&id)
+            .ok_or_else(|| error::not_found(format!("id={} not found", id)))?;
+        if !analysis::decodable(song) {
+            return Err(error::bad_request(format!(
+                "loudness can only be measured for local MP3s, not {}",
+                song.path.display()
+            )));
+        }
+        song.path.clone()
+    };
+
+    // Measuring it means decoding the whole song, so it's done off the async runtime
+    let measured = tokio::task::spawn_blocking(move || loudness::loudness(id, &path))
+        .await
+        .map_err(|e| error::internal(e.to_string()))?;
+    match measured {
+        Ok(loudness) => Ok(warp::reply::json(&loudness)),
+        Err(e) => Err(error::internal(format!(
+            "unable to measure the loudness of id={}: {}",
+            id, e
+        ))),
+    }
+}
+
+#[derive(Deserialize)]
+struct BrowseQuery {
+    #[serde(default)]
+    path: String,
+    section: Option<Section>,
+}
+
+async fn handle_browse(
+    query: BrowseQuery,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+
+    match db.browse(&query.path, query.section) {
+        Some(results) => Ok(warp::reply::json(&results)),
+        None => Err(error::not_found(format!(
+            "directory not found: {}",
+            query.path
+        ))),
+    }
+}
+
+async fn handle_roots(database: Arc<Mutex<MusicDB>>) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    Ok(warp::reply::json(&db.root_summaries()))
+}
+
+async fn handle_years(database: Arc<Mutex<MusicDB>>) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    Ok(warp::reply::json(&db.years()))
+}
+
+async fn handle_random(
+    terms: random::RandomTerms,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    Ok(warp::reply::json(&db.random_songs(&terms)))
+}
+
+async fn handle_random_album(
+    terms: random::RandomTerms,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+
+    match db.random_album(&terms) {
+        Some(album) => Ok(warp::reply::json(&album)),
+        None => Err(error::not_found("no albums match")),
+    }
+}
+
+async fn handle_shuffle(
+    terms: SearchTerms,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    let results = db
+        .shuffle(&terms)
+        .into_iter()
+        .map(SongResult::from)
+        .collect::<Vec<_>>();
+
+    Ok(warp::reply::json(&results))
+}
+
+async fn handle_stats(
+    accept: Option<String>,
+    theme: ThemeChoice,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let db = database.lock().await;
+    let stats = db.stats();
+
+    if wants_json(&accept) {
+        Ok(Box::new(warp::reply::json(&stats)))
+    } else {
+        let body = StatsPage {
+            stats: &stats,
+            theme: theme.theme,
+        }
+        .render()
+        .unwrap();
+        Ok(Box::new(
+            theme.remember(warp::reply::html(body).into_response()),
+        ))
+    }
+}
+
+async fn handle_top(
+    terms: history::ChartTerms,
+    database: Arc<Mutex<MusicDB>>,
+    history: Arc<Mutex<PlayHistory>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    let history = history.lock().await;
+    Ok(warp::reply::json(&history.chart(&db, &terms)))
+}
+
+async fn handle_memories(
+    database: Arc<Mutex<MusicDB>>,
+    history: Arc<Mutex<PlayHistory>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    let history = history.lock().await;
+    Ok(warp::reply::json(&history.memories(&db)))
+}
+
+async fn handle_qr(
+    query: qr::QrQuery,
+    host: Option<String>,
+    port: u16,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let url = qr::server_url(host.as_deref(), port, query.token.as_deref());
+    let (content_type, body) = qr::render(&url, &query).map_err(error::bad_request)?;
+
+    Ok(Response::builder()
+        .header("content-type", content_type)
+        .body(body)
+        .unwrap())
+}
+
+#[derive(Deserialize)]
+struct FeedQuery {
+    /// How many days back to go
+    days: Option<u64>,
+}
+
+async fn handle_feed_new(
+    query: FeedQuery,
+    host: Option<String>,
+    forwarded_proto: Option<String>,
+    port: u16,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let days = query.days.unwrap_or(feed::DEFAULT_DAYS);
+    let since = history::now().saturating_sub(days.saturating_mul(24 * 60 * 60));
+    let albums = feed::new_albums(&*database.lock().await, since);
+
+    let base = format!(
+        "{}://{}",
+        forwarded_proto.as_deref().unwrap_or("http"),
+        host.unwrap_or_else(|| format!("localhost:{}", port))
+    );
+    let body = FeedPage {
+        base,
+        days,
+        albums: &albums,
+    }
+    .render()
+    .unwrap();
+
+    Ok(Response::builder()
+        .header("content-type", "application/atom+xml; charset=utf-8")
+        .body(body)
+        .unwrap())
+}
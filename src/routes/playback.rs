@@ -0,0 +1,389 @@
+//! What's playing: the queue, resume positions, and progress reports.
+
+use bwaabwaa::{
+    audit::{self, Action},
+    devices::Devices,
+    events::{EventBus, ServerEvent},
+    history::PlayHistory,
+    music_db::{MusicDB, SearchTerms},
+    now_playing::Progress,
+    progress::{Listener, Listening},
+    queue::PlayQueue,
+    resume::ResumePositions,
+    song::SongResult,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use warp::{filters::BoxedFilter, http::StatusCode, Filter, Reply};
+
+use super::{with, IdQuery, Server};
+use crate::error;
+
+/// The queue, resume positions, and what's playing.
+pub fn json(server: &Server) -> BoxedFilter<(warp::reply::Response,)> {
+    let who = server.who();
+    let device = server.device();
+    let listener = server.listener();
+    let database = with(&server.database);
+    let history = with(&server.history);
+    let queue = with(&server.queue);
+    let resume = with(&server.resume);
+    let devices = with(&server.devices);
+    let progress = with(&server.progress);
+    let listening = with(&server.listening);
+    let events = with(&server.events);
+
+    let resume_list = warp::path!("resume")
+        .and(warp::get())
+        .and(database.clone())
+        .and(resume.clone())
+        .and(devices.clone())
+        .and_then(handle_resume_list);
+
+    let resume_save = warp::path!("resume")
+        .and(warp::post())
+        .and(warp::query())
+        .and(device.clone())
+        .and(database.clone())
+        .and(resume.clone())
+        .and(devices.clone())
+        .and_then(handle_resume_save);
+
+    let now_playing = warp::path!("now-playing")
+        .and(warp::get())
+        .and(database.clone())
+        .and(progress.clone())
+        .and_then(handle_now_playing);
+
+    let now_playing_report = warp::path!("now-playing")
+        .and(warp::post())
+        .and(warp::query())
+        .and(device.clone())
+        .and(database.clone())
+        .and(progress.clone())
+        .and_then(handle_now_playing_report);
+
+    let progress_report = warp::path!("progress")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(listener.clone())
+        .and(database.clone())
+        .and(progress.clone())
+        .and(listening.clone())
+        .and(resume.clone())
+        .and(history.clone())
+        .and(events.clone())
+        .and_then(handle_progress);
+
+    let queue_state = warp::path!("queue")
+        .and(warp::get())
+        .and(database.clone())
+        .and(queue.clone())
+        .and_then(handle_queue);
+
+    let queue_add = warp::path!("queue" / "add")
+        .and(warp::post())
+        .and(warp::query().map(|q: IdQuery| q.id))
+        .and(who.clone())
+        .and(database.clone())
+        .and(queue.clone())
+        .and_then(handle_queue_add);
+
+    let queue_next = warp::path!("queue" / "next")
+        .and(warp::post())
+        .and(database.clone())
+        .and(queue.clone())
+        .and(events.clone())
+        .and_then(handle_queue_next);
+
+    let queue_clear = warp::path!("queue")
+        .and(warp::delete())
+        .and(who.clone())
+        .and(database.clone())
+        .and(queue.clone())
+        .and_then(handle_queue_clear);
+
+    let radio = warp::path!("radio" / "seed")
+        .and(warp::post())
+        .and(warp::query().map(|q: IdQuery| q.id))
+        .and(who.clone())
+        .and(database.clone())
+        .and(queue.clone())
+        .and_then(handle_radio);
+
+    let queue_shuffle = warp::path!("queue" / "shuffle")
+        .and(warp::post())
+        .and(warp::query())
+        .and(who.clone())
+        .and(database.clone())
+        .and(queue.clone())
+        .and_then(handle_queue_shuffle);
+
+    resume_list
+        .or(resume_save)
+        .or(now_playing)
+        .or(now_playing_report)
+        .or(progress_report)
+        .or(queue_state)
+        .or(queue_add)
+        .or(queue_next)
+        .or(queue_clear)
+        .or(radio)
+        .or(queue_shuffle)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+async fn handle_queue(
+    database: Arc<Mutex<MusicDB>>,
+    queue: Arc<Mutex<PlayQueue>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    let queue = queue.lock().await;
+    Ok(warp::reply::json(&queue.state(&db)))
+}
+
+async fn handle_queue_add(
+    id: String,
+    who: String,
+    database: Arc<Mutex<MusicDB>>,
+    queue: Arc<Mutex<PlayQueue>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    let mut queue = queue.lock().await;
+
+    let id = error::parse_id(&id)?;
+    if !db.records.contains_key(&id) {
+        return Err(error::not_found(format!("id={} not found", id)));
+    }
+    queue.enqueue(id);
+    audit::record(&who, Action::QueueAdd, id.to_string());
+
+    Ok(warp::reply::json(&queue.state(&db)))
+}
+
+async fn handle_resume_list(
+    database: Arc<Mutex<MusicDB>>,
+    resume: Arc<Mutex<ResumePositions>>,
+    devices: Arc<Mutex<Devices>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    let resume = resume.lock().await;
+    Ok(warp::reply::json(
+        &resume.in_progress(&db, &*devices.lock().await),
+    ))
+}
+
+#[derive(Deserialize)]
+struct ResumeQuery {
+    id: String,
+    /// Seconds into the song
+    position: f64,
+}
+
+async fn handle_resume_save(
+    query: ResumeQuery,
+    device: Option<String>,
+    database: Arc<Mutex<MusicDB>>,
+    resume: Arc<Mutex<ResumePositions>>,
+    devices: Arc<Mutex<Devices>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    let mut resume = resume.lock().await;
+
+    let id = error::parse_id(&query.id)?;
+    if !query.position.is_finite() {
+        return Err(error::bad_request("position must be a number of seconds"));
+    }
+    let song = db
+        .records
+        .get(&id)
+        .ok_or_else(|| error::not_found(format!("id={} not found", id)))?;
+    if !song.section.resumes() {
+        return Err(error::bad_request(
+            "only audiobooks and podcasts keep resume positions",
+        ));
+    }
+    resume.record(id, query.position, song.duration.as_secs_f64(), device);
+
+    Ok(warp::reply::json(
+        &resume.in_progress(&db, &*devices.lock().await),
+    ))
+}
+
+async fn handle_now_playing(
+    database: Arc<Mutex<MusicDB>>,
+    progress: Arc<Mutex<Progress>>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let db = database.lock().await;
+    let progress = progress.lock().await;
+    Ok(match progress.current(&db) {
+        Some(now_playing) => warp::reply::json(&now_playing).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    })
+}
+
+#[derive(Deserialize)]
+struct ProgressQuery {
+    id: String,
+    /// Seconds into the song
+    position: f64,
+    #[serde(default)]
+    paused: bool,
+}
+
+async fn handle_now_playing_report(
+    query: ProgressQuery,
+    device: Option<String>,
+    database: Arc<Mutex<MusicDB>>,
+    progress: Arc<Mutex<Progress>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    let id = error::parse_id(&query.id)?;
+    if !query.position.is_finite() {
+        return Err(error::bad_request("position must be a number of seconds"));
+    }
+    if !db.records.contains_key(&id) {
+        return Err(error::not_found(format!("id={} not found", id)));
+    }
+
+    progress
+        .lock()
+        .await
+        .report(id, query.position, query.paused, device);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct ProgressReport {
+    id: String,
+    /// Seconds into the song
+    position: f64,
+    playing: bool,
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_progress(
+    report: ProgressReport,
+    listener: Listener,
+    database: Arc<Mutex<MusicDB>>,
+    progress: Arc<Mutex<Progress>>,
+    listening: Arc<Mutex<Listening>>,
+    resume: Arc<Mutex<ResumePositions>>,
+    history: Arc<Mutex<PlayHistory>>,
+    events: EventBus,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    let id = error::parse_id(&report.id)?;
+    let song = db
+        .records
+        .get(&id)
+        .ok_or_else(|| error::not_found(format!("id={} not found", id)))?;
+    let duration = song.duration.as_secs_f64();
+
+    progress.lock().await.report(
+        id,
+        report.position,
+        !report.playing,
+        listener.device.clone(),
+    );
+
+    if song.section.resumes() {
+        resume
+            .lock()
+            .await
+            .record(id, report.position, duration, listener.device.clone());
+    }
+
+    let update =
+        listening
+            .lock()
+            .await
+            .report(&listener, id, report.position, report.playing, duration);
+    // `/listen` has already counted plays from before the listener started reporting
+    if update.started.is_some() && !update.by_listen {
+        events.publish(ServerEvent::NowPlaying {
+            song: Box::new(song.into()),
+        });
+    }
+    if let Some(started) = update.counted {
+        if !update.by_listen {
+            history.lock().await.record(id, listener.device.clone());
+        }
+        events.publish(ServerEvent::Listened {
+            song: Box::new(song.into()),
+            started,
+        });
+    }
+    if update.finished {
+        events.song_finished(&db, song);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn handle_queue_next(
+    database: Arc<Mutex<MusicDB>>,
+    queue: Arc<Mutex<PlayQueue>>,
+    events: EventBus,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    let mut queue = queue.lock().await;
+
+    // Skip over anything that's disappeared from the library since it was queued
+    while let Some(id) = queue.next(&db) {
+        if let Some(song) = db.records.get(&id) {
+            return Ok(warp::reply::json(&SongResult::from(song)));
+        }
+    }
+
+    events.publish(ServerEvent::QueueEmpty);
+    Err(error::not_found("the queue is empty"))
+}
+
+async fn handle_queue_clear(
+    who: String,
+    database: Arc<Mutex<MusicDB>>,
+    queue: Arc<Mutex<PlayQueue>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    let mut queue = queue.lock().await;
+    queue.clear();
+    audit::record(&who, Action::QueueClear, "");
+    Ok(warp::reply::json(&queue.state(&db)))
+}
+
+async fn handle_radio(
+    id: String,
+    who: String,
+    database: Arc<Mutex<MusicDB>>,
+    queue: Arc<Mutex<PlayQueue>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    let mut queue = queue.lock().await;
+
+    let id = error::parse_id(&id)?;
+    let seed = db
+        .radio_seed(id)
+        .ok_or_else(|| error::not_found(format!("id={} not found", id)))?;
+    queue.start_radio(seed, &db);
+    audit::record(&who, Action::QueueReplace, format!("radio from {}", id));
+
+    Ok(warp::reply::json(&queue.state(&db)))
+}
+
+async fn handle_queue_shuffle(
+    terms: SearchTerms,
+    who: String,
+    database: Arc<Mutex<MusicDB>>,
+    queue: Arc<Mutex<PlayQueue>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    let mut queue = queue.lock().await;
+
+    queue.replace(db.shuffle(&terms).into_iter().map(|s| s.id));
+    audit::record(&who, Action::QueueReplace, "shuffle");
+    Ok(warp::reply::json(&queue.state(&db)))
+}
@@ -0,0 +1,473 @@
+//! Jukebox mode: playing the queue through the server's own audio output, and controlling it.
+
+use bwaabwaa::{
+    audit::{self, Action},
+    dsp::{self, Equalizer, Equalizers},
+    events::{EventBus, ServerEvent},
+    history::PlayHistory,
+    jukebox::{Jukebox, PlayerSettings, Status},
+    music_db::{MusicDB, SearchTerms},
+    queue::PlayQueue,
+    replay_gain::GainMode,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use warp::{filters::BoxedFilter, Filter, Reply};
+
+use super::{with, Server};
+use crate::{
+    api::{ApiCommand, ApiState, EqState, OutputsState},
+    error,
+};
+
+/// The player's state, and commands for it; only under `/api/v1`.
+pub fn api(server: &Server) -> BoxedFilter<(warp::reply::Response,)> {
+    let who = server.who();
+    let database = with(&server.database);
+    let history = with(&server.history);
+    let queue = with(&server.queue);
+    let events = with(&server.events);
+    let jukebox = with(&server.jukebox);
+
+    let api_state = warp::path!("state")
+        .and(warp::get())
+        .and(database.clone())
+        .and(queue.clone())
+        .and(jukebox.clone())
+        .and_then(handle_api_state);
+
+    let api_command = warp::path!("command")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(who.clone())
+        .and(database.clone())
+        .and(queue.clone())
+        .and(history.clone())
+        .and(events.clone())
+        .and(jukebox.clone())
+        .and_then(handle_api_command);
+
+    api_state.or(api_command).map(Reply::into_response).boxed()
+}
+
+/// The jukebox's timers, settings, equalizer and outputs.
+pub fn json(server: &Server) -> BoxedFilter<(warp::reply::Response,)> {
+    let database = with(&server.database);
+    let queue = with(&server.queue);
+    let jukebox = with(&server.jukebox);
+
+    let player_sleep = warp::path!("player" / "sleep")
+        .and(warp::post())
+        .and(warp::query())
+        .and(database.clone())
+        .and(queue.clone())
+        .and(jukebox.clone())
+        .and_then(handle_player_sleep);
+
+    let player_stop_after_current = warp::path!("player" / "stop-after-current")
+        .and(warp::post())
+        .and(warp::query())
+        .and(database.clone())
+        .and(queue.clone())
+        .and(jukebox.clone())
+        .and_then(handle_player_stop_after_current);
+
+    let player_settings = warp::path!("player" / "settings")
+        .and(warp::get())
+        .and(jukebox.clone())
+        .and_then(handle_player_settings);
+
+    let player_settings_update = warp::path!("player" / "settings")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(jukebox.clone())
+        .and_then(handle_player_settings_update);
+
+    let player_eq = warp::path!("player" / "eq")
+        .and(warp::get())
+        .and(jukebox.clone())
+        .and_then(handle_player_eq);
+
+    let player_eq_update = warp::path!("player" / "eq")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(jukebox.clone())
+        .and_then(handle_player_eq_update);
+
+    let player_outputs = warp::path!("player" / "outputs")
+        .and(warp::get())
+        .and(jukebox.clone())
+        .and_then(handle_player_outputs);
+
+    let player_outputs_update = warp::path!("player" / "outputs")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(jukebox.clone())
+        .and_then(handle_player_outputs_update);
+
+    player_sleep
+        .or(player_stop_after_current)
+        .or(player_settings)
+        .or(player_settings_update)
+        .or(player_eq)
+        .or(player_eq_update)
+        .or(player_outputs)
+        .or(player_outputs_update)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+/// Starts jukebox mode, playing the queue through the server's audio output. Each time a song
+/// finishes, the next one in the queue starts.
+pub fn start_jukebox(
+    database: &Arc<Mutex<MusicDB>>,
+    queue: &Arc<Mutex<PlayQueue>>,
+    history: &Arc<Mutex<PlayHistory>>,
+    events: &EventBus,
+) -> Option<Arc<Jukebox>> {
+    let (finished, mut finished_rx) = tokio::sync::mpsc::unbounded_channel();
+    let jukebox = match Jukebox::start(finished) {
+        Ok(jukebox) => Arc::new(jukebox),
+        Err(e) => {
+            eprintln!("Unable to start jukebox mode: {}", e);
+            return None;
+        }
+    };
+    println!("Jukebox mode: playing through the default audio output");
+
+    let device = jukebox.state().device.unwrap_or_default();
+    jukebox.set_equalizer(Equalizers::load().get(&device));
+
+    let (database, queue, history, events) = (
+        Arc::clone(database),
+        Arc::clone(queue),
+        Arc::clone(history),
+        events.clone(),
+    );
+    let player = Arc::clone(&jukebox);
+    tokio::spawn(async move {
+        while let Some(finished) = finished_rx.recv().await {
+            let next = jukebox_next(
+                &player,
+                &database,
+                &queue,
+                &history,
+                &events,
+                finished.early,
+            )
+            .await;
+
+            // A song sent early is only over once something follows it; otherwise it's sent again
+            if next || !finished.early {
+                let db = database.lock().await;
+                if let Some(song) = db.records.get(&finished.id) {
+                    events.song_finished(&db, song);
+                }
+            }
+            if !next && !finished.early {
+                events.publish(ServerEvent::QueueEmpty);
+            }
+        }
+    });
+
+    Some(jukebox)
+}
+
+/// Plays the next song in the queue on the jukebox, or stops if the queue is empty. When `early`,
+/// the last song is still finishing, so an empty queue lets it. Returns whether there was a song.
+async fn jukebox_next(
+    jukebox: &Jukebox,
+    database: &Mutex<MusicDB>,
+    queue: &Mutex<PlayQueue>,
+    history: &Mutex<PlayHistory>,
+    events: &EventBus,
+    early: bool,
+) -> bool {
+    let db = database.lock().await;
+    let mut queue = queue.lock().await;
+
+    // Skip over anything that's disappeared from the library since it was queued
+    while let Some(id) = queue.next(&db) {
+        if let Some(song) = db.records.get(&id) {
+            jukebox.play(song);
+            history.lock().await.record(id, None);
+            events.publish(ServerEvent::NowPlaying {
+                song: Box::new(song.into()),
+            });
+            return true;
+        }
+    }
+
+    if !early {
+        jukebox.stop();
+    }
+    false
+}
+
+fn api_state(db: &MusicDB, queue: &PlayQueue, jukebox: Option<&Jukebox>) -> ApiState {
+    let state = jukebox.map(|j| j.state()).unwrap_or_default();
+
+    ApiState {
+        jukebox: jukebox.is_some(),
+        status: state.status,
+        volume: state.volume,
+        position: state.position,
+        now_playing: state
+            .current
+            .and_then(|id| db.records.get(&id))
+            .map(|s| s.into()),
+        sleep_in: state.sleep_in,
+        stop_after_current: state.stop_after_current,
+        queue: queue.state(db),
+    }
+}
+
+async fn handle_api_state(
+    database: Arc<Mutex<MusicDB>>,
+    queue: Arc<Mutex<PlayQueue>>,
+    jukebox: Option<Arc<Jukebox>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    let queue = queue.lock().await;
+    Ok(warp::reply::json(&api_state(
+        &db,
+        &queue,
+        jukebox.as_deref(),
+    )))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_api_command(
+    command: ApiCommand,
+    who: String,
+    database: Arc<Mutex<MusicDB>>,
+    queue: Arc<Mutex<PlayQueue>>,
+    history: Arc<Mutex<PlayHistory>>,
+    events: EventBus,
+    jukebox: Option<Arc<Jukebox>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if let ApiCommand::Enqueue { id, query } = command {
+        let db = database.lock().await;
+        let id = match (id, query) {
+            (Some(id), _) => {
+                let id = error::parse_id(&id)?;
+                db.records
+                    .contains_key(&id)
+                    .then_some(id)
+                    .ok_or_else(|| error::not_found(format!("id={} not found", id)))?
+            }
+            (None, Some(query)) => {
+                let terms = SearchTerms {
+                    term: Some(query.clone()),
+                    limit: Some(1),
+                    ..Default::default()
+                };
+                let results = db.query(terms).results;
+                let best = results
+                    .first()
+                    .ok_or_else(|| error::not_found(format!("nothing matches {}", query)))?;
+                error::parse_id(&best.id)?
+            }
+            (None, None) => return Err(error::bad_request("enqueue needs an id or a query")),
+        };
+        queue.lock().await.enqueue(id);
+        audit::record(&who, Action::QueueAdd, id.to_string());
+
+        let queue = queue.lock().await;
+        return Ok(warp::reply::json(&api_state(
+            &db,
+            &queue,
+            jukebox.as_deref(),
+        )));
+    }
+
+    let jukebox = jukebox.ok_or_else(|| error::bad_request("jukebox mode is off"))?;
+    match command {
+        ApiCommand::Play if jukebox.state().status == Status::Paused => jukebox.resume(),
+        ApiCommand::Play | ApiCommand::Next => {
+            jukebox_next(&jukebox, &database, &queue, &history, &events, false).await;
+        }
+        ApiCommand::Pause => jukebox.pause(),
+        ApiCommand::Stop => jukebox.stop(),
+        ApiCommand::Volume { level } => jukebox.set_volume(level),
+        ApiCommand::Enqueue { .. } => unreachable!(),
+    }
+
+    // Let the audio thread catch up, so the state reflects the command
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    let db = database.lock().await;
+    let queue = queue.lock().await;
+    Ok(warp::reply::json(&api_state(&db, &queue, Some(&jukebox))))
+}
+
+#[derive(Deserialize)]
+struct SleepQuery {
+    /// 0 cancels the timer
+    minutes: u64,
+}
+
+async fn handle_player_sleep(
+    query: SleepQuery,
+    database: Arc<Mutex<MusicDB>>,
+    queue: Arc<Mutex<PlayQueue>>,
+    jukebox: Option<Arc<Jukebox>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let jukebox = jukebox.ok_or_else(|| error::bad_request("jukebox mode is off"))?;
+    let after = (query.minutes > 0).then(|| std::time::Duration::from_secs(query.minutes * 60));
+    jukebox.sleep(after);
+
+    // Let the audio thread catch up, so the state reflects the change
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    let db = database.lock().await;
+    let queue = queue.lock().await;
+    Ok(warp::reply::json(&api_state(&db, &queue, Some(&jukebox))))
+}
+
+#[derive(Deserialize)]
+struct StopAfterCurrentQuery {
+    /// On by default
+    on: Option<bool>,
+}
+
+async fn handle_player_stop_after_current(
+    query: StopAfterCurrentQuery,
+    database: Arc<Mutex<MusicDB>>,
+    queue: Arc<Mutex<PlayQueue>>,
+    jukebox: Option<Arc<Jukebox>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let jukebox = jukebox.ok_or_else(|| error::bad_request("jukebox mode is off"))?;
+    jukebox.stop_after_current(query.on.unwrap_or(true));
+
+    // Let the audio thread catch up, so the state reflects the change
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    let db = database.lock().await;
+    let queue = queue.lock().await;
+    Ok(warp::reply::json(&api_state(&db, &queue, Some(&jukebox))))
+}
+
+async fn handle_player_settings(
+    jukebox: Option<Arc<Jukebox>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let jukebox = jukebox.ok_or_else(|| error::bad_request("jukebox mode is off"))?;
+    Ok(warp::reply::json(&jukebox.state().settings))
+}
+
+/// The body of `POST /player/settings`; anything left out stays as it is.
+#[derive(Deserialize)]
+struct PlayerSettingsUpdate {
+    crossfade: Option<f64>,
+    gapless: Option<bool>,
+    replay_gain: Option<GainMode>,
+    preamp: Option<f32>,
+    prevent_clipping: Option<bool>,
+    trim_silence: Option<bool>,
+}
+
+async fn handle_player_settings_update(
+    update: PlayerSettingsUpdate,
+    jukebox: Option<Arc<Jukebox>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let jukebox = jukebox.ok_or_else(|| error::bad_request("jukebox mode is off"))?;
+    let settings = jukebox.state().settings;
+    jukebox.set_settings(PlayerSettings {
+        crossfade: update.crossfade.unwrap_or(settings.crossfade),
+        gapless: update.gapless.unwrap_or(settings.gapless),
+        replay_gain: update.replay_gain.unwrap_or(settings.replay_gain),
+        preamp: update.preamp.unwrap_or(settings.preamp),
+        prevent_clipping: update.prevent_clipping.unwrap_or(settings.prevent_clipping),
+        trim_silence: update.trim_silence.unwrap_or(settings.trim_silence),
+    });
+
+    // Let the audio thread catch up, so the state reflects the change
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    Ok(warp::reply::json(&jukebox.state().settings))
+}
+
+fn eq_state(jukebox: &Jukebox) -> EqState {
+    let state = jukebox.state();
+    EqState {
+        device: state.device,
+        bands: dsp::BANDS,
+        equalizer: state.equalizer,
+    }
+}
+
+async fn handle_player_eq(
+    jukebox: Option<Arc<Jukebox>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let jukebox = jukebox.ok_or_else(|| error::bad_request("jukebox mode is off"))?;
+    Ok(warp::reply::json(&eq_state(&jukebox)))
+}
+
+/// The body of `POST /player/eq`; anything left out stays as it is.
+#[derive(Deserialize)]
+struct EqualizerUpdate {
+    gains: Option<[f32; 10]>,
+    mono: Option<bool>,
+    balance: Option<f32>,
+}
+
+async fn handle_player_eq_update(
+    update: EqualizerUpdate,
+    jukebox: Option<Arc<Jukebox>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let jukebox = jukebox.ok_or_else(|| error::bad_request("jukebox mode is off"))?;
+    let state = jukebox.state();
+    let equalizer = Equalizer {
+        gains: update.gains.unwrap_or(state.equalizer.gains),
+        mono: update.mono.unwrap_or(state.equalizer.mono),
+        balance: update.balance.unwrap_or(state.equalizer.balance),
+    }
+    .clamped();
+    jukebox.set_equalizer(equalizer);
+    Equalizers::load().set(&state.device.unwrap_or_default(), equalizer);
+
+    // Let the audio thread catch up, so the state reflects the change
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+    Ok(warp::reply::json(&eq_state(&jukebox)))
+}
+
+fn outputs_state(jukebox: &Jukebox) -> Result<OutputsState, warp::Rejection> {
+    Ok(OutputsState {
+        current: jukebox.state().device,
+        outputs: jukebox.outputs().map_err(error::internal)?,
+    })
+}
+
+async fn handle_player_outputs(
+    jukebox: Option<Arc<Jukebox>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let jukebox = jukebox.ok_or_else(|| error::bad_request("jukebox mode is off"))?;
+    Ok(warp::reply::json(&outputs_state(&jukebox)?))
+}
+
+/// The body of `POST /player/outputs`.
+#[derive(Deserialize)]
+struct OutputUpdate {
+    name: String,
+}
+
+async fn handle_player_outputs_update(
+    update: OutputUpdate,
+    jukebox: Option<Arc<Jukebox>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let jukebox = jukebox.ok_or_else(|| error::bad_request("jukebox mode is off"))?;
+    // Opening an output can take a moment, so keep it off the async runtime
+    let switched = Arc::clone(&jukebox);
+    tokio::task::spawn_blocking(move || switched.set_output(&update.name))
+        .await
+        .map_err(|e| error::internal(e.to_string()))?
+        .map_err(error::bad_request)?;
+
+    // Each output has its own equalizer
+    let device = jukebox.state().device.unwrap_or_default();
+    jukebox.set_equalizer(Equalizers::load().get(&device));
+
+    Ok(warp::reply::json(&outputs_state(&jukebox)?))
+}
@@ -0,0 +1,152 @@
+//! Playlists, importing them, and the wishlist of what they name that isn't in the library.
+
+use bwaabwaa::{
+    audit::{self, Action},
+    music_db::MusicDB,
+    playlist_import::{self, Imported},
+    playlists::Playlists,
+    wishlist::Wishlist,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use warp::{filters::BoxedFilter, http::StatusCode, Filter, Reply};
+
+use super::{with, Server};
+use crate::error;
+
+/// Playlists and the wishlist.
+pub fn json(server: &Server) -> BoxedFilter<(warp::reply::Response,)> {
+    let who = server.who();
+    let database = with(&server.database);
+    let playlists = with(&server.playlists);
+    let wishlist = with(&server.wishlist);
+
+    let playlists_list = warp::path!("playlists")
+        .and(warp::get())
+        .and(playlists.clone())
+        .and_then(handle_playlists_list);
+
+    let playlist_details = warp::path!("playlists" / String)
+        .and(warp::get())
+        .and(database.clone())
+        .and(playlists.clone())
+        .and_then(handle_playlist_details);
+
+    let playlists_import = warp::path!("playlists" / "import")
+        .and(warp::post())
+        .and(warp::query())
+        .and(warp::body::bytes())
+        .and(who.clone())
+        .and(database.clone())
+        .and(playlists.clone())
+        .and(wishlist.clone())
+        .and_then(handle_playlists_import);
+
+    let wishlist_list = warp::path!("wishlist")
+        .and(warp::get())
+        .and(playlists.clone())
+        .and(wishlist.clone())
+        .and_then(handle_wishlist);
+
+    let wishlist_remove = warp::path!("wishlist" / String)
+        .and(warp::delete())
+        .and(wishlist.clone())
+        .and_then(handle_wishlist_remove);
+
+    playlists_list
+        .or(playlist_details)
+        .or(playlists_import)
+        .or(wishlist_list)
+        .or(wishlist_remove)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+async fn handle_playlists_list(
+    playlists: Arc<Mutex<Playlists>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(warp::reply::json(&playlists.lock().await.list()))
+}
+
+async fn handle_playlist_details(
+    id: String,
+    database: Arc<Mutex<MusicDB>>,
+    playlists: Arc<Mutex<Playlists>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let id = error::parse_id(&id)?;
+    let db = database.lock().await;
+    match playlists.lock().await.details(id, &db) {
+        Some(details) => Ok(warp::reply::json(&details)),
+        None => Err(error::not_found(format!("no such playlist: {}", id))),
+    }
+}
+
+#[derive(Deserialize)]
+struct PlaylistImportQuery {
+    name: Option<String>,
+}
+
+/// Makes a playlist from a list of tracks exported from another service, finding each of them in
+/// the library. Those that aren't go on the wishlist.
+async fn handle_playlists_import(
+    query: PlaylistImportQuery,
+    body: warp::hyper::body::Bytes,
+    who: String,
+    database: Arc<Mutex<MusicDB>>,
+    playlists: Arc<Mutex<Playlists>>,
+    wishlist: Arc<Mutex<Wishlist>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let text = std::str::from_utf8(&body).map_err(|_| error::bad_request("expected UTF-8"))?;
+    let tracks = playlist_import::parse(text).map_err(error::bad_request)?;
+    if tracks.is_empty() {
+        return Err(error::bad_request("no tracks to import"));
+    }
+
+    let resolved = playlist_import::resolve(&*database.lock().await, &tracks);
+    let name = query
+        .name
+        .filter(|name| !name.trim().is_empty())
+        .unwrap_or_else(|| "Imported playlist".to_string());
+    let playlist = playlists.lock().await.create(name, resolved.songs);
+    let id = error::parse_id(&playlist.id)?;
+    wishlist.lock().await.add(id, &resolved.unmatched);
+
+    audit::record(
+        &who,
+        Action::PlaylistImport,
+        format!(
+            "{}: {} of {} tracks",
+            playlist.name,
+            playlist.songs,
+            tracks.len()
+        ),
+    );
+    let imported = Imported {
+        playlist,
+        unmatched: resolved.unmatched,
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&imported),
+        StatusCode::CREATED,
+    ))
+}
+
+async fn handle_wishlist(
+    playlists: Arc<Mutex<Playlists>>,
+    wishlist: Arc<Mutex<Wishlist>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let playlists = playlists.lock().await;
+    Ok(warp::reply::json(&wishlist.lock().await.albums(&playlists)))
+}
+
+async fn handle_wishlist_remove(
+    id: String,
+    wishlist: Arc<Mutex<Wishlist>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let id = error::parse_id(&id)?;
+    if !wishlist.lock().await.remove(id) {
+        return Err(error::not_found(format!("no such wish: {}", id)));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,180 @@
+//! Listening rooms: their pages, opening and closing them, and their sockets.
+
+use askama::Template;
+use bwaabwaa::music_db::MusicDB;
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use warp::{filters::BoxedFilter, http::StatusCode, Filter, Reply};
+
+use super::{wants_json, with, Server};
+use crate::{
+    error,
+    room_page::RoomPage,
+    rooms::{Room, Rooms},
+    themes::{self, ThemeChoice},
+};
+
+/// Listing, opening and closing rooms.
+pub fn json(server: &Server) -> BoxedFilter<(warp::reply::Response,)> {
+    let database = with(&server.database);
+    let rooms = with(&server.rooms);
+
+    let rooms_list = warp::path!("rooms")
+        .and(warp::get())
+        .and(database.clone())
+        .and(rooms.clone())
+        .and_then(handle_rooms_list);
+
+    let rooms_create = warp::path!("rooms")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(rooms.clone())
+        .and_then(handle_rooms_create);
+
+    let rooms_close = warp::path!("rooms" / String)
+        .and(warp::delete())
+        .and(rooms.clone())
+        .and_then(handle_rooms_close);
+
+    rooms_list
+        .or(rooms_create)
+        .or(rooms_close)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+/// A room's page, or its JSON when `accept` asks for it.
+pub fn page(
+    server: &Server,
+    accept: BoxedFilter<(Option<String>,)>,
+) -> BoxedFilter<(warp::reply::Response,)> {
+    warp::path!("rooms" / String)
+        .and(warp::get())
+        .and(accept)
+        .and(themes::theme())
+        .and(with(&server.database))
+        .and(with(&server.rooms))
+        .and_then(handle_room)
+        .map(Reply::into_response)
+        .boxed()
+}
+
+/// A room's socket, for listening along.
+pub fn socket(server: &Server) -> BoxedFilter<(warp::reply::Response,)> {
+    let database = with(&server.database);
+    let rooms = with(&server.rooms);
+
+    let room_socket = warp::path!("rooms" / String / "ws")
+        .and(warp::ws())
+        .and(rooms.clone())
+        .and(database.clone())
+        .and_then(handle_room_socket);
+
+    room_socket.map(Reply::into_response).boxed()
+}
+
+async fn handle_room(
+    name: String,
+    accept: Option<String>,
+    theme: ThemeChoice,
+    database: Arc<Mutex<MusicDB>>,
+    rooms: Arc<Mutex<Rooms>>,
+) -> Result<Box<dyn warp::Reply>, warp::Rejection> {
+    let name = decode_room(&name);
+    let db = database.lock().await;
+    let rooms = rooms.lock().await;
+    let Some(room) = rooms.get(&name) else {
+        return Err(error::not_found(format!("no room named {}", name)));
+    };
+
+    if wants_json(&accept) {
+        Ok(Box::new(warp::reply::json(&room.state(&name, &db))))
+    } else {
+        let body = RoomPage {
+            theme: theme.theme,
+            name,
+        }
+        .render()
+        .unwrap();
+        Ok(Box::new(
+            theme.remember(warp::reply::html(body).into_response()),
+        ))
+    }
+}
+
+/// A room's name, from its path.
+fn decode_room(name: &str) -> String {
+    percent_encoding::percent_decode_str(name)
+        .decode_utf8_lossy()
+        .into_owned()
+}
+
+/// The body of `POST /rooms`.
+#[derive(Deserialize)]
+struct NewRoomRequest {
+    name: String,
+}
+
+async fn handle_rooms_list(
+    database: Arc<Mutex<MusicDB>>,
+    rooms: Arc<Mutex<Rooms>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let db = database.lock().await;
+    let rooms = rooms.lock().await;
+    let summaries = rooms
+        .iter()
+        .map(|(name, room)| room.summary(name, &db))
+        .collect::<Vec<_>>();
+    Ok(warp::reply::json(&summaries))
+}
+
+async fn handle_rooms_create(
+    request: NewRoomRequest,
+    rooms: Arc<Mutex<Rooms>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let name = request.name.trim().to_string();
+    if name.is_empty() || name.contains('/') {
+        return Err(error::bad_request(
+            "a room's name can't be empty or contain '/'",
+        ));
+    }
+
+    let mut rooms = rooms.lock().await;
+    if rooms.contains_key(&name) {
+        return Err(error::bad_request(format!(
+            "there's already a room named {}",
+            name
+        )));
+    }
+    rooms.insert(name.clone(), Room::default());
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "name": name })),
+        StatusCode::CREATED,
+    ))
+}
+
+async fn handle_rooms_close(
+    name: String,
+    rooms: Arc<Mutex<Rooms>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let name = decode_room(&name);
+    // Its listeners are disconnected once it's dropped
+    if rooms.lock().await.remove(&name).is_none() {
+        return Err(error::not_found(format!("no room named {}", name)));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn handle_room_socket(
+    name: String,
+    ws: warp::ws::Ws,
+    rooms: Arc<Mutex<Rooms>>,
+    database: Arc<Mutex<MusicDB>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let name = decode_room(&name);
+    if !rooms.lock().await.contains_key(&name) {
+        return Err(error::not_found(format!("no room named {}", name)));
+    }
+    Ok(ws.on_upgrade(move |socket| crate::rooms::listen(socket, name, rooms, database)))
+}
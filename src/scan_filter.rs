@@ -0,0 +1,153 @@
+//! Which files and directories a scan looks at.
+//!
+//! Patterns are globs, like a `.gitignore`'s: one without a slash (eg `*.part` or `.cache`)
+//! matches a file or directory by name, anywhere; one with a slash (eg `incoming/untagged` or
+//! `**/scans/*.pdf`) matches its path relative to the directory being scanned.
+
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use std::path::Path;
+
+#[derive(Debug, Default, Clone)]
+pub struct ScanFilter {
+    /// If any include patterns were given, only files matching one are read
+    include: Option<Patterns>,
+    /// Files and directories to skip entirely
+    exclude: Patterns,
+    /// Whether to read hidden files and descend into hidden directories
+    pub include_hidden: bool,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Patterns {
+    by_name: GlobSet,
+    by_path: GlobSet,
+}
+
+impl Patterns {
+    fn new(patterns: &[String]) -> Result<Self, globset::Error> {
+        let mut by_name = GlobSetBuilder::new();
+        let mut by_path = GlobSetBuilder::new();
+        for pattern in patterns {
+            let pattern = pattern.trim_end_matches('/');
+            if pattern.contains('/') {
+                // Unlike by name, `*` shouldn't match across directories
+                by_path.add(
+                    GlobBuilder::new(pattern.trim_start_matches('/'))
+                        .literal_separator(true)
+                        .build()?,
+                );
+            } else {
+                by_name.add(Glob::new(pattern)?);
+            }
+        }
+
+        Ok(Patterns {
+            by_name: by_name.build()?,
+            by_path: by_path.build()?,
+        })
+    }
+
+    fn matches(&self, relative: &Path) -> bool {
+        relative
+            .file_name()
+            .is_some_and(|name| self.by_name.is_match(name))
+            || self.by_path.is_match(relative)
+    }
+}
+
+impl ScanFilter {
+    pub fn new(
+        include: &[String],
+        exclude: &[String],
+        include_hidden: bool,
+    ) -> Result<Self, globset::Error> {
+        Ok(ScanFilter {
+            include: if include.is_empty() {
+                None
+            } else {
+                Some(Patterns::new(include)?)
+            },
+            exclude: Patterns::new(exclude)?,
+            include_hidden,
+        })
+    }
+
+    /// Whether to descend into `directory`, somewhere under `root`.
+    pub fn wants_directory(&self, root: &Path, directory: &Path) -> bool {
+        self.allowed(root, directory)
+    }
+
+    /// Whether to read `file`, somewhere under `root`.
+    pub fn wants_file(&self, root: &Path, file: &Path) -> bool {
+        self.allowed(root, file)
+            && self
+                .include
+                .as_ref()
+                .is_none_or(|include| include.matches(file.strip_prefix(root).unwrap_or(file)))
+    }
+
+    fn allowed(&self, root: &Path, path: &Path) -> bool {
+        if !self.include_hidden && is_hidden(path) {
+            return false;
+        }
+
+        !self
+            .exclude
+            .matches(path.strip_prefix(root).unwrap_or(path))
+    }
+}
+
+/// Dotfiles, and on Windows, anything with the hidden attribute.
+fn is_hidden(path: &Path) -> bool {
+    let dotfile = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with('.'));
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+
+        dotfile
+            || std::fs::symlink_metadata(path)
+                .is_ok_and(|m| m.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+    }
+
+    #[cfg(not(windows))]
+    dotfile
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> ScanFilter {
+        let strings =
+            |patterns: &[&str]| patterns.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        ScanFilter::new(&strings(include), &strings(exclude), false).unwrap()
+    }
+
+    #[test]
+    fn skips_hidden_and_excluded() {
+        let root = Path::new("/music");
+        let filter = filter(&[], &["incoming/untagged", "*.part"]);
+
+        assert!(!filter.wants_directory(root, Path::new("/music/.git")));
+        assert!(!filter.wants_directory(root, Path::new("/music/incoming/untagged")));
+        assert!(filter.wants_directory(root, Path::new("/music/incoming")));
+        assert!(filter.wants_directory(root, Path::new("/music/other/incoming/untagged")));
+        assert!(!filter.wants_file(root, Path::new("/music/a/song.mp3.part")));
+        assert!(filter.wants_file(root, Path::new("/music/a/song.mp3")));
+    }
+
+    #[test]
+    fn include_patterns_only_apply_to_files() {
+        let root = Path::new("/music");
+        let filter = filter(&["*.mp3"], &[]);
+
+        assert!(filter.wants_directory(root, Path::new("/music/a")));
+        assert!(filter.wants_file(root, Path::new("/music/a/song.mp3")));
+        assert!(!filter.wants_file(root, Path::new("/music/a/cover.jpg")));
+    }
+}
@@ -0,0 +1,111 @@
+//! Scheduled scans. With `--scan-schedule="0 3 * * *"` (a cron expression, in local time: here,
+//! nightly at 3am), the server looks for new files under every root at those times, going on
+//! serving while it does. Each scan is logged and audited; if it finds anything, `scan_complete` is
+//! published to the event bus, and `new_album` for each new album, and the wishlist is checked
+//! again.
+//!
+//! Only new files are read. Rescanning files already in the library still takes `--rescan=`.
+
+use crate::audit::{self, Action};
+use crate::events::EventBus;
+use crate::music_db::{MusicDB, ScanOptions};
+use crate::playlists::Playlists;
+use crate::wishlist::Wishlist;
+use croner::Cron;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+
+/// When to scan.
+pub struct ScanSchedule {
+    cron: Cron,
+}
+
+impl ScanSchedule {
+    /// The schedule given by a cron expression: minute, hour, day of month, month, and day of week.
+    pub fn new(expression: &str) -> Result<Self, String> {
+        let cron = Cron::new(expression).parse().map_err(|e| e.to_string())?;
+        let schedule = ScanSchedule { cron };
+        match schedule.until_next() {
+            Some(_) => Ok(schedule),
+            None => Err(format!("{} never comes round", expression)),
+        }
+    }
+
+    /// How long until the next scan is due.
+    fn until_next(&self) -> Option<Duration> {
+        let now = chrono::Local::now();
+        let next = self.cron.find_next_occurrence(&now, false).ok()?;
+        (next - now).to_std().ok()
+    }
+
+    /// Scans whenever it's due, until the server stops.
+    pub async fn run(
+        self,
+        database: Arc<Mutex<MusicDB>>,
+        options: ScanOptions,
+        events: EventBus,
+        wishlist: Arc<Mutex<Wishlist>>,
+        playlists: Arc<Mutex<Playlists>>,
+    ) {
+        let options = Arc::new(options);
+
+        while let Some(wait) = self.until_next() {
+            tokio::time::sleep(wait).await;
+
+            let started = (crate::history::now(), std::time::Instant::now());
+            let (roots, known_files) = {
+                let db = database.lock().await;
+                (db.roots.clone(), db.known_files())
+            };
+            let options = Arc::clone(&options);
+            let found = tokio::task::spawn_blocking(move || {
+                MusicDB::scan_new(&roots, known_files, &options)
+            })
+            .await;
+            let Ok(found) = found else {
+                eprintln!("Scheduled scan failed");
+                continue;
+            };
+
+            let mut db = database.lock().await;
+            let added = db.merge(found);
+            db.save();
+
+            println!(
+                "Scheduled scan found {} new files in {:.2?}",
+                added,
+                started.1.elapsed()
+            );
+            audit::record(
+                audit::SERVER,
+                Action::Scan,
+                format!(
+                    "scheduled: {} new, {} songs in the library",
+                    added,
+                    db.records.len()
+                ),
+            );
+            if added > 0 {
+                events.scan_complete(&db, started.0, started.1.elapsed());
+                let mut playlists = playlists.lock().await;
+                let found = wishlist.lock().await.resolve(&db, &mut playlists);
+                if found > 0 {
+                    println!("Found {} tracks on the wishlist", found);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expressions() {
+        assert!(ScanSchedule::new("0 3 * * *").is_ok());
+        assert!(ScanSchedule::new("*/15 * * * 1-5").is_ok());
+        assert!(ScanSchedule::new("nightly").is_err());
+        assert!(ScanSchedule::new("0 3 30 2 *").is_err());
+    }
+}
@@ -0,0 +1,119 @@
+//! Scrobbling to ListenBrainz, with `--listenbrainz-token=<token>` (from
+//! https://listenbrainz.org/settings/): what's playing as it starts, and each play once it's
+//! counted, which is half-way through; see `progress`. It subscribes to the event bus (see
+//! `events`), so only plays by clients that report their progress to `POST /progress` are
+//! submitted, though whatever starts playing is shown as playing now.
+//!
+//! `LISTENBRAINZ_API_URL` points it at another server with the same API, eg a self-hosted one.
+
+use crate::events::{Published, ServerEvent, Subscriber};
+use crate::song::SongResult;
+use std::time::Duration;
+
+/// ListenBrainz's own API
+pub const API: &str = "https://api.listenbrainz.org";
+
+/// How long to wait on ListenBrainz before giving up.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A ListenBrainz account to submit listens to. Cheap to clone.
+#[derive(Clone)]
+pub struct ListenBrainz {
+    api: String,
+    token: String,
+    client: reqwest::Client,
+}
+
+impl ListenBrainz {
+    pub fn new(api: &str, token: String) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        ListenBrainz {
+            api: api.trim_end_matches('/').to_string(),
+            token,
+            client,
+        }
+    }
+
+    /// Says `song` has started playing, in the background.
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn now_playing(&self, song: &SongResult) {
+        self.submit("playing_now", listen(song, None));
+    }
+
+    /// Submits a play of `song` that started at `started` (in seconds since the Unix epoch), in
+    /// the background.
+    ///
+    /// Must be called from within a Tokio runtime.
+    pub fn listened(&self, song: &SongResult, started: u64) {
+        self.submit("single", listen(song, Some(started)));
+    }
+
+    /// Failures are logged and otherwise ignored.
+    fn submit(&self, listen_type: &str, listen: serde_json::Value) {
+        let body = serde_json::json!({
+            "listen_type": listen_type,
+            "payload": [listen],
+        });
+        let request = self
+            .client
+            .post(format!("{}/1/submit-listens", self.api))
+            .header("authorization", format!("Token {}", self.token))
+            .json(&body);
+
+        tokio::spawn(async move {
+            if let Err(e) = request.send().await.and_then(|r| r.error_for_status()) {
+                eprintln!("Scrobbling to ListenBrainz failed: {}", e);
+            }
+        });
+    }
+}
+
+impl Subscriber for ListenBrainz {
+    fn notify(&self, published: &Published) {
+        match &published.event {
+            ServerEvent::NowPlaying { song } => self.now_playing(song),
+            ServerEvent::Listened { song, started } => self.listened(song, *started),
+            _ => {}
+        }
+    }
+}
+
+/// A listen, as ListenBrainz wants it; `playing_now` ones have no time.
+fn listen(song: &SongResult, started: Option<u64>) -> serde_json::Value {
+    let mut track = serde_json::json!({
+        "artist_name": song.artist,
+        "track_name": song.title,
+        "additional_info": {
+            "submission_client": "bwaa-bwaa",
+        },
+    });
+    if let Some(millis) = millis(&song.duration) {
+        track["additional_info"]["duration_ms"] = millis.into();
+    }
+    if !song.album.is_empty() {
+        track["release_name"] = song.album.clone().into();
+    }
+    if let Some(number) = song.track {
+        track["additional_info"]["tracknumber"] = number.into();
+    }
+
+    let mut listen = serde_json::json!({ "track_metadata": track });
+    if let Some(started) = started {
+        listen["listened_at"] = started.into();
+    }
+    listen
+}
+
+/// Reads a duration formatted as `[h:]mm:ss` in milliseconds.
+fn millis(duration: &str) -> Option<u64> {
+    duration
+        .split(':')
+        .try_fold(0, |total, part| {
+            Some(total * 60 + part.parse::<u64>().ok()?)
+        })
+        .map(|seconds| seconds * 1000)
+}
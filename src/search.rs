@@ -1,7 +1,207 @@
+use crate::{cache::Validators, i18n::Lang, themes::Theme};
 use askama::Template;
+use bwaabwaa::{
+    music_db::{MusicDB, SortBy},
+    song::SongResult,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
 
 #[derive(Template)]
 #[template(path = "search.html")]
-pub struct SearchResults<'a> {
-    pub results: Vec<&'a crate::song::Song>,
+pub struct SearchResults {
+    /// One page of the library, rendered up front so it shows before any script runs
+    pub results: Vec<SongResult>,
+    /// 1-based
+    pub page: usize,
+    pub pages: usize,
+    pub limit: usize,
+    pub theme: Theme,
+    pub lang: Lang,
+}
+
+impl SearchResults {
+    /// eg, "Page 2 of 5"
+    fn page_of(&self) -> String {
+        self.lang
+            .t_with("page-of", &[("page", self.page), ("pages", self.pages)])
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LibraryQuery {
+    /// 1-based
+    pub page: Option<usize>,
+    pub limit: Option<usize>,
+}
+
+impl LibraryQuery {
+    const DEFAULT_LIMIT: usize = 100;
+    const MAX_LIMIT: usize = 1000;
+}
+
+/// How many rendered pages are kept, at most; the least recently used go first
+const MAX_CACHED_PAGES: usize = 64;
+
+/// Rendered pages of the library, kept until the library changes. Like search, they leave out
+/// hidden songs, and explicit ones in kid mode.
+#[derive(Default)]
+pub struct LibraryPageCache {
+    /// The library's generation, and whether kid mode was on, when `order` was made
+    made: Option<(u64, bool)>,
+    /// Every song id that's shown, in artist order
+    order: Vec<u64>,
+    /// Keyed by (page, limit, theme, language)
+    pages: HashMap<(usize, usize, Theme, Lang), CachedPage>,
+    /// Counts pages gotten, to say which was used least recently
+    gets: u64,
+}
+
+struct CachedPage {
+    html: String,
+    validators: Validators,
+    /// When it was last gotten, by `gets`
+    used: u64,
+}
+
+impl LibraryPageCache {
+    /// Gets a page of the library (and its cache validators), rendering it only if the library
+    /// has changed since it was last rendered.
+    pub fn get(
+        &mut self,
+        db: &MusicDB,
+        query: &LibraryQuery,
+        theme: Theme,
+        lang: Lang,
+    ) -> (&str, &Validators) {
+        let made = (db.generation(), db.kid_mode);
+        if self.made != Some(made) {
+            let mut songs = db
+                .records
+                .values()
+                .filter(|s| !s.hidden && db.kid_safe(s))
+                .collect::<Vec<_>>();
+            songs.sort_unstable_by(|a, b| a.cmp(b, SortBy::artist));
+
+            self.order = songs.into_iter().map(|s| s.id).collect();
+            self.pages.clear();
+            self.made = Some(made);
+        }
+
+        let limit = query
+            .limit
+            .unwrap_or(LibraryQuery::DEFAULT_LIMIT)
+            .clamp(1, LibraryQuery::MAX_LIMIT);
+        let pages = self.order.len().div_ceil(limit).max(1);
+        let page = query.page.unwrap_or(1).clamp(1, pages);
+
+        let key = (page, limit, theme, lang);
+        if !self.pages.contains_key(&key) && self.pages.len() >= MAX_CACHED_PAGES {
+            let oldest = self
+                .pages
+                .iter()
+                .min_by_key(|(_, p)| p.used)
+                .map(|(&k, _)| k);
+            if let Some(oldest) = oldest {
+                self.pages.remove(&oldest);
+            }
+        }
+
+        self.gets += 1;
+        let order = &self.order;
+        let page = self.pages.entry(key).or_insert_with(|| {
+            let results = order
+                .iter()
+                .skip((page - 1) * limit)
+                .take(limit)
+                .filter_map(|id| db.records.get(id))
+                .map(|s| s.into())
+                .collect();
+            let html = SearchResults {
+                results,
+                page,
+                pages,
+                limit,
+                theme,
+                lang,
+            }
+            .render()
+            .unwrap();
+            let validators = Validators::for_static(html.as_bytes());
+
+            CachedPage {
+                html,
+                validators,
+                used: 0,
+            }
+        });
+        page.used = self.gets;
+
+        (&page.html, &page.validators)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bwaabwaa::song::Song;
+
+    #[test]
+    fn leaves_out_what_search_does() {
+        let mut db = MusicDB::default();
+        for (id, hidden, explicit) in [(1, false, false), (2, true, false), (3, false, true)] {
+            db.records.insert(
+                id,
+                Song {
+                    id,
+                    hidden,
+                    explicit,
+                    ..Default::default()
+                },
+            );
+        }
+        let mut cache = LibraryPageCache::default();
+        let mut shown = |db: &MusicDB| {
+            cache.get(
+                db,
+                &LibraryQuery {
+                    page: None,
+                    limit: None,
+                },
+                Theme::default(),
+                Lang::default(),
+            );
+            cache.order.clone()
+        };
+
+        let mut all = shown(&db);
+        all.sort();
+        assert_eq!(all, [1, 3]);
+        // Even without the library changing otherwise
+        db.kid_mode = true;
+        assert_eq!(shown(&db), [1]);
+    }
+
+    #[test]
+    fn keeps_only_the_most_recently_used_pages() {
+        let db = MusicDB::default();
+        let mut cache = LibraryPageCache::default();
+        let mut get = |limit| {
+            let query = LibraryQuery {
+                page: None,
+                limit: Some(limit),
+            };
+            cache.get(&db, &query, Theme::default(), Lang::default());
+        };
+
+        get(1);
+        for limit in 2..MAX_CACHED_PAGES + 10 {
+            get(limit);
+            // Keep using the first
+            get(1);
+        }
+        assert_eq!(cache.pages.len(), MAX_CACHED_PAGES);
+        assert!(cache.pages.keys().any(|&(_, limit, _, _)| limit == 1));
+        assert!(!cache.pages.keys().any(|&(_, limit, _, _)| limit == 2));
+    }
 }
@@ -0,0 +1,37 @@
+//! What kind of audio a library root holds. Each section is kept apart from the others when
+//! searching and browsing, and gets its own defaults: audiobooks and podcasts are never shuffled
+//! and remember where they were stopped (see `resume`), and podcasts list newest first.
+
+use crate::music_db::SortBy;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Section {
+    #[default]
+    Music,
+    Audiobooks,
+    Podcasts,
+}
+
+impl Section {
+    /// How searches are sorted when they don't say
+    pub fn default_sort(self) -> SortBy {
+        match self {
+            Section::Music => SortBy::track,
+            // Keeps each book's chapters together and in order
+            Section::Audiobooks => SortBy::album,
+            Section::Podcasts => SortBy::added,
+        }
+    }
+
+    /// Whether its songs can be shuffled, or picked at random or for a radio station
+    pub fn shuffles(self) -> bool {
+        self == Section::Music
+    }
+
+    /// Whether playback positions are kept, so listening can pick up where it left off
+    pub fn resumes(self) -> bool {
+        self != Section::Music
+    }
+}
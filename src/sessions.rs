@@ -0,0 +1,255 @@
+//! Signed-in browsers. Each session is a random id, kept in a cookie signed with HMAC-SHA256 (so a
+//! forged or tampered cookie is turned away before it's looked up) and saved in `sessions.json`
+//! (so signing out ends it, and restarting the server doesn't).
+//!
+//! The signing key is made on first use and kept in `session.key`; deleting it signs everyone
+//! out.
+
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter},
+};
+
+pub(crate) const SESSIONS_FILE: &str = "sessions.json";
+const KEY_FILE: &str = "session.key";
+
+/// How long a session lasts, in seconds
+const LIFETIME: u64 = 12 * 60 * 60;
+/// How long it lasts with "remember me"
+pub const REMEMBERED_LIFETIME: u64 = 30 * 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    pub user: String,
+    /// In seconds since the Unix epoch
+    pub expires: u64,
+    /// Whether the cookie outlives the browser
+    pub remember: bool,
+}
+
+/// Made with `default()`, eg in tests, they're only kept in memory, with a new signing key.
+pub struct Sessions {
+    key: Vec<u8>,
+    /// By id
+    sessions: HashMap<String, Session>,
+    /// Whether changes are saved to `sessions.json`
+    saved: bool,
+}
+
+pub(crate) fn random(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
+
+/// Whether `a` and `b` are the same, comparing every byte, so the time taken doesn't say how much
+/// matched.
+pub(crate) fn same(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |d, (a, b)| d | (a ^ b)) == 0
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Sessions {
+            key: random(64).into_bytes(),
+            sessions: HashMap::new(),
+            saved: false,
+        }
+    }
+}
+
+impl Sessions {
+    /// Loads the sessions and the signing key, making a key if there isn't one. Expired sessions
+    /// are dropped.
+    pub fn load() -> Self {
+        let key = match std::fs::read(KEY_FILE) {
+            Ok(key) if !key.is_empty() => key,
+            _ => {
+                let key = random(64).into_bytes();
+                if let Err(e) = std::fs::write(KEY_FILE, &key) {
+                    eprintln!(
+                        "Unable to save {KEY_FILE}; sessions won't survive a restart: {:?}",
+                        e
+                    );
+                }
+                key
+            }
+        };
+
+        let mut sessions: HashMap<String, Session> = File::open(SESSIONS_FILE)
+            .ok()
+            .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+            .unwrap_or_default();
+        let now = crate::history::now();
+        sessions.retain(|_, s| s.expires > now);
+
+        Sessions {
+            key,
+            sessions,
+            saved: true,
+        }
+    }
+
+    fn save(&self) {
+        if !self.saved {
+            return;
+        }
+        let saved = File::create(SESSIONS_FILE).and_then(|file| {
+            serde_json::to_writer(BufWriter::new(file), &self.sessions)?;
+            Ok(())
+        });
+        if let Err(e) = saved {
+            eprintln!("Unable to save sessions: {:?}", e);
+        }
+    }
+
+    fn sign(&self, purpose: &str, id: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes any key");
+        mac.update(purpose.as_bytes());
+        mac.update(id.as_bytes());
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Starts a session for `user`, returning its cookie value.
+    pub fn start(&mut self, user: &str, remember: bool) -> String {
+        let id = random(32);
+        let lifetime = if remember {
+            REMEMBERED_LIFETIME
+        } else {
+            LIFETIME
+        };
+        self.sessions.insert(
+            id.clone(),
+            Session {
+                user: user.to_string(),
+                expires: crate::history::now() + lifetime,
+                remember,
+            },
+        );
+        self.save();
+
+        format!("{}.{}", id, self.sign("session:", &id))
+    }
+
+    /// The session `cookie` belongs to, if it's genuine and hasn't expired.
+    pub fn get(&self, cookie: &str) -> Option<&Session> {
+        let (id, signature) = cookie.split_once('.')?;
+        if !same(signature, &self.sign("session:", id)) {
+            return None;
+        }
+
+        self.sessions
+            .get(id)
+            .filter(|s| s.expires > crate::history::now())
+    }
+
+    /// Ends the session `cookie` belongs to.
+    pub fn end(&mut self, cookie: &str) {
+        if self.get(cookie).is_none() {
+            return;
+        }
+        if let Some((id, _)) = cookie.split_once('.') {
+            self.sessions.remove(id);
+            self.save();
+        }
+    }
+
+    /// The token a session's forms must send back, so that another site can't post them on its
+    /// behalf.
+    pub fn csrf_token(&self, cookie: &str) -> String {
+        let id = cookie.split_once('.').map_or(cookie, |(id, _)| id);
+        self.sign("csrf:", id)
+    }
+
+    /// Whether `token` is the CSRF token of the session `cookie` belongs to.
+    pub fn check_csrf(&self, cookie: &str, token: &str) -> bool {
+        same(token, &self.csrf_token(cookie))
+    }
+
+    /// A cookie to give with the sign-in form, and the token the form must send back with it, so
+    /// that another site can't sign a browser in to an account of its choosing.
+    pub fn login_csrf(&self) -> (String, String) {
+        let cookie = random(32);
+        let token = self.sign("login:", &cookie);
+        (cookie, token)
+    }
+
+    /// Whether `token` was given with the sign-in form along with `cookie`.
+    pub fn check_login_csrf(&self, cookie: &str, token: &str) -> bool {
+        !cookie.is_empty() && same(token, &self.sign("login:", cookie))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cookies_are_signed() {
+        let mut sessions = Sessions::default();
+        let cookie = sessions.start("me", false);
+        assert_eq!(sessions.get(&cookie).unwrap().user, "me");
+
+        let (id, signature) = cookie.split_once('.').unwrap();
+        let tampered = format!("{}.{}", id, "0".repeat(signature.len()));
+        assert!(sessions.get(&tampered).is_none());
+        assert!(sessions.get(id).is_none());
+        // Signed with another key
+        assert!(Sessions::default().get(&cookie).is_none());
+    }
+
+    #[test]
+    fn sessions_end_or_expire() {
+        let mut sessions = Sessions::default();
+        let remembered = sessions.start("me", true);
+        let cookie = sessions.start("me", false);
+        let lifetime = |cookie: &str| sessions.get(cookie).unwrap().expires - crate::history::now();
+        assert!(lifetime(&cookie) <= LIFETIME);
+        assert!(lifetime(&remembered) > LIFETIME);
+
+        sessions.end(&remembered);
+        assert!(sessions.get(&remembered).is_none());
+
+        let (id, _) = cookie.split_once('.').unwrap();
+        sessions.sessions.get_mut(id).unwrap().expires = crate::history::now();
+        assert!(sessions.get(&cookie).is_none());
+    }
+
+    #[test]
+    fn csrf_tokens_are_per_session() {
+        let mut sessions = Sessions::default();
+        let mine = sessions.start("me", false);
+        let theirs = sessions.start("them", false);
+        let token = sessions.csrf_token(&mine);
+
+        assert!(sessions.check_csrf(&mine, &token));
+        assert!(!sessions.check_csrf(&theirs, &token));
+        assert!(!sessions.check_csrf(&mine, ""));
+        assert!(!sessions.check_csrf(&mine, &token[1..]));
+    }
+
+    #[test]
+    fn login_csrf_tokens_go_with_their_cookies() {
+        let sessions = Sessions::default();
+        let (cookie, token) = sessions.login_csrf();
+        let (other, _) = sessions.login_csrf();
+
+        assert!(sessions.check_login_csrf(&cookie, &token));
+        assert!(!sessions.check_login_csrf(&other, &token));
+        assert!(!sessions.check_login_csrf(&cookie, ""));
+        assert!(!sessions.check_login_csrf("", &sessions.sign("login:", "")));
+        // Not the same as the session's own CSRF token
+        assert!(!sessions.check_csrf(&cookie, &token));
+    }
+}
@@ -0,0 +1,127 @@
+use crate::music_db::{MusicDB, SearchTerms};
+use crate::song::Song;
+use rand::{seq::SliceRandom, Rng};
+use std::collections::HashMap;
+
+impl MusicDB {
+    /// Shuffles the songs matching `search_terms`, spreading each artist's songs out as evenly as
+    /// possible across the result. Sections that are never shuffled (audiobooks and podcasts)
+    /// are left out.
+    ///
+    /// A plain shuffle of a library dominated by a few artists clumps them together. Instead,
+    /// each artist's songs are shuffled amongst themselves and then placed at roughly even
+    /// intervals (with a random starting offset and a little jitter), and the whole list is
+    /// ordered by those positions.
+    pub fn shuffle(&self, search_terms: &SearchTerms) -> Vec<&Song> {
+        let mut rng = rand::thread_rng();
+
+        let mut by_artist: HashMap<&str, Vec<&Song>> = HashMap::new();
+        for song in self.matching(search_terms).filter(|s| s.section.shuffles()) {
+            by_artist.entry(&song.artist_lower).or_default().push(song);
+        }
+
+        let mut positioned = Vec::new();
+        for songs in by_artist.values_mut() {
+            songs.shuffle(&mut rng);
+
+            let spacing = 1.0 / songs.len() as f64;
+            let offset = rng.gen_range(0.0..spacing);
+            for (i, &song) in songs.iter().enumerate() {
+                let jitter = rng.gen_range(-0.1..0.1) * spacing;
+                positioned.push((offset + i as f64 * spacing + jitter, song));
+            }
+        }
+
+        positioned.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+
+        let limit = search_terms.limit.map_or(usize::MAX, |l| l as usize);
+        positioned
+            .into_iter()
+            .take(limit)
+            .map(|(_, song)| song)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sections::Section;
+
+    /// A library with `count` songs by each artist, numbered from 1 in turn.
+    fn db(artists: &[(&str, usize)]) -> MusicDB {
+        let mut db = MusicDB::default();
+        let mut id = 0;
+        for &(artist, count) in artists {
+            for _ in 0..count {
+                id += 1;
+                db.records.insert(
+                    id,
+                    Song {
+                        id,
+                        artist_lower: artist.to_string(),
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+        db
+    }
+
+    /// The longest run of songs by the same artist.
+    fn longest_run(songs: &[&Song]) -> usize {
+        songs
+            .chunk_by(|a, b| a.artist_lower == b.artist_lower)
+            .map(<[_]>::len)
+            .max()
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn spreads_artists_out() {
+        let even = db(&[("a", 20), ("b", 20)]);
+        let lopsided = db(&[("a", 30), ("b", 3)]);
+        for _ in 0..50 {
+            let shuffled = even.shuffle(&SearchTerms::default());
+            assert_eq!(shuffled.len(), 40);
+            // Each lies within a tenth of its spacing of its place, so at most two can meet
+            assert!(longest_run(&shuffled) <= 2);
+
+            let shuffled = lopsided.shuffle(&SearchTerms::default());
+            let runs = shuffled
+                .chunk_by(|a, b| a.artist_lower == b.artist_lower)
+                .filter(|run| run[0].artist_lower == "b")
+                .collect::<Vec<_>>();
+            assert_eq!(runs.len(), 3, "b's songs are apart");
+        }
+    }
+
+    #[test]
+    fn keeps_every_song_once() {
+        let db = db(&[("a", 5), ("b", 3), ("c", 1)]);
+        let mut ids = db
+            .shuffle(&SearchTerms::default())
+            .iter()
+            .map(|s| s.id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, (1..=9).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn leaves_out_books_and_stops_at_the_limit() {
+        let mut db = db(&[("a", 5), ("b", 5)]);
+        for id in 1..=5 {
+            db.records.get_mut(&id).unwrap().section = Section::Audiobooks;
+        }
+        let shuffled = db.shuffle(&SearchTerms::default());
+        assert!(shuffled.iter().all(|s| s.artist_lower == "b"));
+        assert_eq!(shuffled.len(), 5);
+
+        let terms = SearchTerms {
+            limit: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(db.shuffle(&terms).len(), 2);
+    }
+}
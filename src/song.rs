@@ -1,173 +1,386 @@
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::hash_map::DefaultHasher;
 use std::fmt::{Display, Formatter};
 use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
+use crate::analysis::{Analysis, Mood, Silence};
+use crate::art::CoverColors;
+use crate::camelot::Key;
+use crate::metadata::MetadataReader;
+use crate::mp3::GaplessInfo;
 use crate::music_db::SortBy;
+use crate::overrides::Tagged;
+use crate::replay_gain::ReplayGain;
+use crate::sections::Section;
+use crate::sort_key::sort_key;
+
+/// Whether a song is audio alone, or a music video.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaKind {
+    #[default]
+    Audio,
+    Video,
+}
+
+/// As it's serialized, eg `video`.
+impl Display for MediaKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            MediaKind::Audio => "audio",
+            MediaKind::Video => "video",
+        })
+    }
+}
 
 #[derive(Debug, Hash, Default, Serialize, Deserialize)]
 pub struct Song {
     pub id: u64,
-    pub path: String,
+    #[serde(with = "crate::paths::serde_path")]
+    pub path: PathBuf,
     pub title: String,
 
     pub artist: String,
     pub album: String,
+    /// The album's artist (TPE2), eg "Various Artists", if it's tagged
+    #[serde(default)]
+    pub album_artist: String,
     pub year: u16,
     pub comment: String,
-    //pub genre: Genre,
+    #[serde(default)]
+    pub genre: String,
+
+    // Classical music tags
+    #[serde(default)]
+    pub composer: String,
+    #[serde(default)]
+    pub conductor: String,
+    /// The larger work this is a part of (TIT1), eg "Symphony No. 9 in D minor, Op. 125"
+    #[serde(default)]
+    pub work: String,
+    /// This track's movement of the work (TIT3), eg "II. Molto vivace"
+    #[serde(default)]
+    pub movement: String,
+
     pub duration: Duration,
     pub track: Option<u16>,
+    /// The number of tracks on the disc, eg 12 for "3/12"
+    pub track_total: Option<u16>,
+    pub disc: Option<u16>,
+
+    /// File size, in bytes
+    #[serde(default)]
+    pub size: u64,
+    /// Average bitrate, in kbps
+    #[serde(default)]
+    pub bitrate: u16,
+    /// Sample rate, in Hz
+    #[serde(default)]
+    pub sample_rate: u32,
+    #[serde(default)]
+    pub channels: u8,
+    /// eg, "MPEG-1 Layer 3"
+    #[serde(default)]
+    pub codec: String,
+    #[serde(default)]
+    pub kind: MediaKind,
+    /// Whether `duration` was computed by `mp3::accurate_duration`, rather than estimated
+    #[serde(default)]
+    pub accurate_duration: bool,
+    #[serde(default)]
+    pub gapless: Option<GaplessInfo>,
+    #[serde(default)]
+    pub replay_gain: Option<ReplayGain>,
+    /// Placeholder colors for the song's cover art, if it has any
+    #[serde(default)]
+    pub cover: Option<CoverColors>,
+    /// When the song was added to the library, in seconds since the Unix epoch
+    #[serde(default)]
+    pub added: u64,
+    /// The user's own labels for it, in lowercase and sorted; see `labels`
+    #[serde(default)]
+    pub labels: Vec<String>,
+    /// Whether the user has hidden it from searches, shuffles, and radio, eg a Christmas album;
+    /// see `SearchTerms::include_hidden`
+    #[serde(default)]
+    pub hidden: bool,
+    /// Whether its tags' content advisory says it's explicit; see `explicit`
+    #[serde(default)]
+    pub explicit: bool,
+    /// Whether the user has marked it explicit or not, which wins over its tags
+    #[serde(default)]
+    pub marked_explicit: Option<bool>,
+    /// What listening to it found, if it's been analyzed; see `analysis`
+    #[serde(default)]
+    pub analysis: Option<Analysis>,
+    /// What its tags said, if its album's tags are overridden; see `overrides`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tagged: Option<Tagged>,
+    /// The root directory it was scanned from
+    #[serde(default, with = "crate::paths::serde_path")]
+    pub root: PathBuf,
+    /// Its root's section; not persisted, since the root's setting may change
+    #[serde(skip)]
+    pub section: Section,
+    /// Why the file couldn't be read the last time it was requested, if it couldn't. Cleared by
+    /// the next successful read; not persisted.
+    #[serde(skip)]
+    pub unavailable: Option<String>,
 
     // Lowercase versions for searching
     pub title_lower: String,
     pub artist_lower: String,
     pub album_lower: String,
+    #[serde(default)]
+    pub album_artist_lower: String,
+    /// Sort keys, as computed by `sort_key::sort_key`
+    #[serde(default)]
+    pub sort_artist: String,
+    #[serde(default)]
+    pub sort_album: String,
+    /// Explicit sort names from the file's tags (TSOP/TSOA), if any
+    #[serde(default)]
+    pub artist_sort_tag: String,
+    #[serde(default)]
+    pub album_sort_tag: String,
+    #[serde(default)]
+    pub composer_lower: String,
+    #[serde(default)]
+    pub work_lower: String,
     // the file stem (eg, "11 Everlong.mp3" becomes "11 everlong")
     pub stem_lower: String,
 }
 
 impl Song {
-    pub fn new(filename: &str) -> Result<Self, std::io::Error> {
-        // For now, only mp3s are supported:
-        let mut song = Self::from_mp3(filename).ok_or_else(|| {
-            std::io::Error::new(std::io::ErrorKind::InvalidData, "Can't read MP3 metadata")
+    /// Reads a song with the reader registered for its extension.
+    pub fn new(path: &Path) -> Result<Self, std::io::Error> {
+        let reader = crate::metadata::readers().for_path(path).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "Unsupported format")
         })?;
 
+        Self::with_reader(path, reader)
+    }
+
+    /// Reads a song with a particular reader, filling in the fields derived from its tags.
+    pub fn with_reader(path: &Path, reader: &dyn MetadataReader) -> Result<Self, std::io::Error> {
+        let song = reader.read(path)?;
+        let size = std::fs::metadata(path)?.len();
+
+        Ok(Self::from_tags(song, path.to_path_buf(), size))
+    }
+
+    /// Fills in the fields of a song just read from its tags that are derived from them (and its
+    /// path and size), including its id.
+    pub fn from_tags(mut song: Song, path: PathBuf, size: u64) -> Self {
+        song.path = path;
+        song.size = size;
+
         song.title_lower = song.title.to_lowercase();
         song.artist_lower = song.artist.to_lowercase();
         song.album_lower = song.album.to_lowercase();
+        song.album_artist_lower = song.album_artist.to_lowercase();
+        song.composer_lower = song.composer.to_lowercase();
+        song.work_lower = song.work.to_lowercase();
 
-        song.stem_lower = std::path::Path::new(&song.path)
-            .file_stem()
-            .and_then(|o| o.to_str())
-            .map(|o| o.to_string())
-            .unwrap_or_default();
+        song.stem_lower = song.file_stem().unwrap_or_default().into_owned();
 
         let mut hasher = DefaultHasher::new();
         song.hash(&mut hasher);
         song.id = hasher.finish();
 
-        Ok(song)
-    }
-
-    fn from_mp3(filename: &str) -> Option<Song> {
-        let metadata = mp3_metadata::read_from_file(filename).ok()?;
-
-        let song = if metadata.optional_info.is_empty() {
-            let tags = metadata.tag?;
+        // Set after hashing, so that rescanning an unchanged file yields the same id (and so that
+        // changing the sort locale doesn't change it, either)
+        song.added = crate::history::now();
+        song.update_sort_keys();
 
-            Song {
-                path: filename.to_string(),
-                title: tags.title,
-                duration: metadata.duration,
-                ..Default::default()
-            }
-        } else {
-            let info = metadata.optional_info.into_iter().next()?;
-            let track = Self::get_track(info.track_number.as_ref());
-            Song {
-                path: filename.to_string(),
-                title: info.title.unwrap_or_default(),
-                artist: if info.performers.is_empty() {
-                    "".to_string()
-                } else {
-                    info.performers[0].to_string()
-                },
-                album: info.album_movie_show.unwrap_or_default(),
-                duration: metadata.duration,
-                track,
-                ..Default::default()
-            }
-        };
+        song
+    }
 
-        Some(song)
+    /// Recomputes the sort keys, which depend on the configured sort locale.
+    pub fn update_sort_keys(&mut self) {
+        self.sort_artist = sort_key(&self.artist, &self.artist_sort_tag);
+        self.sort_album = sort_key(&self.album, &self.album_sort_tag);
     }
 
-    fn get_track(track_info: Option<&String>) -> Option<u16> {
-        let s = track_info?;
-        let slash = s.char_indices().find(|(_, c)| c == &'/');
+    /// Replaces the estimated duration with an accurate one, unless that's already been done.
+    pub fn fix_duration(&mut self) {
+        if self.accurate_duration {
+            return;
+        }
 
-        match slash {
-            Some((i, _)) => s[..i].parse(),
-            None => s.parse(),
+        if let Some(duration) = crate::mp3::accurate_duration(&self.path) {
+            self.duration = duration;
+            self.accurate_duration = true;
         }
-        .ok()
     }
 
     pub fn duration_formatted(&self) -> String {
-        let mut formatted = String::new();
+        format_duration(self.duration)
+    }
 
-        let mut s = self.duration.as_secs();
+    /// The file's extension, lowercased (eg, "mp3").
+    pub fn format(&self) -> String {
+        self.path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_lowercase()
+    }
 
-        let sec = s % 60;
-        s /= 60;
+    /// The MIME type to serve the file as.
+    pub fn content_type(&self) -> &'static str {
+        match self.format().as_str() {
+            "m4a" => "audio/mp4",
+            "mp4" | "m4v" => "video/mp4",
+            _ => "audio/mpeg",
+        }
+    }
 
-        let min = s % 60;
-        s /= 60;
+    /// The file's name without its extension. Names that aren't valid UTF-8 are converted lossily.
+    pub fn file_stem(&self) -> Option<Cow<'_, str>> {
+        Some(self.path.file_stem()?.to_string_lossy())
+    }
 
-        let hour = s % 24;
+    /// Compares titles in natural order, falling back to the file stem for untitled songs (as
+    /// `SongResult` does when displaying them).
+    fn cmp_titles(&self, other: &Self) -> std::cmp::Ordering {
+        crate::sort_key::natural_cmp(&self.title_key(), &other.title_key())
+    }
 
-        if hour > 0 {
-            formatted.push_str(&format!("{}:", hour));
+    fn title_key(&self) -> std::borrow::Cow<'_, str> {
+        if self.title_lower.is_empty() {
+            self.stem_lower.to_lowercase().into()
+        } else {
+            self.title_lower.as_str().into()
         }
+    }
 
-        formatted.push_str(&format!("{:02}:", min));
-        formatted.push_str(&format!("{:02}", sec));
+    /// Whether it's explicit, as marked by the user or else as tagged.
+    pub fn is_explicit(&self) -> bool {
+        self.marked_explicit.unwrap_or(self.explicit)
+    }
 
-        formatted
+    /// The key analysis found it to be in, if any.
+    pub fn key(&self) -> Option<Key> {
+        self.analysis.and_then(|a| a.key)
     }
 
-    pub fn file_stem(&self) -> Option<&str> {
-        let stem = std::path::Path::new(&self.path).file_stem()?;
-        stem.to_str()
+    /// Which album it's on; see `AlbumKey`.
+    pub fn album_key(&self) -> AlbumKey<'_> {
+        AlbumKey {
+            artist: if self.album_artist_lower.is_empty() {
+                &self.artist_lower
+            } else {
+                &self.album_artist_lower
+            },
+            album: &self.album_lower,
+            year: self.year,
+        }
     }
 
     pub fn cmp(&self, other: &Self, sort_by: SortBy) -> std::cmp::Ordering {
         match sort_by {
             SortBy::track => self
-                .track
-                .cmp(&other.track)
-                .then(self.title.cmp(&other.title))
+                .disc
+                .cmp(&other.disc)
+                .then(self.track.cmp(&other.track))
+                .then_with(|| self.cmp_titles(other))
                 .then(self.album_lower.cmp(&other.album_lower))
                 .then(self.artist_lower.cmp(&other.artist_lower))
                 .then(self.duration.cmp(&other.duration)),
             SortBy::title => self
-                .title_lower
-                .cmp(&other.title_lower)
+                .cmp_titles(other)
                 .then(self.track.cmp(&other.track))
                 .then(self.album_lower.cmp(&other.album_lower))
                 .then(self.artist_lower.cmp(&other.artist_lower))
                 .then(self.duration.cmp(&other.duration)),
             SortBy::artist => self
-                .artist_lower
-                .cmp(&other.artist_lower)
+                .sort_artist
+                .cmp(&other.sort_artist)
+                .then(self.artist_lower.cmp(&other.artist_lower))
                 .then(self.track.cmp(&other.track))
-                .then(self.title_lower.cmp(&other.title_lower))
+                .then_with(|| self.cmp_titles(other))
                 .then(self.album_lower.cmp(&other.album_lower))
                 .then(self.duration.cmp(&other.duration)),
             SortBy::album => self
-                .album_lower
-                .cmp(&other.album_lower)
+                .sort_album
+                .cmp(&other.sort_album)
+                .then(self.album_key().cmp(&other.album_key()))
                 .then(self.track.cmp(&other.track))
-                .then(self.title_lower.cmp(&other.title_lower))
+                .then_with(|| self.cmp_titles(other))
                 .then(self.artist_lower.cmp(&other.artist_lower))
                 .then(self.duration.cmp(&other.duration)),
+            SortBy::composer => self
+                .composer_lower
+                .cmp(&other.composer_lower)
+                .then(self.work_lower.cmp(&other.work_lower))
+                .then(self.disc.cmp(&other.disc))
+                .then(self.track.cmp(&other.track))
+                .then_with(|| self.cmp_titles(other))
+                .then(self.artist_lower.cmp(&other.artist_lower)),
             SortBy::duration => self
                 .duration
                 .cmp(&other.duration)
                 .then(self.track.cmp(&other.track))
-                .then(self.title_lower.cmp(&other.title_lower))
+                .then_with(|| self.cmp_titles(other))
                 .then(self.album_lower.cmp(&other.album_lower))
                 .then(self.artist_lower.cmp(&other.artist_lower)),
+            // Songs without a key go last
+            SortBy::key => (self.key().is_none(), self.key())
+                .cmp(&(other.key().is_none(), other.key()))
+                .then(self.sort_artist.cmp(&other.sort_artist))
+                .then_with(|| self.cmp_titles(other))
+                .then(self.album_lower.cmp(&other.album_lower)),
+            SortBy::added => other
+                .added
+                .cmp(&self.added)
+                .then(self.album_key().cmp(&other.album_key()))
+                .then(self.track.cmp(&other.track))
+                .then_with(|| self.cmp_titles(other))
+                .then(self.artist_lower.cmp(&other.artist_lower)),
         }
     }
 }
 
+/// Which album a song is on, so that albums of the same name are kept apart: its album artist (or
+/// failing that, its artist), album, and year, all as lowercased. Two "Greatest Hits" by different
+/// artists, or a re-release of an album, are different albums.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AlbumKey<'a> {
+    pub artist: &'a str,
+    pub album: &'a str,
+    pub year: u16,
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let mut formatted = String::new();
+
+    let mut s = duration.as_secs();
+
+    let sec = s % 60;
+    s /= 60;
+
+    let min = s % 60;
+    s /= 60;
+
+    let hour = s % 24;
+
+    if hour > 0 {
+        formatted.push_str(&format!("{}:", hour));
+    }
+
+    formatted.push_str(&format!("{:02}:", min));
+    formatted.push_str(&format!("{:02}", sec));
+
+    formatted
+}
+
 impl Display for Song {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, r#"<a href="{}">{}</a>"#, self.path, self.title)
+        write!(f, r#"<a href="{}">{}</a>"#, self.path.display(), self.title)
     }
 }
 
@@ -177,7 +390,7 @@ impl Display for Song {
 /// * `path` is omitted for security
 /// * `duration` is a string for easy display
 /// * `id` is converted to a string because JS can't handle 64-bit integers
-#[derive(Serialize)]
+#[derive(Serialize, Default, Debug, Clone)]
 pub struct SongResult {
     pub id: String,
     pub title: String,
@@ -186,8 +399,43 @@ pub struct SongResult {
     pub album: String,
     pub year: u16,
     pub comment: String,
+    pub genre: String,
+    pub composer: String,
+    pub conductor: String,
+    pub work: String,
+    pub movement: String,
     pub duration: String,
     pub track: Option<u16>,
+    pub track_total: Option<u16>,
+    pub disc: Option<u16>,
+
+    pub format: String,
+    pub kind: MediaKind,
+    pub codec: String,
+    pub bitrate: u16,
+    pub sample_rate: u32,
+    pub channels: u8,
+    pub size: u64,
+    /// From the LAME tag, so only for MP3s, which `/listen` serves as they are. Transcodes (see
+    /// `transcode`) are of other formats, which have no such tag, and there's no HLS, so this is
+    /// only ever about the original file.
+    pub gapless: Option<GaplessInfo>,
+    pub replay_gain: Option<ReplayGain>,
+    pub cover: Option<CoverColors>,
+    pub labels: Vec<String>,
+    pub hidden: bool,
+    pub explicit: bool,
+    pub mood: Option<Mood>,
+    /// In beats per minute
+    pub bpm: Option<f32>,
+    /// In Camelot notation, eg `8A`
+    pub key: Option<Key>,
+    pub silence: Option<Silence>,
+    pub unavailable: bool,
+    /// How well it matches the search's term, when there is one; higher is better. See
+    /// `relevance`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<u32>,
 }
 
 impl From<&Song> for SongResult {
@@ -208,8 +456,34 @@ impl From<&Song> for SongResult {
             album: song.album.clone(),
             year: song.year,
             comment: song.comment.clone(),
+            genre: song.genre.clone(),
+            composer: song.composer.clone(),
+            conductor: song.conductor.clone(),
+            work: song.work.clone(),
+            movement: song.movement.clone(),
             duration: song.duration_formatted(),
             track: song.track,
+            track_total: song.track_total,
+            disc: song.disc,
+            format: song.format(),
+            kind: song.kind,
+            codec: song.codec.clone(),
+            bitrate: song.bitrate,
+            sample_rate: song.sample_rate,
+            channels: song.channels,
+            size: song.size,
+            gapless: song.gapless,
+            replay_gain: song.replay_gain,
+            cover: song.cover.clone(),
+            labels: song.labels.clone(),
+            hidden: song.hidden,
+            explicit: song.is_explicit(),
+            mood: song.analysis.map(|a| a.mood),
+            bpm: song.analysis.and_then(|a| a.bpm),
+            key: song.key(),
+            silence: song.analysis.map(|a| a.silence),
+            unavailable: song.unavailable.is_some(),
+            score: None,
         }
     }
 }
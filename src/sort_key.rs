@@ -0,0 +1,168 @@
+//! Sort keys that file "The Beatles" under B.
+//!
+//! Leading articles are stripped from artist and album names, according to the locale named by
+//! the `SORT_LOCALE` environment variable (`en` by default). `SORT_ARTICLES` can instead give a
+//! comma-separated list of articles, eg `SORT_ARTICLES="the,a,an,los"`.
+//!
+//! Explicit sort tags (ID3 `TSOP`/`TSOA`) always win over the computed keys.
+//!
+//! Titles and file stems are compared in natural order; see [`natural_cmp`].
+
+use std::{cmp::Ordering, iter::Peekable, str::Chars, sync::OnceLock};
+
+const ENGLISH: &[&str] = &["the", "a", "an"];
+const GERMAN: &[&str] = &["der", "die", "das", "ein", "eine"];
+const FRENCH: &[&str] = &["le", "la", "les", "l'", "un", "une"];
+const SPANISH: &[&str] = &["el", "la", "los", "las", "un", "una"];
+const ITALIAN: &[&str] = &["il", "lo", "la", "i", "gli", "le", "l'", "un", "una"];
+const DUTCH: &[&str] = &["de", "het", "een"];
+
+fn articles() -> &'static [String] {
+    static ARTICLES: OnceLock<Vec<String>> = OnceLock::new();
+
+    ARTICLES.get_or_init(|| {
+        if let Ok(list) = std::env::var("SORT_ARTICLES") {
+            return list
+                .split(',')
+                .map(|a| a.trim().to_lowercase())
+                .filter(|a| !a.is_empty())
+                .collect();
+        }
+
+        let locale = std::env::var("SORT_LOCALE").unwrap_or_default();
+        let language = locale.split(['-', '_']).next().unwrap_or_default();
+        let articles = match &language.to_lowercase()[..] {
+            "de" => GERMAN,
+            "fr" => FRENCH,
+            "es" => SPANISH,
+            "it" => ITALIAN,
+            "nl" => DUTCH,
+            _ => ENGLISH,
+        };
+        articles.iter().map(|a| a.to_string()).collect()
+    })
+}
+
+/// Computes the key to sort `name` by: lowercased, without a leading article.
+///
+/// A non-empty `sort_tag` (from the file's tags) is used as-is instead.
+pub fn sort_key(name: &str, sort_tag: &str) -> String {
+    if !sort_tag.trim().is_empty() {
+        return sort_tag.trim().to_lowercase();
+    }
+
+    let lower = name.trim().to_lowercase();
+    for article in articles() {
+        if let Some(rest) = lower.strip_prefix(&article[..]) {
+            // Elided articles ("l'") run straight into the next word; others need a space after
+            let rest = if article.ends_with('\'') {
+                rest
+            } else if let Some(rest) = rest.strip_prefix(' ') {
+                rest
+            } else {
+                continue;
+            };
+
+            if !rest.trim().is_empty() {
+                return rest.trim_start().to_string();
+            }
+        }
+    }
+
+    lower
+}
+
+/// Compares two strings in "natural" order, so that runs of digits compare by their numeric value
+/// and "track 2" sorts before "track 10".
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+
+    loop {
+        match (a.peek(), b.peek()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_number(&mut a);
+                let y = take_number(&mut b);
+
+                // Compare by magnitude first (ignoring leading zeros), then digit by digit, so
+                // numbers too long for any integer type still compare correctly
+                let ordering = x
+                    .trim_start_matches('0')
+                    .len()
+                    .cmp(&y.trim_start_matches('0').len())
+                    .then_with(|| x.trim_start_matches('0').cmp(y.trim_start_matches('0')))
+                    .then_with(|| x.len().cmp(&y.len()));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.cmp(y);
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+fn take_number(chars: &mut Peekable<Chars>) -> String {
+    let mut number = String::new();
+    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+        number.push(c);
+    }
+    number
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_numbers_by_value() {
+        use Ordering::*;
+        for (a, b, ordering) in [
+            ("track 2", "track 10", Less),
+            ("track 10", "track 10", Equal),
+            ("9", "10", Less),
+            ("a1b10", "a1b2", Greater),
+            ("", "a", Less),
+            ("a", "", Greater),
+            // Leading zeros don't change the value, but break ties
+            ("007", "7", Greater),
+            ("007", "8", Less),
+            ("0", "00", Less),
+            // Longer than any integer type
+            (
+                "123456789012345678901234567890",
+                "123456789012345678901234567891",
+                Less,
+            ),
+            ("99999999999999999999999", "100000000000000000000000", Less),
+            // Otherwise, by character
+            ("1a", "1b", Less),
+            ("1a", "10", Less),
+            ("x", "1", Greater),
+        ] {
+            assert_eq!(natural_cmp(a, b), ordering, "{:?} {:?}", a, b);
+            assert_eq!(natural_cmp(b, a), ordering.reverse(), "{:?} {:?}", b, a);
+        }
+    }
+
+    #[test]
+    fn sorts_track_names() {
+        let mut names = [
+            "track 10", "track 1", "track 02", "track 2", "intro", "track 9",
+        ];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(
+            names,
+            ["intro", "track 1", "track 2", "track 02", "track 9", "track 10"]
+        );
+    }
+}
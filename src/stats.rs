@@ -0,0 +1,170 @@
+use crate::music_db::MusicDB;
+use crate::song::{format_duration, Song};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
+
+/// Library-wide statistics, as returned by `/stats`.
+#[derive(Serialize)]
+pub struct LibraryStats {
+    pub tracks: usize,
+    pub artists: usize,
+    pub albums: usize,
+    pub duration: String,
+    pub duration_secs: u64,
+    pub size: String,
+    pub size_bytes: u64,
+
+    pub genres: Vec<Count>,
+    pub years: Vec<Count>,
+    pub formats: Vec<Count>,
+    pub bitrates: Vec<Count>,
+
+    pub biggest_albums: Vec<AlbumStats>,
+}
+
+#[derive(Serialize)]
+pub struct Count {
+    pub name: String,
+    pub count: usize,
+}
+
+#[derive(Serialize)]
+pub struct AlbumStats {
+    pub artist: String,
+    pub album: String,
+    pub tracks: usize,
+    pub size: String,
+    pub size_bytes: u64,
+}
+
+impl MusicDB {
+    const BIGGEST_ALBUMS: usize = 10;
+
+    pub fn stats(&self) -> LibraryStats {
+        let songs = self.records.values().collect::<Vec<_>>();
+
+        let artists = songs
+            .iter()
+            .map(|s| &s.artist_lower)
+            .filter(|a| !a.is_empty())
+            .collect::<HashSet<_>>();
+
+        let mut albums: HashMap<(&str, &str), Vec<&Song>> = HashMap::new();
+        for song in songs.iter().filter(|s| !s.album.is_empty()) {
+            albums
+                .entry((&song.artist_lower, &song.album_lower))
+                .or_default()
+                .push(song);
+        }
+
+        let duration = songs.iter().map(|s| s.duration).sum::<Duration>();
+        let size_bytes = songs.iter().map(|s| s.size).sum();
+
+        let mut genres = counts_by(&songs, |s| {
+            if s.genre.is_empty() {
+                "(none)".to_string()
+            } else {
+                s.genre.clone()
+            }
+        });
+        genres.sort_unstable_by(|a, b| b.count.cmp(&a.count).then(a.name.cmp(&b.name)));
+
+        let mut formats = counts_by(&songs, |s| s.format());
+        formats.sort_unstable_by(|a, b| b.count.cmp(&a.count).then(a.name.cmp(&b.name)));
+
+        let years = ordered_counts(
+            &songs,
+            |s| (s.year > 0).then_some(s.year),
+            |y| y.to_string(),
+        );
+        let bitrates = ordered_counts(
+            &songs,
+            |s| (s.bitrate > 0).then_some(s.bitrate / 32 * 32),
+            |b| format!("{}-{} kbps", b, b + 31),
+        );
+
+        let mut biggest_albums = albums
+            .values()
+            .map(|songs| {
+                let size_bytes = songs.iter().map(|s| s.size).sum();
+                AlbumStats {
+                    artist: songs[0].artist.clone(),
+                    album: songs[0].album.clone(),
+                    tracks: songs.len(),
+                    size: format_size(size_bytes),
+                    size_bytes,
+                }
+            })
+            .collect::<Vec<_>>();
+        biggest_albums.sort_unstable_by_key(|a| std::cmp::Reverse(a.size_bytes));
+        biggest_albums.truncate(Self::BIGGEST_ALBUMS);
+
+        LibraryStats {
+            tracks: songs.len(),
+            artists: artists.len(),
+            albums: albums.len(),
+            duration: format_duration(duration),
+            duration_secs: duration.as_secs(),
+            size: format_size(size_bytes),
+            size_bytes,
+            genres,
+            years,
+            formats,
+            bitrates,
+            biggest_albums,
+        }
+    }
+}
+
+fn counts_by(songs: &[&Song], key: impl Fn(&Song) -> String) -> Vec<Count> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for &song in songs {
+        *counts.entry(key(song)).or_default() += 1;
+    }
+
+    counts
+        .into_iter()
+        .map(|(name, count)| Count { name, count })
+        .collect()
+}
+
+/// Counts songs by a sortable key, skipping songs for which `key` returns `None`.
+fn ordered_counts<K: Ord>(
+    songs: &[&Song],
+    key: impl Fn(&Song) -> Option<K>,
+    name: impl Fn(K) -> String,
+) -> Vec<Count> {
+    let mut counts: BTreeMap<K, usize> = BTreeMap::new();
+    for &song in songs {
+        if let Some(k) = key(song) {
+            *counts.entry(k).or_default() += 1;
+        }
+    }
+
+    counts
+        .into_iter()
+        .map(|(k, count)| Count {
+            name: name(k),
+            count,
+        })
+        .collect()
+}
+
+/// Formats a byte count for display, eg "1.2 GB".
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["bytes", "KB", "MB", "GB", "TB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
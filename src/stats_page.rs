@@ -0,0 +1,10 @@
+use crate::themes::Theme;
+use askama::Template;
+use bwaabwaa::stats::LibraryStats;
+
+#[derive(Template)]
+#[template(path = "stats.html")]
+pub struct StatsPage<'a> {
+    pub stats: &'a LibraryStats,
+    pub theme: Theme,
+}
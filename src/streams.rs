@@ -0,0 +1,196 @@
+//! The files being sent to clients right now, listed at `/admin/streams`, and a limit on how many
+//! can be at once (`--max-streams=`), for low-power hardware or a slow upload link.
+//!
+//! A stream starts when `/listen` or `/download` is requested and ends once its file has been
+//! handed off to the connection (or the client goes away).
+
+use bwaabwaa::devices::Devices;
+use bwaabwaa::music_db::MusicDB;
+use bwaabwaa::song::SongResult;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+#[derive(Default)]
+pub struct Streams {
+    /// No limit if `None`
+    max: Option<usize>,
+    next: AtomicU64,
+    active: Mutex<BTreeMap<u64, Arc<Active>>>,
+}
+
+struct Active {
+    client: Option<SocketAddr>,
+    /// The registered device it's to, if any
+    device: Option<String>,
+    /// The song id, as requested
+    song: String,
+    kind: &'static str,
+    /// In seconds since the Unix epoch
+    started: u64,
+    size: AtomicU64,
+    sent: AtomicU64,
+}
+
+/// One entry of `/admin/streams`.
+#[derive(Serialize)]
+pub struct StreamSummary {
+    pub client: Option<String>,
+    /// The name of the device it's to, if it's registered
+    pub device: Option<String>,
+    /// `listen` or `download`
+    pub kind: &'static str,
+    pub started: u64,
+    /// Bytes sent so far, of `size`
+    pub sent: u64,
+    pub size: u64,
+    /// `None` if it's since been removed from the library
+    pub song: Option<SongResult>,
+}
+
+/// A stream's place among the active streams, given up when it's dropped.
+pub struct Stream {
+    streams: Arc<Streams>,
+    id: u64,
+    active: Arc<Active>,
+}
+
+impl Streams {
+    pub fn new(max: Option<usize>) -> Self {
+        Streams {
+            max,
+            ..Default::default()
+        }
+    }
+
+    /// Starts a stream of `song` to `client`, on `device` if it's registered, or returns `None`
+    /// if there are already as many as allowed.
+    pub fn start(
+        self: &Arc<Self>,
+        client: Option<SocketAddr>,
+        device: Option<String>,
+        song: String,
+        kind: &'static str,
+    ) -> Option<Stream> {
+        let mut active = self.active.lock().unwrap();
+        if self.max.is_some_and(|max| active.len() >= max) {
+            return None;
+        }
+
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        let stream = Arc::new(Active {
+            client,
+            device,
+            song,
+            kind,
+            started: bwaabwaa::history::now(),
+            size: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+        });
+        active.insert(id, Arc::clone(&stream));
+
+        Some(Stream {
+            streams: Arc::clone(self),
+            id,
+            active: stream,
+        })
+    }
+
+    /// Lists the active streams, oldest first.
+    pub fn summaries(&self, db: &MusicDB, devices: &Devices) -> Vec<StreamSummary> {
+        self.active
+            .lock()
+            .unwrap()
+            .values()
+            .map(|stream| StreamSummary {
+                client: stream.client.map(|c| c.to_string()),
+                device: stream
+                    .device
+                    .as_deref()
+                    .and_then(|d| devices.name(d))
+                    .map(str::to_string),
+                kind: stream.kind,
+                started: stream.started,
+                sent: stream.sent.load(Ordering::Relaxed),
+                size: stream.size.load(Ordering::Relaxed),
+                song: stream
+                    .song
+                    .parse()
+                    .ok()
+                    .and_then(|id| db.records.get(&id))
+                    .map(SongResult::from),
+            })
+            .collect()
+    }
+}
+
+impl Stream {
+    pub fn client(&self) -> Option<SocketAddr> {
+        self.active.client
+    }
+
+    pub fn set_size(&self, size: u64) {
+        self.active.size.store(size, Ordering::Relaxed);
+    }
+
+    pub fn add_sent(&self, bytes: u64) {
+        self.active.sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        if let Ok(mut active) = self.streams.active.lock() {
+            active.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start(streams: &Arc<Streams>, song: &str) -> Option<Stream> {
+        streams.start(None, None, song.to_string(), "listen")
+    }
+
+    #[test]
+    fn gives_up_slots_when_streams_end() {
+        let streams = Arc::new(Streams::new(Some(2)));
+        let first = start(&streams, "1").unwrap();
+        let second = start(&streams, "2").unwrap();
+        assert!(start(&streams, "3").is_none());
+
+        drop(first);
+        let third = start(&streams, "3").unwrap();
+        assert!(start(&streams, "4").is_none());
+        drop((second, third));
+        assert!(streams.active.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn counts_whats_been_sent() {
+        let streams = Arc::new(Streams::default());
+        let stream = start(&streams, "7").unwrap();
+        stream.set_size(1000);
+        stream.add_sent(400);
+        stream.add_sent(100);
+
+        let summaries = streams.summaries(&MusicDB::default(), &Devices::default());
+        assert_eq!(
+            summaries
+                .iter()
+                .map(|s| (s.kind, s.sent, s.size))
+                .collect::<Vec<_>>(),
+            [("listen", 500, 1000)]
+        );
+        // Songs no longer in the library are still listed
+        assert!(summaries[0].song.is_none());
+    }
+}
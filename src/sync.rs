@@ -0,0 +1,222 @@
+//! `bwaabwaa sync`: mirrors albums from another running server (a "peer") into a local directory,
+//! then scans them into this one's library. Handy for a server with room for only some of the
+//! music that another has.
+//!
+//! What to mirror is listed in `sync.json`, eg
+//!
+//! ```json
+//! [
+//!     { "artist": "Foo Fighters" },
+//!     { "artist": "ABBA", "album": "Arrival" }
+//! ]
+//! ```
+//!
+//! An entry with no `album` mirrors every album by that artist. Each album's songs are saved to
+//! `<into>/<artist>/<album>/`, along with its cover art; the songs' tags carry the rest. Songs
+//! already saved are skipped, so running it again (eg nightly, from cron) fetches just what's new.
+//! Nothing is ever deleted.
+//!
+//! The peer is `--server=<url>` or `BWAA_SERVER`; if it needs an API key, give it in
+//! `BWAA_API_KEY`, as for `bwaabwaa remote`.
+//!
+//! The albums are scanned as the server would scan them, so give the same `--include=`,
+//! `--exclude=`, and other scan options as it's started with. If a server is running on this
+//! machine, they aren't scanned, since it would save its own library over the result; it finds
+//! them the next time it's started with `--scan=<into>`.
+
+use crate::{
+    client::{self, Client},
+    config::{self, Problems},
+};
+use bwaabwaa::{
+    music_db,
+    paths::{self, file_name},
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+const SYNC_FILE: &str = "sync.json";
+
+const USAGE: &str = "Usage: bwaabwaa sync --server=<url> --into=<directory> [scan options]
+
+Mirrors the albums listed in sync.json from the server at <url> into <directory>, then scans them
+with the scan options (eg --exclude=<glob>) the server here is started with.";
+
+/// One entry of `sync.json`.
+#[derive(Deserialize)]
+struct Mirror {
+    artist: String,
+    /// Every album by `artist` if not given
+    album: Option<String>,
+}
+
+/// The albums to mirror, as `(artist, album)`.
+async fn albums(client: &Client, mirrors: &[Mirror]) -> Result<Vec<(String, String)>, String> {
+    let mut albums = Vec::new();
+    for mirror in mirrors {
+        match &mirror.album {
+            Some(album) => albums.push((mirror.artist.clone(), album.clone())),
+            None => {
+                let artist = client
+                    .get("artist", &[("name", &mirror.artist)])
+                    .await
+                    .map_err(|e| format!("{}: {}", mirror.artist, e))?
+                    .unwrap_or_default();
+                let found = artist["albums"].as_array().into_iter().flatten();
+                albums.extend(found.filter_map(|a| {
+                    Some((mirror.artist.clone(), a["album"].as_str()?.to_string()))
+                }));
+            }
+        }
+    }
+    Ok(albums)
+}
+
+/// A track's file extension, as the server gives it. It's the server's word, so anything but
+/// letters and digits, such as a path, is taken as an MP3.
+fn extension(track: &Value) -> &str {
+    track["format"]
+        .as_str()
+        .filter(|f| !f.is_empty() && f.len() <= 8 && f.chars().all(|c| c.is_ascii_alphanumeric()))
+        .unwrap_or("mp3")
+}
+
+/// Saves an album's songs and cover art to `directory`, returning how many songs were new.
+async fn mirror_album(client: &Client, album: &Value, directory: &Path) -> Result<usize, String> {
+    std::fs::create_dir_all(directory)
+        .map_err(|e| format!("unable to create {}: {}", directory.display(), e))?;
+
+    let mut fetched = 0;
+    let tracks = album["tracks"].as_array().cloned().unwrap_or_default();
+    for track in &tracks {
+        let id = track["id"].as_str().unwrap_or_default();
+        let title = file_name(track["title"].as_str().unwrap_or(id));
+        let extension = extension(track);
+        let name = match (track["disc"].as_u64(), track["track"].as_u64()) {
+            (Some(disc), Some(number)) if disc > 1 => {
+                format!("{}-{:02} {}.{}", disc, number, title, extension)
+            }
+            (_, Some(number)) => format!("{:02} {}.{}", number, title, extension),
+            _ => format!("{}.{}", title, extension),
+        };
+        let path = directory.join(name);
+
+        // Already saved, as long as it's complete
+        let size = track["size"].as_u64().unwrap_or_default();
+        if std::fs::metadata(&path).is_ok_and(|m| m.len() == size) {
+            continue;
+        }
+
+        let Some((song, _)) = client.file("download", &[("id", id)]).await? else {
+            eprintln!("{} is no longer on the server; skipping it", path.display());
+            continue;
+        };
+        std::fs::write(&path, song)
+            .map_err(|e| format!("unable to write {}: {}", path.display(), e))?;
+        println!("Fetched {}", path.display());
+        fetched += 1;
+    }
+
+    let has_cover = std::fs::read_dir(directory).is_ok_and(|entries| {
+        entries
+            .flatten()
+            .any(|e| e.path().file_stem().is_some_and(|s| s == "cover"))
+    });
+    let first = tracks.first().and_then(|t| t["id"].as_str());
+    if let (false, Some(_), Some(id)) = (has_cover, album["art"].as_str(), first) {
+        if let Some((art, content_type)) = client.file("art", &[("id", id)]).await? {
+            let extension = match content_type.as_str() {
+                "image/png" => "png",
+                "image/gif" => "gif",
+                "image/webp" => "webp",
+                _ => "jpg",
+            };
+            let path = directory.join(format!("cover.{}", extension));
+            std::fs::write(&path, art)
+                .map_err(|e| format!("unable to write {}: {}", path.display(), e))?;
+        }
+    }
+
+    Ok(fetched)
+}
+
+/// Runs a sync, with `args` being the arguments after `sync`.
+pub async fn run(args: &[String]) -> Result<(), String> {
+    let server = client::server(args).ok_or(USAGE)?;
+    let into = args
+        .iter()
+        .find_map(|a| a.strip_prefix("--into="))
+        .map(PathBuf::from)
+        .ok_or(USAGE)?;
+    let mut problems = Problems::default();
+    let options = config::scan_options(args, &mut problems);
+    problems.report();
+
+    let mirrors: Vec<Mirror> = File::open(SYNC_FILE)
+        .map_err(|e| format!("unable to read {}: {}", SYNC_FILE, e))
+        .and_then(|file| {
+            serde_json::from_reader(BufReader::new(file))
+                .map_err(|e| format!("{}: {}", SYNC_FILE, e))
+        })?;
+
+    let client = Client::new(&server);
+    let mut fetched = 0;
+    for (artist, album) in albums(&client, &mirrors).await? {
+        let found = match client
+            .get("album", &[("artist", &artist), ("album", &album)])
+            .await
+        {
+            Ok(Some(found)) => found,
+            Ok(None) => continue,
+            // Most likely it's gone from the server; the rest can still be mirrored
+            Err(e) => {
+                eprintln!("Skipping {} by {}: {}", album, artist, e);
+                continue;
+            }
+        };
+        let directory = into.join(file_name(&artist)).join(file_name(&album));
+        fetched += mirror_album(&client, &found, &directory).await?;
+    }
+    println!("Fetched {} songs from {}", fetched, server);
+
+    if fetched > 0 {
+        let into = paths::canonicalize(&into)
+            .map_err(|e| format!("unable to scan {}: {}", into.display(), e))?;
+        let local = client::local();
+        // Any answer at all means it's running
+        if reqwest::get(format!("{}/capabilities", local))
+            .await
+            .is_ok()
+        {
+            println!(
+                "Not scanning {}, since the server at {} would save over it; start it with --scan={} to add them",
+                into.display(),
+                local,
+                into.display()
+            );
+            return Ok(());
+        }
+        music_db::load_db(vec![(into, false)], options);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extensions_cant_leave_the_directory() {
+        let extension =
+            |format: Value| extension(&serde_json::json!({ "format": format })).to_string();
+        assert_eq!(extension("flac".into()), "flac");
+        assert_eq!(extension("mp3/../../../.bashrc".into()), "mp3");
+        assert_eq!(extension("".into()), "mp3");
+        assert_eq!(extension(Value::Null), "mp3");
+    }
+}
@@ -0,0 +1,288 @@
+//! An optional Telegram bot, for searching the library and adding to the queue from a phone. It
+//! long-polls Telegram for messages, so the server needn't be reachable from the internet.
+//!
+//! Set `TELEGRAM_BOT_TOKEN` to the token @BotFather gave, and pass `--telegram-chat=<id>` for each
+//! chat allowed to use it; the bot tells other chats their id, and nothing else. Allowed chats are
+//! also told each time a song starts playing, as a subscriber to the event bus. To use a
+//! self-hosted Bot API server, set `TELEGRAM_API_URL`, eg to `http://localhost:8081/bot`.
+//!
+//! Commands:
+//! - `/search <terms>`: the best matches, numbered
+//! - `/play <n>`: adds the nth match of the last search to the queue
+//! - `/play <terms>`: adds the best match for `terms`
+//! - `/queue`: what's coming up
+
+use crate::audit::{self, Action};
+use crate::events::{Published, ServerEvent, Subscriber};
+use crate::music_db::{MusicDB, SearchTerms};
+use crate::queue::PlayQueue;
+use crate::song::SongResult;
+use serde::Deserialize;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+
+/// Where the Bot API is, unless `TELEGRAM_API_URL` says otherwise
+pub const API: &str = "https://api.telegram.org/bot";
+
+/// How long each poll waits for messages, in seconds
+const POLL_TIMEOUT: u64 = 50;
+
+/// How long to wait before polling again after a failure
+const RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// How many matches `/search` lists
+const SEARCH_RESULTS: u16 = 10;
+
+/// How many songs `/queue` lists
+const QUEUE_SHOWN: usize = 10;
+
+const HELP: &str = "/search <terms> lists the best matches\n\
+    /play <n> adds the nth match to the queue\n\
+    /play <terms> adds the best match\n\
+    /queue shows what's coming up";
+
+#[derive(Deserialize)]
+struct Response<T> {
+    ok: bool,
+    result: Option<T>,
+    description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Update {
+    update_id: i64,
+    message: Option<Message>,
+}
+
+#[derive(Deserialize)]
+struct Message {
+    chat: Chat,
+    from: Option<Sender>,
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Chat {
+    id: i64,
+}
+
+#[derive(Deserialize)]
+struct Sender {
+    username: Option<String>,
+}
+
+/// The bot. Cheap to clone.
+#[derive(Clone)]
+pub struct Telegram {
+    url: Arc<String>,
+    chats: Arc<Vec<i64>>,
+    client: reqwest::Client,
+}
+
+fn describe(song: &SongResult) -> String {
+    match song.artist.as_str() {
+        "" => song.title.clone(),
+        artist => format!("{} - {}", artist, song.title),
+    }
+}
+
+impl Telegram {
+    /// The bot with `token`, answering `chats`, using the Bot API at `api`.
+    pub fn new(api: &str, token: &str, chats: Vec<i64>) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(POLL_TIMEOUT + 10))
+            .build()
+            .unwrap_or_default();
+
+        Telegram {
+            url: Arc::new(format!("{api}{token}")),
+            chats: Arc::new(chats),
+            client,
+        }
+    }
+
+    async fn call<T: for<'de> Deserialize<'de>>(
+        &self,
+        method: &str,
+        body: serde_json::Value,
+    ) -> Result<T, String> {
+        let response: Response<T> = self
+            .client
+            .post(format!("{}/{}", self.url, method))
+            .json(&body)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .map_err(|e| e.without_url().to_string())?
+            .json()
+            .await
+            .map_err(|e| e.without_url().to_string())?;
+
+        match (response.ok, response.result) {
+            (true, Some(result)) => Ok(result),
+            _ => Err(response.description.unwrap_or_default()),
+        }
+    }
+
+    async fn send(&self, chat: i64, text: &str) {
+        let sent = self
+            .call::<serde_json::Value>(
+                "sendMessage",
+                serde_json::json!({ "chat_id": chat, "text": text }),
+            )
+            .await;
+        if let Err(e) = sent {
+            eprintln!("Unable to send a Telegram message: {}", e);
+        }
+    }
+
+    /// Tells every allowed chat that `song` is playing, in the background.
+    ///
+    /// Must be called from within a Tokio runtime.
+    fn now_playing(&self, song: &SongResult) {
+        let bot = self.clone();
+        let text = format!("Now playing: {}", describe(song));
+        tokio::spawn(async move {
+            for &chat in bot.chats.iter() {
+                bot.send(chat, &text).await;
+            }
+        });
+    }
+
+    /// Answers messages until the server stops.
+    pub async fn run(self, database: Arc<Mutex<MusicDB>>, queue: Arc<Mutex<PlayQueue>>) {
+        let mut offset = 0;
+        // Each chat's last search, for `/play <n>`
+        let mut searches = HashMap::new();
+
+        loop {
+            let updates = self
+                .call::<Vec<Update>>(
+                    "getUpdates",
+                    serde_json::json!({ "offset": offset, "timeout": POLL_TIMEOUT }),
+                )
+                .await;
+            let updates = match updates {
+                Ok(updates) => updates,
+                Err(e) => {
+                    eprintln!("Unable to get Telegram messages: {}", e);
+                    tokio::time::sleep(RETRY_AFTER).await;
+                    continue;
+                }
+            };
+
+            for update in updates {
+                offset = offset.max(update.update_id + 1);
+                let Some(Message {
+                    chat,
+                    from,
+                    text: Some(text),
+                }) = update.message
+                else {
+                    continue;
+                };
+
+                let reply = if self.chats.contains(&chat.id) {
+                    let who = match from.and_then(|f| f.username) {
+                        Some(name) => format!("telegram (@{})", name),
+                        None => format!("telegram ({})", chat.id),
+                    };
+                    let db = database.lock().await;
+                    let mut queue = queue.lock().await;
+                    let last = searches.entry(chat.id).or_default();
+                    answer(&text, &who, last, &db, &mut queue)
+                } else {
+                    format!(
+                        "This chat isn't allowed to use this bot. To allow it, start the server with --telegram-chat={}",
+                        chat.id
+                    )
+                };
+                self.send(chat.id, &reply).await;
+            }
+        }
+    }
+}
+
+/// Carries out one command, returning the reply.
+fn answer(
+    text: &str,
+    who: &str,
+    last_search: &mut Vec<u64>,
+    db: &MusicDB,
+    queue: &mut PlayQueue,
+) -> String {
+    let (command, args) = text.trim().split_once(' ').unwrap_or((text.trim(), ""));
+    // In groups, commands may be addressed to a bot, eg `/search@bwaa_bot`
+    let command = command.split('@').next().unwrap_or_default();
+    let args = args.trim();
+
+    let search = |term: &str, limit| {
+        db.query(SearchTerms {
+            term: Some(term.to_string()),
+            limit: Some(limit),
+            ..Default::default()
+        })
+        .results
+    };
+
+    match (command, args) {
+        ("/search", "") | ("/play", "") => HELP.to_string(),
+        ("/search", terms) => {
+            let results = search(terms, SEARCH_RESULTS);
+            if results.is_empty() {
+                return format!("Nothing matches {}", terms);
+            }
+            *last_search = results.iter().filter_map(|s| s.id.parse().ok()).collect();
+            results
+                .iter()
+                .enumerate()
+                .map(|(i, song)| format!("{}. {}", i + 1, describe(song)))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+        ("/play", args) => {
+            let id = match args.parse::<usize>() {
+                Ok(n) => match n.checked_sub(1).and_then(|i| last_search.get(i)) {
+                    Some(&id) => id,
+                    None => return format!("There's no match number {} to play", n),
+                },
+                Err(_) => match search(args, 1).first().and_then(|s| s.id.parse().ok()) {
+                    Some(id) => id,
+                    None => return format!("Nothing matches {}", args),
+                },
+            };
+            let Some(song) = db.records.get(&id) else {
+                return "That song is no longer in the library".to_string();
+            };
+
+            queue.enqueue(id);
+            audit::record(who, Action::QueueAdd, id.to_string());
+            format!("Added {}", describe(&song.into()))
+        }
+        ("/queue", _) => {
+            let songs = queue.state(db).songs;
+            if songs.is_empty() {
+                return "The queue is empty".to_string();
+            }
+            let mut lines = songs
+                .iter()
+                .take(QUEUE_SHOWN)
+                .enumerate()
+                .map(|(i, song)| format!("{}. {}", i + 1, describe(song)))
+                .collect::<Vec<_>>();
+            if songs.len() > QUEUE_SHOWN {
+                lines.push(format!("and {} more", songs.len() - QUEUE_SHOWN));
+            }
+            lines.join("\n")
+        }
+        _ => HELP.to_string(),
+    }
+}
+
+impl Subscriber for Telegram {
+    fn notify(&self, published: &Published) {
+        if let ServerEvent::NowPlaying { song } = &published.event {
+            self.now_playing(song);
+        }
+    }
+}
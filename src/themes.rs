@@ -0,0 +1,96 @@
+//! Color themes for the rendered pages. Each is a set of CSS variables in `static/themes`, which
+//! `static/style.css` is written in terms of.
+//!
+//! `?theme=dark` on any page picks a theme, and remembers it in a cookie for later visits.
+
+use serde::Deserialize;
+use std::convert::Infallible;
+use warp::{http::Response, Filter};
+
+const COOKIE: &str = "theme";
+
+/// How long the theme cookie lasts, in seconds
+const COOKIE_MAX_AGE: u32 = 365 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Theme {
+    #[default]
+    Light,
+    Dark,
+    HighContrast,
+}
+
+impl Theme {
+    pub const ALL: [Theme; 3] = [Theme::Light, Theme::Dark, Theme::HighContrast];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Theme::Light => "light",
+            Theme::Dark => "dark",
+            Theme::HighContrast => "high-contrast",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| t.name() == name)
+    }
+
+    /// Its stylesheet, relative to `static`
+    pub fn stylesheet(self) -> String {
+        format!("themes/{}.css", self.name())
+    }
+}
+
+#[derive(Deserialize)]
+struct ThemeQuery {
+    theme: Option<String>,
+}
+
+/// The theme a request asked for, and whether it was newly chosen (and so should be saved).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThemeChoice {
+    pub theme: Theme,
+    chosen: bool,
+}
+
+impl ThemeChoice {
+    /// Adds a cookie saving the theme to a response, if it was just chosen.
+    pub fn remember<T>(&self, mut response: Response<T>) -> Response<T> {
+        if self.chosen {
+            let cookie = format!(
+                "{}={}; Path=/; Max-Age={}; SameSite=Lax",
+                COOKIE,
+                self.theme.name(),
+                COOKIE_MAX_AGE
+            );
+            response
+                .headers_mut()
+                .append("set-cookie", cookie.parse().unwrap());
+        }
+        response
+    }
+}
+
+/// Works out the theme from `?theme=` or, failing that, the cookie. Unknown themes are ignored.
+pub fn theme() -> impl Filter<Extract = (ThemeChoice,), Error = Infallible> + Clone {
+    warp::query::<ThemeQuery>()
+        .map(|q: ThemeQuery| q.theme)
+        .or(warp::any().map(|| None))
+        .unify()
+        .and(warp::cookie::optional::<String>(COOKIE))
+        .map(|query: Option<String>, cookie: Option<String>| {
+            match query.as_deref().and_then(Theme::from_name) {
+                Some(theme) => ThemeChoice {
+                    theme,
+                    chosen: true,
+                },
+                None => ThemeChoice {
+                    theme: cookie
+                        .as_deref()
+                        .and_then(Theme::from_name)
+                        .unwrap_or_default(),
+                    chosen: false,
+                },
+            }
+        })
+}
@@ -0,0 +1,244 @@
+//! Per-stream bandwidth limits, so a listener on the far side of a home upload link doesn't use
+//! all of it. `--max-kbps=` caps each `/listen` and `/download`; clients in the networks given by
+//! `--unthrottled=` (eg `--unthrottled=192.168.0.0/16`, repeatable) are exempt, as is loopback.
+//!
+//! Behind a reverse proxy, every client looks like the proxy, so this is better done there.
+
+use crate::streams::Stream;
+use bwaabwaa::remote::ByteStream;
+use futures::StreamExt;
+use ipnet::IpNet;
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+use warp::http::Response;
+use warp::hyper::{body::Bytes, Body};
+
+/// How much is sent at a time, at most
+const CHUNK: usize = 16 * 1024;
+
+/// What `/listen` and `/download` send: a file read into memory, or one streamed from a remote
+/// source as it comes.
+pub enum FileBody {
+    Whole(Vec<u8>),
+    Streamed { len: u64, data: ByteStream },
+}
+
+impl From<Vec<u8>> for FileBody {
+    fn from(data: Vec<u8>) -> Self {
+        FileBody::Whole(data)
+    }
+}
+
+#[derive(Default)]
+pub struct Throttle {
+    /// Bytes per second per stream; `None` for no limit
+    rate: Option<u64>,
+    unthrottled: Vec<IpNet>,
+}
+
+impl Throttle {
+    /// A limit of `kbps` kilobits per second, except for clients in `unthrottled`, a list of
+    /// networks (`10.0.0.0/8`) or addresses. A limit of 0 isn't one; it would stop every stream.
+    pub fn new(kbps: Option<u64>, unthrottled: &[String]) -> Result<Self, String> {
+        let rate = match kbps {
+            Some(0) => return Err("a limit of 0 kbps would stop every stream".to_string()),
+            Some(kbps) => Some(
+                kbps.checked_mul(1000)
+                    .ok_or_else(|| format!("{} kbps is more than can be sent", kbps))?
+                    / 8,
+            ),
+            None => None,
+        };
+        let unthrottled = unthrottled
+            .iter()
+            .map(|network| {
+                network
+                    .parse::<IpNet>()
+                    .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("{} isn't a network or an address", network))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Throttle { rate, unthrottled })
+    }
+
+    fn rate_for(&self, client: Option<SocketAddr>) -> Option<u64> {
+        let rate = self.rate?;
+        match client {
+            Some(client)
+                if client.ip().is_loopback()
+                    || self.unthrottled.iter().any(|n| n.contains(&client.ip())) =>
+            {
+                None
+            }
+            _ => Some(rate),
+        }
+    }
+
+    /// Sends a response's body for `stream`, no faster than its client's limit. The stream ends
+    /// once it's all been sent.
+    pub fn apply(&self, response: Response<FileBody>, stream: Stream) -> Response<Body> {
+        let (parts, body) = response.into_parts();
+        let (len, data) = match body {
+            FileBody::Whole(data) if data.is_empty() => {
+                return Response::from_parts(parts, Body::empty())
+            }
+            FileBody::Whole(data) => (
+                data.len() as u64,
+                futures::stream::once(std::future::ready(Ok(Bytes::from(data)))).boxed(),
+            ),
+            FileBody::Streamed { len, data } => (len, data),
+        };
+
+        stream.set_size(len);
+        let rate = self.rate_for(stream.client());
+        Response::from_parts(parts, send(data, rate, stream))
+    }
+}
+
+/// A body that sends `data` a chunk at a time, at `rate` bytes per second if there's a limit. A
+/// second's worth goes at once, so playback can start right away.
+fn send(mut data: ByteStream, rate: Option<u64>, stream: Stream) -> Body {
+    let (mut sender, body) = Body::channel();
+
+    tokio::spawn(async move {
+        let rate = rate.map(|rate| rate.max(1));
+        // No bigger than a second's worth, so the first goes out right away
+        let chunk = rate.map_or(CHUNK, |rate| CHUNK.min(rate as usize));
+        let mut bucket = rate.map(|rate| TokenBucket::new(rate as f64));
+        while let Some(next) = data.next().await {
+            let mut next = match next {
+                Ok(next) => next,
+                Err(e) => {
+                    // Cut short, so the client can tell it didn't get it all
+                    eprintln!("Stream failed: {}", e);
+                    sender.abort();
+                    return;
+                }
+            };
+            while !next.is_empty() {
+                let part = next.split_to(chunk.min(next.len()));
+                let len = part.len() as u64;
+                if let Some(bucket) = &mut bucket {
+                    bucket.take(len as f64).await;
+                }
+                if sender.send_data(part).await.is_err() {
+                    // The client went away
+                    return;
+                }
+                stream.add_sent(len);
+            }
+        }
+    });
+
+    body
+}
+
+struct TokenBucket {
+    /// Bytes per second
+    rate: f64,
+    /// Bytes that can be sent right now, up to a second's worth
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Waits until `bytes` can be sent.
+    async fn take(&mut self, bytes: f64) {
+        self.refill();
+        if self.tokens < bytes {
+            let wait = (bytes - self.tokens) / self.rate;
+            tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+            self.refill();
+        }
+        self.tokens -= bytes;
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::streams::Streams;
+    use std::sync::Arc;
+
+    fn client(address: &str) -> Option<SocketAddr> {
+        Some(SocketAddr::new(address.parse().unwrap(), 50000))
+    }
+
+    #[test]
+    fn exempts_loopback_and_unthrottled_networks() {
+        let throttle =
+            Throttle::new(Some(800), &["192.168.0.0/16".into(), "10.0.0.7".into()]).unwrap();
+        assert_eq!(throttle.rate_for(client("203.0.113.9")), Some(100_000));
+        assert_eq!(throttle.rate_for(None), Some(100_000));
+        assert_eq!(throttle.rate_for(client("127.0.0.1")), None);
+        assert_eq!(throttle.rate_for(client("192.168.1.20")), None);
+        assert_eq!(throttle.rate_for(client("10.0.0.7")), None);
+        assert_eq!(throttle.rate_for(client("10.0.0.8")), Some(100_000));
+
+        assert_eq!(Throttle::default().rate_for(client("203.0.113.9")), None);
+        assert!(Throttle::new(Some(800), &["the office".into()]).is_err());
+    }
+
+    #[test]
+    fn refuses_limits_it_cant_keep() {
+        assert!(Throttle::new(Some(0), &[]).is_err());
+        assert!(Throttle::new(Some(u64::MAX), &[]).is_err());
+        assert!(Throttle::new(Some(u64::MAX / 1000 + 1), &[]).is_err());
+
+        let fastest = Throttle::new(Some(u64::MAX / 1000), &[]).unwrap();
+        assert_eq!(fastest.rate, Some(u64::MAX / 1000 * 1000 / 8));
+        assert_eq!(Throttle::new(Some(1), &[]).unwrap().rate, Some(125));
+    }
+
+    #[tokio::test]
+    async fn sends_no_faster_than_the_rate() {
+        let bucket = &mut TokenBucket::new(10_000.0);
+        let start = Instant::now();
+        // A second's worth goes at once
+        bucket.take(10_000.0).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+        bucket.take(2000.0).await;
+        assert!(start.elapsed() >= Duration::from_millis(190));
+    }
+
+    #[tokio::test]
+    async fn sends_the_whole_body_and_counts_it() {
+        let streams = Arc::new(Streams::new(Some(1)));
+        let stream = streams.start(None, None, "1".into(), "listen").unwrap();
+        let data = (0..100_000).map(|i| i as u8).collect::<Vec<_>>();
+        let response = Throttle::default().apply(Response::new(data.clone().into()), stream);
+
+        let sent = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        assert_eq!(sent, data);
+        // The stream gives up its slot once it's all been sent, as the task sending it finishes
+        let mut next = None;
+        for _ in 0..100 {
+            next = streams.start(None, None, "2".into(), "listen");
+            if next.is_some() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert!(next.is_some());
+    }
+}
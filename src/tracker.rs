@@ -0,0 +1,469 @@
+//! Tracker modules: MOD, S3M, XM, and IT files, which hold their own samples and the patterns that
+//! play them rather than recorded audio. Their titles come from the module, and their lengths from
+//! playing through the patterns in order, following speed and tempo changes, jumps, and breaks,
+//! until the song ends or loops back on itself.
+//!
+//! Browsers can't play them, so `/listen` serves them transcoded; see `transcode`.
+
+use crate::metadata::MetadataReader;
+use crate::song::Song;
+use std::{collections::HashSet, io, path::Path, time::Duration};
+
+/// The extensions of tracker modules
+pub const FORMATS: &[&str] = &["mod", "s3m", "xm", "it"];
+
+/// What's played in one row of a pattern, as far as timing goes.
+#[derive(Debug, Default, Clone, Copy)]
+struct Row {
+    /// Ticks per row
+    speed: Option<u8>,
+    /// Beats per minute, which sets the length of a tick
+    tempo: Option<u8>,
+    /// The position in the order list to go on from
+    jump: Option<usize>,
+    /// The row of the next pattern to go on from
+    row_break: Option<usize>,
+}
+
+impl Row {
+    /// Notes MOD's and XM's `F` effect, which sets the speed below 32 and the tempo from there.
+    fn set_speed_or_tempo(&mut self, param: u8) {
+        if param < 0x20 {
+            self.speed = Some(param);
+        } else {
+            self.tempo = Some(param);
+        }
+    }
+}
+
+/// A module, as far as timing goes.
+#[derive(Debug, Default)]
+struct Module {
+    title: String,
+    /// eg "ProTracker module"
+    format: &'static str,
+    channels: u8,
+    /// The patterns to play, in order; `None` for markers that are skipped over
+    orders: Vec<Option<usize>>,
+    patterns: Vec<Vec<Row>>,
+    speed: u8,
+    tempo: u8,
+}
+
+impl Module {
+    /// How long it takes to play, without repeating.
+    fn duration(&self) -> Duration {
+        let (mut speed, mut tempo) = (self.speed.max(1) as f64, self.tempo.max(1) as f64);
+        let mut seconds = 0.0;
+        let mut seen = HashSet::new();
+        let (mut order, mut row) = (0, 0);
+
+        while let Some(entry) = self.orders.get(order) {
+            let Some(pattern) = entry.and_then(|p| self.patterns.get(p)) else {
+                // Markers, and patterns that aren't there
+                (order, row) = (order + 1, 0);
+                continue;
+            };
+            if row >= pattern.len() {
+                (order, row) = (order + 1, 0);
+                continue;
+            }
+            // A song that jumps back to somewhere it's already played loops forever
+            if !seen.insert((order, row)) {
+                break;
+            }
+
+            let effects = pattern[row];
+            match effects.speed {
+                // Speed 0 stops the song, in players that follow ProTracker
+                Some(0) => break,
+                Some(s) => speed = s as f64,
+                None => {}
+            }
+            if let Some(t) = effects.tempo {
+                tempo = t as f64;
+            }
+            // A tick lasts 2.5 / tempo seconds
+            seconds += speed * 2.5 / tempo;
+
+            (order, row) = match (effects.jump, effects.row_break) {
+                (Some(jump), row_break) => (jump, row_break.unwrap_or(0)),
+                (None, Some(row_break)) => (order + 1, row_break),
+                (None, None) => (order, row + 1),
+            };
+        }
+        Duration::from_secs_f64(seconds)
+    }
+}
+
+fn u16_le(buf: &[u8], pos: usize) -> Option<u16> {
+    let b = buf.get(pos..pos + 2)?;
+    Some(u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_le(buf: &[u8], pos: usize) -> Option<u32> {
+    let b = buf.get(pos..pos + 4)?;
+    Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// A fixed-length, NUL-padded name.
+fn name(buf: &[u8], pos: usize, len: usize) -> Option<String> {
+    let bytes = buf.get(pos..pos + len)?;
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(len);
+    Some(String::from_utf8_lossy(&bytes[..end]).trim().to_string())
+}
+
+/// A row break's parameter, which MOD, S3M, and XM write as two decimal digits.
+fn decimal(param: u8) -> usize {
+    (param >> 4) as usize * 10 + (param & 0x0F) as usize
+}
+
+/// Reads a ProTracker-style MOD, which has 31 samples and its channel count in a tag.
+fn read_mod(buf: &[u8]) -> Option<Module> {
+    let channels = match buf.get(1080..1084)? {
+        b"M.K." | b"M!K!" | b"FLT4" | b"4CHN" => 4,
+        b"6CHN" => 6,
+        b"8CHN" | b"OCTA" | b"FLT8" | b"CD81" => 8,
+        // eg "12CH" or "16CN"
+        tag if tag[0].is_ascii_digit() && tag[1].is_ascii_digit() => {
+            std::str::from_utf8(&tag[..2]).ok()?.parse().ok()?
+        }
+        tag if tag[0].is_ascii_digit() && &tag[1..] == b"CHN" => (tag[0] - b'0') as usize,
+        _ => return None,
+    };
+
+    let length = (*buf.get(950)? as usize).min(128);
+    let orders = buf.get(952..952 + length)?;
+    let pattern_count = *buf.get(952..1080)?.iter().max()? as usize + 1;
+
+    let mut patterns = Vec::with_capacity(pattern_count);
+    for p in 0..pattern_count {
+        let start = 1084 + p * 64 * channels * 4;
+        let Some(data) = buf.get(start..start + 64 * channels * 4) else {
+            break;
+        };
+        let rows = data
+            .chunks(channels * 4)
+            .map(|cells| {
+                let mut row = Row::default();
+                for cell in cells.chunks(4) {
+                    let (effect, param) = (cell[2] & 0x0F, cell[3]);
+                    match effect {
+                        0xB => row.jump = Some(param as usize),
+                        0xD => row.row_break = Some(decimal(param)),
+                        0xF => row.set_speed_or_tempo(param),
+                        _ => {}
+                    }
+                }
+                row
+            })
+            .collect();
+        patterns.push(rows);
+    }
+
+    Some(Module {
+        title: name(buf, 0, 20)?,
+        format: "ProTracker module",
+        channels: channels as u8,
+        orders: orders.iter().map(|&o| Some(o as usize)).collect(),
+        patterns,
+        speed: 6,
+        tempo: 125,
+    })
+}
+
+/// Reads a Scream Tracker 3 module.
+fn read_s3m(buf: &[u8]) -> Option<Module> {
+    if buf.get(0x2C..0x30)? != b"SCRM" {
+        return None;
+    }
+    let order_count = u16_le(buf, 0x20)? as usize;
+    let instrument_count = u16_le(buf, 0x22)? as usize;
+    let pattern_count = u16_le(buf, 0x24)? as usize;
+    let orders = buf.get(0x60..0x60 + order_count)?;
+    // Patterns are found by "parapointers", in 16-byte paragraphs
+    let pointers = 0x60 + order_count + instrument_count * 2;
+    // Channels that are turned off are 255
+    let channels = buf.get(0x40..0x60)?.iter().filter(|&&c| c < 16).count();
+
+    let mut patterns = Vec::with_capacity(pattern_count);
+    for p in 0..pattern_count {
+        let start = u16_le(buf, pointers + p * 2)? as usize * 16;
+        let mut rows = vec![Row::default(); 64];
+        // Each row is a run of cells, ended by a 0; each cell says which fields it has
+        let mut pos = start + 2;
+        let mut index = 0;
+        while index < 64 {
+            let Some(&what) = buf.get(pos) else {
+                break;
+            };
+            pos += 1;
+            if what == 0 {
+                index += 1;
+                continue;
+            }
+            if what & 0x20 != 0 {
+                pos += 2;
+            }
+            if what & 0x40 != 0 {
+                pos += 1;
+            }
+            if what & 0x80 != 0 {
+                let (command, info) = (*buf.get(pos)?, *buf.get(pos + 1)?);
+                pos += 2;
+                let row = &mut rows[index];
+                match command {
+                    // A: set speed, B: jump, C: break, T: set tempo
+                    1 => row.speed = Some(info),
+                    2 => row.jump = Some(info as usize),
+                    3 => row.row_break = Some(decimal(info)),
+                    20 if info >= 0x20 => row.tempo = Some(info),
+                    _ => {}
+                }
+            }
+        }
+        patterns.push(rows);
+    }
+
+    Some(Module {
+        title: name(buf, 0, 28)?,
+        format: "Scream Tracker 3 module",
+        channels: channels as u8,
+        orders: orders_with_markers(orders),
+        patterns,
+        speed: *buf.get(0x31)?,
+        tempo: *buf.get(0x32)?,
+    })
+}
+
+/// S3M's and IT's order lists, where 254 is a marker to skip and 255 the end.
+fn orders_with_markers(orders: &[u8]) -> Vec<Option<usize>> {
+    orders
+        .iter()
+        .take_while(|&&o| o != 255)
+        .map(|&o| (o != 254).then_some(o as usize))
+        .collect()
+}
+
+/// Reads a FastTracker 2 extended module.
+fn read_xm(buf: &[u8]) -> Option<Module> {
+    if buf.get(..17)? != b"Extended Module: " {
+        return None;
+    }
+    let header_size = u32_le(buf, 60)? as usize;
+    let length = (u16_le(buf, 64)? as usize).min(256);
+    let channels = u16_le(buf, 68)?;
+    if channels == 0 {
+        return None;
+    }
+    let pattern_count = u16_le(buf, 70)? as usize;
+    let orders = buf.get(80..80 + length)?;
+
+    let mut patterns = Vec::with_capacity(pattern_count);
+    let mut pos = 60 + header_size;
+    for _ in 0..pattern_count {
+        let header_length = u32_le(buf, pos)? as usize;
+        let row_count = u16_le(buf, pos + 5)? as usize;
+        let data_size = u16_le(buf, pos + 7)? as usize;
+        let data = buf.get(pos + header_length..pos + header_length + data_size)?;
+        pos += header_length + data_size;
+
+        let mut rows = vec![Row::default(); row_count];
+        // Cells are packed: a byte with the top bit set says which of the five fields follow
+        let (mut at, mut cell) = (0, 0);
+        while at < data.len() && cell < row_count * channels as usize {
+            let flags = data[at];
+            let fields = if flags & 0x80 != 0 {
+                at += 1;
+                flags
+            } else {
+                0x1F
+            };
+            let mut values = [0u8; 5];
+            for (bit, value) in values.iter_mut().enumerate() {
+                if fields & (1 << bit) != 0 {
+                    *value = *data.get(at)?;
+                    at += 1;
+                }
+            }
+            let (effect, param) = (values[3], values[4]);
+            let row = &mut rows[cell / channels as usize];
+            match effect {
+                0xB => row.jump = Some(param as usize),
+                0xD => row.row_break = Some(decimal(param)),
+                0xF => row.set_speed_or_tempo(param),
+                _ => {}
+            }
+            cell += 1;
+        }
+        patterns.push(rows);
+    }
+
+    Some(Module {
+        title: name(buf, 17, 20)?,
+        format: "FastTracker 2 module",
+        channels: channels.min(u8::MAX as u16) as u8,
+        orders: orders.iter().map(|&o| Some(o as usize)).collect(),
+        patterns,
+        speed: u16_le(buf, 76)?.min(u8::MAX as u16) as u8,
+        tempo: u16_le(buf, 78)?.min(u8::MAX as u16) as u8,
+    })
+}
+
+/// Reads an Impulse Tracker module.
+fn read_it(buf: &[u8]) -> Option<Module> {
+    if buf.get(..4)? != b"IMPM" {
+        return None;
+    }
+    let order_count = u16_le(buf, 0x20)? as usize;
+    let instrument_count = u16_le(buf, 0x22)? as usize;
+    let sample_count = u16_le(buf, 0x24)? as usize;
+    let pattern_count = u16_le(buf, 0x26)? as usize;
+    let orders = buf.get(0xC0..0xC0 + order_count)?;
+    let pointers = 0xC0 + order_count + (instrument_count + sample_count) * 4;
+    // Channels that are turned off have their top bit set
+    let channels = buf.get(0x40..0x80)?.iter().filter(|&&p| p < 0x80).count();
+
+    let mut patterns = Vec::with_capacity(pattern_count);
+    for p in 0..pattern_count {
+        let start = u32_le(buf, pointers + p * 4)? as usize;
+        // A pattern that's left out is 64 empty rows
+        if start == 0 {
+            patterns.push(vec![Row::default(); 64]);
+            continue;
+        }
+        let row_count = u16_le(buf, start + 2)? as usize;
+        let mut rows = vec![Row::default(); row_count];
+        // Each channel remembers the last fields it had, which later cells can reuse
+        let mut masks = [0u8; 64];
+        let mut commands = [(0u8, 0u8); 64];
+        let (mut pos, mut index) = (start + 8, 0);
+        while index < row_count {
+            let what = *buf.get(pos)?;
+            pos += 1;
+            if what == 0 {
+                index += 1;
+                continue;
+            }
+            let channel = ((what - 1) & 63) as usize;
+            if what & 0x80 != 0 {
+                masks[channel] = *buf.get(pos)?;
+                pos += 1;
+            }
+            let mask = masks[channel];
+            if mask & 1 != 0 {
+                pos += 1;
+            }
+            if mask & 2 != 0 {
+                pos += 1;
+            }
+            if mask & 4 != 0 {
+                pos += 1;
+            }
+            if mask & 8 != 0 {
+                commands[channel] = (*buf.get(pos)?, *buf.get(pos + 1)?);
+                pos += 2;
+            }
+            if mask & (8 | 0x80) != 0 {
+                let (command, param) = commands[channel];
+                let row = &mut rows[index];
+                match command {
+                    // A: set speed, B: jump, C: break (in plain hex, unlike the others), T: tempo
+                    1 => row.speed = Some(param),
+                    2 => row.jump = Some(param as usize),
+                    3 => row.row_break = Some(param as usize),
+                    20 if param >= 0x20 => row.tempo = Some(param),
+                    _ => {}
+                }
+            }
+        }
+        patterns.push(rows);
+    }
+
+    Some(Module {
+        title: name(buf, 4, 26)?,
+        format: "Impulse Tracker module",
+        channels: channels as u8,
+        orders: orders_with_markers(orders),
+        patterns,
+        speed: *buf.get(0x32)?,
+        tempo: *buf.get(0x33)?,
+    })
+}
+
+/// Reads tracker modules, by their extension.
+pub struct ModuleReader;
+
+impl MetadataReader for ModuleReader {
+    fn read(&self, path: &Path) -> Result<Song, io::Error> {
+        let buf = std::fs::read(path)?;
+        let extension = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        let module = match extension.as_str() {
+            "mod" => read_mod(&buf),
+            "s3m" => read_s3m(&buf),
+            "xm" => read_xm(&buf),
+            "it" => read_it(&buf),
+            _ => None,
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Can't read {} module", extension.to_uppercase()),
+            )
+        })?;
+
+        Ok(Song {
+            title: module.title.clone(),
+            duration: module.duration(),
+            channels: module.channels,
+            codec: module.format.to_string(),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A four-channel MOD whose patterns have the given effects, as (pattern, row, effect, param).
+    fn mod_file(orders: &[u8], effects: &[(usize, usize, u8, u8)]) -> Vec<u8> {
+        let patterns = *orders.iter().max().unwrap() as usize + 1;
+        let mut buf = vec![0; 1084 + patterns * 1024];
+        buf[..5].copy_from_slice(b"Tunes");
+        buf[950] = orders.len() as u8;
+        buf[952..952 + orders.len()].copy_from_slice(orders);
+        buf[1080..1084].copy_from_slice(b"M.K.");
+        for &(pattern, row, effect, param) in effects {
+            let cell = 1084 + pattern * 1024 + row * 16;
+            buf[cell + 2] = effect;
+            buf[cell + 3] = param;
+        }
+        buf
+    }
+
+    fn millis(module: &Module) -> u64 {
+        (module.duration().as_secs_f64() * 1000.0).round() as u64
+    }
+
+    #[test]
+    fn plays_through_the_patterns() {
+        let module = read_mod(&mod_file(&[0, 1], &[])).unwrap();
+        assert_eq!(module.title, "Tunes");
+        assert_eq!(module.channels, 4);
+        // 64 rows at the default speed of 6 and tempo of 125 take 7.68 seconds
+        assert_eq!(millis(&module), 15360);
+
+        // Twice as fast from the start, and breaking out of the first pattern halfway through
+        let module = read_mod(&mod_file(&[0, 1], &[(0, 0, 0xF, 3), (0, 31, 0xD, 0)])).unwrap();
+        assert_eq!(millis(&module), 1920 + 3840);
+
+        // Jumping back to the start loops forever, so it's only played once
+        let module = read_mod(&mod_file(&[0, 1], &[(1, 63, 0xB, 0)])).unwrap();
+        assert_eq!(millis(&module), 15360);
+    }
+}
@@ -0,0 +1,97 @@
+//! Transcoding the formats browsers can't play, for `/listen`. They're converted to FLAC, which
+//! every current browser can play and which loses nothing (bar DSD's ultrasonics), by `ffmpeg` (see `bundle::ffmpeg`); for
+//! tracker modules, it needs to have been built with libopenmpt.
+//!
+//! Each transcode is made the first time it's asked for and kept in `transcodes/`, by song id,
+//! like previews, so that it can be seeked in and replayed without transcoding it again.
+//!
+//! Built without the `transcode` feature, songs are served as they are, for players that can play
+//! them.
+
+use crate::{dsd, lossless, paths, song::Song, tracker};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::OnceLock,
+};
+
+pub const TRANSCODES_DIR: &str = "transcodes";
+
+/// What transcodes are served as
+pub const CONTENT_TYPE: &str = "audio/flac";
+
+/// DSD is converted to PCM at four times the CD sample rate, in 24 bits, which keeps everything
+/// audible while leaving most of DSD's ultrasonic noise behind
+const DSD_ARGS: &[&str] = &[
+    "-ar",
+    "176400",
+    "-sample_fmt",
+    "s32",
+    "-bits_per_raw_sample",
+    "24",
+];
+
+/// Whether a song has to be transcoded for browsers to play it: tracker modules, WavPack and
+/// Monkey's Audio files, DSD, and ALAC, which only Safari plays.
+pub fn needed(song: &Song) -> bool {
+    let format = song.format();
+    let unplayable = unplayable(&format) || (format == "m4a" && song.codec == "ALAC");
+    cfg!(feature = "transcode") && unplayable && !paths::is_remote(&song.path)
+}
+
+/// Whether no browser plays a format, by its extension, so it's always transcoded.
+pub fn unplayable(format: &str) -> bool {
+    tracker::FORMATS.contains(&format)
+        || lossless::FORMATS.contains(&format)
+        || dsd::FORMATS.contains(&format)
+}
+
+/// Whether songs can be transcoded: the server was built with the `transcode` feature, and
+/// `ffmpeg` runs. It's only looked for once.
+pub fn available() -> bool {
+    static FOUND: OnceLock<bool> = OnceLock::new();
+    cfg!(feature = "transcode")
+        && *FOUND.get_or_init(|| {
+            Command::new(crate::bundle::ffmpeg())
+                .arg("-version")
+                .output()
+                .is_ok_and(|output| output.status.success())
+        })
+}
+
+/// Where a song's transcode is kept.
+fn path(id: u64) -> PathBuf {
+    Path::new(TRANSCODES_DIR).join(format!("{:016x}.flac", id))
+}
+
+/// The path to a song's transcode, making it first if it hasn't been made yet.
+pub fn transcoded(id: u64, song: &Path) -> io::Result<PathBuf> {
+    let path = path(id);
+    if path.exists() {
+        return Ok(path);
+    }
+
+    fs::create_dir_all(TRANSCODES_DIR)?;
+    // Written alongside and then moved into place, so a transcode being made is never served
+    let partial = path.with_extension("partial");
+    let is_dsd = song
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| dsd::FORMATS.contains(&e.to_lowercase().as_str()));
+    let output = Command::new(crate::bundle::ffmpeg())
+        .args(["-nostdin", "-v", "error", "-y", "-i"])
+        .arg(song)
+        .args(["-vn", "-map_metadata", "0", "-c:a", "flac"])
+        .args(if is_dsd { DSD_ARGS } else { &[] })
+        .args(["-f", "flac"])
+        .arg(&partial)
+        .output()?;
+    if !output.status.success() {
+        let _ = fs::remove_file(&partial);
+        let error = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(error.trim().to_string()));
+    }
+    fs::rename(&partial, &path)?;
+    Ok(path)
+}
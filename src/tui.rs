@@ -0,0 +1,443 @@
+//! `bwaabwaa tui`: browsing the library in the terminal. Needs the `tui` feature.
+//!
+//! It reads the library saved in the current directory, playing through this machine's audio
+//! output (which needs the `jukebox` feature too); or, with `--server=<url>` or `BWAA_SERVER`,
+//! browses a running server and adds to its queue, as `bwaabwaa remote` does.
+//!
+//! Keys: `/` searches; the arrow keys (or `j` and `k`) move; Enter plays; `a` adds to the queue;
+//! `o` opens the selected song's album; Esc goes back; `q` quits.
+
+#[cfg(not(feature = "tui"))]
+pub async fn run(_args: &[String]) -> Result<(), String> {
+    Err("Built without the TUI; rebuild with `--features tui`".to_string())
+}
+
+#[cfg(feature = "tui")]
+pub use ui::run;
+
+#[cfg(feature = "tui")]
+mod ui {
+    use crate::client::{self, Client};
+    use bwaabwaa::{
+        jukebox::{Finished, Jukebox},
+        music_db::{self, MusicDB, SearchTerms},
+        queue::PlayQueue,
+        song::SongResult,
+    };
+    use ratatui::{
+        crossterm::event::{self, Event, KeyCode, KeyEventKind},
+        layout::{Constraint, Layout},
+        style::{Modifier, Style},
+        widgets::{Block, Borders, Paragraph, Row, Table, TableState},
+        DefaultTerminal, Frame,
+    };
+    use serde_json::Value;
+    use std::time::Duration;
+    use tokio::runtime::Handle;
+
+    /// How many songs a search shows
+    const SEARCH_RESULTS: u16 = 200;
+
+    /// How often the screen refreshes while waiting for a key
+    const TICK: Duration = Duration::from_millis(250);
+
+    /// A song, as listed.
+    #[derive(Clone)]
+    struct Song {
+        id: String,
+        title: String,
+        artist: String,
+        album: String,
+        /// 0 when unknown
+        year: u16,
+        duration: String,
+    }
+
+    impl From<SongResult> for Song {
+        fn from(song: SongResult) -> Self {
+            Song {
+                id: song.id,
+                title: song.title,
+                artist: song.artist,
+                album: song.album,
+                year: song.year,
+                duration: song.duration,
+            }
+        }
+    }
+
+    impl From<&Value> for Song {
+        fn from(song: &Value) -> Self {
+            let field = |name: &str| song[name].as_str().unwrap_or_default().to_string();
+            Song {
+                id: field("id"),
+                title: field("title"),
+                artist: field("artist"),
+                album: field("album"),
+                year: song["year"].as_u64().unwrap_or_default() as u16,
+                duration: field("duration"),
+            }
+        }
+    }
+
+    /// Where songs come from, and go to be played.
+    trait Library: Send {
+        fn search(&mut self, terms: &str) -> Result<Vec<Song>, String>;
+        /// The tracks of `album`; given a year, only that year's album of the name.
+        fn album(&mut self, artist: &str, album: &str, year: u16) -> Result<Vec<Song>, String>;
+        /// Plays `song` now, or as soon as it can; returns what happened.
+        fn play(&mut self, song: &Song) -> Result<String, String>;
+        fn enqueue(&mut self, song: &Song) -> Result<String, String>;
+        /// What's playing, for the status line.
+        fn now_playing(&mut self) -> Option<String>;
+    }
+
+    /// The library saved in the current directory, played through the jukebox.
+    struct Local {
+        db: MusicDB,
+        queue: PlayQueue,
+        jukebox: Result<Jukebox, String>,
+        finished: tokio::sync::mpsc::UnboundedReceiver<Finished>,
+    }
+
+    impl Local {
+        fn jukebox(&self) -> Result<&Jukebox, String> {
+            self.jukebox.as_ref().map_err(Clone::clone)
+        }
+
+        fn play_id(&mut self, id: u64) -> Result<(), String> {
+            let song = self.db.records.get(&id).ok_or("That song is gone")?;
+            self.jukebox()?.play(song);
+            Ok(())
+        }
+    }
+
+    fn parse_id(song: &Song) -> Result<u64, String> {
+        song.id
+            .parse()
+            .map_err(|_| format!("Invalid id {}", song.id))
+    }
+
+    impl Library for Local {
+        fn search(&mut self, terms: &str) -> Result<Vec<Song>, String> {
+            let results = self.db.query(SearchTerms {
+                term: Some(terms.to_string()),
+                limit: Some(SEARCH_RESULTS),
+                ..Default::default()
+            });
+            Ok(results.results.into_iter().map(Song::from).collect())
+        }
+
+        fn album(&mut self, artist: &str, album: &str, year: u16) -> Result<Vec<Song>, String> {
+            let year = Some(year).filter(|&year| year != 0);
+            let album = self.db.album(artist, album, year).ok_or("No such album")?;
+            Ok(album.tracks.into_iter().map(Song::from).collect())
+        }
+
+        fn play(&mut self, song: &Song) -> Result<String, String> {
+            self.play_id(parse_id(song)?)?;
+            Ok(format!("Playing {}", song.title))
+        }
+
+        fn enqueue(&mut self, song: &Song) -> Result<String, String> {
+            self.jukebox()?;
+            self.queue.enqueue(parse_id(song)?);
+            Ok(format!("Added {}", song.title))
+        }
+
+        fn now_playing(&mut self) -> Option<String> {
+            // Line up the next song whenever one finishes
+            while let Ok(finished) = self.finished.try_recv() {
+                match self.queue.next(&self.db) {
+                    Some(id) => self.play_id(id).ok()?,
+                    // Still finishing; it's sent again once it has
+                    None if finished.early => {}
+                    None => self.jukebox().ok()?.stop(),
+                }
+            }
+
+            let state = self.jukebox().ok()?.state();
+            let song = self.db.records.get(&state.current?)?;
+            Some(format!("{} - {}", song.artist, song.title))
+        }
+    }
+
+    /// A running server, through its API.
+    struct Remote {
+        client: Client,
+        runtime: Handle,
+    }
+
+    impl Remote {
+        fn get(&self, path: &str, query: &[(&str, &str)]) -> Result<Value, String> {
+            let response = self.runtime.block_on(self.client.get(path, query))?;
+            Ok(response.unwrap_or_default())
+        }
+
+        fn command(&self, command: Value) -> Result<Value, String> {
+            let response = self.runtime.block_on(self.client.command(command))?;
+            Ok(response.unwrap_or_default())
+        }
+    }
+
+    impl Library for Remote {
+        fn search(&mut self, terms: &str) -> Result<Vec<Song>, String> {
+            let limit = SEARCH_RESULTS.to_string();
+            let found = self.get("search", &[("term", terms), ("limit", &limit)])?;
+            let results = found["results"].as_array().into_iter().flatten();
+            Ok(results.map(Song::from).collect())
+        }
+
+        fn album(&mut self, artist: &str, album: &str, year: u16) -> Result<Vec<Song>, String> {
+            let year = year.to_string();
+            let mut query = vec![("artist", artist), ("album", album)];
+            if year != "0" {
+                query.push(("year", &year));
+            }
+            let found = self.get("album", &query)?;
+            let tracks = found["tracks"].as_array().into_iter().flatten();
+            Ok(tracks.map(Song::from).collect())
+        }
+
+        fn play(&mut self, song: &Song) -> Result<String, String> {
+            let state = self.command(serde_json::json!({ "command": "enqueue", "id": song.id }))?;
+            // Only start the queue if nothing's playing; there's no jumping the queue
+            if state["jukebox"] == true && state["status"] == "stopped" {
+                self.command(serde_json::json!({ "command": "play" }))?;
+                return Ok(format!("Playing {}", song.title));
+            }
+            Ok(format!("Added {}", song.title))
+        }
+
+        fn enqueue(&mut self, song: &Song) -> Result<String, String> {
+            self.command(serde_json::json!({ "command": "enqueue", "id": song.id }))?;
+            Ok(format!("Added {}", song.title))
+        }
+
+        fn now_playing(&mut self) -> Option<String> {
+            let state = self.get("state", &[]).ok()?;
+            let song = state["now_playing"].as_object()?;
+            let field = |name: &str| song.get(name)?.as_str();
+            Some(format!("{} - {}", field("artist")?, field("title")?))
+        }
+    }
+
+    /// A list of songs, and how it came about.
+    struct View {
+        title: String,
+        songs: Vec<Song>,
+        selected: TableState,
+    }
+
+    impl View {
+        fn new(title: String, songs: Vec<Song>) -> Self {
+            let selected = TableState::default().with_selected((!songs.is_empty()).then_some(0));
+            View {
+                title,
+                songs,
+                selected,
+            }
+        }
+
+        fn selected(&self) -> Option<&Song> {
+            self.songs.get(self.selected.selected()?)
+        }
+    }
+
+    struct App {
+        library: Box<dyn Library>,
+        /// The views opened, most recent last
+        views: Vec<View>,
+        /// What's typed into the search box, while searching
+        searching: Option<String>,
+        message: String,
+        now_playing: Option<String>,
+    }
+
+    impl App {
+        fn view(&mut self) -> &mut View {
+            self.views.last_mut().expect("there's always a view")
+        }
+
+        fn open(&mut self, title: String, songs: Result<Vec<Song>, String>) {
+            match songs {
+                Ok(songs) => {
+                    self.message = format!("{} songs", songs.len());
+                    self.views.push(View::new(title, songs));
+                }
+                Err(e) => self.message = e,
+            }
+        }
+
+        fn act(&mut self, action: fn(&mut dyn Library, &Song) -> Result<String, String>) {
+            let Some(song) = self.view().selected().cloned() else {
+                return;
+            };
+            self.message = action(self.library.as_mut(), &song).unwrap_or_else(|e| e);
+        }
+
+        /// Handles a key; returns whether to quit.
+        fn key(&mut self, key: KeyCode) -> bool {
+            if let Some(terms) = &mut self.searching {
+                match key {
+                    KeyCode::Char(c) => terms.push(c),
+                    KeyCode::Backspace => {
+                        terms.pop();
+                    }
+                    KeyCode::Enter => {
+                        let terms = self.searching.take().unwrap_or_default();
+                        let songs = self.library.search(&terms);
+                        self.open(format!("Search: {}", terms), songs);
+                    }
+                    KeyCode::Esc => self.searching = None,
+                    _ => {}
+                }
+                return false;
+            }
+
+            match key {
+                KeyCode::Char('q') => return true,
+                KeyCode::Char('/') => self.searching = Some(String::new()),
+                KeyCode::Down | KeyCode::Char('j') => self.view().selected.select_next(),
+                KeyCode::Up | KeyCode::Char('k') => self.view().selected.select_previous(),
+                KeyCode::Enter => self.act(|library, song| library.play(song)),
+                KeyCode::Char('a') => self.act(|library, song| library.enqueue(song)),
+                KeyCode::Char('o') => {
+                    if let Some(song) = self.view().selected().cloned() {
+                        let songs = self.library.album(&song.artist, &song.album, song.year);
+                        self.open(format!("Album: {} by {}", song.album, song.artist), songs);
+                    }
+                }
+                KeyCode::Esc | KeyCode::Backspace if self.views.len() > 1 => {
+                    self.views.pop();
+                }
+                _ => {}
+            }
+            false
+        }
+
+        fn draw(&mut self, frame: &mut Frame) {
+            let [search, list, status] = Layout::vertical([
+                Constraint::Length(3),
+                Constraint::Min(0),
+                Constraint::Length(2),
+            ])
+            .areas(frame.area());
+
+            let (typed, hint) = match &self.searching {
+                Some(terms) => (
+                    format!("{}_", terms),
+                    "Search (Enter to search, Esc to cancel)",
+                ),
+                None => (String::new(), "Press / to search"),
+            };
+            frame.render_widget(
+                Paragraph::new(typed).block(Block::default().borders(Borders::ALL).title(hint)),
+                search,
+            );
+
+            let view = self.views.last_mut().expect("there's always a view");
+            let rows = view.songs.iter().map(|s| {
+                Row::new([
+                    s.title.as_str(),
+                    s.artist.as_str(),
+                    s.album.as_str(),
+                    s.duration.as_str(),
+                ])
+            });
+            let table = Table::new(
+                rows,
+                [
+                    Constraint::Percentage(40),
+                    Constraint::Percentage(25),
+                    Constraint::Percentage(25),
+                    Constraint::Length(8),
+                ],
+            )
+            .header(
+                Row::new(["Title", "Artist", "Album", "Length"])
+                    .style(Style::default().add_modifier(Modifier::BOLD)),
+            )
+            .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(view.title.as_str()),
+            );
+            frame.render_stateful_widget(table, list, &mut view.selected);
+
+            let playing = match &self.now_playing {
+                Some(song) => format!("Now playing: {}", song),
+                None => "Nothing playing".to_string(),
+            };
+            let help = "Enter play | a add to queue | o album | Esc back | q quit";
+            frame.render_widget(
+                Paragraph::new(format!("{}   {}\n{}", playing, self.message, help)),
+                status,
+            );
+        }
+
+        fn run(&mut self, terminal: &mut DefaultTerminal) -> std::io::Result<()> {
+            loop {
+                self.now_playing = self.library.now_playing();
+                terminal.draw(|frame| self.draw(frame))?;
+
+                if !event::poll(TICK)? {
+                    continue;
+                }
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press && self.key(key.code) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    fn local() -> Result<Box<dyn Library>, String> {
+        let db = MusicDB::from_file(music_db::LIBRARY_FILE).map_err(|e| {
+            format!(
+                "Unable to read {}; run the server here first to scan your music: {}",
+                music_db::LIBRARY_FILE,
+                e
+            )
+        })?;
+        let (finished_tx, finished) = tokio::sync::mpsc::unbounded_channel();
+        Ok(Box::new(Local {
+            db,
+            queue: PlayQueue::default(),
+            jukebox: Jukebox::start(finished_tx),
+            finished,
+        }))
+    }
+
+    /// Runs the TUI until it's quit.
+    pub async fn run(args: &[String]) -> Result<(), String> {
+        let library = match client::server(args) {
+            Some(server) => Box::new(Remote {
+                client: Client::new(&server),
+                runtime: Handle::current(),
+            }),
+            None => local()?,
+        };
+
+        let mut app = App {
+            library,
+            views: vec![View::new("Press / to search".to_string(), Vec::new())],
+            searching: None,
+            message: String::new(),
+            now_playing: None,
+        };
+
+        // The remote library blocks on the runtime, so keep the UI off its threads
+        let ran = tokio::task::spawn_blocking(move || {
+            let mut terminal = ratatui::init();
+            let ran = app.run(&mut terminal);
+            ratatui::restore();
+            ran
+        })
+        .await
+        .map_err(|e| e.to_string())?;
+        ran.map_err(|e| e.to_string())
+    }
+}
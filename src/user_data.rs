@@ -0,0 +1,269 @@
+//! Exporting the play history and resume positions to a portable JSON file, and importing them
+//! back, so that they survive rebuilding `library.json` or moving to another machine.
+//!
+//! Song ids depend on where the files are, so each song is also given by its artist, album, and
+//! title; importing matches songs by id, or failing that, by those. Plays already recorded aren't
+//! added twice, so importing the same file again changes nothing.
+//!
+//! There are no ratings or favorites to export, since the server doesn't keep any. Labels, hiding,
+//! and explicit marks are kept in `library.json` rather than alongside the plays; to carry those
+//! over too, use a backup instead.
+
+use crate::history::{Play, PlayHistory};
+use crate::music_db::MusicDB;
+use crate::resume::{Position, ResumePositions};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+/// The format written by `export`, bumped whenever it changes incompatibly
+pub const VERSION: u32 = 1;
+
+/// Everything exported, as written to the file.
+#[derive(Serialize, Deserialize)]
+pub struct Export {
+    pub version: u32,
+    /// When it was exported, in seconds since the Unix epoch
+    pub exported: u64,
+    pub songs: Vec<SongData>,
+}
+
+/// One song's plays and resume position.
+#[derive(Serialize, Deserialize)]
+pub struct SongData {
+    pub id: String,
+    #[serde(default)]
+    pub artist: String,
+    #[serde(default)]
+    pub album: String,
+    #[serde(default)]
+    pub title: String,
+    /// When it was played, in seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub plays: Vec<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resume: Option<Position>,
+}
+
+/// What an import did.
+#[derive(Serialize, Debug)]
+pub struct Imported {
+    pub plays: usize,
+    pub resume_positions: usize,
+    /// Songs that aren't in the library, whose data was skipped
+    pub unmatched: usize,
+}
+
+/// Gathers the data of every song that's been played or has a resume position. Songs no longer in
+/// the library are included with just their ids.
+pub fn export(db: &MusicDB, history: &PlayHistory, resume: &ResumePositions) -> Export {
+    let mut songs: BTreeMap<u64, SongData> = BTreeMap::new();
+    let new = |id: u64| {
+        let song = db.records.get(&id);
+        SongData {
+            id: id.to_string(),
+            artist: song.map(|s| s.artist.clone()).unwrap_or_default(),
+            album: song.map(|s| s.album.clone()).unwrap_or_default(),
+            title: song.map(|s| s.title.clone()).unwrap_or_default(),
+            plays: Vec::new(),
+            resume: None,
+        }
+    };
+
+    for play in &history.plays {
+        songs
+            .entry(play.id)
+            .or_insert_with(|| new(play.id))
+            .plays
+            .push(play.at);
+    }
+    for (&id, position) in resume.positions() {
+        songs.entry(id).or_insert_with(|| new(id)).resume = Some(position.clone());
+    }
+
+    Export {
+        version: VERSION,
+        exported: crate::history::now(),
+        songs: songs.into_values().collect(),
+    }
+}
+
+/// Adds the plays and resume positions in `export` for the songs in the library.
+pub fn import(
+    export: Export,
+    db: &MusicDB,
+    history: &mut PlayHistory,
+    resume: &mut ResumePositions,
+) -> Result<Imported, String> {
+    if export.version != VERSION {
+        return Err(format!(
+            "unsupported export version {} (expected {})",
+            export.version, VERSION
+        ));
+    }
+
+    let by_name = db
+        .records
+        .values()
+        .map(|s| ((&*s.artist_lower, &*s.album_lower, &*s.title_lower), s.id))
+        .collect::<HashMap<_, _>>();
+
+    let mut plays = Vec::new();
+    let mut positions = Vec::new();
+    let mut unmatched = 0;
+    for song in export.songs {
+        let by_id = song
+            .id
+            .parse()
+            .ok()
+            .filter(|id| db.records.contains_key(id));
+        let (artist, album, title) = (
+            song.artist.to_lowercase(),
+            song.album.to_lowercase(),
+            song.title.to_lowercase(),
+        );
+        let id = by_id.or_else(|| {
+            let found = by_name.get(&(artist.as_str(), album.as_str(), title.as_str()));
+            found.copied().filter(|_| !title.is_empty())
+        });
+        let Some(id) = id else {
+            unmatched += 1;
+            continue;
+        };
+
+        plays.extend(song.plays.into_iter().map(|at| Play {
+            id,
+            at,
+            device: None,
+        }));
+        positions.extend(song.resume.map(|position| (id, position)));
+    }
+
+    let plays = history
+        .import(plays)
+        .map_err(|e| format!("unable to save play history: {}", e))?;
+    Ok(Imported {
+        plays,
+        resume_positions: resume.import(positions),
+        unmatched,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::song::Song;
+
+    fn song(id: u64, title: &str) -> Song {
+        Song {
+            id,
+            artist: "ABBA".into(),
+            artist_lower: "abba".into(),
+            album: "Arrival".into(),
+            album_lower: "arrival".into(),
+            title: title.into(),
+            title_lower: title.to_lowercase(),
+            ..Default::default()
+        }
+    }
+
+    fn db(ids: [u64; 2]) -> MusicDB {
+        let mut db = MusicDB::default();
+        for (id, title) in ids
+            .into_iter()
+            .zip(["Dancing Queen", "Knowing Me, Knowing You"])
+        {
+            db.records.insert(id, song(id, title));
+        }
+        db
+    }
+
+    #[test]
+    fn round_trips_by_name_once() {
+        let mut history = PlayHistory::default();
+        history
+            .import(
+                [1, 2, 1]
+                    .into_iter()
+                    .zip([100, 200, 300])
+                    .map(|(id, at)| Play {
+                        id,
+                        at,
+                        device: None,
+                    }),
+            )
+            .unwrap();
+        let mut resume = ResumePositions::default();
+        resume.import([(
+            2,
+            Position {
+                position: 61.5,
+                at: 200,
+                device: None,
+            },
+        )]);
+        let json = serde_json::to_string(&export(&db([1, 2]), &history, &resume)).unwrap();
+
+        // The files moved, so the songs' ids did too
+        let elsewhere = db([11, 12]);
+        let (mut history, mut resume) = (PlayHistory::default(), ResumePositions::default());
+        let imported = import(
+            serde_json::from_str(&json).unwrap(),
+            &elsewhere,
+            &mut history,
+            &mut resume,
+        )
+        .unwrap();
+        assert_eq!(
+            (
+                imported.plays,
+                imported.resume_positions,
+                imported.unmatched
+            ),
+            (3, 1, 0)
+        );
+        let plays = history
+            .plays
+            .iter()
+            .map(|p| (p.id, p.at))
+            .collect::<Vec<_>>();
+        assert_eq!(plays, [(11, 100), (12, 200), (11, 300)]);
+        assert_eq!(resume.positions()[&12].position, 61.5);
+
+        let again = import(
+            serde_json::from_str(&json).unwrap(),
+            &elsewhere,
+            &mut history,
+            &mut resume,
+        )
+        .unwrap();
+        assert_eq!((again.plays, again.resume_positions), (0, 0));
+    }
+
+    #[test]
+    fn counts_plays_given_twice_once() {
+        let export = Export {
+            version: VERSION,
+            exported: 0,
+            songs: ["1", "1"]
+                .map(|id| SongData {
+                    id: id.into(),
+                    artist: String::new(),
+                    album: String::new(),
+                    title: String::new(),
+                    plays: vec![100, 100, 200],
+                    resume: None,
+                })
+                .into(),
+        };
+        let mut history = PlayHistory::default();
+        let imported = import(
+            export,
+            &db([1, 2]),
+            &mut history,
+            &mut ResumePositions::default(),
+        )
+        .unwrap();
+        assert_eq!(imported.plays, 2);
+        assert_eq!(history.plays.len(), 2);
+    }
+}
@@ -0,0 +1,244 @@
+//! Accounts for signing in to the web UI, saved in `users.json` with their passwords hashed
+//! (PBKDF2-SHA256). While there are none, the UI is open to anyone, as before.
+//!
+//! Accounts are added, or their passwords changed, from the command line, which reads the password
+//! from standard input, eg `echo 'hunter2' | bwaabwaa --set-password=me`. The first account is an
+//! admin; to make others admins, edit `users.json` while the server isn't running.
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{fs::File, io::BufReader, path::Path, sync::OnceLock};
+
+pub(crate) const USERS_FILE: &str = "users.json";
+
+/// PBKDF2 rounds for new passwords; saved with each hash, so it can be raised later
+const ROUNDS: u32 = 600_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct User {
+    pub name: String,
+    /// Can use `/admin` and `/api/v1` without an API key
+    #[serde(default)]
+    pub admin: bool,
+    /// `pbkdf2-sha256$<rounds>$<salt>$<hash>`, with the salt and hash in hex
+    password: String,
+}
+
+/// The accounts. Made with `default()`, eg in tests, they're only kept in memory, and their
+/// passwords are hashed with few rounds, to be quick.
+pub struct Users {
+    users: Vec<User>,
+    /// Whether changes are saved to `users.json`
+    saved: bool,
+    /// PBKDF2 rounds for new passwords
+    rounds: u32,
+    /// An account no password matches, to check passwords for names without one against
+    nobody: OnceLock<User>,
+}
+
+impl Default for Users {
+    fn default() -> Self {
+        Users {
+            users: Vec::new(),
+            saved: false,
+            rounds: 1000,
+            nobody: OnceLock::new(),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn derive(password: &str, salt: &[u8], rounds: u32) -> [u8; 32] {
+    let mut hash = [0; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, rounds, &mut hash);
+    hash
+}
+
+fn hash_password(password: &str, rounds: u32) -> String {
+    let mut salt = [0; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let hash = derive(password, &salt, rounds);
+    format!("pbkdf2-sha256${}${}${}", rounds, hex(&salt), hex(&hash))
+}
+
+impl User {
+    /// Whether `password` is this user's. Slow, by design.
+    pub fn verify(&self, password: &str) -> bool {
+        let mut parts = self.password.split('$');
+        let (Some("pbkdf2-sha256"), Some(rounds), Some(salt), Some(hash)) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return false;
+        };
+        let (Ok(rounds), Some(salt), Some(hash)) = (rounds.parse(), unhex(salt), unhex(hash))
+        else {
+            return false;
+        };
+
+        // Compare every byte, so the time taken doesn't say how much matched
+        let derived = derive(password, &salt, rounds);
+        hash.len() == derived.len()
+            && hash.iter().zip(derived).fold(0, |d, (a, b)| d | (a ^ b)) == 0
+    }
+}
+
+/// The accounts saved in `path`, or none if there's no such file.
+pub(crate) fn read(path: &Path) -> Result<Vec<User>, String> {
+    let users = match File::open(path) {
+        Ok(file) => serde_json::from_reader(BufReader::new(file)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("unable to read {}: {}", path.display(), e)),
+    };
+    users.map_err(|e| format!("unable to read {}: {}", path.display(), e))
+}
+
+impl Users {
+    /// Loads the accounts. Without `users.json` there are none; but one that can't be read is an
+    /// error, since taking it for none would open the UI to everyone.
+    pub fn load() -> Result<Self, String> {
+        Ok(Self {
+            users: read(Path::new(USERS_FILE))?,
+            saved: true,
+            rounds: ROUNDS,
+            nobody: OnceLock::new(),
+        })
+    }
+
+    fn save(&self) -> Result<(), std::io::Error> {
+        if !self.saved {
+            return Ok(());
+        }
+        let json = serde_json::to_vec_pretty(&self.users)?;
+        crate::paths::replace(Path::new(USERS_FILE), &json)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&User> {
+        self.users.iter().find(|u| u.name == name)
+    }
+
+    /// The account to check the password `name` signs in with against, and whether it's theirs.
+    /// For a name without an account, it's one that no password matches, but just as slow to
+    /// check, so that how long signing in takes doesn't say which names have accounts.
+    pub fn for_sign_in(&self, name: &str) -> (User, bool) {
+        match self.get(name) {
+            Some(user) => (user.clone(), true),
+            None => {
+                let nobody = self.nobody.get_or_init(|| {
+                    let mut password = [0; 16];
+                    rand::thread_rng().fill_bytes(&mut password);
+                    User {
+                        name: String::new(),
+                        admin: false,
+                        password: hash_password(&hex(&password), self.rounds),
+                    }
+                });
+                (nobody.clone(), false)
+            }
+        }
+    }
+
+    /// Sets a user's password, adding them if they're new.
+    pub fn set_password(&mut self, name: &str, password: &str) -> Result<(), std::io::Error> {
+        let password = hash_password(password, self.rounds);
+        match self.users.iter_mut().find(|u| u.name == name) {
+            Some(user) => user.password = password,
+            None => {
+                let admin = self.users.is_empty();
+                self.users.push(User {
+                    name: name.to_string(),
+                    admin,
+                    password,
+                });
+            }
+        }
+        self.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(password: String) -> User {
+        User {
+            name: "me".to_string(),
+            admin: false,
+            password,
+        }
+    }
+
+    #[test]
+    fn verifies_passwords() {
+        // Fewer rounds than for real, to keep the test quick
+        let me = user(hash_password("hunter2", 1000));
+        assert!(me.verify("hunter2"));
+        assert!(!me.verify("hunter3"));
+        assert!(!me.verify(""));
+
+        // The rounds are the hash's own
+        let (_, rest) = me.password.split_once("$1000$").unwrap();
+        assert!(!user(format!("pbkdf2-sha256$1001${}", rest)).verify("hunter2"));
+    }
+
+    #[test]
+    fn refuses_broken_files_but_not_missing_ones() {
+        let path = std::env::temp_dir().join(format!("bwaabwaa-users-{:x}", rand::random::<u64>()));
+        assert!(read(&path).unwrap().is_empty());
+        for broken in ["", "{", "{}", "[{\"name\": \"me\"}]"] {
+            std::fs::write(&path, broken).unwrap();
+            assert!(read(&path).is_err(), "{:?}", broken);
+        }
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn checks_names_without_accounts_as_slowly() {
+        let mut users = Users::default();
+        users.set_password("me", "hunter2").unwrap();
+
+        let (me, mine) = users.for_sign_in("me");
+        assert!(mine && me.verify("hunter2"));
+        let (nobody, theirs) = users.for_sign_in("them");
+        assert!(!theirs);
+        assert!(!nobody.verify("hunter2") && !nobody.verify(""));
+        // Hashed the same way, so it takes as long
+        assert_eq!(
+            nobody.password.split('$').nth(1),
+            me.password.split('$').nth(1)
+        );
+    }
+
+    #[test]
+    fn refuses_malformed_hashes() {
+        for password in [
+            "",
+            "hunter2",
+            "sha1$1000$00$00",
+            "pbkdf2-sha256$many$00$00",
+            "pbkdf2-sha256$1000$zz$00",
+            "pbkdf2-sha256$1000$00",
+            "pbkdf2-sha256$1000$00$00",
+        ] {
+            assert!(
+                !user(password.to_string()).verify("hunter2"),
+                "{}",
+                password
+            );
+        }
+    }
+}
@@ -0,0 +1,103 @@
+//! Outbound webhooks: JSON POSTs to the URLs listed in `webhooks.json` when things happen, eg
+//!
+//! ```json
+//! [
+//!     { "url": "http://homeassistant.local:8123/api/webhook/music" },
+//!     { "url": "https://discord.com/api/webhooks/...", "events": ["new_album"] }
+//! ]
+//! ```
+//!
+//! A hook with no `events` gets every event. Each POST body is the event, as published to the
+//! event bus; see `events`.
+//!
+//! `album_finished` and `queue_empty` are for home automation to react to the music stopping, eg
+//! Home Assistant turning the lights up or announcing something. They come from the jukebox, and
+//! from the web UI as it reports its progress (`POST /progress`) and asks for the next song.
+
+use crate::events::{EventKind, Published, Subscriber};
+use serde::Deserialize;
+use std::{fs::File, io::BufReader, sync::Arc, time::Duration};
+
+pub(crate) const WEBHOOKS_FILE: &str = "webhooks.json";
+
+/// How long to wait on a webhook before giving up on it.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct Webhook {
+    url: String,
+    /// The events to send; all of them if empty
+    #[serde(default)]
+    events: Vec<EventKind>,
+}
+
+impl Webhook {
+    fn wants(&self, kind: EventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&kind)
+    }
+}
+
+/// The configured webhooks. Cheap to clone.
+#[derive(Clone, Default)]
+pub struct Webhooks {
+    hooks: Arc<Vec<Webhook>>,
+    client: reqwest::Client,
+}
+
+impl Webhooks {
+    /// Loads the webhooks from `webhooks.json`, if it exists.
+    pub fn load() -> Self {
+        let hooks = match File::open(WEBHOOKS_FILE) {
+            Ok(file) => match serde_json::from_reader(BufReader::new(file)) {
+                Ok(hooks) => hooks,
+                Err(e) => {
+                    eprintln!("Ignoring {WEBHOOKS_FILE}: {}", e);
+                    Vec::new()
+                }
+            },
+            Err(_) => Vec::new(),
+        };
+
+        let client = reqwest::Client::builder()
+            .timeout(TIMEOUT)
+            .build()
+            .unwrap_or_default();
+
+        Webhooks {
+            hooks: Arc::new(hooks),
+            client,
+        }
+    }
+}
+
+impl Subscriber for Webhooks {
+    /// Sends the event to every webhook that wants it, in the background. Failures are logged and
+    /// otherwise ignored.
+    fn notify(&self, published: &Published) {
+        let kind = published.event.kind();
+        if !self.hooks.iter().any(|h| h.wants(kind)) {
+            return;
+        }
+
+        let body = match serde_json::to_vec(published) {
+            Ok(body) => body,
+            Err(_) => return,
+        };
+
+        for hook in self.hooks.iter().filter(|h| h.wants(kind)) {
+            let request = self
+                .client
+                .post(&hook.url)
+                .header("content-type", "application/json")
+                .body(body.clone());
+            let url = hook.url.clone();
+
+            tokio::spawn(async move {
+                match request.send().await.and_then(|r| r.error_for_status()) {
+                    Ok(_) => {}
+                    Err(e) => eprintln!("Webhook {} failed: {}", url, e),
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! The wishlist: tracks from imported playlists that weren't in the library, kept in
+//! `wishlist.json` so that `/wishlist` can show which albums are worth buying or ripping.
+//!
+//! After each scan, the wishlist is checked against the library again. Tracks found are taken off
+//! it and added to the end of the playlists they came from.
+
+use crate::music_db::MusicDB;
+use crate::playlist_import::{normalize, Matcher, TrackRef};
+use crate::playlists::Playlists;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, BufWriter},
+};
+
+pub(crate) const WISHLIST_FILE: &str = "wishlist.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Wish {
+    #[serde(flatten)]
+    pub track: TrackRef,
+    /// The ids of the playlists it was imported into
+    pub playlists: Vec<u64>,
+    /// When it was first wished for, in seconds since the Unix epoch
+    pub added: u64,
+}
+
+/// A track on the wishlist, as listed by `/wishlist`.
+#[derive(Serialize)]
+pub struct WishedTrack {
+    pub id: String,
+    pub title: String,
+    /// The names of the playlists waiting for it
+    pub playlists: Vec<String>,
+    pub added: u64,
+}
+
+/// The tracks wished for from one album. Tracks whose album isn't known are grouped by artist,
+/// with an empty `album`.
+#[derive(Serialize)]
+pub struct WishedAlbum {
+    pub artist: String,
+    pub album: String,
+    pub tracks: Vec<WishedTrack>,
+}
+
+/// Every track wished for, by id.
+#[derive(Default)]
+pub struct Wishlist {
+    wishes: BTreeMap<u64, Wish>,
+}
+
+/// What makes two wishes the same track
+fn key(track: &TrackRef) -> (String, String) {
+    (normalize(&track.artist), normalize(&track.title))
+}
+
+impl Wishlist {
+    pub fn load() -> Self {
+        let wishes = File::open(WISHLIST_FILE)
+            .ok()
+            .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+            .unwrap_or_default();
+
+        Self { wishes }
+    }
+
+    fn save(&self) {
+        let saved = File::create(WISHLIST_FILE).and_then(|file| {
+            serde_json::to_writer_pretty(BufWriter::new(file), &self.wishes)?;
+            Ok(())
+        });
+        if let Err(e) = saved {
+            eprintln!("Unable to save the wishlist: {:?}", e);
+        }
+    }
+
+    /// Wishes for tracks missing from `playlist`. A track already wished for is just noted as
+    /// wanted by this playlist too.
+    pub fn add(&mut self, playlist: u64, tracks: &[TrackRef]) {
+        if tracks.is_empty() {
+            return;
+        }
+        for track in tracks {
+            let wanted = key(track);
+            match self
+                .wishes
+                .values_mut()
+                .find(|wish| key(&wish.track) == wanted)
+            {
+                Some(wish) if !wish.playlists.contains(&playlist) => wish.playlists.push(playlist),
+                Some(_) => {}
+                None => {
+                    let id = self.wishes.keys().next_back().map_or(1, |last| last + 1);
+                    let wish = Wish {
+                        track: track.clone(),
+                        playlists: vec![playlist],
+                        added: crate::history::now(),
+                    };
+                    self.wishes.insert(id, wish);
+                }
+            }
+        }
+        self.save();
+    }
+
+    /// Takes a track off the wishlist, eg once it's clear it won't be found. Returns whether it
+    /// was there.
+    pub fn remove(&mut self, id: u64) -> bool {
+        let removed = self.wishes.remove(&id).is_some();
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    /// Every track wished for, by album, sorted by artist and album.
+    pub fn albums(&self, playlists: &Playlists) -> Vec<WishedAlbum> {
+        let mut albums: BTreeMap<(String, String), WishedAlbum> = BTreeMap::new();
+        for (&id, wish) in &self.wishes {
+            let TrackRef {
+                artist,
+                title,
+                album,
+            } = &wish.track;
+            let names = wish
+                .playlists
+                .iter()
+                .filter_map(|&playlist| playlists.get(playlist))
+                .map(|playlist| playlist.name.clone())
+                .collect();
+            albums
+                .entry((normalize(artist), normalize(album)))
+                .or_insert_with(|| WishedAlbum {
+                    artist: artist.clone(),
+                    album: album.clone(),
+                    tracks: Vec::new(),
+                })
+                .tracks
+                .push(WishedTrack {
+                    id: id.to_string(),
+                    title: title.clone(),
+                    playlists: names,
+                    added: wish.added,
+                });
+        }
+        albums.into_values().collect()
+    }
+
+    /// Looks for the tracks wished for in the library, adding those found to their playlists and
+    /// taking them off the wishlist. Returns how many were found.
+    pub fn resolve(&mut self, db: &MusicDB, playlists: &mut Playlists) -> usize {
+        let matcher = Matcher::new(db);
+        let found = self
+            .wishes
+            .iter()
+            .filter_map(|(&id, wish)| Some((id, matcher.find(&wish.track)?.id)))
+            .collect::<Vec<_>>();
+
+        for &(id, song) in &found {
+            if let Some(wish) = self.wishes.remove(&id) {
+                for playlist in wish.playlists {
+                    playlists.append(playlist, &[song]);
+                }
+            }
+        }
+        if !found.is_empty() {
+            self.save();
+        }
+        found.len()
+    }
+}
@@ -0,0 +1,49 @@
+// Keeps the app's shell cached so the page still opens without a connection to the server.
+// Assets under /static/ are cached as they're fetched; their URLs change with their content.
+// Everything else (searches, songs, art) always goes to the network.
+const CACHE = "bwaa-bwaa-v2";
+const SHELL = ["/", "/favicon.ico", "/manifest.json", "/icons/192.png", "/icons/512.png"];
+
+self.addEventListener("install", function (event) {
+	event.waitUntil(caches.open(CACHE).then(cache => cache.addAll(SHELL)));
+	self.skipWaiting();
+});
+
+self.addEventListener("activate", function (event) {
+	// Drop the caches of older versions
+	event.waitUntil(caches.keys().then(keys =>
+		Promise.all(keys.filter(key => key !== CACHE).map(key => caches.delete(key)))
+	));
+	self.clients.claim();
+});
+
+self.addEventListener("fetch", function (event) {
+	const url = new URL(event.request.url);
+	if (event.request.method !== "GET" || url.origin !== self.location.origin) {
+		return;
+	}
+
+	if (event.request.mode === "navigate") {
+		// The library page: prefer a fresh copy, refreshing the cached one
+		event.respondWith(fetch(event.request)
+			.then(function (response) {
+				if (url.pathname === "/" && response.ok) {
+					const copy = response.clone();
+					caches.open(CACHE).then(cache => cache.put("/", copy));
+				}
+				return response;
+			})
+			.catch(() => caches.match("/")));
+	} else if (SHELL.includes(url.pathname)) {
+		event.respondWith(caches.match(event.request).then(cached => cached || fetch(event.request)));
+	} else if (url.pathname.startsWith("/static/")) {
+		event.respondWith(caches.match(event.request).then(cached => cached || fetch(event.request)
+			.then(function (response) {
+				if (response.ok) {
+					const copy = response.clone();
+					caches.open(CACHE).then(cache => cache.put(event.request, copy));
+				}
+				return response;
+			})));
+	}
+});